@@ -0,0 +1,10 @@
+#![no_main]
+
+use kaitai::{testkit::FuzzSpec, KError};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Err(KError::Internal { msg }) = kaitai::parse_bytes_no_panic::<FuzzSpec>(data) {
+        panic!("runtime panicked: {}", msg);
+    }
+});
@@ -0,0 +1,246 @@
+use crate::BytesReader;
+use std::{cell::RefCell, rc::Rc};
+
+/// Number of buckets of the histograms in [`AccessStats`].
+pub const HISTOGRAM_BUCKETS: usize = 65;
+
+// thresholds of the recommendation
+const MIN_READS: u64 = 16;
+const TINY_READ: u64 = 16;
+const LARGE_READ: f64 = 4096.0;
+const FAR_SEEK: u64 = 64 * 1024;
+
+/// Accesses of a reader to its backend, recorded after
+/// [`BytesReader::track_access`].
+///
+/// The histograms count values by their magnitude: bucket `i` counts values
+/// `v` with `2^(i-1) <= v < 2^i`, bucket 0 counts zeros.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessStats {
+    pub reads: u64,
+    pub bytes_read: u64,
+    /// Sizes of the reads.
+    pub read_sizes: [u64; HISTOGRAM_BUCKETS],
+    /// Repositionings of the backend, i.e. reads not continuing where the
+    /// previous one ended.
+    pub seeks: u64,
+    pub backward_seeks: u64,
+    /// Distances of the seeks in either direction.
+    pub seek_distances: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl Default for AccessStats {
+    fn default() -> Self {
+        AccessStats {
+            reads: 0,
+            bytes_read: 0,
+            read_sizes: [0; HISTOGRAM_BUCKETS],
+            seeks: 0,
+            backward_seeks: 0,
+            seek_distances: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+}
+
+/// Histogram bucket of `v` in [`AccessStats`].
+pub fn histogram_bucket(v: u64) -> usize {
+    (u64::BITS - v.leading_zeros()) as usize
+}
+
+impl AccessStats {
+    // reads of at most `max` bytes, exact as `max` is one less than a power of two
+    fn reads_up_to(&self, max: u64) -> u64 {
+        self.read_sizes[..histogram_bucket(max) + 1].iter().sum()
+    }
+
+    fn seeks_beyond(&self, min: u64) -> u64 {
+        self.seek_distances[histogram_bucket(min)..].iter().sum()
+    }
+}
+
+/// How the backend of a reader could be configured better, judging by its
+/// accesses so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AccessRecommendation {
+    /// Too few reads to tell.
+    Insufficient,
+    /// Mostly tiny reads: buffer the source, e.g. read it into memory or
+    /// wrap it in a `BufReader`.
+    EnableBuffering,
+    /// Many long seeks: memory-map the file.
+    ConsiderMmap,
+    /// Large reads in order: read ahead, see
+    /// [`BytesReader::open_with_prefetch`].
+    Prefetch,
+    /// The current backend suits the accesses.
+    NoChange,
+}
+
+/// Summary of the [`AccessStats`] of a reader, see
+/// [`BytesReader::access_pattern_report`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessPatternReport {
+    pub stats: AccessStats,
+    pub mean_read_size: f64,
+    /// Share of reads of fewer than 16 bytes.
+    pub tiny_reads: f64,
+    /// Seeks farther than 64 KiB per read.
+    pub far_seeks: f64,
+    pub recommendation: AccessRecommendation,
+}
+
+impl AccessPatternReport {
+    pub fn new(stats: AccessStats) -> AccessPatternReport {
+        let reads = stats.reads.max(1) as f64;
+        let mean_read_size = stats.bytes_read as f64 / reads;
+        let tiny_reads = stats.reads_up_to(TINY_READ - 1) as f64 / reads;
+        let far_seeks = stats.seeks_beyond(FAR_SEEK) as f64 / reads;
+        let recommendation = if stats.reads < MIN_READS {
+            AccessRecommendation::Insufficient
+        } else if far_seeks > 0.25 {
+            AccessRecommendation::ConsiderMmap
+        } else if tiny_reads > 0.5 {
+            AccessRecommendation::EnableBuffering
+        } else if stats.backward_seeks == 0 && mean_read_size >= LARGE_READ {
+            AccessRecommendation::Prefetch
+        } else {
+            AccessRecommendation::NoChange
+        };
+        AccessPatternReport {
+            stats,
+            mean_read_size,
+            tiny_reads,
+            far_seeks,
+            recommendation,
+        }
+    }
+}
+
+impl BytesReader {
+    /// Record the accesses to the backend from now on, for
+    /// [`access_pattern_report`](Self::access_pattern_report). Clones made
+    /// afterwards record into the same [`AccessStats`].
+    pub fn track_access(mut self) -> Self {
+        self.access = Some(Rc::new(RefCell::new(AccessStats::default())));
+        self
+    }
+
+    /// The accesses recorded so far, if [`track_access`](Self::track_access)
+    /// was called.
+    pub fn access_stats(&self) -> Option<AccessStats> {
+        self.access.as_ref().map(|a| a.borrow().clone())
+    }
+
+    /// Summary of the accesses recorded so far with a recommendation for
+    /// the backend, if [`track_access`](Self::track_access) was called.
+    pub fn access_pattern_report(&self) -> Option<AccessPatternReport> {
+        self.access_stats().map(AccessPatternReport::new)
+    }
+
+    pub(crate) fn record_read(&self, len: usize) {
+        if let Some(access) = &self.access {
+            let mut stats = access.borrow_mut();
+            stats.reads += 1;
+            stats.bytes_read += len as u64;
+            stats.read_sizes[histogram_bucket(len as u64)] += 1;
+        }
+    }
+
+    pub(crate) fn record_seek(&self, from: u64, to: u64) {
+        if let Some(access) = &self.access {
+            let mut stats = access.borrow_mut();
+            stats.seeks += 1;
+            if to < from {
+                stats.backward_seeks += 1;
+            }
+            stats.seek_distances[histogram_bucket(from.abs_diff(to))] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KStream;
+
+    fn tracked(len: usize) -> BytesReader {
+        BytesReader::from(vec![0; len]).track_access()
+    }
+
+    fn recommendation(io: &BytesReader) -> AccessRecommendation {
+        io.access_pattern_report().unwrap().recommendation
+    }
+
+    #[test]
+    fn histograms() {
+        assert_eq!(histogram_bucket(0), 0);
+        assert_eq!(histogram_bucket(1), 1);
+        assert_eq!(histogram_bucket(3), 2);
+        assert_eq!(histogram_bucket(4), 3);
+        assert_eq!(histogram_bucket(u64::MAX), 64);
+
+        let io = tracked(10_000);
+        assert!(BytesReader::from(vec![]).access_stats().is_none());
+        io.read_u1().unwrap();
+        io.read_bytes(3).unwrap();
+        io.seek(5000).unwrap();
+        io.read_u4le().unwrap();
+        let clone = KStream::clone(&io);
+        clone.seek(1000).unwrap();
+        clone.read_bytes(1000).unwrap();
+        let stats = io.access_stats().unwrap();
+        assert_eq!(stats.reads, 4);
+        assert_eq!(stats.bytes_read, 1008);
+        assert_eq!(stats.read_sizes[1..4], [1, 1, 1]);
+        assert_eq!(stats.read_sizes[10], 1);
+        assert_eq!(stats.seeks, 2);
+        assert_eq!(stats.backward_seeks, 1);
+        // 4996 forward, 4004 backward
+        assert_eq!(stats.seek_distances[12..14], [1, 1]);
+    }
+
+    #[test]
+    fn recommendations() {
+        let io = tracked(100);
+        io.read_bytes(10).unwrap();
+        assert_eq!(recommendation(&io), AccessRecommendation::Insufficient);
+
+        // byte by byte
+        let io = tracked(1000);
+        while !io.is_eof() {
+            io.read_u1().unwrap();
+        }
+        assert_eq!(recommendation(&io), AccessRecommendation::EnableBuffering);
+
+        // records all over a large file
+        let io = tracked(4 << 20);
+        for i in 0..100u64 {
+            io.seek((i * 2_654_435_761 % ((4 << 20) - 512)) as usize)
+                .unwrap();
+            io.read_bytes(512).unwrap();
+        }
+        let report = io.access_pattern_report().unwrap();
+        assert_eq!(report.recommendation, AccessRecommendation::ConsiderMmap);
+        assert!(report.far_seeks > 0.9);
+
+        // large chunks in order
+        let io = tracked(1 << 20);
+        while !io.is_eof() {
+            io.read_bytes(16 * 1024).unwrap();
+        }
+        assert_eq!(recommendation(&io), AccessRecommendation::Prefetch);
+
+        // medium records with small gaps
+        let io = tracked(100_000);
+        for _ in 0..50 {
+            io.read_u4le().unwrap();
+            io.read_bytes(1000).unwrap();
+            io.skip(100).unwrap();
+        }
+        let report = io.access_pattern_report().unwrap();
+        assert_eq!(report.recommendation, AccessRecommendation::NoChange);
+        assert_eq!(report.tiny_reads, 0.5);
+        assert_eq!(report.mean_read_size, 502.0);
+    }
+}
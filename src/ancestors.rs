@@ -0,0 +1,216 @@
+use crate::{KError, KResult, KStructUnit, OptRc, SharedType};
+use std::{
+    any::{type_name, Any},
+    rc::{Rc, Weak},
+};
+
+/// Conversion of an `Rc` into `Rc<dyn Any>`, usable through trait objects.
+/// Implemented for every `'static` type.
+pub trait AsAnyRc {
+    fn into_any_rc(self: Rc<Self>) -> Rc<dyn Any>;
+}
+
+impl<T: Any> AsAnyRc for T {
+    fn into_any_rc(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
+}
+
+/// Object-safe access to the `_parent` link of a parsed struct, so that the
+/// tree can be climbed without knowing the nesting depth. Implementations
+/// usually forward to [`SharedType::link`]:
+///
+/// ```
+/// # use kaitai::*;
+/// # use std::rc::Rc;
+/// # #[derive(Default)]
+/// # struct Header;
+/// # impl KParentLink for Header {
+/// #     fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+/// #         Ok(None)
+/// #     }
+/// # }
+/// #[derive(Default)]
+/// struct Chunk {
+///     _parent: SharedType<Header>,
+/// }
+///
+/// impl KParentLink for Chunk {
+///     fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+///         self._parent.link()
+///     }
+/// }
+/// ```
+pub trait KParentLink: AsAnyRc {
+    /// The parent of this node, `None` at the top of the tree.
+    fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>>;
+
+    fn type_name(&self) -> &'static str {
+        type_name::<Self>()
+    }
+
+    /// Iterate over the parent, grandparent, ... of this node. A broken link
+    /// is reported as the last item.
+    fn ancestors(&self) -> Ancestors
+    where
+        Self: Sized,
+    {
+        Ancestors {
+            next: Some(self.parent_link()),
+        }
+    }
+
+    /// The nearest ancestor of type `T`. Fails with
+    /// [`KError::AncestorNotFound`] listing the types passed on the way up.
+    fn find_ancestor<T: Any>(&self) -> KResult<OptRc<T>>
+    where
+        Self: Sized,
+    {
+        let mut traversed = vec![];
+        let mut next = self.parent_link()?;
+        while let Some(link) = next {
+            traversed.push(link.type_name().to_string());
+            if let Ok(rc) = link.clone().into_any_rc().downcast::<T>() {
+                return Ok(OptRc::from(rc));
+            }
+            next = link.parent_link()?;
+        }
+        Err(KError::AncestorNotFound {
+            wanted: type_name::<T>().to_string(),
+            traversed,
+        })
+    }
+}
+
+impl KParentLink for KStructUnit {
+    fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+        Ok(None)
+    }
+}
+
+impl<T: KParentLink + 'static> SharedType<T> {
+    /// The linked node as a [`KParentLink`]. A link that was never set
+    /// yields `None`, one whose target has been dropped is an error.
+    pub fn link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+        let w = self.get_value().borrow();
+        match w.upgrade() {
+            Some(rc) => Ok(Some(rc)),
+            None if Weak::ptr_eq(&w, &Weak::new()) => Ok(None),
+            None => Err(KError::MissingParent),
+        }
+    }
+}
+
+/// Iterator returned by [`KParentLink::ancestors`].
+pub struct Ancestors {
+    next: Option<KResult<Option<Rc<dyn KParentLink>>>>,
+}
+
+impl Iterator for Ancestors {
+    type Item = KResult<Rc<dyn Any>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next.take()? {
+            Ok(Some(link)) => {
+                self.next = Some(link.parent_link());
+                Some(Ok(link.into_any_rc()))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default, Debug)]
+    struct Top {
+        version: u8,
+    }
+
+    impl KParentLink for Top {
+        fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+            Ok(None)
+        }
+    }
+
+    #[derive(Default, Debug)]
+    struct Mid {
+        tag: u8,
+        _parent: SharedType<Top>,
+    }
+
+    impl KParentLink for Mid {
+        fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+            self._parent.link()
+        }
+    }
+
+    #[derive(Default, Debug)]
+    struct Leaf {
+        _parent: SharedType<Mid>,
+    }
+
+    impl KParentLink for Leaf {
+        fn parent_link(&self) -> KResult<Option<Rc<dyn KParentLink>>> {
+            self._parent.link()
+        }
+    }
+
+    fn tree() -> (Rc<Top>, Rc<Mid>, Leaf) {
+        let top = Rc::new(Top { version: 3 });
+        let mid = Rc::new(Mid {
+            tag: 7,
+            _parent: SharedType::new(top.clone()),
+        });
+        let leaf = Leaf {
+            _parent: SharedType::new(mid.clone()),
+        };
+        (top, mid, leaf)
+    }
+
+    #[test]
+    fn find_middle_and_root() {
+        let (_top, _mid, leaf) = tree();
+        assert_eq!(leaf.find_ancestor::<Mid>().unwrap().tag, 7);
+        assert_eq!(leaf.find_ancestor::<Top>().unwrap().version, 3);
+        let types: Vec<_> = leaf
+            .ancestors()
+            .map(|a| {
+                let a = a.unwrap();
+                (a.is::<Mid>(), a.is::<Top>())
+            })
+            .collect();
+        assert_eq!(types, [(true, false), (false, true)]);
+    }
+
+    #[test]
+    fn not_found() {
+        let (_top, _mid, leaf) = tree();
+        match leaf.find_ancestor::<Leaf>().unwrap_err() {
+            KError::AncestorNotFound { wanted, traversed } => {
+                assert!(wanted.ends_with("Leaf"));
+                assert_eq!(traversed.len(), 2);
+                assert!(traversed[0].ends_with("::Mid"));
+                assert!(traversed[1].ends_with("::Top"));
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn broken_link() {
+        let (top, _mid, leaf) = tree();
+        drop(top);
+        assert!(leaf.find_ancestor::<Mid>().is_ok());
+        assert_eq!(
+            leaf.find_ancestor::<Top>().unwrap_err(),
+            KError::MissingParent
+        );
+        let items: Vec<_> = leaf.ancestors().map(|a| a.is_ok()).collect();
+        assert_eq!(items, [true, false]);
+        assert!(Leaf::default().ancestors().next().is_none());
+    }
+}
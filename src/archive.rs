@@ -0,0 +1,19 @@
+use crate::KError;
+
+// errors of the archive readers, with offsets within the archive file
+
+pub(crate) fn corrupt(offset: u64, msg: impl Into<String>) -> KError {
+    KError::CorruptArchive {
+        offset,
+        msg: msg.into(),
+    }
+}
+
+// structures running past the end of the file mean a broken archive
+pub(crate) fn corrupt_if_eof(e: KError, offset: u64) -> KError {
+    if e.is_eof() {
+        corrupt(offset, "unexpected end of archive")
+    } else {
+        e
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{KError, KResult, MAX_BITS_READ};
+
+/// The `width` bits starting at bit `bit_offset` of `bytes`, counting bits
+/// from the most significant one of each byte, as read by
+/// [`KStream::read_bits_int_be`](crate::KStream::read_bits_int_be).
+///
+/// Fails with [`KError::ReadBitsTooLarge`] for more than
+/// [`MAX_BITS_READ`] bits and with [`KError::EofBits`] if the bits are not
+/// all within `bytes`.
+///
+/// ```
+/// # use kaitai::*;
+/// assert_eq!(extract_bits_be(&[0b1010_0110, 0b1100_0000], 4, 6)?, 0b0110_11);
+/// # Ok::<(), KError>(())
+/// ```
+pub fn extract_bits_be(bytes: &[u8], bit_offset: usize, width: usize) -> KResult<u64> {
+    let (first, last) = byte_span(bytes, bit_offset, width)?;
+    let window = bytes[first..last]
+        .iter()
+        .fold(0u128, |acc, &b| acc << 8 | u128::from(b));
+    let trailing = (last - first) * 8 - (bit_offset % 8) - width;
+    Ok(((window >> trailing) & mask(width)) as u64)
+}
+
+/// [`extract_bits_be`] counting bits from the least significant one of
+/// each byte, as read by
+/// [`KStream::read_bits_int_le`](crate::KStream::read_bits_int_le).
+pub fn extract_bits_le(bytes: &[u8], bit_offset: usize, width: usize) -> KResult<u64> {
+    let (first, last) = byte_span(bytes, bit_offset, width)?;
+    let window = bytes[first..last]
+        .iter()
+        .rev()
+        .fold(0u128, |acc, &b| acc << 8 | u128::from(b));
+    Ok(((window >> (bit_offset % 8)) & mask(width)) as u64)
+}
+
+/// Store `value` in the `width` bits starting at bit `bit_offset` of
+/// `bytes`, the inverse of [`extract_bits_be`]. The other bits are kept.
+/// Fails as `extract_bits_be` does and with [`KError::ValueOutOfRange`]
+/// if `value` has more than `width` bits.
+pub fn insert_bits_be(
+    bytes: &mut [u8],
+    bit_offset: usize,
+    width: usize,
+    value: u64,
+) -> KResult<()> {
+    let (first, last) = byte_span(bytes, bit_offset, width)?;
+    check_fits(value, width)?;
+    let trailing = (last - first) * 8 - (bit_offset % 8) - width;
+    let window = &mut bytes[first..last];
+    let old = window
+        .iter()
+        .fold(0u128, |acc, &b| acc << 8 | u128::from(b));
+    let new = old & !(mask(width) << trailing) | u128::from(value) << trailing;
+    for (i, b) in window.iter_mut().rev().enumerate() {
+        *b = (new >> (i * 8)) as u8;
+    }
+    Ok(())
+}
+
+/// [`insert_bits_be`] with the bit order of [`extract_bits_le`].
+pub fn insert_bits_le(
+    bytes: &mut [u8],
+    bit_offset: usize,
+    width: usize,
+    value: u64,
+) -> KResult<()> {
+    let (first, last) = byte_span(bytes, bit_offset, width)?;
+    check_fits(value, width)?;
+    let shift = bit_offset % 8;
+    let window = &mut bytes[first..last];
+    let old = window
+        .iter()
+        .rev()
+        .fold(0u128, |acc, &b| acc << 8 | u128::from(b));
+    let new = old & !(mask(width) << shift) | u128::from(value) << shift;
+    for (i, b) in window.iter_mut().enumerate() {
+        *b = (new >> (i * 8)) as u8;
+    }
+    Ok(())
+}
+
+/// `value` of a `width`-bit two's complement field as a signed integer,
+/// e.g. `0b101` of 3 bits is -3. Bits above `width` are ignored; 0 bits
+/// are the value 0, more than 64 bits the same as 64.
+pub fn sign_extend(value: u64, width: usize) -> i64 {
+    if width == 0 {
+        return 0;
+    }
+    let shift = 64usize.saturating_sub(width);
+    ((value << shift) as i64) >> shift
+}
+
+// the bytes holding `width` bits at `bit_offset`, at most 9 of them
+fn byte_span(bytes: &[u8], bit_offset: usize, width: usize) -> KResult<(usize, usize)> {
+    if width > MAX_BITS_READ {
+        return Err(KError::ReadBitsTooLarge {
+            requested: width,
+            max: MAX_BITS_READ,
+        });
+    }
+    let total = (bytes.len() as u64).saturating_mul(8);
+    let available = total.saturating_sub(bit_offset as u64);
+    // an offset past the end is out of bounds even for 0 bits
+    if width as u64 > available || bit_offset as u64 > total {
+        return Err(KError::eof_bits(width, available, bit_offset / 8));
+    }
+    let first = bit_offset / 8;
+    let last = (bit_offset + width).div_ceil(8).max(first);
+    Ok((first, last))
+}
+
+fn mask(width: usize) -> u128 {
+    (1u128 << width) - 1
+}
+
+fn check_fits(value: u64, width: usize) -> KResult<()> {
+    if u128::from(value) > mask(width) {
+        return Err(KError::value_out_of_range(
+            value,
+            format!("{}-bit field", width),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{reference_bits_be, reference_bits_le, ScriptGen};
+    use crate::{BytesReader, KStream};
+
+    fn below(gen: &mut ScriptGen, n: usize) -> usize {
+        (gen.next_u64() % n as u64) as usize
+    }
+
+    #[test]
+    fn matches_reference() {
+        let mut gen = ScriptGen::new(5);
+        for _ in 0..2000 {
+            let data = gen.data(12);
+            let offset = below(&mut gen, data.len() * 8 + 2);
+            let width = below(&mut gen, 66);
+            let be = reference_bits_be(&data, offset, width);
+            let le = reference_bits_le(&data, offset, width);
+            assert_eq!(extract_bits_be(&data, offset, width).ok(), be);
+            assert_eq!(extract_bits_le(&data, offset, width).ok(), le);
+
+            let (Some(be), Some(le)) = (be, le) else {
+                continue;
+            };
+            // written back into other bits, only the field changes
+            let mut other: Vec<u8> = data.iter().map(|b| !b).collect();
+            insert_bits_be(&mut other, offset, width, be).unwrap();
+            assert_eq!(extract_bits_be(&other, offset, width).unwrap(), be);
+            for k in (0..data.len() * 8).filter(|k| !(offset..offset + width).contains(k)) {
+                assert_eq!(
+                    reference_bits_be(&other, k, 1),
+                    reference_bits_be(&data, k, 1).map(|b| b ^ 1)
+                );
+            }
+            let mut other: Vec<u8> = data.iter().map(|b| !b).collect();
+            insert_bits_le(&mut other, offset, width, le).unwrap();
+            assert_eq!(extract_bits_le(&other, offset, width).unwrap(), le);
+            for k in (0..data.len() * 8).filter(|k| !(offset..offset + width).contains(k)) {
+                assert_eq!(
+                    reference_bits_le(&other, k, 1),
+                    reference_bits_le(&data, k, 1).map(|b| b ^ 1)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_stream_reads() {
+        let mut gen = ScriptGen::new(9);
+        for _ in 0..500 {
+            let data = gen.data(16);
+            for le in [false, true] {
+                let reader = BytesReader::from(data.clone());
+                let mut bit_pos = 0;
+                loop {
+                    let width = below(&mut gen, 65);
+                    let read = if le {
+                        reader.read_bits_int_le(width)
+                    } else {
+                        reader.read_bits_int_be(width)
+                    };
+                    let extracted = if le {
+                        extract_bits_le(&data, bit_pos, width)
+                    } else {
+                        extract_bits_be(&data, bit_pos, width)
+                    };
+                    assert_eq!(read.is_ok(), extracted.is_ok());
+                    match read {
+                        Ok(v) => assert_eq!(v, extracted.unwrap()),
+                        Err(_) => break,
+                    }
+                    bit_pos += width;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sign_extension() {
+        assert_eq!(sign_extend(0, 1), 0);
+        assert_eq!(sign_extend(1, 1), -1);
+        assert_eq!(sign_extend(0b101, 3), -3);
+        assert_eq!(sign_extend(0b011, 3), 3);
+        assert_eq!(sign_extend(0xfff, 12), -1);
+        assert_eq!(sign_extend(0x800, 12), -2048);
+        assert_eq!(sign_extend(0x7ff, 12), 2047);
+        assert_eq!(sign_extend(u64::MAX, 64), -1);
+        assert_eq!(sign_extend(1 << 63, 64), i64::MIN);
+        assert_eq!(sign_extend(0xff, 0), 0);
+        // bits above the width do not matter
+        assert_eq!(sign_extend(0xf0f, 4), -1);
+    }
+
+    #[test]
+    fn bounds() {
+        let data = [0xff; 9];
+        assert_eq!(extract_bits_be(&data, 8, 64).unwrap(), u64::MAX);
+        assert_eq!(extract_bits_le(&data, 7, 64).unwrap(), u64::MAX);
+        assert_eq!(extract_bits_be(&data, 72, 0).unwrap(), 0);
+        assert_eq!(extract_bits_le(&[], 0, 0).unwrap(), 0);
+        assert_eq!(extract_bits_be(&data, 71, 1).unwrap(), 1);
+        assert_eq!(
+            extract_bits_be(&data, 71, 2).unwrap_err(),
+            KError::eof_bits(2, 1, 8)
+        );
+        assert!(extract_bits_le(&data, 73, 0).unwrap_err().is_eof());
+        assert!(extract_bits_le(&data, usize::MAX, 1).unwrap_err().is_eof());
+        assert_eq!(
+            extract_bits_be(&data, 0, 65).unwrap_err(),
+            KError::ReadBitsTooLarge {
+                requested: 65,
+                max: 64
+            }
+        );
+
+        let mut buf = [0; 2];
+        insert_bits_be(&mut buf, 4, 8, 0xab).unwrap();
+        assert_eq!(buf, [0x0a, 0xb0]);
+        insert_bits_le(&mut buf, 12, 4, 0x3).unwrap();
+        assert_eq!(buf, [0x0a, 0x30]);
+        assert_eq!(
+            insert_bits_be(&mut buf, 0, 3, 8).unwrap_err().category(),
+            crate::ErrorCategory::InvalidInput
+        );
+        assert!(insert_bits_le(&mut buf, 10, 7, 0).unwrap_err().is_eof());
+        assert_eq!(buf, [0x0a, 0x30]);
+    }
+}
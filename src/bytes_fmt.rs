@@ -0,0 +1,152 @@
+use std::fmt::{self, Write};
+
+/// Bytes as lowercase hex pairs, at most `max` of them followed by a count
+/// of the rest: `89 50 4e 47 … +1020 bytes`.
+pub fn format_bytes_hex(bytes: &[u8], max: usize) -> String {
+    let mut res = hex_prefix(bytes, max);
+    push_rest(&mut res, bytes.len(), max);
+    res
+}
+
+// the first `max` bytes as space-separated hex, without a marker for the
+// rest
+pub(crate) fn hex_prefix(bytes: &[u8], max: usize) -> String {
+    let mut res = String::new();
+    for (i, b) in bytes.iter().take(max).enumerate() {
+        if i > 0 {
+            res.push(' ');
+        }
+        write!(res, "{:02x}", b).unwrap();
+    }
+    res
+}
+
+/// Bytes as text: printable ASCII as is, a backslash doubled, anything
+/// else as `\xNN`. At most `max` bytes are shown, followed by a count of the
+/// rest: `IHDR\x00\x00\x01 … +1020 bytes`.
+pub fn format_bytes_escaped(bytes: &[u8], max: usize) -> String {
+    let mut res = String::new();
+    for &b in bytes.iter().take(max) {
+        match b {
+            b'\\' => res.push_str("\\\\"),
+            0x20..=0x7e => res.push(b as char),
+            _ => write!(res, "\\x{:02x}", b).unwrap(),
+        }
+    }
+    push_rest(&mut res, bytes.len(), max);
+    res
+}
+
+fn push_rest(res: &mut String, len: usize, max: usize) {
+    if len > max {
+        if !res.is_empty() {
+            res.push(' ');
+        }
+        write!(res, "… +{} bytes", len - max).unwrap();
+    }
+}
+
+/// Classic hex dump with 16 bytes per line and an ASCII column, as `Display`:
+///
+/// ```text
+/// 00000100  89 50 4e 47 0d 0a 1a 0a  00 00 00 0d 49 48 44 52  |.PNG........IHDR|
+/// ```
+///
+/// Lines are labelled with offsets counted from
+/// [`with_base_offset`](Self::with_base_offset), 0 by default.
+#[derive(Debug, Clone, Copy)]
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    base_offset: u64,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        HexDump {
+            bytes,
+            base_offset: 0,
+        }
+    }
+
+    pub fn with_base_offset(mut self, offset: u64) -> Self {
+        self.base_offset = offset;
+        self
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.bytes.chunks(16).enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:08x} ", self.base_offset + 16 * i as u64)?;
+            for j in 0..16 {
+                if j % 8 == 0 {
+                    f.write_str(" ")?;
+                }
+                match line.get(j) {
+                    Some(b) => write!(f, "{:02x} ", b)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str(" |")?;
+            for &b in line {
+                let c = if (0x20..=0x7e).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                };
+                f.write_char(c)?;
+            }
+            f.write_str("|")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\x01\0";
+
+    #[test]
+    fn hex() {
+        assert_eq!(format_bytes_hex(PNG, 4), "89 50 4e 47 … +16 bytes");
+        assert_eq!(
+            format_bytes_hex(PNG, 20),
+            "89 50 4e 47 0d 0a 1a 0a 00 00 00 0d 49 48 44 52 00 00 01 00"
+        );
+        assert_eq!(format_bytes_hex(PNG, 0), "… +20 bytes");
+        assert_eq!(format_bytes_hex(&[], 0), "");
+    }
+
+    #[test]
+    fn escaped() {
+        assert_eq!(
+            format_bytes_escaped(PNG, 100),
+            "\\x89PNG\\x0d\\x0a\\x1a\\x0a\\x00\\x00\\x00\\x0dIHDR\\x00\\x00\\x01\\x00"
+        );
+        assert_eq!(format_bytes_escaped(b"a\\b \"c\"~", 8), "a\\\\b \"c\"~");
+        assert_eq!(format_bytes_escaped(&PNG[12..], 4), "IHDR … +4 bytes");
+        assert_eq!(format_bytes_escaped(PNG, 0), "… +20 bytes");
+    }
+
+    #[test]
+    fn hex_dump() {
+        assert_eq!(
+            HexDump::new(PNG).to_string(),
+            "00000000  89 50 4e 47 0d 0a 1a 0a  00 00 00 0d 49 48 44 52  |.PNG........IHDR|\n\
+             00000010  00 00 01 00                                       |....|"
+        );
+        let text: Vec<u8> = (b'a'..=b'z').collect();
+        assert_eq!(
+            HexDump::new(&text[..9])
+                .with_base_offset(0x1_0000_0ff8)
+                .to_string(),
+            "100000ff8  61 62 63 64 65 66 67 68  69                       |abcdefghi|"
+        );
+        assert_eq!(HexDump::new(&[]).to_string(), "");
+    }
+}
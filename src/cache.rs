@@ -0,0 +1,333 @@
+use crate::{BytesReader, KError, KResult, KStruct};
+use std::{
+    any::Any,
+    convert::TryFrom,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// What identifies the contents of a file without reading it: a cached
+/// parse of the file is only used while its identity is unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileIdentity {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Hash of the contents, for files whose modification time is not
+    /// reliable, e.g. when copied with their time preserved.
+    pub content_hash: Option<u64>,
+}
+
+impl FileIdentity {
+    /// Identity of the file at `path` from its metadata and, with
+    /// `hash_content`, a hash of its contents.
+    pub fn of<P: AsRef<Path>>(path: P, hash_content: bool) -> KResult<Self> {
+        let meta = fs::metadata(&path)?;
+        let content_hash = if hash_content {
+            let mut hash = Fnv::new();
+            let mut file = File::open(&path)?;
+            let mut buf = [0; 8192];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hash.write(&buf[..n]);
+            }
+            Some(hash.0)
+        } else {
+            None
+        };
+        Ok(FileIdentity {
+            size: meta.len(),
+            modified: meta.modified().ok(),
+            content_hash,
+        })
+    }
+
+    // one line, compared as a whole against the line stored with an entry
+    fn encode(&self) -> String {
+        let modified = self
+            .modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or("-".to_string(), |d| d.as_nanos().to_string());
+        let hash = self
+            .content_hash
+            .map_or("-".to_string(), |h| format!("{:016x}", h));
+        format!("{} {} {}", self.size, modified, hash)
+    }
+}
+
+/// Storage of parse results by key, see [`parse_file_cached`].
+pub trait ParseCache {
+    /// The value stored for `key`, or `None` for a miss. An entry that
+    /// cannot be read is a miss.
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+    fn put(&self, key: &str, value: &[u8]) -> KResult<()>;
+}
+
+/// [`ParseCache`] keeping one file per entry in a directory, removing the
+/// least recently used entries when they take more than `max_bytes`.
+#[derive(Debug, Clone)]
+pub struct FsParseCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl FsParseCache {
+    /// Cache in `dir`, which is created if missing.
+    pub fn new<P: Into<PathBuf>>(dir: P, max_bytes: u64) -> KResult<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FsParseCache { dir, max_bytes })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        let mut hash = Fnv::new();
+        hash.write(key.as_bytes());
+        self.dir.join(format!("{:016x}.entry", hash.0))
+    }
+
+    // remove the least recently used entries until they fit
+    fn evict(&self) -> KResult<()> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "entry") {
+                continue;
+            }
+            let meta = fs::metadata(&path)?;
+            entries.push((meta.modified().unwrap_or(UNIX_EPOCH), meta.len(), path));
+        }
+        entries.sort();
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+// an entry is the length of the key, the key, guarding against hash
+// collisions, and the value
+impl ParseCache for FsParseCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.entry_path(key);
+        let data = fs::read(&path).ok()?;
+        let mut key_len = [0; 4];
+        key_len.copy_from_slice(data.get(..4)?);
+        let value_start = usize::try_from(u32::from_le_bytes(key_len))
+            .ok()?
+            .checked_add(4)?;
+        if data.get(4..value_start)? != key.as_bytes() {
+            return None;
+        }
+        // mark as recently used
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(data[value_start..].to_vec())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> KResult<()> {
+        let key_len = u32::try_from(key.len())
+            .map_err(|_| KError::value_out_of_range(key.len() as u64, "cache key length"))?;
+        let mut data = Vec::with_capacity(4 + key.len() + value.len());
+        data.extend_from_slice(&key_len.to_le_bytes());
+        data.extend_from_slice(key.as_bytes());
+        data.extend_from_slice(value);
+        if data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        // readers never see a partly written entry
+        let path = self.entry_path(key);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &data)?;
+        fs::rename(&tmp, &path)?;
+        self.evict()
+    }
+}
+
+/// The projection by `project` of the file at `path` parsed as `T`, from
+/// `cache` if it has one for the file as it is now.
+///
+/// A projection is whatever the caller needs of the parse in serialized
+/// form, e.g. an index of the records. It is stored with the
+/// [`FileIdentity`] of the file, with a content hash if `hash_content`, and
+/// only returned while the file has the same identity; otherwise the file
+/// is parsed again and the entry replaced. A cache that cannot be written
+/// does not fail the parse.
+pub fn parse_file_cached<T, P>(
+    path: P,
+    cache: &dyn ParseCache,
+    hash_content: bool,
+    project: impl FnOnce(&T) -> Vec<u8>,
+) -> KResult<Vec<u8>>
+where
+    T: KStruct + Default + Any,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let key = fs::canonicalize(path)?.display().to_string();
+    let identity = FileIdentity::of(path, hash_content)?.encode();
+    if let Some(entry) = cache.get(&key) {
+        if let Some(value) = entry
+            .strip_prefix(identity.as_bytes())
+            .and_then(|rest| rest.strip_prefix(b"\n"))
+        {
+            return Ok(value.to_vec());
+        }
+    }
+    let reader = BytesReader::open(path)?;
+    let parsed = T::read_into::<_, T>(&reader, None, None)?;
+    let value = project(&parsed);
+    let mut entry = identity.into_bytes();
+    entry.push(b'\n');
+    entry.extend_from_slice(&value);
+    let _ = cache.put(&key, &entry);
+    Ok(value)
+}
+
+// FNV-1a: fully specified, so entries stay valid across builds, unlike
+// with `DefaultHasher`
+pub(crate) struct Fnv(pub(crate) u64);
+
+impl Fnv {
+    pub(crate) fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KStream, KStructUnit, OptRc, SharedType};
+    use std::cell::{Cell, RefCell};
+    use std::time::Duration;
+
+    thread_local! {
+        static PARSES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[derive(Default)]
+    struct Index {
+        offsets: RefCell<Vec<u32>>,
+    }
+
+    impl KStruct for Index {
+        type Root = Index;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            PARSES.with(|n| n.set(n.get() + 1));
+            while !_io.is_eof() {
+                self_rc.offsets.borrow_mut().push(_io.read_u4le()?);
+            }
+            Ok(())
+        }
+    }
+
+    fn project(index: &Index) -> Vec<u8> {
+        index.offsets.borrow().iter().map(|&o| o as u8).collect()
+    }
+
+    fn parses() -> usize {
+        PARSES.with(Cell::get)
+    }
+
+    #[test]
+    fn hit_and_invalidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FsParseCache::new(dir.path().join("cache"), 1 << 20).unwrap();
+        let path = dir.path().join("index.bin");
+        fs::write(&path, [1, 0, 0, 0, 2, 0, 0, 0]).unwrap();
+        let before = parses();
+
+        for hash_content in [false, true] {
+            let parse = || parse_file_cached::<Index, _>(&path, &cache, hash_content, project);
+            assert_eq!(parse().unwrap(), [1, 2]);
+            let after_first = parses();
+            assert_eq!(parse().unwrap(), [1, 2]);
+            assert_eq!(parses(), after_first);
+        }
+        assert_eq!(parses(), before + 2);
+
+        // the same size and a new time
+        fs::write(&path, [3, 0, 0, 0, 4, 0, 0, 0]).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        let parse = || parse_file_cached::<Index, _>(&path, &cache, false, project);
+        assert_eq!(parse().unwrap(), [3, 4]);
+        assert_eq!(parse().unwrap(), [3, 4]);
+        assert_eq!(parses(), before + 3);
+
+        // the same size and time, other contents: only a hash notices
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, [5, 0, 0, 0, 6, 0, 0, 0]).unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(parse().unwrap(), [3, 4]);
+        let parse = || parse_file_cached::<Index, _>(&path, &cache, true, project);
+        assert_eq!(parse().unwrap(), [5, 6]);
+        assert_eq!(parses(), before + 4);
+
+        // parse errors are not cached
+        fs::write(&path, [7, 0]).unwrap();
+        assert!(parse().unwrap_err().is_eof());
+        assert!(parse().is_err());
+        assert_eq!(parses(), before + 6);
+    }
+
+    #[test]
+    fn eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FsParseCache::new(dir.path(), 250).unwrap();
+        let entry_len = 4 + 1 + 100;
+        let old = SystemTime::now() - Duration::from_secs(60);
+        for key in ["a", "b"] {
+            cache.put(key, &[0; 100]).unwrap();
+            File::options()
+                .write(true)
+                .open(cache.entry_path(key))
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+        // reading "a" makes "b" the least recently used
+        assert_eq!(cache.get("a").unwrap().len(), 100);
+        cache.put("c", &[0; 100]).unwrap();
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        let total: u64 = fs::read_dir(cache.dir())
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum();
+        assert_eq!(total, 2 * entry_len);
+
+        // too large to keep at all
+        cache.put("d", &[0; 300]).unwrap();
+        assert!(cache.get("d").is_none());
+        assert!(cache.get("missing").is_none());
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{cache::Fnv, usize_from_u64, BitState, BytesReader, KError, KResult, KStream};
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Bytes before the position whose hash identifies the data a
+/// [`Checkpoint`] was taken on.
+pub const CHECKPOINT_FINGERPRINT: usize = 4096;
+
+/// Where a reader over a file was, to continue reading after a restart
+/// with [`BytesReader::resume`].
+///
+/// Besides the position and bit state, a checkpoint keeps the size of the
+/// file and a hash of the [`CHECKPOINT_FINGERPRINT`] bytes before the
+/// position, so that data appended since is accepted but data rewritten is
+/// not. For a reader from [`BytesReader::open`] or
+/// [`BytesReader::open_lazy`] it also keeps the path and modification
+/// time of the file. With the `serde` feature, checkpoints implement
+/// `Serialize` and `Deserialize` to be persisted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    pos: u64,
+    bits: BitState,
+    size: u64,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    fingerprint_len: u64,
+    fingerprint: u64,
+}
+
+impl Checkpoint {
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn bit_state(&self) -> BitState {
+        self.bits
+    }
+
+    /// Size of the data when the checkpoint was taken.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Canonical path of the file, if the reader was opened from one.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Modification time of the file when the checkpoint was taken, if
+    /// known.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    // a deserialized checkpoint is not necessarily one `checkpoint` made
+    fn check_consistent(&self) -> KResult<()> {
+        if self.bits.bits_left >= 8 || self.fingerprint_len > self.pos || self.pos > self.size {
+            return Err(KError::invalid_input(0, "inconsistent checkpoint"));
+        }
+        Ok(())
+    }
+}
+
+// hash of the `len` bytes before `pos`
+fn fingerprint<S: KStream + ?Sized>(io: &S, pos: u64, len: u64) -> KResult<u64> {
+    let mut hash = Fnv::new();
+    hash.write(&io.read_at_checked(pos - len, usize_from_u64(len, "length")?, None)?);
+    Ok(hash.0)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl BytesReader {
+    /// Capture where this reader is, see [`Checkpoint`]. Meant for a
+    /// reader over a whole file, as positions are those of this reader.
+    pub fn checkpoint(&self) -> KResult<Checkpoint> {
+        let pos = self.pos() as u64;
+        let fingerprint_len = pos.min(CHECKPOINT_FINGERPRINT as u64);
+        let path = self.path.as_deref().map(canonical);
+        let modified = match &path {
+            Some(path) => fs::metadata(path)?.modified().ok(),
+            None => None,
+        };
+        Ok(Checkpoint {
+            pos,
+            bits: self.bit_state(),
+            size: self.size() as u64,
+            path,
+            modified,
+            fingerprint_len,
+            fingerprint: fingerprint(self, pos, fingerprint_len)?,
+        })
+    }
+
+    /// Open the file at `path` and continue where `checkpoint` was taken.
+    /// The file may have grown since, but nothing else about it may have
+    /// changed.
+    ///
+    /// Fails with [`KError::InvalidInput`] if `checkpoint` is inconsistent
+    /// or was taken on another file, with [`KError::SourceTruncated`] if
+    /// the file is now shorter than the checkpoint's position, and with
+    /// [`KError::SourceChanged`] if it shrank, was modified without
+    /// growing, or the bytes before the position differ.
+    pub fn resume<P: AsRef<Path>>(path: P, checkpoint: &Checkpoint) -> KResult<BytesReader> {
+        checkpoint.check_consistent()?;
+        let path = path.as_ref();
+        if let Some(expected) = checkpoint.path() {
+            if canonical(path) != expected {
+                return Err(KError::invalid_input(
+                    0,
+                    format!(
+                        "checkpoint of {}, not of {}",
+                        expected.display(),
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        let changed = || KError::source_changed(path.display().to_string());
+
+        let reader = BytesReader::open(path)?;
+        let pos = usize::try_from(checkpoint.pos)
+            .map_err(|_| KError::value_out_of_range(checkpoint.pos, "checkpoint position"))?;
+        let size = reader.size() as u64;
+        if size < checkpoint.pos {
+            return Err(KError::SourceTruncated { size, pos });
+        }
+        if size < checkpoint.size {
+            return Err(changed());
+        }
+        // appending is the only change allowed
+        let modified = fs::metadata(path)?.modified().ok();
+        if size == checkpoint.size
+            && checkpoint.modified.is_some()
+            && modified != checkpoint.modified
+        {
+            return Err(changed());
+        }
+        let found = fingerprint(&reader, checkpoint.pos, checkpoint.fingerprint_len)?;
+        if found != checkpoint.fingerprint {
+            return Err(changed());
+        }
+        reader.seek(pos)?;
+        reader.set_bit_state(checkpoint.bits);
+        Ok(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCategory;
+    use std::{
+        fs,
+        io::Write,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    // a u2le length, the bytes, and a 4-bit tag
+    fn record(io: &BytesReader) -> KResult<(Vec<u8>, u64)> {
+        io.align_to_byte()?;
+        let len = io.read_u2le()?;
+        let body = io.read_bytes(len.into())?;
+        Ok((body, io.read_bits_int_be(4)?))
+    }
+
+    fn records(n: usize) -> Vec<u8> {
+        (0..n)
+            .flat_map(|i| {
+                let len = (i * 7 % 600) as u16;
+                let mut rec = len.to_le_bytes().to_vec();
+                rec.extend((0..len).map(|k| (k as usize * 31 + i) as u8));
+                rec.push((i as u8 & 0xf) << 4 | 0x5);
+                rec
+            })
+            .collect()
+    }
+
+    // what a restarted process would read back
+    #[cfg(feature = "serde")]
+    fn persisted(checkpoint: Checkpoint) -> Checkpoint {
+        let text = ron::to_string(&checkpoint).unwrap();
+        ron::from_str(&text).unwrap()
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn persisted(checkpoint: Checkpoint) -> Checkpoint {
+        checkpoint
+    }
+
+    #[test]
+    fn resume_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.bin");
+        let data = records(40);
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+
+        let saved = {
+            let reader = BytesReader::open(&path).unwrap();
+            for _ in 0..10 {
+                record(&reader).unwrap();
+            }
+            // within the byte of a tag
+            assert_eq!(reader.bit_state().bits_left, 4);
+            let checkpoint = reader.checkpoint().unwrap();
+            assert_eq!(checkpoint.path(), Some(&*fs::canonicalize(&path).unwrap()));
+            assert!(checkpoint.modified().is_some());
+            persisted(checkpoint)
+        };
+
+        // appended to while the job was down
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&data[data.len() / 2..]).unwrap();
+        drop(file);
+
+        let resumed = BytesReader::resume(&path, &saved).unwrap();
+        let sequential = BytesReader::from(data.clone());
+        for _ in 0..10 {
+            record(&sequential).unwrap();
+        }
+        assert_eq!(resumed.pos(), sequential.pos());
+        assert_eq!(resumed.read_bits_int_be(4).unwrap(), 0x5);
+        sequential.read_bits_int_be(4).unwrap();
+        for _ in 10..40 {
+            assert_eq!(record(&resumed).unwrap(), record(&sequential).unwrap());
+        }
+        assert_eq!(resumed.pos(), data.len());
+    }
+
+    #[test]
+    fn changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.bin");
+        let data = records(20);
+        fs::write(&path, &data).unwrap();
+        let reader = BytesReader::open(&path).unwrap();
+        for _ in 0..12 {
+            record(&reader).unwrap();
+        }
+        let checkpoint = reader.checkpoint().unwrap();
+        assert_eq!(checkpoint.size(), data.len() as u64);
+        let changed = KError::source_changed(path.display().to_string());
+
+        let mut rewritten = data.clone();
+        rewritten[checkpoint.pos() as usize - 1] ^= 1;
+        fs::write(&path, &rewritten).unwrap();
+        assert_eq!(
+            BytesReader::resume(&path, &checkpoint).unwrap_err(),
+            changed
+        );
+
+        // the same bytes, written again
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&data).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        drop(file);
+        assert_eq!(
+            BytesReader::resume(&path, &checkpoint).unwrap_err(),
+            changed
+        );
+
+        // shorter, although still past the position
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(
+            BytesReader::resume(&path, &checkpoint).unwrap_err(),
+            changed
+        );
+
+        fs::write(&path, &data[..100]).unwrap();
+        assert!(matches!(
+            BytesReader::resume(&path, &checkpoint),
+            Err(KError::SourceTruncated { size: 100, .. })
+        ));
+
+        // at the start nothing is fingerprinted
+        let start = BytesReader::from(vec![1, 2]).checkpoint().unwrap();
+        assert_eq!(start.path(), None);
+        assert_eq!(BytesReader::resume(&path, &start).unwrap().pos(), 0);
+    }
+
+    #[test]
+    fn other_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.bin"), dir.path().join("b.bin"));
+        fs::write(&a, records(5)).unwrap();
+        fs::write(&b, records(5)).unwrap();
+        let reader = BytesReader::open(&a).unwrap();
+        record(&reader).unwrap();
+        let checkpoint = reader.checkpoint().unwrap();
+
+        let err = BytesReader::resume(&b, &checkpoint).unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::InvalidInput, "{:?}", err);
+        assert!(BytesReader::resume(&a, &checkpoint).is_ok());
+    }
+
+    #[test]
+    fn inconsistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.bin");
+        fs::write(&path, records(5)).unwrap();
+        let reader = BytesReader::from(records(5));
+        reader.read_bits_int_be(13).unwrap();
+        let checkpoint = reader.checkpoint().unwrap();
+
+        let bad = [
+            Checkpoint {
+                bits: BitState {
+                    bits: 0,
+                    bits_left: 8,
+                },
+                ..checkpoint.clone()
+            },
+            Checkpoint {
+                fingerprint_len: checkpoint.pos + 1,
+                ..checkpoint.clone()
+            },
+            Checkpoint {
+                size: checkpoint.pos - 1,
+                ..checkpoint.clone()
+            },
+        ];
+        for bad in &bad {
+            let err = BytesReader::resume(&path, bad).unwrap_err();
+            assert_eq!(err.category(), ErrorCategory::InvalidInput, "{:?}", err);
+        }
+    }
+}
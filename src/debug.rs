@@ -0,0 +1,269 @@
+use crate::visit::{KValue, KVisit, KVisitor};
+use crate::{bytes_fmt::hex_prefix, format_float};
+use std::fmt;
+
+/// `Debug` rendering of a parsed tree with limits on its size, so that large
+/// roots can be printed safely. Built through [`KVisit::kdebug`] or
+/// [`KDebug::new`]:
+///
+/// ```
+/// # use kaitai::*;
+/// struct Blob {
+///     data: Vec<u8>,
+/// }
+///
+/// impl KVisit for Blob {
+///     fn visit(&self, v: &mut dyn KVisitor) {
+///         v.field("data", (&self.data).into());
+///     }
+/// }
+///
+/// let blob = Blob { data: vec![0; 4100] };
+/// assert_eq!(
+///     format!("{:?}", blob.kdebug().max_bytes(4)),
+///     "{data: [00 00 00 00 … 4096 more bytes]}"
+/// );
+/// ```
+///
+/// All limits are unset by default.
+pub struct KDebug<'a, T: ?Sized> {
+    root: &'a T,
+    limits: Limits,
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    max_depth: usize,
+    max_bytes: usize,
+    max_list: usize,
+}
+
+impl<'a, T: KVisit + ?Sized> KDebug<'a, T> {
+    pub fn new(root: &'a T) -> Self {
+        KDebug {
+            root,
+            limits: Limits {
+                max_depth: usize::MAX,
+                max_bytes: usize::MAX,
+                max_list: usize::MAX,
+            },
+        }
+    }
+
+    /// Number of struct levels shown below the root; deeper structs are
+    /// rendered as `{…}`.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.limits.max_depth = depth;
+        self
+    }
+
+    /// Number of bytes shown per byte array.
+    pub fn max_bytes(mut self, len: usize) -> Self {
+        self.limits.max_bytes = len;
+        self
+    }
+
+    /// Number of items shown per list.
+    pub fn max_list(mut self, len: usize) -> Self {
+        self.limits.max_list = len;
+        self
+    }
+}
+
+impl<T: KVisit + ?Sized> fmt::Debug for KDebug<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = f.debug_map();
+        self.root.visit(&mut Fields {
+            map: &mut fields,
+            depth: 0,
+            limits: self.limits,
+        });
+        fields.finish()
+    }
+}
+
+struct Fields<'a, 'b, 'c> {
+    map: &'a mut fmt::DebugMap<'b, 'c>,
+    depth: usize,
+    limits: Limits,
+}
+
+impl KVisitor for Fields<'_, '_, '_> {
+    fn field(&mut self, name: &str, value: KValue<'_>) {
+        let value = Value {
+            value,
+            depth: self.depth + 1,
+            limits: self.limits,
+        };
+        self.map.entry(&Raw(name), &value);
+    }
+}
+
+// written as is, without the quotes of `str`'s `Debug`
+struct Raw<'a>(&'a str);
+
+impl fmt::Debug for Raw<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+struct Value<'a> {
+    value: KValue<'a>,
+    depth: usize,
+    limits: Limits,
+}
+
+impl Value<'_> {
+    fn nested<'a>(&self, value: KValue<'a>) -> Value<'a> {
+        Value {
+            value,
+            depth: self.depth,
+            limits: self.limits,
+        }
+    }
+}
+
+impl fmt::Debug for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            KValue::None => f.write_str("None"),
+            KValue::Bool(v) => write!(f, "{}", v),
+            KValue::Int(v) => write!(f, "{}", v),
+            KValue::UInt(v) => write!(f, "{}", v),
+            KValue::Float(v) => f.write_str(&format_float(v)),
+            KValue::Bytes(v) => {
+                f.write_str("[")?;
+                f.write_str(&hex_prefix(v, self.limits.max_bytes))?;
+                if v.len() > self.limits.max_bytes {
+                    if self.limits.max_bytes > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "… {} more bytes", v.len() - self.limits.max_bytes)?;
+                }
+                f.write_str("]")
+            }
+            KValue::Str(v) => write!(f, "{:?}", v),
+            KValue::Enum {
+                name: Some(name),
+                value,
+            } => write!(f, "{}({})", name, value),
+            KValue::Enum { name: None, value } => write!(f, "{}", value),
+            KValue::Struct(_) if self.depth > self.limits.max_depth => f.write_str("{…}"),
+            KValue::Struct(s) => {
+                let mut fields = f.debug_map();
+                s.visit(&mut Fields {
+                    map: &mut fields,
+                    depth: self.depth,
+                    limits: self.limits,
+                });
+                fields.finish()
+            }
+            KValue::List(l) => {
+                let mut items = f.debug_list();
+                let shown = l.len().min(self.limits.max_list);
+                for i in 0..shown {
+                    items.entry(&self.nested(l.item(i)));
+                }
+                if l.len() > shown {
+                    items.entry(&Raw(&format!("… {} more items", l.len() - shown)));
+                }
+                items.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OptRc;
+
+    struct Chunk {
+        tag: String,
+        data: Vec<u8>,
+        children: Vec<OptRc<Chunk>>,
+    }
+
+    impl KVisit for Chunk {
+        fn visit(&self, v: &mut dyn KVisitor) {
+            v.field("tag", (&self.tag).into());
+            v.field("data", (&self.data).into());
+            v.field("children", KValue::List(&self.children));
+        }
+    }
+
+    fn chunk(tag: &str, len: usize, children: Vec<OptRc<Chunk>>) -> OptRc<Chunk> {
+        OptRc::from(Chunk {
+            tag: tag.to_string(),
+            data: (0..len).map(|i| i as u8).collect(),
+            children,
+        })
+    }
+
+    fn tree() -> OptRc<Chunk> {
+        let leaves = (0..10).map(|_| chunk("leaf", 1, vec![])).collect();
+        chunk("root", 4096, vec![chunk("mid", 2, leaves)])
+    }
+
+    #[test]
+    fn unlimited() {
+        let t = chunk("a", 2, vec![chunk("b", 0, vec![])]);
+        assert_eq!(
+            format!("{:?}", t.kdebug()),
+            r#"{tag: "a", data: [00 01], children: [{tag: "b", data: [], children: []}]}"#
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        let t = tree();
+        assert_eq!(
+            format!("{:?}", t.kdebug().max_depth(1).max_bytes(0).max_list(1)),
+            concat!(
+                r#"{tag: "root", data: [… 4096 more bytes], children: [{tag: "mid", "#,
+                r#"data: [… 2 more bytes], children: [{…}, … 9 more items]}]}"#
+            )
+        );
+        assert_eq!(
+            format!("{:?}", KDebug::new(&t).max_depth(0).max_bytes(0)),
+            r#"{tag: "root", data: [… 4096 more bytes], children: [{…}]}"#
+        );
+    }
+
+    #[test]
+    fn max_bytes() {
+        let t = chunk("a", 4100, vec![]);
+        assert_eq!(
+            format!("{:?}", t.kdebug().max_bytes(4)),
+            r#"{tag: "a", data: [00 01 02 03 … 4096 more bytes], children: []}"#
+        );
+        assert_eq!(
+            format!("{:?}", t.kdebug().max_bytes(4100)),
+            format!("{:?}", t.kdebug())
+        );
+    }
+
+    #[test]
+    fn max_list() {
+        let t = tree();
+        assert_eq!(
+            format!("{:?}", t.kdebug().max_depth(3).max_bytes(1).max_list(2)),
+            concat!(
+                r#"{tag: "root", data: [00 … 4095 more bytes], children: [{tag: "mid", "#,
+                r#"data: [00 … 1 more bytes], children: [{tag: "leaf", data: [00], "#,
+                r#"children: []}, {tag: "leaf", data: [00], children: []}, "#,
+                r#"… 8 more items]}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn pretty() {
+        let t = chunk("a", 3, vec![chunk("b", 0, vec![]), chunk("c", 0, vec![])]);
+        assert_eq!(
+            format!("{:#?}", t.kdebug().max_depth(0).max_list(1).max_bytes(2)),
+            "{\n    tag: \"a\",\n    data: [00 01 … 1 more bytes],\n    children: [\n        {…},\n        … 1 more items,\n    ],\n}"
+        );
+    }
+}
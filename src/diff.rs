@@ -0,0 +1,287 @@
+use crate::visit::{KValue, KVisit, Node};
+use crate::{format_bytes_hex, format_float};
+
+/// How two trees differ at a [`DiffEntry::path`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Changed,
+    /// Only present in the second tree (its list is longer).
+    Added,
+    /// Only present in the first tree (its list is longer).
+    Removed,
+}
+
+/// Single difference found by [`deep_diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    /// Location of the value, e.g. `header.entries[3].name`.
+    pub path: String,
+    pub kind: DiffKind,
+    pub left: Option<String>,
+    pub right: Option<String>,
+    /// For byte arrays, index of the first differing byte.
+    pub first_difference: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Number of bytes shown when rendering byte arrays.
+    pub max_bytes: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions { max_bytes: 16 }
+    }
+}
+
+/// Compare two parsed trees field by field.
+pub fn deep_diff(a: &impl KVisit, b: &impl KVisit, opts: &DiffOptions) -> Vec<DiffEntry> {
+    diff_trees(
+        &Node::of(KValue::Struct(a)),
+        &Node::of(KValue::Struct(b)),
+        opts,
+    )
+}
+
+// `deep_diff` of trees already collected
+pub(crate) fn diff_trees(a: &Node, b: &Node, opts: &DiffOptions) -> Vec<DiffEntry> {
+    let mut res = vec![];
+    diff_nodes("", a, b, opts, &mut res);
+    res
+}
+
+fn diff_nodes(path: &str, a: &Node, b: &Node, opts: &DiffOptions, res: &mut Vec<DiffEntry>) {
+    match (a, b) {
+        (Node::Struct(fa), Node::Struct(fb)) if same_fields(fa, fb) => {
+            for ((name, va), (_, vb)) in fa.iter().zip(fb) {
+                let path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                diff_nodes(&path, va, vb, opts, res);
+            }
+        }
+        (Node::List(la), Node::List(lb)) => {
+            for (i, (va, vb)) in la.iter().zip(lb).enumerate() {
+                diff_nodes(&format!("{}[{}]", path, i), va, vb, opts, res);
+            }
+            for (i, v) in la.iter().enumerate().skip(lb.len()) {
+                res.push(DiffEntry {
+                    path: format!("{}[{}]", path, i),
+                    kind: DiffKind::Removed,
+                    left: Some(render(v, opts)),
+                    right: None,
+                    first_difference: None,
+                });
+            }
+            for (i, v) in lb.iter().enumerate().skip(la.len()) {
+                res.push(DiffEntry {
+                    path: format!("{}[{}]", path, i),
+                    kind: DiffKind::Added,
+                    left: None,
+                    right: Some(render(v, opts)),
+                    first_difference: None,
+                });
+            }
+        }
+        _ if same_value(a, b) => {}
+        _ => {
+            let first_difference = match (a, b) {
+                (Node::Bytes(x), Node::Bytes(y)) => Some(
+                    x.iter()
+                        .zip(y)
+                        .position(|(p, q)| p != q)
+                        .unwrap_or_else(|| x.len().min(y.len())),
+                ),
+                _ => None,
+            };
+            let (mut left, mut right) = (render(a, opts), render(b, opts));
+            // e.g. NaNs with different payloads
+            if let (Node::Float(x), Node::Float(y)) = (a, b) {
+                if left == right {
+                    left = format!("{} ({:#018x})", left, x.to_bits());
+                    right = format!("{} ({:#018x})", right, y.to_bits());
+                }
+            }
+            res.push(DiffEntry {
+                path: path.to_string(),
+                kind: DiffKind::Changed,
+                left: Some(left),
+                right: Some(right),
+                first_difference,
+            });
+        }
+    }
+}
+
+// floats compare by bit pattern, so that NaN equals itself and -0.0 does
+// not equal 0.0
+fn same_value(a: &Node, b: &Node) -> bool {
+    match (a, b) {
+        (Node::Float(x), Node::Float(y)) => x.to_bits() == y.to_bits(),
+        _ => a == b,
+    }
+}
+
+fn same_fields(a: &[(String, Node)], b: &[(String, Node)]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|((x, _), (y, _))| x == y)
+}
+
+fn render(node: &Node, opts: &DiffOptions) -> String {
+    match node {
+        Node::None => "none".to_string(),
+        Node::Bool(v) => v.to_string(),
+        Node::Int(v) => v.to_string(),
+        Node::UInt(v) => v.to_string(),
+        Node::Float(v) => format_float(*v),
+        Node::Bytes(v) => format!("[{}]", format_bytes_hex(v, opts.max_bytes)),
+        Node::Str(v) => format!("{:?}", v),
+        Node::Enum {
+            name: Some(name),
+            value,
+        } => format!("{}({})", name, value),
+        Node::Enum { name: None, value } => value.to_string(),
+        Node::Struct(fields) => format!("{{{} fields}}", fields.len()),
+        Node::List(items) => format!("[{} items]", items.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visit::KVisitor;
+    use crate::OptRc;
+
+    #[derive(Clone)]
+    struct Entry {
+        id: u16,
+        payload: Vec<u8>,
+    }
+
+    impl KVisit for Entry {
+        fn visit(&self, v: &mut dyn KVisitor) {
+            v.field("id", (&self.id).into());
+            v.field("payload", (&self.payload).into());
+        }
+    }
+
+    #[derive(Clone)]
+    struct File {
+        version: u8,
+        ratio: f32,
+        entries: Vec<OptRc<Entry>>,
+    }
+
+    impl KVisit for File {
+        fn visit(&self, v: &mut dyn KVisitor) {
+            v.field("version", (&self.version).into());
+            v.field("ratio", (&self.ratio).into());
+            v.field("entries", KValue::List(&self.entries));
+        }
+    }
+
+    fn sample() -> File {
+        File {
+            version: 1,
+            ratio: 0.5,
+            entries: vec![
+                OptRc::from(Entry {
+                    id: 1,
+                    payload: vec![1, 2, 3],
+                }),
+                OptRc::from(Entry {
+                    id: 2,
+                    payload: vec![0; 40],
+                }),
+            ],
+        }
+    }
+
+    #[test]
+    fn identical_trees() {
+        assert_eq!(deep_diff(&sample(), &sample(), &DiffOptions::default()), []);
+    }
+
+    #[test]
+    fn changed_scalars() {
+        let a = sample();
+        let mut b = sample();
+        b.version = 2;
+        b.ratio = 0.1;
+        let mut payload = vec![0; 40];
+        payload[20] = 0xff;
+        b.entries[1] = OptRc::from(Entry { id: 2, payload });
+
+        let opts = DiffOptions { max_bytes: 4 };
+        assert_eq!(
+            deep_diff(&a, &b, &opts),
+            [
+                DiffEntry {
+                    path: "version".to_string(),
+                    kind: DiffKind::Changed,
+                    left: Some("1".to_string()),
+                    right: Some("2".to_string()),
+                    first_difference: None,
+                },
+                DiffEntry {
+                    path: "ratio".to_string(),
+                    kind: DiffKind::Changed,
+                    left: Some("0.5".to_string()),
+                    right: Some("0.10000000149011612".to_string()),
+                    first_difference: None,
+                },
+                DiffEntry {
+                    path: "entries[1].payload".to_string(),
+                    kind: DiffKind::Changed,
+                    left: Some("[00 00 00 00 … +36 bytes]".to_string()),
+                    right: Some("[00 00 00 00 … +36 bytes]".to_string()),
+                    first_difference: Some(20),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn list_length() {
+        let a = sample();
+        let mut b = sample();
+        b.entries.push(OptRc::from(Entry {
+            id: 3,
+            payload: vec![],
+        }));
+
+        let diff = deep_diff(&a, &b, &DiffOptions::default());
+        assert_eq!(
+            diff,
+            [DiffEntry {
+                path: "entries[2]".to_string(),
+                kind: DiffKind::Added,
+                left: None,
+                right: Some("{2 fields}".to_string()),
+                first_difference: None,
+            }]
+        );
+        let diff = deep_diff(&b, &a, &DiffOptions::default());
+        assert_eq!(diff[0].kind, DiffKind::Removed);
+        assert_eq!(diff[0].left, Some("{2 fields}".to_string()));
+    }
+
+    #[test]
+    fn float_bit_patterns() {
+        let with_ratio = |ratio: f32| File { ratio, ..sample() };
+        let opts = DiffOptions::default();
+        let nan = f32::from_bits(0x7fc0_0001);
+        assert_eq!(deep_diff(&with_ratio(nan), &with_ratio(nan), &opts), []);
+
+        let diff = deep_diff(&with_ratio(0.0), &with_ratio(-0.0), &opts);
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].path, "ratio");
+
+        let diff = deep_diff(&with_ratio(nan), &with_ratio(f32::NAN), &opts);
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].left.as_deref(), Some("NaN (0x7ff8000020000000)"));
+        assert_eq!(diff[0].right.as_deref(), Some("NaN (0x7ff8000000000000)"));
+    }
+}
@@ -0,0 +1,335 @@
+//! Marker types and traits for [`KStream::read_int`](crate::KStream::read_int)
+//! and [`KStream::read_float`](crate::KStream::read_float).
+
+pub(crate) mod sealed {
+    pub trait ByteOrder {
+        const BIG: bool;
+    }
+
+    /// Fixed-size value decodable from its byte representation.
+    pub trait Primitive: Sized {
+        const SIZE: usize;
+        fn from_be_slice(bytes: &[u8]) -> Self;
+        fn from_le_slice(bytes: &[u8]) -> Self;
+        fn from_ne_slice(bytes: &[u8]) -> Self;
+    }
+}
+
+use crate::{KError, KResult};
+use sealed::Primitive;
+use std::convert::TryInto;
+
+/// Byte order of a multi-byte value, [`BigEndian`] or [`LittleEndian`].
+pub trait ByteOrder: sealed::ByteOrder {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BigEndian {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LittleEndian {}
+
+impl sealed::ByteOrder for BigEndian {
+    const BIG: bool = true;
+}
+
+impl sealed::ByteOrder for LittleEndian {
+    const BIG: bool = false;
+}
+
+impl ByteOrder for BigEndian {}
+impl ByteOrder for LittleEndian {}
+
+/// Integer types readable by [`KStream::read_int`](crate::KStream::read_int).
+pub trait KaitaiInt: Primitive {}
+
+/// Float types readable by [`KStream::read_float`](crate::KStream::read_float).
+pub trait KaitaiFloat: Primitive {}
+
+macro_rules! primitive {
+    ($marker:ident, $($t:ty),+) => {$(
+        impl Primitive for $t {
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn from_be_slice(bytes: &[u8]) -> Self {
+                <$t>::from_be_bytes(bytes.try_into().unwrap())
+            }
+
+            fn from_le_slice(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            fn from_ne_slice(bytes: &[u8]) -> Self {
+                <$t>::from_ne_bytes(bytes.try_into().unwrap())
+            }
+        }
+
+        impl $marker for $t {}
+    )+};
+}
+
+primitive!(KaitaiInt, u8, u16, u32, u64, i8, i16, i32, i64);
+primitive!(KaitaiFloat, f32, f64);
+
+pub(crate) fn decode<T: Primitive, E: ByteOrder>(bytes: &[u8]) -> T {
+    if E::BIG {
+        T::from_be_slice(bytes)
+    } else {
+        T::from_le_slice(bytes)
+    }
+}
+
+// whether values in byte order `E` have to be swapped to be native
+pub(crate) fn is_foreign<E: ByteOrder>() -> bool {
+    E::BIG != cfg!(target_endian = "big")
+}
+
+// reverse the bytes of each `size`-byte element
+pub(crate) fn swap_elements(bytes: &mut [u8], size: usize) -> KResult<()> {
+    if !bytes.len().is_multiple_of(size) {
+        return Err(KError::PartialElement {
+            len: bytes.len(),
+            element_size: size,
+        });
+    }
+    if size > 1 {
+        bytes.chunks_exact_mut(size).for_each(<[u8]>::reverse);
+    }
+    Ok(())
+}
+
+macro_rules! swap_endianness {
+    ($($size:literal: $swap:ident, $swapped:ident;)+) => {$(
+        #[doc = concat!(
+            "Swap the byte order of each ", $size, "-byte element of `bytes` in place, e.g.\n",
+            "to fix up an array stored in the other byte order. Fails with\n",
+            "[`KError::PartialElement`] if `bytes` does not hold a whole number\n",
+            "of elements, leaving it unchanged.",
+        )]
+        pub fn $swap(bytes: &mut [u8]) -> KResult<()> {
+            swap_elements(bytes, $size)
+        }
+
+        #[doc = concat!("[`", stringify!($swap), "`] on a copy of `bytes`.")]
+        pub fn $swapped(bytes: &[u8]) -> KResult<Vec<u8>> {
+            let mut res = bytes.to_vec();
+            $swap(&mut res)?;
+            Ok(res)
+        }
+    )+};
+}
+
+swap_endianness! {
+    2: swap_endianness_16, swapped_endianness_16;
+    4: swap_endianness_32, swapped_endianness_32;
+    8: swap_endianness_64, swapped_endianness_64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BytesReader, KError, KStream};
+
+    const DATA: [u8; 8] = [0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32, 0x10];
+
+    macro_rules! agree {
+        ($($read:ident, $generic:ident, $t:ty, $e:ty;)+) => {$(
+            let a = BytesReader::from(DATA.to_vec());
+            let b = BytesReader::from(DATA.to_vec());
+            let concrete = a.$read().unwrap();
+            let generic = b.$generic::<$t, $e>().unwrap();
+            assert_eq!(
+                concrete.to_ne_bytes(),
+                generic.to_ne_bytes(),
+                "{} vs {}::<{}, {}>",
+                stringify!($read),
+                stringify!($generic),
+                stringify!($t),
+                stringify!($e)
+            );
+            assert_eq!(a.pos(), b.pos());
+        )+};
+    }
+
+    #[test]
+    fn generic_matches_concrete() {
+        agree! {
+            read_u1, read_int, u8, BigEndian;
+            read_u1, read_int, u8, LittleEndian;
+            read_s1, read_int, i8, BigEndian;
+            read_s1, read_int, i8, LittleEndian;
+            read_u2be, read_int, u16, BigEndian;
+            read_u2le, read_int, u16, LittleEndian;
+            read_s2be, read_int, i16, BigEndian;
+            read_s2le, read_int, i16, LittleEndian;
+            read_u4be, read_int, u32, BigEndian;
+            read_u4le, read_int, u32, LittleEndian;
+            read_s4be, read_int, i32, BigEndian;
+            read_s4le, read_int, i32, LittleEndian;
+            read_u8be, read_int, u64, BigEndian;
+            read_u8le, read_int, u64, LittleEndian;
+            read_s8be, read_int, i64, BigEndian;
+            read_s8le, read_int, i64, LittleEndian;
+            read_f4be, read_float, f32, BigEndian;
+            read_f4le, read_float, f32, LittleEndian;
+            read_f8be, read_float, f64, BigEndian;
+            read_f8le, read_float, f64, LittleEndian;
+        }
+    }
+
+    #[test]
+    fn generic_eof() {
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        assert!(reader.read_int::<u32, BigEndian>().unwrap_err().is_eof());
+        assert_eq!(reader.read_int::<u16, LittleEndian>().unwrap(), 0x0201);
+    }
+
+    #[test]
+    fn int_array_matches_elementwise() {
+        let data: Vec<u8> = (0..48).map(|i| i * 5 + 1).collect();
+        macro_rules! check {
+            ($($t:ty, $e:ty;)+) => {$(
+                let a = BytesReader::from(data.clone());
+                let b = BytesReader::from(data.clone());
+                let n = data.len() / std::mem::size_of::<$t>() - 1;
+                let bulk = a.read_int_array::<$t, $e>(n).unwrap();
+                let single: Vec<$t> = (0..n).map(|_| b.read_int::<$t, $e>().unwrap()).collect();
+                assert_eq!(bulk, single);
+                assert_eq!(a.pos(), b.pos());
+            )+};
+        }
+        check! {
+            u8, BigEndian; i8, LittleEndian;
+            u16, BigEndian; u16, LittleEndian; i16, BigEndian; i16, LittleEndian;
+            u32, BigEndian; u32, LittleEndian; i32, BigEndian; i32, LittleEndian;
+            u64, BigEndian; u64, LittleEndian; i64, BigEndian; i64, LittleEndian;
+        }
+    }
+
+    #[test]
+    fn int_array_lengths() {
+        let reader = BytesReader::from(vec![1, 0, 2, 0, 3]);
+        assert_eq!(reader.read_int_array::<u16, LittleEndian>(0).unwrap(), []);
+        let err = reader
+            .read_int_array::<u16, LittleEndian>(usize::MAX)
+            .unwrap_err();
+        assert_eq!(err, KError::eof(usize::MAX, 5));
+        assert!(reader
+            .read_int_array::<u16, BigEndian>(3)
+            .unwrap_err()
+            .is_eof());
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(
+            reader.read_int_array::<u16, LittleEndian>(2).unwrap(),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn float_array_keeps_nan_bits() {
+        // signaling NaNs with payloads, a quiet NaN, -0 and a normal value
+        let bits32: [u32; 5] = [
+            0x7f80_0001,
+            0xffa0_beef,
+            0x7fc0_0000,
+            0x8000_0000,
+            0x3fc0_0000,
+        ];
+        let bits64: [u64; 3] = [
+            0x7ff0_0000_0000_0001,
+            0xfff4_dead_beef_0000,
+            0x3ff8_0000_0000_0000,
+        ];
+
+        let le: Vec<u8> = bits32.iter().flat_map(|b| b.to_le_bytes()).collect();
+        let be: Vec<u8> = bits32.iter().flat_map(|b| b.to_be_bytes()).collect();
+        let from_le = BytesReader::from(le).read_f4le_array(5).unwrap();
+        let from_be = BytesReader::from(be).read_f4be_array(5).unwrap();
+        let generic = BytesReader::from(
+            bits32
+                .iter()
+                .flat_map(|b| b.to_be_bytes())
+                .collect::<Vec<_>>(),
+        )
+        .read_float_array::<f32, BigEndian>(5)
+        .unwrap();
+        for vals in &[from_le, from_be, generic] {
+            let out: Vec<u32> = vals.iter().map(|v| v.to_bits()).collect();
+            assert_eq!(out, bits32);
+        }
+
+        let le: Vec<u8> = bits64.iter().flat_map(|b| b.to_le_bytes()).collect();
+        let be: Vec<u8> = bits64.iter().flat_map(|b| b.to_be_bytes()).collect();
+        let from_le = BytesReader::from(le).read_f8le_array(3).unwrap();
+        let from_be = BytesReader::from(be).read_f8be_array(3).unwrap();
+        for vals in &[from_le, from_be] {
+            let out: Vec<u64> = vals.iter().map(|v| v.to_bits()).collect();
+            assert_eq!(out, bits64);
+        }
+    }
+
+    #[test]
+    fn float_array_lengths() {
+        let reader = BytesReader::from(vec![0; 12]);
+        assert_eq!(reader.read_f8le_array(0).unwrap(), []);
+        assert_eq!(
+            reader.read_f4be_array(usize::MAX).unwrap_err(),
+            KError::eof(usize::MAX, 12)
+        );
+        assert!(reader.read_f8be_array(2).unwrap_err().is_eof());
+        assert_eq!(reader.read_f4le_array(3).unwrap(), [0.0; 3]);
+    }
+
+    #[test]
+    fn swap_endianness() {
+        let data: Vec<u8> = (1..=16).collect();
+        macro_rules! check {
+            ($($swap:ident, $swapped:ident, $t:ty, $from:ty, $to:ty;)+) => {$(
+                let swapped = $swapped(&data).unwrap();
+                let mut in_place = data.clone();
+                $swap(&mut in_place).unwrap();
+                assert_eq!(in_place, swapped);
+                let n = data.len() / std::mem::size_of::<$t>();
+                let a = BytesReader::from(data.clone());
+                let b = BytesReader::from(swapped);
+                for _ in 0..n {
+                    assert_eq!(
+                        a.read_int::<$t, $from>().unwrap(),
+                        b.read_int::<$t, $to>().unwrap()
+                    );
+                }
+                // twice is the original
+                $swap(&mut in_place).unwrap();
+                assert_eq!(in_place, data);
+            )+};
+        }
+        check! {
+            swap_endianness_16, swapped_endianness_16, u16, BigEndian, LittleEndian;
+            swap_endianness_32, swapped_endianness_32, u32, LittleEndian, BigEndian;
+            swap_endianness_64, swapped_endianness_64, i64, BigEndian, LittleEndian;
+        }
+        assert_eq!(
+            swapped_endianness_32(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap(),
+            [4, 3, 2, 1, 8, 7, 6, 5]
+        );
+
+        assert_eq!(swapped_endianness_64(&[]).unwrap(), []);
+        let mut empty = [];
+        swap_endianness_16(&mut empty).unwrap();
+
+        let mut odd = [1, 2, 3];
+        assert_eq!(
+            swap_endianness_16(&mut odd).unwrap_err(),
+            KError::PartialElement {
+                len: 3,
+                element_size: 2
+            }
+        );
+        assert_eq!(odd, [1, 2, 3]);
+        let err = swapped_endianness_64(&data[..12]).unwrap_err();
+        assert_eq!(err.category(), crate::ErrorCategory::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "12 bytes are not a whole number of 8-byte elements"
+        );
+    }
+}
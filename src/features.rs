@@ -0,0 +1,183 @@
+//! Stand-ins for the functions of optional features, so that parsing code
+//! compiles whichever features the runtime is built with. Without its
+//! feature, a function fails with [`KError::FeatureDisabled`].
+
+#[cfg(not(all(feature = "zip", feature = "http")))]
+use crate::ParseSession;
+#[cfg(not(feature = "http"))]
+use crate::ReadAt;
+#[cfg(not(all(feature = "zip", feature = "tar", feature = "mmap", feature = "http")))]
+use crate::{BytesReader, KError, KResult};
+#[cfg(not(all(feature = "zip", feature = "tar", feature = "mmap")))]
+use std::path::Path;
+#[cfg(not(feature = "http"))]
+use std::{io, time::Duration};
+
+/// Optional features this build of the runtime has, e.g. `["zip", "http"]`.
+pub fn runtime_features() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "http")]
+        "http",
+        #[cfg(feature = "mmap")]
+        "mmap",
+        #[cfg(feature = "tar")]
+        "tar",
+        #[cfg(feature = "test-support")]
+        "test-support",
+        #[cfg(feature = "type_name_of_val")]
+        "type_name_of_val",
+        #[cfg(feature = "zip")]
+        "zip",
+    ]
+}
+
+#[cfg(not(all(feature = "zip", feature = "tar", feature = "mmap", feature = "http")))]
+fn disabled<T>(feature: &'static str) -> KResult<T> {
+    Err(KError::FeatureDisabled { feature })
+}
+
+#[cfg(not(feature = "zip"))]
+impl BytesReader {
+    /// Open a member of a zip archive; needs the `zip` feature.
+    pub fn open_zip_member<P: AsRef<Path>>(_path: P, _member_name: &str) -> KResult<BytesReader> {
+        disabled("zip")
+    }
+}
+
+#[cfg(not(feature = "zip"))]
+impl ParseSession {
+    /// Open a member of a zip archive with the session; needs the `zip`
+    /// feature.
+    pub fn reader_from_zip_member<P: AsRef<Path>>(
+        &self,
+        _path: P,
+        _member_name: &str,
+    ) -> KResult<BytesReader> {
+        disabled("zip")
+    }
+}
+
+#[cfg(not(feature = "tar"))]
+impl BytesReader {
+    /// Open a member of a tar archive; needs the `tar` feature.
+    pub fn open_tar_member<P: AsRef<Path>>(_path: P, _member_path: &str) -> KResult<BytesReader> {
+        disabled("tar")
+    }
+}
+
+#[cfg(not(feature = "mmap"))]
+impl BytesReader {
+    /// Map a file into memory; needs the `mmap` feature.
+    ///
+    /// # Safety
+    ///
+    /// Unsafe for the same signature with and without the feature; this
+    /// stand-in only fails.
+    pub unsafe fn open_mmap<P: AsRef<Path>>(_path: P) -> KResult<BytesReader> {
+        disabled("mmap")
+    }
+}
+
+/// Reader over a file served over HTTP; needs the `http` feature. Without
+/// it, [`new`](Self::new) fails and no value of this type exists.
+#[cfg(not(feature = "http"))]
+#[derive(Debug)]
+pub struct HttpReader {
+    never: Never,
+}
+
+#[cfg(not(feature = "http"))]
+#[derive(Debug)]
+enum Never {}
+
+#[cfg(not(feature = "http"))]
+impl HttpReader {
+    pub fn new(_url: &str) -> KResult<HttpReader> {
+        disabled("http")
+    }
+
+    pub fn block_size(self, _len: usize) -> Self {
+        match self.never {}
+    }
+
+    pub fn cache_blocks(self, _n: usize) -> Self {
+        match self.never {}
+    }
+
+    pub fn timeout(self, _timeout: Duration) -> Self {
+        match self.never {}
+    }
+}
+
+#[cfg(not(feature = "http"))]
+impl ReadAt for HttpReader {
+    fn size(&mut self) -> io::Result<u64> {
+        match self.never {}
+    }
+
+    fn read_at(&mut self, _offset: u64, _buf: &mut [u8]) -> io::Result<usize> {
+        match self.never {}
+    }
+}
+
+#[cfg(not(feature = "http"))]
+impl ParseSession {
+    /// Reader over a file served over HTTP; needs the `http` feature.
+    pub fn reader_from_http(&self, _url: &str) -> KResult<BytesReader> {
+        disabled("http")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_features() {
+        let features = runtime_features();
+        assert_eq!(features.contains(&"zip"), cfg!(feature = "zip"));
+        assert_eq!(features.contains(&"http"), cfg!(feature = "http"));
+        assert_eq!(features.contains(&"mmap"), cfg!(feature = "mmap"));
+        let mut sorted = features.to_vec();
+        sorted.sort_unstable();
+        assert_eq!(sorted, features);
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "zip", feature = "tar", feature = "mmap", feature = "http")))]
+    fn disabled_features() {
+        use crate::ErrorCategory;
+        let check = |res: KResult<BytesReader>, feature: &str| {
+            let err = res.unwrap_err();
+            assert!(
+                matches!(&err, KError::FeatureDisabled { feature: f } if *f == feature),
+                "{:?}",
+                err
+            );
+            assert_eq!(err.category(), ErrorCategory::InvalidInput);
+            assert!(err.to_string().contains(feature));
+        };
+        #[cfg(not(feature = "zip"))]
+        check(BytesReader::open_zip_member("a.zip", "m"), "zip");
+        #[cfg(not(feature = "zip"))]
+        check(
+            ParseSession::default().reader_from_zip_member("a.zip", "m"),
+            "zip",
+        );
+        #[cfg(not(feature = "tar"))]
+        check(BytesReader::open_tar_member("a.tar", "m"), "tar");
+        #[cfg(not(feature = "mmap"))]
+        // SAFETY: nothing is mapped
+        check(unsafe { BytesReader::open_mmap("a.bin") }, "mmap");
+        #[cfg(not(feature = "http"))]
+        check(
+            ParseSession::default().reader_from_http("http://localhost/"),
+            "http",
+        );
+        #[cfg(not(feature = "http"))]
+        check(
+            HttpReader::new("http://localhost/").and_then(BytesReader::from_read_at),
+            "http",
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::visit::{KValue, KVisit, KVisitor};
+use std::hash::Hasher;
+
+// one tag per kind of value, so that e.g. `Int(1)` and `UInt(1)` differ
+const TAG_NONE: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_INT: u8 = 2;
+const TAG_UINT: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+const TAG_BYTES: u8 = 5;
+const TAG_STR: u8 = 6;
+const TAG_ENUM: u8 = 7;
+const TAG_STRUCT: u8 = 8;
+const TAG_LIST: u8 = 9;
+const TAG_FIELD: u8 = 10;
+const TAG_END: u8 = 11;
+
+/// Feed the field values of `root` to `hasher` in traversal order.
+///
+/// Every value is written with a type tag and variable-sized values with
+/// their length, so adjacent fields cannot run into each other. Integers are
+/// written as little-endian 64-bit values and floats as their bit pattern,
+/// with every NaN mapped to the same canonical pattern; with a hasher that is
+/// itself deterministic the result is the same on every run and platform.
+/// `_root`/`_parent` links are not part of the traversal, see [`KVisit`].
+pub fn deep_hash(root: &impl KVisit, hasher: &mut impl Hasher) {
+    deep_hash_filtered(root, hasher, |_| true);
+}
+
+/// [`deep_hash`] leaving out every field, at any depth, whose name is
+/// rejected by `filter`, e.g. padding that differs between equivalent files.
+pub fn deep_hash_filtered(
+    root: &impl KVisit,
+    hasher: &mut impl Hasher,
+    filter: impl Fn(&str) -> bool,
+) {
+    let mut state = HashVisitor {
+        hasher,
+        filter: &filter,
+    };
+    state.value(KValue::Struct(root));
+}
+
+struct HashVisitor<'a> {
+    hasher: &'a mut dyn Hasher,
+    filter: &'a dyn Fn(&str) -> bool,
+}
+
+impl HashVisitor<'_> {
+    fn tag(&mut self, tag: u8) {
+        self.hasher.write(&[tag]);
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.hasher.write(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u64(v.len() as u64);
+        self.hasher.write(v);
+    }
+
+    fn value(&mut self, value: KValue<'_>) {
+        match value {
+            KValue::None => self.tag(TAG_NONE),
+            KValue::Bool(v) => {
+                self.tag(TAG_BOOL);
+                self.hasher.write(&[v as u8]);
+            }
+            KValue::Int(v) => {
+                self.tag(TAG_INT);
+                self.u64(v as u64);
+            }
+            KValue::UInt(v) => {
+                self.tag(TAG_UINT);
+                self.u64(v);
+            }
+            KValue::Float(v) => {
+                self.tag(TAG_FLOAT);
+                let bits = if v.is_nan() { f64::NAN } else { v }.to_bits();
+                self.u64(bits);
+            }
+            KValue::Bytes(v) => {
+                self.tag(TAG_BYTES);
+                self.bytes(v);
+            }
+            KValue::Str(v) => {
+                self.tag(TAG_STR);
+                self.bytes(v.as_bytes());
+            }
+            KValue::Enum { name, value } => {
+                self.tag(TAG_ENUM);
+                self.u64(value as u64);
+                match name {
+                    Some(name) => self.bytes(name.as_bytes()),
+                    None => self.tag(TAG_NONE),
+                }
+            }
+            KValue::Struct(s) => {
+                self.tag(TAG_STRUCT);
+                s.visit(self);
+                self.tag(TAG_END);
+            }
+            KValue::List(l) => {
+                self.tag(TAG_LIST);
+                self.u64(l.len() as u64);
+                for i in 0..l.len() {
+                    self.value(l.item(i));
+                }
+            }
+        }
+    }
+}
+
+impl KVisitor for HashVisitor<'_> {
+    fn field(&mut self, name: &str, value: KValue<'_>) {
+        if !(self.filter)(name) {
+            return;
+        }
+        self.tag(TAG_FIELD);
+        self.bytes(name.as_bytes());
+        self.value(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OptRc;
+
+    // FNV-1a, fully specified, unlike `DefaultHasher`
+    struct Fnv(u64);
+
+    impl Hasher for Fnv {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for b in bytes {
+                self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+    }
+
+    fn hash_of(v: &impl KVisit) -> u64 {
+        let mut h = Fnv(0xcbf2_9ce4_8422_2325);
+        deep_hash(v, &mut h);
+        h.finish()
+    }
+
+    struct Inner {
+        flags: u32,
+        pad: Vec<u8>,
+    }
+
+    impl KVisit for Inner {
+        fn visit(&self, v: &mut dyn KVisitor) {
+            v.field("flags", (&self.flags).into());
+            v.field("pad", (&self.pad).into());
+        }
+    }
+
+    struct Outer {
+        name: String,
+        ratio: f64,
+        inner: Vec<OptRc<Inner>>,
+    }
+
+    impl KVisit for Outer {
+        fn visit(&self, v: &mut dyn KVisitor) {
+            v.field("name", (&self.name).into());
+            v.field("ratio", (&self.ratio).into());
+            v.field("inner", KValue::List(&self.inner));
+        }
+    }
+
+    fn sample(flags: u32, pad: u8, ratio: f64) -> Outer {
+        Outer {
+            name: "abc".to_string(),
+            ratio,
+            inner: vec![
+                OptRc::from(Inner {
+                    flags: 1,
+                    pad: vec![],
+                }),
+                OptRc::from(Inner {
+                    flags,
+                    pad: vec![pad; 3],
+                }),
+            ],
+        }
+    }
+
+    #[test]
+    fn equal_structures() {
+        assert_eq!(hash_of(&sample(7, 0, 0.5)), hash_of(&sample(7, 0, 0.5)));
+        // fixed for every platform
+        assert_eq!(hash_of(&sample(7, 0, 0.5)), 0x7d3c_ddfd_a0bd_c550);
+    }
+
+    #[test]
+    fn nested_bit_flip() {
+        let base = hash_of(&sample(7, 0, 0.5));
+        assert_ne!(base, hash_of(&sample(7 ^ 0x100, 0, 0.5)));
+        assert_ne!(base, hash_of(&sample(7, 1, 0.5)));
+        assert_ne!(base, hash_of(&sample(7, 0, -0.5)));
+    }
+
+    #[test]
+    fn filtered_fields() {
+        let hash = |v: &Outer| {
+            let mut h = Fnv(0);
+            deep_hash_filtered(v, &mut h, |name| name != "pad");
+            h.finish()
+        };
+        assert_eq!(hash(&sample(7, 0, 0.5)), hash(&sample(7, 0xff, 0.5)));
+        assert_ne!(hash(&sample(7, 0, 0.5)), hash(&sample(8, 0, 0.5)));
+    }
+
+    #[test]
+    fn nan_payloads() {
+        let quiet = f64::NAN;
+        let payload = f64::from_bits(0x7ff0_0000_dead_beef);
+        let negative = f64::from_bits(quiet.to_bits() | 1 << 63);
+        assert!(payload.is_nan() && negative.is_nan());
+        let base = hash_of(&sample(7, 0, quiet));
+        assert_eq!(base, hash_of(&sample(7, 0, payload)));
+        assert_eq!(base, hash_of(&sample(7, 0, negative)));
+        assert_ne!(base, hash_of(&sample(7, 0, f64::INFINITY)));
+    }
+
+    #[test]
+    fn adjacent_fields_are_delimited() {
+        struct Pair(String, String);
+
+        impl KVisit for Pair {
+            fn visit(&self, v: &mut dyn KVisitor) {
+                v.field("a", (&self.0).into());
+                v.field("b", (&self.1).into());
+            }
+        }
+
+        let ab = Pair("a".to_string(), "b".to_string());
+        let a_b = Pair("ab".to_string(), String::new());
+        assert_ne!(hash_of(&ab), hash_of(&a_b));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{BytesReader, KResult, ParseSession, ReadAt};
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+/// Blocking [`ReadAt`] backend for a file served over HTTP or HTTPS. The
+/// size comes from a `HEAD` request, data is fetched in blocks with `Range`
+/// requests and the most recently used blocks are cached. Redirects of the
+/// `HEAD` request are followed, and the blocks are requested from where it
+/// ended up.
+///
+/// Failures, including responses with an unexpected status, are reported
+/// as [`KError::IoError`](crate::KError::IoError).
+///
+/// A response is checked before its body is read: a server ignoring the
+/// `Range` header, or sending a larger body than the range, fails the
+/// request without the body being buffered. Connecting, sending and each
+/// wait for data time out after [`timeout`](Self::timeout).
+///
+/// ```no_run
+/// # use kaitai::*;
+/// let reader = BytesReader::from_read_at(
+///     HttpReader::new("https://example.com/firmware.bin")?.block_size(64 * 1024),
+/// )?;
+/// let magic = reader.read_u4be()?;
+/// # Ok::<(), KError>(())
+/// ```
+#[derive(Debug)]
+pub struct HttpReader {
+    agent: ureq::Agent,
+    url: String,
+    size: u64,
+    block_size: usize,
+    cache_blocks: usize,
+    // largest body accepted whatever the range, see `reader_from_http`
+    max_alloc: Option<usize>,
+    // least recently used first
+    cache: Vec<(u64, Vec<u8>)>,
+}
+
+fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout)
+        .build()
+}
+
+impl HttpReader {
+    /// Blocks are 16 KiB, 64 of them are cached and the timeout is 30
+    /// seconds unless configured otherwise.
+    pub fn new(url: &str) -> KResult<HttpReader> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: only http:// and https:// URLs are supported", url),
+            )
+            .into());
+        }
+        let mut res = HttpReader {
+            agent: agent(Duration::from_secs(30)),
+            url: url.to_string(),
+            size: 0,
+            block_size: 16 * 1024,
+            cache_blocks: 64,
+            max_alloc: None,
+            cache: vec![],
+        };
+        let head = res.request(res.agent.head(url), 200)?;
+        res.size = head
+            .header("content-length")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| res.error("no Content-Length in HEAD response"))?;
+        res.url = head.get_url().to_string();
+        Ok(res)
+    }
+
+    pub fn block_size(mut self, len: usize) -> Self {
+        self.block_size = len.max(1);
+        self.cache.clear();
+        self
+    }
+
+    /// Number of blocks kept in memory.
+    pub fn cache_blocks(mut self, n: usize) -> Self {
+        self.cache_blocks = n;
+        self.cache.truncate(n);
+        self
+    }
+
+    /// Limit on connecting, sending a request and each wait for response
+    /// data. The `HEAD` request of [`new`](Self::new) has the default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.agent = agent(timeout);
+        self
+    }
+
+    fn error(&self, msg: impl std::fmt::Display) -> io::Error {
+        io::Error::other(format!("{}: {}", self.url, msg))
+    }
+
+    // the response to `req`, failing unless it has `status`
+    fn request(&self, req: ureq::Request, status: u16) -> io::Result<ureq::Response> {
+        let resp = match req.call() {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(code, _)) => return Err(self.unexpected(code, status)),
+            Err(e) => return Err(self.error(e)),
+        };
+        if resp.status() != status {
+            return Err(self.unexpected(resp.status(), status));
+        }
+        Ok(resp)
+    }
+
+    fn unexpected(&self, found: u16, expected: u16) -> io::Error {
+        self.error(format!("HTTP status {} (expected {})", found, expected))
+    }
+
+    // the bytes `first..=last`, in a body no larger than the range
+    fn get_range(&self, first: u64, last: u64) -> io::Result<Vec<u8>> {
+        let req = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", first, last));
+        let resp = self.request(req, 206)?;
+        let mut max = last - first + 1;
+        if let Some(limit) = self.max_alloc {
+            max = max.min(limit as u64);
+        }
+        let too_large = || self.error(format!("body larger than the {} bytes requested", max));
+        let len = resp.header("content-length").map(str::parse::<u64>);
+        if let Some(len) = len {
+            if len.map_err(|_| self.error("malformed Content-Length"))? > max {
+                return Err(too_large());
+            }
+        }
+        let mut body = vec![];
+        resp.into_reader()
+            .take(max + 1)
+            .read_to_end(&mut body)
+            .map_err(|e| self.error(e))?;
+        if body.len() as u64 > max {
+            return Err(too_large());
+        }
+        Ok(body)
+    }
+
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        match self.cache.iter().position(|(i, _)| *i == index) {
+            Some(pos) => {
+                let entry = self.cache.remove(pos);
+                self.cache.push(entry);
+            }
+            None => {
+                let first = index * self.block_size as u64;
+                let last = std::cmp::min(first + self.block_size as u64, self.size) - 1;
+                let body = self.get_range(first, last)?;
+                if body.len() as u64 != last - first + 1 {
+                    return Err(self.error(format!(
+                        "got {} bytes for range {}-{}",
+                        body.len(),
+                        first,
+                        last
+                    )));
+                }
+                if self.cache.len() >= self.cache_blocks.max(1) {
+                    self.cache.remove(0);
+                }
+                self.cache.push((index, body));
+            }
+        }
+        Ok(&self.cache.last().unwrap().1)
+    }
+}
+
+impl ReadAt for HttpReader {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.block_size as u64;
+        let skip = (offset % block_size) as usize;
+        let block = self.block(offset / block_size)?;
+        let n = std::cmp::min(buf.len(), block.len() - skip);
+        buf[..n].copy_from_slice(&block[skip..skip + n]);
+        Ok(n)
+    }
+}
+
+impl ParseSession {
+    /// Reader over the file at `url`, see [`HttpReader`]. Blocks, and the
+    /// response bodies accepted, are no larger than
+    /// [`max_alloc`](Self::max_alloc). The reader is named after the URL.
+    pub fn reader_from_http(&self, url: &str) -> KResult<BytesReader> {
+        let mut backend = HttpReader::new(url)?;
+        if let Some(limit) = self.max_alloc() {
+            let block_size = backend.block_size.min(limit);
+            backend = backend.block_size(block_size);
+            backend.max_alloc = Some(limit);
+        }
+        Ok(BytesReader::from_read_at(backend)?
+            .with_name(url)
+            .with_session(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCategory, KError, KStream};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // serves `data` at `/data.bin`, counting the GET requests
+    fn serve(data: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let gets = Arc::new(AtomicUsize::new(0));
+        let counter = gets.clone();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut lines = BufReader::new(conn.try_clone().unwrap()).lines();
+                let request = lines.next().unwrap().unwrap();
+                let mut range = None;
+                for line in lines {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(r) = line.strip_prefix("Range: bytes=") {
+                        let (a, b) = r.split_at(r.find('-').unwrap());
+                        range = Some((
+                            a.parse::<usize>().unwrap(),
+                            b[1..].parse::<usize>().unwrap(),
+                        ));
+                    }
+                }
+                let parts: Vec<_> = request.split(' ').collect();
+                let resp = match (parts[0], parts[1], range) {
+                    (_, "/moved", _) => "HTTP/1.1 301 Moved Permanently\r\nLocation: /data.bin\r\n\
+                         Content-Length: 0\r\n\r\n"
+                        .as_bytes()
+                        .to_vec(),
+                    (_, path, _) if path != "/data.bin" => {
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                            .as_bytes()
+                            .to_vec()
+                    }
+                    ("HEAD", _, _) => {
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len())
+                            .into_bytes()
+                    }
+                    ("GET", _, Some((a, b))) => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let body = &data[a..=b];
+                        let mut resp = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        resp.extend_from_slice(body);
+                        resp
+                    }
+                    _ => "HTTP/1.1 400 Bad Request\r\n\r\n".as_bytes().to_vec(),
+                };
+                conn.write_all(&resp).unwrap();
+            }
+        });
+        (base, gets)
+    }
+
+    #[test]
+    fn range_requests_are_cached() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let (base, gets) = serve(data.clone());
+        let backend = HttpReader::new(&format!("{}/data.bin", base))
+            .unwrap()
+            .block_size(64)
+            .cache_blocks(2);
+        let reader = BytesReader::from_read_at(backend).unwrap();
+        assert_eq!(reader.size(), 1000);
+        assert_eq!(gets.load(Ordering::SeqCst), 0);
+
+        reader.seek(60).unwrap();
+        assert_eq!(reader.read_bytes(10).unwrap(), data[60..70]);
+        assert_eq!(gets.load(Ordering::SeqCst), 2);
+        reader.seek(62).unwrap();
+        assert_eq!(
+            reader.read_u4be().unwrap(),
+            u32::from_be_bytes([data[62], data[63], data[64], data[65]])
+        );
+        assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+        // the last block is short
+        reader.seek(990).unwrap();
+        assert_eq!(reader.read_bytes_full().unwrap(), data[990..]);
+        assert_eq!(gets.load(Ordering::SeqCst), 3);
+        // evicted
+        reader.seek(0).unwrap();
+        reader.read_u1().unwrap();
+        assert_eq!(gets.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn session_limits() {
+        let (base, gets) = serve(vec![1; 100]);
+        let session = ParseSession::builder().max_alloc(8).build();
+        let reader = session
+            .reader_from_http(&format!("{}/data.bin", base))
+            .unwrap();
+        assert_eq!(reader.read_bytes(8).unwrap(), [1; 8]);
+        assert_eq!(gets.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            reader.read_bytes(9).unwrap_err().category(),
+            ErrorCategory::Limit
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let (base, _) = serve(vec![]);
+        let err = HttpReader::new(&format!("{}/missing", base)).unwrap_err();
+        assert!(
+            matches!(&err, KError::IoError { msg } if msg.contains("404")),
+            "{}",
+            err
+        );
+        let err = HttpReader::new("ftp://example.com/").unwrap_err();
+        assert_eq!(err.category(), crate::ErrorCategory::Io);
+        assert!(err.to_string().contains("only http://"), "{}", err);
+    }
+
+    #[test]
+    fn redirect() {
+        let (base, gets) = serve(vec![7; 10]);
+        let reader =
+            BytesReader::from_read_at(HttpReader::new(&format!("{}/moved", base)).unwrap())
+                .unwrap();
+        assert_eq!(reader.size(), 10);
+        assert_eq!(reader.read_bytes_full().unwrap(), [7; 10]);
+        assert_eq!(gets.load(Ordering::SeqCst), 1);
+    }
+
+    // answers `HEAD` for a file of `size` bytes and every `GET` with
+    // `get`, after `delay`
+    fn serve_raw(size: usize, get: Vec<u8>, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/data.bin", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut lines = BufReader::new(conn.try_clone().unwrap()).lines();
+                let request = lines.next().unwrap().unwrap();
+                for line in lines {
+                    if line.unwrap().is_empty() {
+                        break;
+                    }
+                }
+                let resp = if request.starts_with("HEAD") {
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", size).into_bytes()
+                } else {
+                    std::thread::sleep(delay);
+                    get.clone()
+                };
+                // the client may hang up first
+                let _ = conn.write_all(&resp);
+            }
+        });
+        url
+    }
+
+    fn first_block(url: &str) -> KResult<Vec<u8>> {
+        let backend = HttpReader::new(url)?
+            .block_size(4)
+            .timeout(Duration::from_millis(200));
+        BytesReader::from_read_at(backend)?.read_bytes(4)
+    }
+
+    #[test]
+    fn range_ignored() {
+        // the whole file with a 200 is not read
+        let mut resp = b"HTTP/1.1 200 OK\r\nContent-Length: 1000000\r\n\r\n".to_vec();
+        resp.extend(vec![1; 1_000_000]);
+        let err = first_block(&serve_raw(1_000_000, resp, Duration::ZERO)).unwrap_err();
+        assert!(err.to_string().contains("HTTP status 200"), "{}", err);
+
+        // nor a body larger than the range
+        let mut resp = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\n".to_vec();
+        resp.extend([1; 5]);
+        let err = first_block(&serve_raw(8, resp, Duration::ZERO)).unwrap_err();
+        assert!(
+            err.to_string().contains("larger than the 4 bytes"),
+            "{}",
+            err
+        );
+        let mut resp = b"HTTP/1.1 206 Partial Content\r\n\r\n".to_vec();
+        resp.extend([1; 5]);
+        assert!(first_block(&serve_raw(8, resp, Duration::ZERO)).is_err());
+        let mut resp = b"HTTP/1.1 206 Partial Content\r\n\r\n".to_vec();
+        resp.extend([1; 4]);
+        assert_eq!(
+            first_block(&serve_raw(8, resp, Duration::ZERO)).unwrap(),
+            [1; 4]
+        );
+    }
+
+    #[test]
+    fn chunked() {
+        let resp = b"HTTP/1.1 206 Partial Content\r\nTransfer-Encoding: chunked\r\n\r\n\
+            1\r\n\x01\r\n3;ext=1\r\n\x02\x03\x04\r\n0\r\n\r\n"
+            .to_vec();
+        assert_eq!(
+            first_block(&serve_raw(8, resp, Duration::ZERO)).unwrap(),
+            [1, 2, 3, 4]
+        );
+
+        for bad in [
+            &b"5\r\n\x01\x02\x03\x04\x05\r\n0\r\n\r\n"[..],
+            b"4\r\n\x01\x02\x03\x04XX0\r\n\r\n",
+            b"zz\r\n",
+            b"4\r\n\x01\x02",
+        ] {
+            let mut resp =
+                b"HTTP/1.1 206 Partial Content\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            resp.extend_from_slice(bad);
+            let err = first_block(&serve_raw(8, resp, Duration::ZERO)).unwrap_err();
+            assert_eq!(err.category(), crate::ErrorCategory::Io);
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let resp =
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 4\r\n\r\n\x01\x02\x03\x04".to_vec();
+        let url = serve_raw(8, resp, Duration::from_secs(5));
+        let start = std::time::Instant::now();
+        let err = first_block(&url).unwrap_err();
+        assert_eq!(err.category(), crate::ErrorCategory::Io);
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+}
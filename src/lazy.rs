@@ -0,0 +1,264 @@
+use crate::{resolve_seek, usize_from_u64, BytesReader, KResult, ReadSeek};
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+// the descriptors of all lazily opened files; each has a lock of its own, so
+// that reads of different files do not wait for each other
+struct Registry {
+    limit: Option<usize>,
+    next_id: u64,
+    // least recently used first
+    open: Vec<(u64, Arc<Mutex<File>>)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    limit: None,
+    next_id: 0,
+    open: Vec::new(),
+});
+
+fn registry() -> MutexGuard<'static, Registry> {
+    // the registry stays consistent even if a read panicked
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Registry {
+    fn evict(&mut self, keep: usize) {
+        let excess = self.open.len().saturating_sub(keep);
+        self.open.drain(..excess);
+    }
+}
+
+impl BytesReader {
+    /// Reader over the file at `path` that only opens it on the first read.
+    /// The size is taken from the file's metadata right away. The reader is
+    /// named after the path, see [`KStream::name`](crate::KStream::name).
+    ///
+    /// The descriptor may be closed again to stay within
+    /// [`set_max_open_files`](Self::set_max_open_files) and is then reopened
+    /// on demand. If the file's size or modification time differ from
+    /// those seen here when it is (re)opened, reading fails with
+    /// [`KError::SourceChanged`](crate::KError::SourceChanged).
+    pub fn open_lazy<P: AsRef<Path>>(path: P) -> KResult<BytesReader> {
+        let meta = std::fs::metadata(&path)?;
+        usize_from_u64(meta.len(), "file size")?;
+        let mut reg = registry();
+        let file = LazyFile {
+            id: reg.next_id,
+            path: path.as_ref().to_path_buf(),
+            size: meta.len(),
+            modified: meta.modified().ok(),
+            pos: 0,
+        };
+        reg.next_id += 1;
+        drop(reg);
+        let backend: Box<dyn ReadSeek> = Box::new(file);
+        let name = path.as_ref().display().to_string();
+        let mut reader = BytesReader::try_from(backend)?.with_name(&name);
+        reader.path = Some(path.as_ref().into());
+        Ok(reader)
+    }
+
+    /// Limit the number of files of [`open_lazy`](Self::open_lazy) readers
+    /// open at the same time, process-wide. When the limit is reached, the
+    /// least recently read file is closed. `None`, the default, removes the
+    /// limit.
+    pub fn set_max_open_files(limit: Option<usize>) {
+        let mut reg = registry();
+        reg.limit = limit.map(|n| n.max(1));
+        if let Some(n) = reg.limit {
+            reg.evict(n);
+        }
+    }
+}
+
+/// Cause of an `io::Error` for a file that changed while closed, turned into
+/// [`KError::SourceChanged`](crate::KError::SourceChanged).
+#[derive(Debug)]
+pub(crate) struct ChangedError {
+    pub(crate) path: String,
+}
+
+impl fmt::Display for ChangedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} changed since it was opened", self.path)
+    }
+}
+
+impl std::error::Error for ChangedError {}
+
+struct LazyFile {
+    id: u64,
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+    pos: u64,
+}
+
+impl LazyFile {
+    fn reopen(&self) -> io::Result<File> {
+        let file = File::open(&self.path)?;
+        let meta = file.metadata()?;
+        if meta.len() != self.size || meta.modified().ok() != self.modified {
+            return Err(io::Error::other(ChangedError {
+                path: self.path.display().to_string(),
+            }));
+        }
+        Ok(file)
+    }
+
+    // the open file, reopened if it was closed, as the most recently used
+    fn file(&self) -> io::Result<Arc<Mutex<File>>> {
+        let mut reg = registry();
+        if let Some(i) = reg.open.iter().position(|(id, _)| *id == self.id) {
+            let entry = reg.open.remove(i);
+            let file = Arc::clone(&entry.1);
+            reg.open.push(entry);
+            return Ok(file);
+        }
+        drop(reg);
+        // no other reader has this id, so nothing adds it while unlocked
+        let file = Arc::new(Mutex::new(self.reopen()?));
+        let mut reg = registry();
+        if let Some(n) = reg.limit {
+            reg.evict(n - 1);
+        }
+        reg.open.push((self.id, Arc::clone(&file)));
+        Ok(file)
+    }
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+        let file = self.file()?;
+        // a file evicted meanwhile is closed once this read is done
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(self.pos))?;
+        let n = file.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for LazyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = resolve_seek(pos, self.pos, || Ok(self.size))?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for LazyFile {
+    fn drop(&mut self) {
+        registry().open.retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KError, KStream};
+    use std::time::Duration;
+
+    // the limit is process-wide
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn open_files() -> usize {
+        registry().open.len()
+    }
+
+    #[test]
+    fn many_readers_few_descriptors() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let readers: Vec<_> = (0..2000u32)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.bin", i));
+                let data: Vec<u8> = [i.to_le_bytes(), (i * 3).to_be_bytes()].concat();
+                std::fs::write(&path, data).unwrap();
+                BytesReader::open_lazy(&path).unwrap()
+            })
+            .collect();
+        assert_eq!(open_files(), 0);
+        assert!(readers.iter().all(|r| r.size() == 8));
+
+        BytesReader::set_max_open_files(Some(16));
+        // every reader is read twice, so each file is opened twice
+        for (i, r) in (0..).zip(&readers) {
+            assert_eq!(r.read_u4le().unwrap(), i);
+        }
+        assert_eq!(open_files(), 16);
+        for (i, r) in (0..).zip(&readers) {
+            assert_eq!(r.read_u4be().unwrap(), i * 3);
+            assert!(r.is_eof());
+        }
+        BytesReader::set_max_open_files(Some(4));
+        assert_eq!(open_files(), 4);
+        BytesReader::set_max_open_files(None);
+        drop(readers);
+        assert_eq!(open_files(), 0);
+    }
+
+    #[test]
+    fn changed_while_closed() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, [1, 2, 3, 4]).unwrap();
+        std::fs::write(&b, [5, 6]).unwrap();
+        let ra = BytesReader::open_lazy(&a).unwrap();
+        let rb = BytesReader::open_lazy(&b).unwrap();
+        BytesReader::set_max_open_files(Some(1));
+
+        assert_eq!(ra.read_u1().unwrap(), 1);
+        // closes `a`
+        assert_eq!(rb.read_u1().unwrap(), 5);
+        // same size, but a different modification time
+        let f = std::fs::OpenOptions::new().write(true).open(&a).unwrap();
+        f.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let err = ra.read_u1().unwrap_err();
+        assert_eq!(err, KError::source_changed(a.display().to_string()));
+        assert_eq!(err.category(), crate::ErrorCategory::Io);
+        // `b` is still open
+        assert_eq!(rb.read_u1().unwrap(), 6);
+
+        BytesReader::set_max_open_files(None);
+    }
+
+    #[test]
+    fn files_locked_separately() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, [1, 2]).unwrap();
+        std::fs::write(&b, [3, 4]).unwrap();
+        let ra = BytesReader::open_lazy(&a).unwrap();
+        let rb = BytesReader::open_lazy(&b).unwrap();
+        assert_eq!(ra.read_u1().unwrap(), 1);
+
+        // a read of `a` in progress does not hold up reads of `b`
+        let file_a = Arc::clone(&registry().open[0].1);
+        let guard = file_a.lock().unwrap();
+        assert_eq!(rb.read_u2be().unwrap(), 0x0304);
+
+        // `a` is evicted while in use and closed once no longer used
+        BytesReader::set_max_open_files(Some(1));
+        assert_eq!(open_files(), 1);
+        assert_eq!(Arc::strong_count(&file_a), 1);
+        drop(guard);
+        assert_eq!(ra.read_u1().unwrap(), 2);
+        BytesReader::set_max_open_files(None);
+    }
+}
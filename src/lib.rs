@@ -1,11 +1,13 @@
-use encoding::{label::encoding_from_whatwg_label, DecoderTrap};
+use encoding::{label::encoding_from_whatwg_label, DecoderTrap, EncodingRef};
 use flate2::read::ZlibDecoder;
 
 use std::{
     any::{type_name, Any},
-    cell::{Ref, RefCell, RefMut},
-    convert::{TryFrom, TryInto},
+    borrow::Cow,
+    cell::{Cell, Ref, RefCell, RefMut},
+    convert::TryFrom,
     fmt,
+    hash::{Hash, Hasher},
     io::{Read, Seek, SeekFrom},
     ops::Deref,
     path::Path,
@@ -13,24 +15,701 @@ use std::{
 };
 use unicode_segmentation::UnicodeSegmentation;
 
+mod access;
+mod ancestors;
+#[cfg(any(feature = "tar", feature = "zip"))]
+mod archive;
+mod bits;
+mod bytes_fmt;
+mod cache;
+mod checkpoint;
+mod debug;
+mod diff;
+mod endian;
+mod features;
+mod hash;
+#[cfg(feature = "http")]
+mod http;
+mod lazy;
+mod memory;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod net;
+mod obfuscate;
+mod offsets;
+mod ordered_map;
+mod prefetch;
+mod presence;
+mod remote;
+mod repeat;
+mod search;
+mod session;
+mod string_pool;
+#[cfg(feature = "tar")]
+mod tar;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testkit;
+mod visit;
+#[cfg(feature = "zip")]
+mod zip;
+
+pub use access::*;
+pub use ancestors::*;
+pub use bits::*;
+pub use bytes_fmt::*;
+pub use cache::*;
+pub use checkpoint::*;
+pub use debug::*;
+pub use diff::*;
+pub use endian::{
+    swap_endianness_16, swap_endianness_32, swap_endianness_64, swapped_endianness_16,
+    swapped_endianness_32, swapped_endianness_64, BigEndian, ByteOrder, KaitaiFloat, KaitaiInt,
+    LittleEndian,
+};
+pub use features::*;
+pub use hash::*;
+#[cfg(feature = "http")]
+pub use http::*;
+pub use memory::*;
+pub use net::*;
+pub use obfuscate::*;
+pub use offsets::*;
+pub use ordered_map::*;
+pub use presence::*;
+pub use remote::*;
+pub use repeat::*;
+pub use search::*;
+pub use session::*;
+pub use string_pool::*;
+pub use visit::*;
+
+/// Errors of the runtime and of generated parsers.
+///
+/// New variants and fields are added over time, so the enum is
+/// `#[non_exhaustive]`. Rather than matching variants exhaustively, prefer
+/// the accessors ([`category`](Self::category), [`offset`](Self::offset),
+/// [`is_eof`](Self::is_eof)) and keep a wildcard arm where matching on
+/// specific variants is needed. Code constructing errors should use the
+/// helper functions such as [`KError::eof`] instead of struct literals.
 #[derive(Debug, PartialEq, Eq, Clone)]
 #[non_exhaustive]
 pub enum KError {
-    Eof { requested: usize, available: usize },
+    Eof {
+        requested: usize,
+        available: usize,
+    },
     EmptyIterator,
-    UnknownEncoding { name: String },
+    Encoding {
+        label: String,
+        desc: String,
+        input_len: usize,
+        bad_offset: Option<usize>,
+    },
+    /// No longer produced, an unknown label is an
+    /// [`Encoding`](Self::Encoding) error.
+    #[deprecated(note = "unknown labels are reported as `KError::Encoding`")]
+    UnknownEncoding {
+        name: String,
+    },
+    /// No longer produced, decoding failures are
+    /// [`Encoding`](Self::Encoding) errors.
+    #[deprecated(note = "decoding failures are reported as `KError::Encoding`")]
+    BytesDecodingError {
+        msg: String,
+    },
     MissingRoot,
     MissingParent,
-    ReadBitsTooLarge { requested: usize },
+    /// A bit read ran past the end of the stream.
+    EofBits {
+        requested: usize,
+        available: u64,
+        /// Byte position of the stream when the read started.
+        offset: usize,
+    },
+    ReadBitsTooLarge {
+        requested: usize,
+        max: usize,
+    },
     ValidationFailed(ValidationFailedError),
     NoTerminatorFound,
-    IoError { msg: String },
-    BytesDecodingError { msg: String },
+    /// A terminator search reached the limit of a substream, at position
+    /// `end`, that was created with `size` bytes.
+    NoTerminatorInSubstream {
+        end: usize,
+        size: usize,
+    },
+    IoError {
+        msg: String,
+    },
+    InvalidInput {
+        offset: usize,
+        msg: String,
+    },
     CastError,
-    UndecidedEndianness { src_path: String },
+    SnapshotMismatch,
+    UndecidedEndianness {
+        src_path: String,
+    },
+    /// A read would allocate more than [`KStream::max_alloc`] allows.
+    AllocationLimitExceeded {
+        requested: usize,
+        limit: usize,
+    },
+    /// `source` occurred at stream position `pos`, see
+    /// [`KStream::enrich_error`]. Built with [`KError::at`].
+    #[non_exhaustive]
+    At {
+        pos: usize,
+        /// `pos` translated to the root stream, if possible.
+        root_offset: Option<RootOffset>,
+        /// [`KStream::name`] of the stream.
+        stream: Option<String>,
+        source: Box<KError>,
+    },
+    /// A bug in parsing code, such as an empty [`OptRc`] accessed with
+    /// [`OptRc::try_get`], or a panic caught by [`parse_bytes_no_panic`].
+    Internal {
+        msg: String,
+    },
+    /// No ancestor of the wanted type, see [`KParentLink::find_ancestor`].
+    AncestorNotFound {
+        wanted: String,
+        /// Types of the ancestors visited, nearest first.
+        traversed: Vec<String>,
+    },
+    /// A value cannot be used as what `context` names, e.g. a negative
+    /// length, see [`to_len`].
+    ValueOutOfRange {
+        value: i128,
+        context: String,
+    },
+    /// An archive has no member of this name.
+    ArchiveMemberNotFound {
+        name: String,
+    },
+    /// The structure of an archive is broken at `offset`.
+    CorruptArchive {
+        offset: u64,
+        msg: String,
+    },
+    /// The operation could affect other readers and is not available on a
+    /// reader returned by [`BytesReader::freeze`].
+    FrozenReader {
+        operation: String,
+    },
+    /// A file was modified while a lazy reader had it closed, see
+    /// [`BytesReader::open_lazy`], or before a [`BytesReader::resume`].
+    SourceChanged {
+        path: String,
+    },
+    /// The header of an archive member at `offset` is damaged.
+    ArchiveChecksumMismatch {
+        offset: u64,
+        stored: u64,
+        computed: u64,
+    },
+    /// The data source shrank below the current position, see
+    /// [`BytesReader::refresh_size`].
+    SourceTruncated {
+        size: u64,
+        pos: usize,
+    },
+    /// A size computed from the data came out negative, see
+    /// [`checked_sub_len`].
+    SizeUnderflow {
+        minuend: usize,
+        subtrahend: usize,
+    },
+    /// Fewer bytes are available than requested, `requested - available`
+    /// short: the data source ended in the middle of a read although its
+    /// size promised more bytes, or [`KStream::ensure_remaining`] found too
+    /// few left. This is an end of data like [`KError::Eof`], with the same
+    /// [`ErrorCategory::Eof`].
+    Incomplete {
+        requested: usize,
+        available: usize,
+    },
+    /// Bytes were left after a parse that should have consumed the whole
+    /// stream, see [`KStream::expect_fully_consumed`].
+    TrailingBytes {
+        /// Position where the unconsumed bytes start.
+        offset: usize,
+        len: usize,
+        /// The first [`TRAILING_PREVIEW`] bytes at most.
+        preview: Vec<u8>,
+    },
+    /// A byte-sized read at `offset` while `bits_left` bits of a bit-sized
+    /// read were pending, under [`BitAlignment::Error`].
+    UnalignedBitRead {
+        offset: usize,
+        bits_left: u8,
+    },
+    /// The `len` bytes at `offset` of a positioned read do not lie within
+    /// the allowed region `start..end`, see [`KStream::read_at_checked`].
+    PointerOutOfBounds {
+        offset: u64,
+        len: usize,
+        start: u64,
+        end: u64,
+    },
+    /// A buffer of `len` bytes to process as elements of `element_size`
+    /// bytes does not hold a whole number of them, see
+    /// [`swap_endianness_16`].
+    PartialElement {
+        len: usize,
+        element_size: usize,
+    },
+    /// The runtime was built without the optional `feature` the operation
+    /// needs, see [`runtime_features`].
+    FeatureDisabled {
+        feature: &'static str,
+    },
+    /// The stream ended before the condition of a `repeat: until` held, see
+    /// [`read_repeat_until`].
+    RepeatUntilEof {
+        /// Number of complete elements before the end.
+        parsed: usize,
+        source: Box<KError>,
+    },
 }
 pub type KResult<T> = Result<T, KError>;
 
+/// A wrapped error is shown as its root cause with the innermost position,
+/// `{:#}` shows every layer of the [`chain`](KError::chain) on a line of
+/// its own, outermost first.
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.wrapped().is_none() {
+            return self.fmt_layer(f);
+        }
+        if f.alternate() {
+            for (i, e) in self.chain().enumerate() {
+                if i > 0 {
+                    f.write_str("\ncaused by: ")?;
+                }
+                e.fmt_layer(f)?;
+            }
+            return Ok(());
+        }
+        self.root_cause().fmt_layer(f)?;
+        let innermost = self
+            .chain()
+            .filter(|e| matches!(e, KError::At { .. }))
+            .last();
+        if let Some(at) = innermost {
+            f.write_str(" (")?;
+            at.fmt_layer(f)?;
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+impl KError {
+    // the message of this error without the errors it wraps
+    fn fmt_layer(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KError::Eof {
+                requested,
+                available,
+            } => write!(
+                f,
+                "unexpected end of stream: requested {} bytes, {} available",
+                requested, available
+            ),
+            KError::EmptyIterator => write!(f, "empty iterator"),
+            #[allow(deprecated)]
+            KError::UnknownEncoding { name } => write!(f, "unknown encoding {:?}", name),
+            #[allow(deprecated)]
+            KError::BytesDecodingError { msg } => write!(f, "cannot decode bytes: {}", msg),
+            KError::Encoding {
+                label,
+                desc,
+                input_len,
+                bad_offset,
+            } => {
+                write!(
+                    f,
+                    "cannot decode {} bytes as {:?}: {}",
+                    input_len, label, desc
+                )?;
+                if let Some(offset) = bad_offset {
+                    write!(f, " (at byte {})", offset)?;
+                }
+                Ok(())
+            }
+            KError::MissingRoot => write!(f, "missing root"),
+            KError::MissingParent => write!(f, "missing parent"),
+            KError::EofBits {
+                requested,
+                available,
+                offset,
+            } => write!(
+                f,
+                "unexpected end of stream at byte {}: requested {} bits, {} available",
+                offset, requested, available
+            ),
+            KError::ReadBitsTooLarge { requested, max } => {
+                write!(
+                    f,
+                    "cannot read {} bits at once (maximum is {})",
+                    requested, max
+                )
+            }
+            KError::ValidationFailed(e) => write!(f, "{}", e),
+            KError::NoTerminatorFound => write!(f, "terminator not found before end of stream"),
+            KError::NoTerminatorInSubstream { end, size } => write!(
+                f,
+                "terminator not found before end of {}-byte substream at {}",
+                size, end
+            ),
+            KError::IoError { msg } => write!(f, "I/O error: {}", msg),
+            KError::InvalidInput { offset, msg } => {
+                write!(f, "invalid input at offset {}: {}", offset, msg)
+            }
+            KError::CastError => write!(f, "cast error"),
+            KError::SnapshotMismatch => write!(f, "snapshot was taken from another stream"),
+            KError::UndecidedEndianness { src_path } => {
+                write!(f, "unable to decide endianness at {}", src_path)
+            }
+            KError::AllocationLimitExceeded { requested, limit } => write!(
+                f,
+                "refusing to allocate {} bytes (limit is {})",
+                requested, limit
+            ),
+            KError::At {
+                pos,
+                root_offset,
+                stream,
+                ..
+            } => {
+                write!(f, "at byte {}", pos)?;
+                if let Some(name) = stream {
+                    write!(f, " of {}", name)?;
+                }
+                if let Some(root) = root_offset {
+                    write!(f, ", offset {} in root stream", root.offset)?;
+                }
+                Ok(())
+            }
+            KError::Internal { msg } => write!(f, "internal error: {}", msg),
+            KError::AncestorNotFound { wanted, traversed } => write!(
+                f,
+                "no ancestor of type {} (visited: {})",
+                wanted,
+                traversed.join(", ")
+            ),
+            KError::ValueOutOfRange { value, context } => {
+                write!(f, "{} out of range for a {}", value, context)
+            }
+            KError::ArchiveMemberNotFound { name } => {
+                write!(f, "archive has no member {:?}", name)
+            }
+            KError::CorruptArchive { offset, msg } => {
+                write!(f, "corrupt archive at offset {}: {}", offset, msg)
+            }
+            KError::FrozenReader { operation } => {
+                write!(f, "{} is not allowed on a frozen reader", operation)
+            }
+            KError::SourceChanged { path } => write!(f, "{} changed since it was opened", path),
+            KError::ArchiveChecksumMismatch {
+                offset,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "archive header checksum mismatch at offset {}: stored {}, computed {}",
+                offset, stored, computed
+            ),
+            KError::SourceTruncated { size, pos } => write!(
+                f,
+                "data source truncated to {} bytes, before the current position {}",
+                size, pos
+            ),
+            KError::SizeUnderflow {
+                minuend,
+                subtrahend,
+            } => write!(
+                f,
+                "size {} - {} is negative: {} exceeds {}",
+                minuend, subtrahend, subtrahend, minuend
+            ),
+            KError::Incomplete {
+                requested,
+                available,
+            } => write!(
+                f,
+                "{} of {} requested bytes available, {} short",
+                available,
+                requested,
+                requested - available
+            ),
+            KError::TrailingBytes {
+                offset,
+                len,
+                preview,
+            } => write!(
+                f,
+                "{} trailing bytes at offset {}: {}{}",
+                len,
+                offset,
+                format_bytes_hex(preview, preview.len()),
+                if *len > preview.len() { " …" } else { "" }
+            ),
+            KError::UnalignedBitRead { offset, bits_left } => write!(
+                f,
+                "byte-sized read at offset {} with {} bits of a bit-sized read pending",
+                offset, bits_left
+            ),
+            KError::PointerOutOfBounds {
+                offset,
+                len,
+                start,
+                end,
+            } => write!(
+                f,
+                "{} bytes at offset {} are outside of the region {}..{}",
+                len, offset, start, end
+            ),
+            KError::PartialElement { len, element_size } => write!(
+                f,
+                "{} bytes are not a whole number of {}-byte elements",
+                len, element_size
+            ),
+            KError::FeatureDisabled { feature } => {
+                write!(f, "the runtime was built without the `{}` feature", feature)
+            }
+            KError::RepeatUntilEof { parsed, .. } => write!(
+                f,
+                "repeat-until condition not met after {} elements",
+                parsed
+            ),
+        }
+    }
+
+    // the error this one wraps
+    fn wrapped(&self) -> Option<&KError> {
+        match self {
+            KError::At { source, .. } | KError::RepeatUntilEof { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+
+    /// This error followed by the errors it wraps, innermost last.
+    pub fn chain(&self) -> impl Iterator<Item = &KError> {
+        std::iter::successors(Some(self), |e| e.wrapped())
+    }
+
+    /// The innermost error, below all [`KError::At`] and similar wrappers.
+    pub fn root_cause(&self) -> &KError {
+        self.chain().last().unwrap_or(self)
+    }
+}
+
+impl std::error::Error for KError {}
+
+/// Coarse classification of a [`KError`], see [`KError::category`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCategory {
+    /// The data ended before a read could complete.
+    Eof,
+    /// Bytes could not be decoded as a string.
+    Encoding,
+    /// A `valid` constraint of the spec was violated.
+    Validation,
+    /// The data does not fit the structure the spec describes, e.g. a
+    /// missing terminator.
+    Format,
+    /// The object tree is not linked up as expected (missing root/parent,
+    /// failed casts).
+    Structure,
+    /// Failure of the underlying reader.
+    Io,
+    /// Invalid argument passed to the runtime.
+    InvalidInput,
+    /// A configured resource limit was hit.
+    Limit,
+    /// Bug in the runtime or in parsing code.
+    Internal,
+}
+
+impl KError {
+    pub fn eof(requested: usize, available: usize) -> Self {
+        KError::Eof {
+            requested,
+            available,
+        }
+    }
+
+    pub fn eof_bits(requested: usize, available: u64, offset: usize) -> Self {
+        KError::EofBits {
+            requested,
+            available,
+            offset,
+        }
+    }
+
+    pub fn incomplete(requested: usize, available: usize) -> Self {
+        KError::Incomplete {
+            requested,
+            available,
+        }
+    }
+
+    pub fn encoding(
+        label: impl Into<String>,
+        desc: impl Into<String>,
+        input_len: usize,
+        bad_offset: Option<usize>,
+    ) -> Self {
+        KError::Encoding {
+            label: label.into(),
+            desc: desc.into(),
+            input_len,
+            bad_offset,
+        }
+    }
+
+    pub fn validation_failed(kind: ValidationKind, src_path: impl Into<String>) -> Self {
+        KError::ValidationFailed(ValidationFailedError {
+            kind,
+            src_path: src_path.into(),
+        })
+    }
+
+    pub fn io(msg: impl Into<String>) -> Self {
+        KError::IoError { msg: msg.into() }
+    }
+
+    /// `source` at position `pos` of the stream named `stream`, see
+    /// [`KError::At`].
+    pub fn at(
+        pos: usize,
+        root_offset: Option<RootOffset>,
+        stream: Option<String>,
+        source: KError,
+    ) -> Self {
+        KError::At {
+            pos,
+            root_offset,
+            stream,
+            source: Box::new(source),
+        }
+    }
+
+    pub fn invalid_input(offset: usize, msg: impl Into<String>) -> Self {
+        KError::InvalidInput {
+            offset,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn undecided_endianness(src_path: impl Into<String>) -> Self {
+        KError::UndecidedEndianness {
+            src_path: src_path.into(),
+        }
+    }
+
+    pub fn value_out_of_range(value: impl Into<i128>, context: impl Into<String>) -> Self {
+        KError::ValueOutOfRange {
+            value: value.into(),
+            context: context.into(),
+        }
+    }
+
+    pub fn source_changed(path: impl Into<String>) -> Self {
+        KError::SourceChanged { path: path.into() }
+    }
+
+    pub fn trailing_bytes(offset: usize, len: usize, preview: Vec<u8>) -> Self {
+        KError::TrailingBytes {
+            offset,
+            len,
+            preview,
+        }
+    }
+
+    pub fn unaligned_bit_read(offset: usize, bits_left: u8) -> Self {
+        KError::UnalignedBitRead { offset, bits_left }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            KError::At { source, .. } => source.category(),
+            KError::Eof { .. }
+            | KError::EofBits { .. }
+            | KError::Incomplete { .. }
+            | KError::RepeatUntilEof { .. } => ErrorCategory::Eof,
+            KError::Encoding { .. } => ErrorCategory::Encoding,
+            #[allow(deprecated)]
+            KError::UnknownEncoding { .. } | KError::BytesDecodingError { .. } => {
+                ErrorCategory::Encoding
+            }
+            KError::ValidationFailed(_) => ErrorCategory::Validation,
+            KError::NoTerminatorFound
+            | KError::NoTerminatorInSubstream { .. }
+            | KError::UndecidedEndianness { .. }
+            | KError::CorruptArchive { .. }
+            | KError::ArchiveChecksumMismatch { .. }
+            | KError::SizeUnderflow { .. }
+            | KError::TrailingBytes { .. }
+            | KError::PointerOutOfBounds { .. } => ErrorCategory::Format,
+            KError::EmptyIterator
+            | KError::MissingRoot
+            | KError::MissingParent
+            | KError::CastError
+            | KError::AncestorNotFound { .. } => ErrorCategory::Structure,
+            KError::IoError { .. }
+            | KError::SourceTruncated { .. }
+            | KError::SourceChanged { .. } => ErrorCategory::Io,
+            KError::AllocationLimitExceeded { .. } => ErrorCategory::Limit,
+            KError::Internal { .. } => ErrorCategory::Internal,
+            KError::InvalidInput { .. }
+            | KError::ValueOutOfRange { .. }
+            | KError::ArchiveMemberNotFound { .. }
+            | KError::FrozenReader { .. }
+            | KError::FeatureDisabled { .. }
+            | KError::PartialElement { .. }
+            | KError::UnalignedBitRead { .. }
+            | KError::ReadBitsTooLarge { .. }
+            | KError::SnapshotMismatch => ErrorCategory::InvalidInput,
+        }
+    }
+
+    /// Byte offset the error points at, if it carries one: the stream
+    /// position for bit-level EOF and enriched errors, or the offset into the
+    /// input for encoding and invalid input errors.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            KError::EofBits { offset, .. }
+            | KError::NoTerminatorInSubstream { end: offset, .. }
+            | KError::InvalidInput { offset, .. }
+            | KError::TrailingBytes { offset, .. }
+            | KError::UnalignedBitRead { offset, .. }
+            | KError::At { pos: offset, .. } => Some(*offset),
+            KError::Encoding { bad_offset, .. } => *bad_offset,
+            KError::PointerOutOfBounds { offset, .. } => usize::try_from(*offset).ok(),
+            KError::RepeatUntilEof { source, .. } => source.offset(),
+            _ => None,
+        }
+    }
+
+    /// True if the data ended too early, at byte or bit level.
+    pub fn is_eof(&self) -> bool {
+        self.category() == ErrorCategory::Eof
+    }
+
+    /// Offset in the root stream, for enriched errors whose position could
+    /// be translated.
+    pub fn root_offset(&self) -> Option<RootOffset> {
+        match self {
+            KError::At { root_offset, .. } => *root_offset,
+            _ => None,
+        }
+    }
+}
+
 /// Details of the failed validation.
 ///
 /// <div class="warning">
@@ -44,10 +723,29 @@ pub struct ValidationFailedError {
     pub src_path: String,
 }
 
+impl fmt::Display for ValidationFailedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ValidationKind::ContentsNotEqual { actual } => write!(
+                f,
+                "validation failed (NotEqual) at {}: found [{}]",
+                self.src_path,
+                format_bytes_hex(actual, actual.len())
+            ),
+            kind => write!(f, "validation failed ({:?}) at {}", kind, self.src_path),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 #[non_exhaustive]
 pub enum ValidationKind {
     NotEqual,
+    /// [`NotEqual`](Self::NotEqual) with the bytes found, from
+    /// [`ensure_fixed_contents_ct`] when not redacting.
+    ContentsNotEqual {
+        actual: Vec<u8>,
+    },
     LessThan,
     GreaterThan,
     NotAnyOf,
@@ -105,8 +803,8 @@ impl<T> SharedType<T> {
 
     pub fn set(&self, rc: KResult<OptRc<T>>) {
         *self.0.borrow_mut() = match rc.ok() {
-            Some(v) => Rc::downgrade(&v.get()),
-            None => Weak::new(),
+            Some(OptRc(Some(rc))) => Rc::downgrade(&rc),
+            _ => Weak::new(),
         }
     }
 }
@@ -125,8 +823,23 @@ impl<T> OptRc<T> {
         }
     }
 
+    /// The contained `Rc`. Panics if empty, see [`try_get`](Self::try_get).
     pub fn get(&self) -> Rc<T> {
-        self.0.as_ref().unwrap().clone()
+        self.0.as_ref().expect("empty OptRc").clone()
+    }
+
+    /// The contained `Rc`, failing with [`KError::Internal`] if empty.
+    /// Parsing code uses it instead of [`get`](Self::get) and `Deref`, so
+    /// that a bug in a spec is an error rather than a panic.
+    pub fn try_get(&self) -> KResult<Rc<T>> {
+        self.try_deref().map(|_| self.get())
+    }
+
+    /// The value, failing with [`KError::Internal`] if empty.
+    pub fn try_deref(&self) -> KResult<&T> {
+        self.0.as_deref().ok_or_else(|| KError::Internal {
+            msg: format!("empty OptRc<{}>", type_name::<T>()),
+        })
     }
 
     pub fn get_value(&self) -> &Option<Rc<T>> {
@@ -137,8 +850,96 @@ impl<T> OptRc<T> {
         self.0.is_none()
     }
 
+    /// Panics if empty.
     pub fn get_mut(&mut self) -> &mut Rc<T> {
-        self.0.as_mut().unwrap()
+        self.0.as_mut().expect("empty OptRc")
+    }
+
+    /// True if both point to the same allocation, or both are empty.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.as_ptr() == other.as_ptr()
+    }
+
+    /// Address of the value, null for an empty `OptRc`.
+    pub fn as_ptr(&self) -> *const T {
+        match &self.0 {
+            Some(rc) => Rc::as_ptr(rc),
+            None => std::ptr::null(),
+        }
+    }
+
+    /// Iterate over the value, if any.
+    ///
+    /// ```
+    /// # use kaitai::OptRc;
+    /// let v = OptRc::from(3);
+    /// assert_eq!(v.iter().sum::<i32>(), 3);
+    /// assert_eq!(OptRc::<i32>::default().iter().count(), 0);
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.0.iter().map(|rc| &**rc)
+    }
+
+    /// ```
+    /// # use kaitai::OptRc;
+    /// assert_eq!(OptRc::from(3).as_deref(), Some(&3));
+    /// assert_eq!(OptRc::<i32>::default().as_deref(), None);
+    /// ```
+    pub fn as_deref(&self) -> Option<&T> {
+        self.0.as_deref()
+    }
+
+    /// ```
+    /// # use kaitai::OptRc;
+    /// assert_eq!(OptRc::from(3).map_or(0, |v| v * 2), 6);
+    /// assert_eq!(OptRc::<i32>::default().map_or(0, |v| v * 2), 0);
+    /// ```
+    pub fn map_or<U>(&self, default: U, f: impl FnOnce(&T) -> U) -> U {
+        self.as_deref().map_or(default, f)
+    }
+
+    /// The contained `Rc`, or the one computed by `f` if empty.
+    ///
+    /// ```
+    /// # use kaitai::OptRc;
+    /// # use std::rc::Rc;
+    /// assert_eq!(*OptRc::from(3).unwrap_or_else(|| Rc::new(0)), 3);
+    /// assert_eq!(*OptRc::<i32>::default().unwrap_or_else(|| Rc::new(0)), 0);
+    /// ```
+    pub fn unwrap_or_else(&self, f: impl FnOnce() -> Rc<T>) -> Rc<T> {
+        self.0.clone().unwrap_or_else(f)
+    }
+}
+
+/// Wrapper comparing and hashing an [`OptRc`] by address, so that parsed
+/// nodes can key a `HashMap` regardless of their content. All empty
+/// `OptRc`s form a single key.
+#[derive(Debug)]
+pub struct OptRcKey<T>(pub OptRc<T>);
+
+impl<T> Clone for OptRcKey<T> {
+    fn clone(&self) -> Self {
+        OptRcKey(self.0.clone())
+    }
+}
+
+impl<T> PartialEq for OptRcKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ptr_eq(&other.0)
+    }
+}
+
+impl<T> Eq for OptRcKey<T> {}
+
+impl<T> Hash for OptRcKey<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ptr().hash(state)
+    }
+}
+
+impl<T> From<OptRc<T>> for OptRcKey<T> {
+    fn from(v: OptRc<T>) -> Self {
+        OptRcKey(v)
     }
 }
 
@@ -161,18 +962,44 @@ impl<T> From<Rc<T>> for OptRc<T> {
     }
 }
 
+/// ```
+/// # use kaitai::OptRc;
+/// # use std::rc::Rc;
+/// let v = OptRc::<i32>::from(Some(Rc::new(3)));
+/// assert_eq!(v.as_deref(), Some(&3));
+/// assert!(OptRc::<i32>::from(None).is_none());
+/// ```
+impl<T> From<Option<Rc<T>>> for OptRc<T> {
+    fn from(v: Option<Rc<T>>) -> Self {
+        OptRc(v)
+    }
+}
+
+/// ```
+/// # use kaitai::OptRc;
+/// # use std::rc::Rc;
+/// let v: Option<Rc<i32>> = OptRc::from(3).into();
+/// assert_eq!(v, Some(Rc::new(3)));
+/// ```
+impl<T> From<OptRc<T>> for Option<Rc<T>> {
+    fn from(v: OptRc<T>) -> Self {
+        v.0
+    }
+}
+
 impl<T> From<T> for OptRc<T> {
     fn from(v: T) -> Self {
         OptRc(Some(v.into()))
     }
 }
 
+/// Panics if empty, see [`OptRc::try_deref`].
 impl<T> Deref for OptRc<T> {
     type Target = T;
 
     #[inline(always)]
     fn deref(&self) -> &Self::Target {
-        self.0.as_ref().unwrap()
+        self.0.as_ref().expect("empty OptRc")
     }
 }
 
@@ -195,8 +1022,8 @@ pub trait KStruct: Default {
         _parent: Option<SharedType<T::Parent>>,
     ) -> KResult<OptRc<T>> {
         let t = OptRc::from(T::default());
-        let root = Self::downcast(_root, t.clone(), true);
-        let parent = Self::downcast(_parent, t.clone(), false);
+        let root = Self::root_of(_root, &t)?;
+        let parent = Self::downcast(_parent, t.clone(), false)?;
         T::read(&t, _io, root, parent)?;
         Ok(t)
     }
@@ -208,45 +1035,178 @@ pub trait KStruct: Default {
         _parent: Option<SharedType<T::Parent>>,
         init: &dyn Fn(&mut T) -> KResult<()>,
     ) -> KResult<OptRc<T>> {
-        let mut t = OptRc::from(T::default());
-        init(Rc::get_mut(t.get_mut()).unwrap())?;
+        let mut value = T::default();
+        init(&mut value)?;
+        let t = OptRc::from(value);
 
-        let root = Self::downcast(_root, t.clone(), true);
-        let parent = Self::downcast(_parent, t.clone(), false);
+        let root = Self::root_of(_root, &t)?;
+        let parent = Self::downcast(_parent, t.clone(), false)?;
         T::read(&t, _io, root, parent)?;
         Ok(t)
     }
 
-    fn downcast<T, U>(opt_rc: Option<SharedType<U>>, t: OptRc<T>, panic: bool) -> SharedType<U>
+    // without an explicit root, `t` has to be the root itself
+    #[doc(hidden)]
+    fn root_of<T>(root: Option<SharedType<T::Root>>, t: &OptRc<T>) -> KResult<SharedType<T::Root>>
+    where
+        T: KStruct + Default + Any,
+    {
+        let missing = root.is_none();
+        let root = Self::downcast(root, t.clone(), false)?;
+        if missing && root.is_empty() {
+            return Err(KError::MissingRoot);
+        }
+        Ok(root)
+    }
+
+    /// `opt_rc`, or else `t` as a `U`. If `t` is not a `U` the result is
+    /// empty, or with `required` a [`KError::Internal`].
+    fn downcast<T, U>(
+        opt_rc: Option<SharedType<U>>,
+        t: OptRc<T>,
+        required: bool,
+    ) -> KResult<SharedType<U>>
     where
         T: KStruct + Default + Any,
         U: 'static,
     {
         if let Some(rc) = opt_rc {
-            rc
-        } else {
-            let t_any = &t.get() as &dyn Any;
-            //println!("`{}` is a '{}' type", type_name_of_val(&t), type_name::<Rc<U>>());
-            match t_any.downcast_ref::<Rc<U>>() {
-                Some(as_result) => SharedType::<U>::new(Rc::clone(as_result)),
-                None => {
-                    if panic {
-                        #[cfg(feature = "type_name_of_val")]
-                        panic!(
-                            "`{}` is not a '{}' type",
-                            std::any::type_name_of_val(&t),
-                            type_name::<Rc<U>>()
-                        );
-                        #[cfg(not(feature = "type_name_of_val"))]
-                        panic!("`{:p}` is not a '{}' type", &t, type_name::<Rc<U>>());
-                    }
-                    SharedType::<U>::empty()
-                }
+            return Ok(rc);
+        }
+        let t_rc = t.try_get()?;
+        match (&t_rc as &dyn Any).downcast_ref::<Rc<U>>() {
+            Some(as_result) => Ok(SharedType::<U>::new(Rc::clone(as_result))),
+            None if required => {
+                #[cfg(feature = "type_name_of_val")]
+                let name = std::any::type_name_of_val(&t).to_string();
+                #[cfg(not(feature = "type_name_of_val"))]
+                let name = format!("{:p}", &t);
+                Err(KError::Internal {
+                    msg: format!("`{}` is not a '{}' type", name, type_name::<Rc<U>>()),
+                })
             }
+            None => Ok(SharedType::<U>::empty()),
+        }
+    }
+}
+
+/// Types that can be stepped over without materializing their fields, for
+/// use with [`read_skip`].
+pub trait KStructSkip {
+    /// Advance `io` past one instance of the type, ending up exactly where
+    /// a full parse would.
+    fn skip<S: KStream>(io: &S) -> KResult<()>;
+}
+
+/// Location of a skipped struct in its stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkipInfo {
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Step over one instance of `T`, returning where it was. Fails with
+/// [`KError::InvalidInput`] if the skip leaves `io` before where it started.
+pub fn read_skip<S: KStream, T: KStructSkip>(io: &S) -> KResult<SkipInfo> {
+    let offset = io.pos();
+    T::skip(io)?;
+    let len = io
+        .pos()
+        .checked_sub(offset)
+        .ok_or_else(|| KError::invalid_input(offset, "skip moved the stream backwards"))?;
+    Ok(SkipInfo { offset, len })
+}
+
+/// Struct field parsed on first access, see [`defer_read`].
+pub struct KLazyStruct<T: KStruct> {
+    io: BytesReader,
+    offset: usize,
+    root: Option<SharedType<T::Root>>,
+    parent: Option<SharedType<T::Parent>>,
+    value: RefCell<Option<OptRc<T>>>,
+}
+
+impl<T: KStruct + Any> KLazyStruct<T> {
+    /// Parse the struct, or return the result of an earlier successful parse.
+    pub fn get(&self) -> KResult<OptRc<T>> {
+        if let Some(v) = &*self.value.borrow() {
+            return Ok(v.clone());
+        }
+        let io = KStream::clone(&self.io);
+        io.seek(self.offset)?;
+        let v = T::read_into::<_, T>(&io, self.root.clone(), self.parent.clone())?;
+        *self.value.borrow_mut() = Some(v.clone());
+        Ok(v)
+    }
+
+    pub fn is_parsed(&self) -> bool {
+        self.value.borrow().is_some()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<T: KStruct> Default for KLazyStruct<T> {
+    fn default() -> Self {
+        KLazyStruct {
+            io: BytesReader::default(),
+            offset: 0,
+            root: None,
+            parent: None,
+            value: RefCell::new(None),
         }
     }
 }
 
+impl<T: KStruct + fmt::Debug> fmt::Debug for KLazyStruct<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KLazyStruct")
+            .field("offset", &self.offset)
+            .field("value", &self.value.borrow())
+            .finish()
+    }
+}
+
+/// Step over one `T` (see [`KStructSkip`]) and return a handle that parses
+/// it on first access. Errors of the actual parse surface on access.
+pub fn defer_read<S: KStream, T: KStruct + KStructSkip>(
+    io: &S,
+    root: Option<SharedType<T::Root>>,
+    parent: Option<SharedType<T::Parent>>,
+) -> KResult<KLazyStruct<T>> {
+    let offset = io.pos();
+    T::skip(io)?;
+    Ok(KLazyStruct {
+        io: io.clone(),
+        offset,
+        root,
+        parent,
+        value: RefCell::new(None),
+    })
+}
+
+/// Like [`defer_read`] for a struct occupying the next `len` bytes, which
+/// is parsed from a substream of that size.
+pub fn defer_read_sized<S: KStream, T: KStruct>(
+    io: &S,
+    len: usize,
+    root: Option<SharedType<T::Root>>,
+    parent: Option<SharedType<T::Parent>>,
+) -> KResult<KLazyStruct<T>> {
+    let offset = io.pos();
+    let sub = io.try_substream(len)?;
+    io.advance(len);
+    Ok(KLazyStruct {
+        io: sub,
+        offset,
+        root,
+        parent,
+        value: RefCell::new(None),
+    })
+}
+
 /// Dummy struct used to indicate an absence of value; needed for
 /// root structs to satisfy the associated type bounds in the
 /// `KStruct` trait.
@@ -269,679 +1229,6355 @@ impl KStruct for KStructUnit {
 
 impl From<std::io::Error> for KError {
     fn from(err: std::io::Error) -> Self {
-        Self::IoError {
-            msg: err.to_string(),
+        if let Some(changed) = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<lazy::ChangedError>())
+        {
+            return KError::source_changed(changed.path.clone());
         }
+        Self::io(err.to_string())
     }
 }
 
-pub trait KStream {
-    fn clone(&self) -> BytesReader;
-    fn size(&self) -> usize;
-
-    fn is_eof(&self) -> bool {
-        if self.get_state().bits_left > 0 {
-            return false;
-        }
-        self.pos() >= self.size()
-    }
-
-    fn seek(&self, position: usize) -> KResult<()> {
-        self.get_state_mut().pos = position;
-        Ok(())
+// `n` values decoded from a single read of their backing bytes
+fn read_array<S, T, E>(io: &S, n: usize) -> KResult<Vec<T>>
+where
+    S: KStream + ?Sized,
+    T: endian::sealed::Primitive,
+    E: ByteOrder,
+{
+    let mut bytes = match n.checked_mul(T::SIZE) {
+        Some(len) => io.read_bytes(len)?,
+        None => return Err(KError::eof(usize::MAX, io.size().saturating_sub(io.pos()))),
+    };
+    // swapped all at once, then taken as they are
+    if endian::is_foreign::<E>() {
+        endian::swap_elements(&mut bytes, T::SIZE)?;
     }
+    Ok(bytes.chunks_exact(T::SIZE).map(T::from_ne_slice).collect())
+}
 
-    fn pos(&self) -> usize {
-        self.get_state().pos
-    }
+/// Widest bit-sized integer that can be read at once.
+pub const MAX_BITS_READ: usize = 64;
 
-    fn read_s1(&self) -> KResult<i8> {
-        Ok(self.read_bytes(1)?[0] as i8)
-    }
-    fn read_s2be(&self) -> KResult<i16> {
-        Ok(i16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
-    }
-    fn read_s4be(&self) -> KResult<i32> {
-        Ok(i32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
-    }
-    fn read_s8be(&self) -> KResult<i64> {
-        Ok(i64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
-    }
-    fn read_s2le(&self) -> KResult<i16> {
-        Ok(i16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+// checked up front, so that a failed read leaves the bit state untouched
+fn check_bits_available<S: KStream + ?Sized>(io: &S, n: usize) -> KResult<()> {
+    if n > MAX_BITS_READ {
+        return Err(KError::ReadBitsTooLarge {
+            requested: n,
+            max: MAX_BITS_READ,
+        });
     }
-    fn read_s4le(&self) -> KResult<i32> {
-        Ok(i32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    if n == 0 && io.session().is_some_and(ParseSession::strict_bits) {
+        return Err(KError::invalid_input(io.pos(), "zero-width bit read"));
     }
-    fn read_s8le(&self) -> KResult<i64> {
-        Ok(i64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    let available = io.total_bits_remaining();
+    if n as u64 > available {
+        return Err(KError::eof_bits(n, available, io.pos()));
     }
+    Ok(())
+}
 
-    fn read_u1(&self) -> KResult<u8> {
-        Ok(self.read_bytes(1)?[0])
+// the byte the pending bits of `state` came from, with the consumed bits
+// cleared, and the offset of the first pending bit in it
+fn pending_byte(state: BitState, le: bool) -> (u8, usize) {
+    let consumed = 8 - usize::from(state.bits_left);
+    let byte = if le {
+        (state.bits << consumed) as u8
+    } else {
+        state.bits as u8
+    };
+    (byte, consumed)
+}
+
+// the bit state after consuming `bits` bits of `bytes`, which start with the
+// byte of `pending_byte`
+fn bit_state_after(bytes: &[u8], bits: usize, le: bool) -> BitState {
+    let bits_left = (bytes.len() * 8 - bits) as u8;
+    let last = u64::from(*bytes.last().unwrap());
+    BitState {
+        bits: if le {
+            last >> (8 - bits_left)
+        } else {
+            last & ((1 << bits_left) - 1)
+        },
+        bits_left,
     }
-    fn read_u2be(&self) -> KResult<u16> {
-        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+}
+
+// apply the `BitAlignment` of `io` before a byte-sized read
+// bytes a byte-sized read can still get
+fn unread_bytes<S: KStream + ?Sized>(io: &S) -> usize {
+    io.size().saturating_sub(io.pos())
+}
+
+fn byte_aligned<S: KStream + ?Sized>(io: &S) -> KResult<()> {
+    let bits_left = io.bit_state().bits_left;
+    if bits_left == 0 {
+        return Ok(());
     }
-    fn read_u4be(&self) -> KResult<u32> {
-        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    match io.bit_alignment() {
+        BitAlignment::Ignore => Ok(()),
+        BitAlignment::AutoAlign => io.align_to_byte(),
+        BitAlignment::Error => Err(KError::unaligned_bit_read(io.pos(), bits_left)),
     }
-    fn read_u8be(&self) -> KResult<u64> {
-        Ok(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+}
+
+// move to `bit_pos` for reads in the bit order of `read_bits_int_le` if `le`
+fn seek_bits<S: KStream + ?Sized>(io: &S, bit_pos: u64, le: bool) -> KResult<()> {
+    let byte = usize_from_u64(bit_pos / 8, "bit position")?;
+    let consumed = (bit_pos % 8) as usize;
+    let guard = PosGuard::new(io);
+    io.seek(byte)?;
+    io.set_bit_state(BitState::default());
+    if consumed > 0 {
+        let b = io.read_u1()?;
+        io.set_bit_state(bit_state_after(&[b], consumed, le));
     }
-    fn read_u2le(&self) -> KResult<u16> {
-        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    guard.commit();
+    Ok(())
+}
+
+// `read_bytes_into` without applying the `BitAlignment`, for bit-sized
+// reads and peeks; the bit state is left as it was
+fn read_into_unaligned<S: KStream + ?Sized>(io: &S, buf: &mut [u8]) -> KResult<()> {
+    let bits = io.bit_state();
+    io.set_bit_state(BitState::default());
+    let res = io.read_bytes_into(buf);
+    io.set_bit_state(bits);
+    res
+}
+
+// `n` bits, with the bit order of `read_bits_int_le` if `le`
+fn read_bits_int<S: KStream + ?Sized>(io: &S, n: usize, le: bool) -> KResult<u64> {
+    check_bits_available(io, n)?;
+    let state = io.bit_state();
+    let (byte, start) = pending_byte(state, le);
+    // the pending byte and up to 8 more
+    let mut buf = [0; 9];
+    buf[0] = byte;
+    let new_bytes = n.saturating_sub(state.bits_left.into()).div_ceil(8);
+    read_into_unaligned(io, &mut buf[1..=new_bytes])?;
+    let bytes = &buf[..=new_bytes];
+    let res = if le {
+        bits::extract_bits_le(bytes, start, n)?
+    } else {
+        bits::extract_bits_be(bytes, start, n)?
+    };
+    io.set_bit_state(bit_state_after(bytes, start + n, le));
+    Ok(res)
+}
+
+// `count` values of `width` bits, unpacked from a single read; `le` selects
+// the bit order of `read_bits_int_le`
+fn read_bits_array<S: KStream + ?Sized>(
+    io: &S,
+    width: usize,
+    count: usize,
+    le: bool,
+) -> KResult<Vec<u64>> {
+    if count == 0 {
+        return Ok(vec![]);
     }
-    fn read_u4le(&self) -> KResult<u32> {
-        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    // any number of values would fit in no bits at all
+    if width == 0 {
+        return Err(KError::invalid_input(io.pos(), "zero-width bit array"));
     }
-    fn read_u8le(&self) -> KResult<u64> {
-        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    check_bits_available(io, width)?;
+    let available = io.total_bits_remaining();
+    let total = (width as u64).saturating_mul(count as u64);
+    if total > available {
+        return Err(KError::eof_bits(
+            usize::try_from(total).unwrap_or(usize::MAX),
+            available,
+            io.pos(),
+        ));
     }
+    session::check_alloc(io, count.saturating_mul(8))?;
+    let state = io.bit_state();
+    // at most `available` bits, so this fits
+    let new_bits = total.saturating_sub(state.bits_left.into()) as usize;
+    let (byte, start) = pending_byte(state, le);
+    let mut bytes = vec![0; 1 + new_bits.div_ceil(8)];
+    bytes[0] = byte;
+    read_into_unaligned(io, &mut bytes[1..])?;
+    memory::record_alloc(io, count.saturating_mul(8));
 
-    fn read_f4be(&self) -> KResult<f32> {
-        Ok(f32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
-    }
-    fn read_f8be(&self) -> KResult<f64> {
-        Ok(f64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
-    }
-    fn read_f4le(&self) -> KResult<f32> {
-        Ok(f32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    let extract = if le {
+        bits::extract_bits_le
+    } else {
+        bits::extract_bits_be
+    };
+    let res = (0..count)
+        .map(|i| extract(&bytes, start + i * width, width))
+        .collect::<KResult<Vec<_>>>()?;
+    io.set_bit_state(bit_state_after(&bytes, start + count * width, le));
+    Ok(res)
+}
+
+// a field of `n` bits of any width, as a big-endian number left-padded to
+// whole bytes; `le` selects the bit order of `read_bits_int_le`
+fn read_bits_bytes<S: KStream + ?Sized>(io: &S, n: usize, le: bool) -> KResult<Vec<u8>> {
+    if n == 0 {
+        check_bits_available(io, 0)?;
+        return Ok(vec![]);
     }
-    fn read_f8le(&self) -> KResult<f64> {
-        Ok(f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    let available = io.total_bits_remaining();
+    if n as u64 > available {
+        return Err(KError::eof_bits(n, available, io.pos()));
     }
+    let len = n.div_ceil(8);
+    session::check_alloc(io, len)?;
+    let state = io.bit_state();
+    let new_bits = n.saturating_sub(state.bits_left.into());
+    let (byte, start) = pending_byte(state, le);
+    let mut bytes = vec![0; 1 + new_bits.div_ceil(8)];
+    bytes[0] = byte;
+    read_into_unaligned(io, &mut bytes[1..])?;
 
-    fn get_state(&self) -> Ref<ReaderState>;
-    fn get_state_mut(&self) -> RefMut<ReaderState>;
+    // the most significant byte takes the bits beyond whole bytes
+    let top = n - (len - 1) * 8;
+    let res = if le {
+        let mut res = (0..len)
+            .map(|i| {
+                let width = if i == len - 1 { top } else { 8 };
+                bits::extract_bits_le(&bytes, start + i * 8, width).map(|b| b as u8)
+            })
+            .collect::<KResult<Vec<_>>>()?;
+        res.reverse();
+        res
+    } else {
+        (0..len)
+            .map(|i| match i {
+                0 => bits::extract_bits_be(&bytes, start, top),
+                _ => bits::extract_bits_be(&bytes, start + top + (i - 1) * 8, 8),
+            })
+            .map(|b| b.map(|b| b as u8))
+            .collect::<KResult<Vec<_>>>()?
+    };
+    io.set_bit_state(bit_state_after(&bytes, start + n, le));
+    memory::record_alloc(io, len);
+    Ok(res)
+}
 
-    fn align_to_byte(&self) -> KResult<()> {
-        let mut inner = self.get_state_mut();
-        inner.bits = 0;
-        inner.bits_left = 0;
+/// Bytes shown by [`KError::TrailingBytes`].
+pub const TRAILING_PREVIEW: usize = 16;
 
-        Ok(())
-    }
+/// Bytes read at once by [`KStream::read_bytes_term`].
+pub const TERM_CHUNK: usize = 256;
 
-    fn read_bits_int_be(&self, n: usize) -> KResult<u64> {
-        let mut res: u64 = 0;
+/// Bytes read at once by [`KStream::scan_for`].
+pub const SCAN_CHUNK: usize = 64 * 1024;
 
-        if n > 64 {
-            return Err(KError::ReadBitsTooLarge { requested: n });
+// first match of `pattern` starting at most `max_distance` after `start`
+fn scan<S: KStream + ?Sized>(
+    io: &S,
+    start: usize,
+    pattern: &[u8],
+    max_distance: Option<usize>,
+) -> KResult<Option<usize>> {
+    let mut end = io.size();
+    if let Some(max) = max_distance {
+        end = end.min(start.saturating_add(max).saturating_add(pattern.len()));
+    }
+    if pattern.is_empty() {
+        return Ok(Some(start).filter(|&s| s <= end));
+    }
+    let limit = io.max_alloc();
+    let chunk_len = limit
+        .map_or(SCAN_CHUNK, |l| l.min(SCAN_CHUNK))
+        .max(pattern.len());
+    let mut chunk_start = start;
+    while end.saturating_sub(chunk_start) >= pattern.len() {
+        let len = chunk_len.min(end - chunk_start);
+        io.set_pos(chunk_start);
+        let chunk = read_bytes_unaligned(io, len)?;
+        if let Some(i) = bytes_find(&chunk, pattern) {
+            return Ok(Some(chunk_start + i));
         }
+        if chunk_start + len == end {
+            break;
+        }
+        // a match may straddle the chunks
+        chunk_start += len - (pattern.len() - 1);
+    }
+    Ok(None)
+}
 
-        let n: i32 = n.try_into().unwrap();
-        let bits_needed = n - self.get_state().bits_left;
-        self.get_state_mut().bits_left = -bits_needed & 7;
+// `f` reading `len` bytes, with the position and bit state restored after
+fn peeking<S, T>(io: &S, len: usize, f: impl FnOnce() -> KResult<T>) -> KResult<T>
+where
+    S: KStream + ?Sized,
+{
+    io.check_len(len)?;
+    let (pos, bits) = (io.pos(), io.bit_state());
+    let res = f();
+    io.set_pos(pos);
+    io.set_bit_state(bits);
+    res
+}
 
-        if bits_needed > 0 {
-            let bytes_needed = ((bits_needed - 1) / 8) + 1;
-            let buf = self.read_bytes(bytes_needed.try_into().unwrap())?;
-            for b in buf {
-                res = res << 8 | u64::from(b);
-            }
-            let mut inner = self.get_state_mut();
-            let new_bits = res;
-            res >>= inner.bits_left;
-            if bits_needed < 64 {
-                res |= inner.bits << bits_needed;
-            }
-            inner.bits = new_bits;
-        } else {
-            res = self.get_state().bits >> -bits_needed;
+// offset of position 0 of `io` in the root stream, where `AbsOffset`s count
+// from
+fn abs_base<S: KStream + ?Sized>(io: &S) -> KResult<u64> {
+    io.translate_to_root(0)
+        .map(|root| root.offset)
+        .ok_or_else(|| KError::invalid_input(io.pos(), "processed data has no absolute offsets"))
+}
+
+fn abs_to_pos<S: KStream + ?Sized>(io: &S, offset: AbsOffset) -> KResult<usize> {
+    let rel = offset
+        .relative_to(AbsOffset(abs_base(io)?))
+        .ok_or_else(|| KError::value_out_of_range(offset.0, "position before the stream"))?;
+    usize_from_u64(rel.0, "position")
+}
+
+fn peek_fixed<const N: usize, S: KStream + ?Sized>(io: &S) -> KResult<[u8; N]> {
+    peeking(io, N, || {
+        let mut buf = [0; N];
+        read_into_unaligned(io, &mut buf)?;
+        Ok(buf)
+    })
+}
+
+// result of a terminator search reaching the end of `io` after reading `buf`
+fn no_terminator<S: KStream + ?Sized>(io: &S, buf: Vec<u8>, eos_error: bool) -> KResult<Vec<u8>> {
+    let strict = io.session().is_some_and(ParseSession::strict_terminators);
+    match io.stream_end() {
+        StreamEnd::SubstreamLimit { end, size } if eos_error || strict => {
+            Err(KError::NoTerminatorInSubstream { end, size })
         }
+        _ if eos_error => Err(KError::NoTerminatorFound),
+        _ => {
+            memory::record_alloc(io, buf.len());
+            Ok(buf)
+        }
+    }
+}
 
-        let mut inner = self.get_state_mut();
-        let mask = (1u64 << inner.bits_left) - 1;
-        inner.bits &= mask;
+// a terminated read that already has `len` bytes over the allocation
+// `limit`, undone so that the stream is where the read started
+fn term_too_long<S: KStream + ?Sized>(
+    io: &S,
+    (pos, bits): (usize, BitState),
+    len: usize,
+    limit: usize,
+) -> KError {
+    io.set_pos(pos);
+    io.set_bit_state(bits);
+    KError::AllocationLimitExceeded {
+        requested: len,
+        limit,
+    }
+}
 
-        Ok(res)
+// the `len` bytes of a length-prefixed string
+fn read_str_len<S: KStream + ?Sized>(io: &S, len: usize, encoding: &str) -> KResult<String> {
+    session::check_alloc(io, len)?;
+    bytes_to_str(&io.read_bytes(len)?, encoding)
+}
+
+// `read_bytes` without applying the `BitAlignment`, for reads that leave
+// the position and bit state as they were
+fn read_bytes_unaligned<S: KStream + ?Sized>(io: &S, len: usize) -> KResult<Vec<u8>> {
+    session::check_alloc(io, len)?;
+    io.check_len(len)?;
+    let mut buf = vec![0; len];
+    read_into_unaligned(io, &mut buf)?;
+    memory::record_alloc(io, len);
+    Ok(buf)
+}
+
+// fixed-width fields are read into the stack, not a `Vec`
+fn read_fixed<const N: usize, S: KStream + ?Sized>(io: &S) -> KResult<[u8; N]> {
+    byte_aligned(io)?;
+    let mut buf = [0; N];
+    io.read_bytes_into(&mut buf)?;
+    Ok(buf)
+}
+
+// `read_fixed` for a primitive of any size, decoded in byte order `E`
+fn read_primitive<S, T, E>(io: &S) -> KResult<T>
+where
+    S: KStream + ?Sized,
+    T: endian::sealed::Primitive,
+    E: ByteOrder,
+{
+    byte_aligned(io)?;
+    let mut buf = [0; 8];
+    io.read_bytes_into(&mut buf[..T::SIZE])?;
+    Ok(endian::decode::<T, E>(&buf[..T::SIZE]))
+}
+
+/// A stream to parse from. It can be used as `dyn KStream`, except for the
+/// methods generic over a type; [`clone`](Self::clone) gives a
+/// [`BytesReader`] whatever the implementor is.
+pub trait KStream {
+    fn clone(&self) -> BytesReader;
+    fn size(&self) -> usize;
+
+    fn is_eof(&self) -> bool {
+        if self.bit_state().bits_left > 0 {
+            return false;
+        }
+        self.pos() >= self.size()
     }
 
-    fn read_bits_int_le(&self, n: usize) -> KResult<u64> {
-        let mut res: u64 = 0;
+    /// Number of bytes from the position to the end, 0 after a seek past
+    /// the end. The byte of bits left over from a bit-sized read counts as
+    /// long as some are, as for [`is_eof`](Self::is_eof): nothing remains
+    /// exactly at the end of the stream.
+    fn remaining(&self) -> usize {
+        unread_bytes(self) + usize::from(self.bit_state().bits_left > 0)
+    }
 
-        if n > 64 {
-            return Err(KError::ReadBitsTooLarge { requested: n });
+    /// Fail with [`KError::Incomplete`] unless at least `n` bytes
+    /// [`remain`](Self::remaining).
+    fn ensure_remaining(&self, n: usize) -> KResult<()> {
+        let available = self.remaining();
+        if n > available {
+            return Err(KError::incomplete(n, available));
         }
+        Ok(())
+    }
 
-        let n: i32 = n.try_into().unwrap();
-        let bits_needed = n - self.get_state().bits_left;
+    /// Move to `position`, which may be at or past the end: only a read
+    /// from there fails, as in the other Kaitai runtimes, e.g. for instances
+    /// of empty trailing structures.
+    fn seek(&self, position: usize) -> KResult<()> {
+        self.set_pos(position);
+        Ok(())
+    }
 
-        if bits_needed > 0 {
-            let bytes_needed = ((bits_needed - 1) / 8) + 1;
-            let buf = self.read_bytes(bytes_needed.try_into().unwrap())?;
-            for (i, &b) in buf.iter().enumerate() {
-                res |= u64::from(b) << (i * 8);
-            }
-            let mut inner = self.get_state_mut();
-            let new_bits = if bits_needed < 64 {
-                res >> bits_needed
-            } else {
-                0
-            };
-            res = res << inner.bits_left | inner.bits;
-            inner.bits = new_bits;
+    /// Move by `delta` bytes, backwards if negative. The bit state is kept,
+    /// as with [`seek`](Self::seek). Moving before the start or past the
+    /// end fails with [`KError::Eof`], stating the distance and the
+    /// bytes there are in that direction, and leaves the position as it is.
+    fn seek_relative(&self, delta: i64) -> KResult<()> {
+        let pos = self.pos();
+        let distance = usize::try_from(delta.unsigned_abs()).unwrap_or(usize::MAX);
+        let (target, available) = if delta < 0 {
+            (pos.checked_sub(distance), pos)
         } else {
-            let mut inner = self.get_state_mut();
-            res = inner.bits;
-            inner.bits >>= n;
+            let available = self.size().saturating_sub(pos);
+            (
+                Some(pos + distance).filter(|_| distance <= available),
+                available,
+            )
+        };
+        match target {
+            Some(target) => self.seek(target),
+            None => Err(KError::eof(distance, available)),
         }
+    }
 
-        self.get_state_mut().bits_left = -bits_needed & 7;
-
-        if n < 64 {
-            let mask = (1u64 << n) - 1;
-            res &= mask;
+    /// Fail with [`KError::Eof`], stating `len` and the bytes left, unless
+    /// `len` bytes are left. This is the check behind [`skip`](Self::skip),
+    /// [`read_bytes`](Self::read_bytes) of [`BytesReader`] and
+    /// [`try_substream`](Self::try_substream).
+    fn check_len(&self, len: usize) -> KResult<()> {
+        let available = unread_bytes(self);
+        if len > available {
+            return Err(KError::eof(len, available));
         }
+        Ok(())
+    }
 
-        Ok(res)
+    /// Move forward by `n` bytes without reading them.
+    fn skip(&self, n: usize) -> KResult<()> {
+        self.check_len(n)?;
+        self.advance(n);
+        Ok(())
     }
 
-    fn substream(&self, len: usize) -> BytesReader {
-        let reader = self.clone();
+    /// Current byte position.
+    fn pos(&self) -> usize;
 
-        let limit = reader.pos() + len;
-        let mut state = reader.get_state_mut();
-        state.max_pos = Some(std::cmp::min(limit, state.max_pos.unwrap_or(limit)));
-        drop(state);
+    /// Move to `pos` without any checks. Bounds are checked by the next read.
+    fn set_pos(&self, pos: usize);
 
-        reader
+    /// Move forward by `n` bytes without any checks, e.g. after reading them.
+    fn advance(&self, n: usize) {
+        self.set_pos(self.pos() + n);
     }
 
-    fn read_bytes(&self, len: usize) -> KResult<Vec<u8>>;
-    fn read_bytes_full(&self) -> KResult<Vec<u8>>;
+    /// Pending bits of the last bit-sized read.
+    fn bit_state(&self) -> BitState;
+    fn set_bit_state(&self, state: BitState);
 
-    fn read_bytes_term(
-        &self,
-        term: u8,
-        include: bool,
-        consume: bool,
-        eos_error: bool,
-    ) -> KResult<Vec<u8>> {
-        let mut buf = vec![];
-        loop {
-            let c = match self.read_u1() {
-                Ok(c) => c,
-                Err(KError::Eof { .. }) => {
-                    if eos_error {
-                        return Err(KError::NoTerminatorFound);
-                    }
-                    return Ok(buf);
-                }
-                Err(e) => return Err(e),
-            };
-            if c == term {
-                if include {
-                    buf.push(c);
-                }
-                if !consume {
-                    self.get_state_mut().pos -= 1;
-                }
-                return Ok(buf);
-            }
-            buf.push(c);
-        }
+    fn read_s1(&self) -> KResult<i8> {
+        Ok(read_fixed::<1, _>(self)?[0] as i8)
+    }
+    fn read_s2be(&self) -> KResult<i16> {
+        Ok(i16::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_s4be(&self) -> KResult<i32> {
+        Ok(i32::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_s8be(&self) -> KResult<i64> {
+        Ok(i64::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_s2le(&self) -> KResult<i16> {
+        Ok(i16::from_le_bytes(read_fixed(self)?))
+    }
+    fn read_s4le(&self) -> KResult<i32> {
+        Ok(i32::from_le_bytes(read_fixed(self)?))
+    }
+    fn read_s8le(&self) -> KResult<i64> {
+        Ok(i64::from_le_bytes(read_fixed(self)?))
     }
-}
 
-#[derive(Default, Debug, Clone)]
-pub struct ReaderState {
-    pos: usize,
-    max_pos: Option<usize>,
-    bits: u64,
-    bits_left: i32,
-}
+    fn read_u1(&self) -> KResult<u8> {
+        Ok(read_fixed::<1, _>(self)?[0])
+    }
+    fn read_u2be(&self) -> KResult<u16> {
+        Ok(u16::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_u4be(&self) -> KResult<u32> {
+        Ok(u32::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_u8be(&self) -> KResult<u64> {
+        Ok(u64::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_u2le(&self) -> KResult<u16> {
+        Ok(u16::from_le_bytes(read_fixed(self)?))
+    }
+    fn read_u4le(&self) -> KResult<u32> {
+        Ok(u32::from_le_bytes(read_fixed(self)?))
+    }
+    fn read_u8le(&self) -> KResult<u64> {
+        Ok(u64::from_le_bytes(read_fixed(self)?))
+    }
 
-trait ReadSeek: Read + Seek {}
+    /// 16-byte integers, e.g. UUIDs or keys, named by their width in bits
+    /// as `read_u16le` would be a 2-byte read.
+    fn read_u128be(&self) -> KResult<u128> {
+        Ok(u128::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_u128le(&self) -> KResult<u128> {
+        Ok(u128::from_le_bytes(read_fixed(self)?))
+    }
+    fn read_s128be(&self) -> KResult<i128> {
+        Ok(i128::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_s128le(&self) -> KResult<i128> {
+        Ok(i128::from_le_bytes(read_fixed(self)?))
+    }
 
-impl<T> ReadSeek for T where T: Read + Seek {}
+    fn read_f4be(&self) -> KResult<f32> {
+        Ok(f32::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_f8be(&self) -> KResult<f64> {
+        Ok(f64::from_be_bytes(read_fixed(self)?))
+    }
+    fn read_f4le(&self) -> KResult<f32> {
+        Ok(f32::from_le_bytes(read_fixed(self)?))
+    }
+    fn read_f8le(&self) -> KResult<f64> {
+        Ok(f64::from_le_bytes(read_fixed(self)?))
+    }
+    /// IEEE 754 half-precision float, see [`f16_to_f32`].
+    fn read_f2be(&self) -> KResult<f32> {
+        Ok(f16_to_f32(u16::from_be_bytes(read_fixed(self)?)))
+    }
+    fn read_f2le(&self) -> KResult<f32> {
+        Ok(f16_to_f32(u16::from_le_bytes(read_fixed(self)?)))
+    }
 
-impl fmt::Display for dyn ReadSeek {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ReadSeek")
+    /// Read an integer of type `T` in byte order `E`, e.g.
+    /// `io.read_int::<u32, LittleEndian>()`.
+    fn read_int<T: KaitaiInt, E: ByteOrder>(&self) -> KResult<T>
+    where
+        Self: Sized,
+    {
+        read_primitive::<_, T, E>(self)
     }
-}
 
-impl fmt::Debug for dyn ReadSeek {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ReadSeek")
+    /// Read a float of type `T` in byte order `E`.
+    fn read_float<T: KaitaiFloat, E: ByteOrder>(&self) -> KResult<T>
+    where
+        Self: Sized,
+    {
+        read_primitive::<_, T, E>(self)
     }
-}
 
-#[derive(Debug, Default, Clone)]
-pub struct BytesReader {
-    state: RefCell<ReaderState>,
-    // share same "instance" of data beetween all clones
-    // reposition before each read call
-    buf: OptRc<RefCell<Box<dyn ReadSeek>>>,
-    file_size: u64,
-}
+    /// Read `n` consecutive integers of type `T` in byte order `E` with a
+    /// single read, e.g. `io.read_int_array::<u16, LittleEndian>(n)`.
+    fn read_int_array<T: KaitaiInt, E: ByteOrder>(&self, n: usize) -> KResult<Vec<T>>
+    where
+        Self: Sized,
+    {
+        read_array::<_, T, E>(self, n)
+    }
 
-impl From<Vec<u8>> for BytesReader {
-    fn from(bytes: Vec<u8>) -> BytesReader {
-        BytesReader::from_buffer(bytes)
+    /// Read `n` consecutive floats of type `T` in byte order `E` with a
+    /// single read. Values are converted by bit pattern, so NaN payloads,
+    /// including signaling NaNs, are kept exactly.
+    fn read_float_array<T: KaitaiFloat, E: ByteOrder>(&self, n: usize) -> KResult<Vec<T>>
+    where
+        Self: Sized,
+    {
+        read_array::<_, T, E>(self, n)
     }
-}
 
-impl From<&[u8]> for BytesReader {
-    fn from(slice: &[u8]) -> BytesReader {
-        BytesReader::from_buffer(slice.to_vec())
+    fn read_f4be_array(&self, n: usize) -> KResult<Vec<f32>> {
+        read_array::<_, f32, BigEndian>(self, n)
+    }
+    fn read_f8be_array(&self, n: usize) -> KResult<Vec<f64>> {
+        read_array::<_, f64, BigEndian>(self, n)
+    }
+    fn read_f4le_array(&self, n: usize) -> KResult<Vec<f32>> {
+        read_array::<_, f32, LittleEndian>(self, n)
+    }
+    fn read_f8le_array(&self, n: usize) -> KResult<Vec<f64>> {
+        read_array::<_, f64, LittleEndian>(self, n)
     }
-}
 
-impl TryFrom<Box<dyn ReadSeek>> for BytesReader {
-    type Error = KError;
-    fn try_from(reader: Box<dyn ReadSeek>) -> KResult<BytesReader> {
-        BytesReader::from_reader(reader)
+    fn read_mac(&self) -> KResult<MacAddress> {
+        let mut buf = [0; 6];
+        self.read_bytes_into(&mut buf)?;
+        Ok(MacAddress(buf))
+    }
+    fn read_ipv4(&self) -> KResult<Ipv4Address> {
+        let mut buf = [0; 4];
+        self.read_bytes_into(&mut buf)?;
+        Ok(Ipv4Address(buf))
+    }
+    fn read_ipv6(&self) -> KResult<Ipv6Address> {
+        let mut buf = [0; 16];
+        self.read_bytes_into(&mut buf)?;
+        Ok(Ipv6Address(buf))
     }
-}
 
-impl BytesReader {
-    pub fn open<T: AsRef<Path>>(filename: T) -> KResult<Self> {
-        let f = std::fs::File::open(filename)?;
-        let file_size = f.metadata().unwrap().len();
-        let r: Box<dyn ReadSeek> = Box::new(f);
-        Ok(BytesReader {
-            state: RefCell::new(ReaderState::default()),
-            file_size,
-            buf: OptRc::from(RefCell::new(r)),
-        })
+    /// Identity of the data source, shared by all clones of this stream but
+    /// not by its substreams, whose positions and end differ.
+    /// [`SourceId::unknown`] by default, so that
+    /// [`restore`](Self::restore) rejects every snapshot on such streams.
+    fn source_id(&self) -> SourceId {
+        SourceId::unknown()
     }
 
-    fn from_buffer(bytes: Vec<u8>) -> Self {
-        let file_size = bytes.len() as u64;
-        let r: Box<dyn ReadSeek> = Box::new(std::io::Cursor::new(bytes));
-        BytesReader {
-            state: RefCell::new(ReaderState::default()),
-            file_size,
-            buf: OptRc::from(RefCell::new(r)),
-        }
+    /// Translate position `pos` of this stream to an offset in the root
+    /// stream (usually a file) it was derived from. Returns `None` if the
+    /// bytes at `pos` do not exist there as such, e.g. for decompressed data.
+    fn translate_to_root(&self, pos: usize) -> Option<RootOffset> {
+        Some(RootOffset { offset: pos as u64 })
     }
 
-    fn from_reader(reader: Box<dyn ReadSeek>) -> KResult<Self> {
-        let mut reader = reader;
+    /// Attach the current position, and its translation to the root stream,
+    /// to `e`. Already enriched errors are returned as they are.
+    fn enrich_error(&self, e: KError) -> KError {
+        match e {
+            KError::At { .. } => e,
+            e => KError::at(
+                self.pos(),
+                self.translate_to_root(self.pos()),
+                self.name().map(|n| n.to_string()),
+                e,
+            ),
+        }
+    }
 
-        let file_size = reader.stream_position()?;
-        reader.seek(SeekFrom::End(0))?;
-        reader.seek(SeekFrom::Start(0))?;
+    /// Name of the data source for messages, e.g. the path of a file.
+    /// Readers derived from a named one extend its name: `a.bin[0x200..0x400]`
+    /// for the bytes at 0x200 up to 0x400, `a.bin[0x200..0x400]/zlib` for
+    /// their unpacked content.
+    fn name(&self) -> Option<Rc<str>> {
+        None
+    }
 
-        Ok(BytesReader {
-            state: RefCell::new(ReaderState::default()),
-            file_size,
-            buf: OptRc::from(RefCell::new(reader)),
-        })
+    /// Settings of the parse this stream belongs to, if any.
+    fn session(&self) -> Option<&ParseSession> {
+        None
     }
 
-    // sync stream pos with state.pos
-    fn sync_pos(&self) -> KResult<()> {
-        let cur_pos = self.buf.borrow_mut().stream_position()?;
-        if self.pos() != cur_pos as usize {
-            self.buf
-                .borrow_mut()
-                .seek(SeekFrom::Start(self.pos() as u64))?;
-        }
-        Ok(())
+    /// Largest buffer a single read may allocate, `None` if unlimited. Reads
+    /// asking for more fail with [`KError::AllocationLimitExceeded`] before
+    /// allocating.
+    fn max_alloc(&self) -> Option<usize> {
+        self.session().and_then(ParseSession::max_alloc)
     }
-}
 
-impl KStream for BytesReader {
-    fn clone(&self) -> Self {
-        Clone::clone(self)
+    /// What byte-sized reads do while bits of a bit-sized read are pending,
+    /// see [`BitAlignment`].
+    fn bit_alignment(&self) -> BitAlignment {
+        BitAlignment::Ignore
     }
 
-    fn get_state(&self) -> Ref<ReaderState> {
-        self.state.borrow()
+    /// Attribute the allocations of reads to `type_name` until the returned
+    /// scope is dropped, if the session has a [`MemoryAccountant`]. Parsing
+    /// code opens one at the start of each struct.
+    fn memory_scope(&self, type_name: &str) -> Option<MemoryScope> {
+        let accountant = self.session()?.memory_accountant()?;
+        Some(MemoryScope::new(accountant.clone(), type_name))
     }
 
-    fn get_state_mut(&self) -> RefMut<ReaderState> {
-        self.state.borrow_mut()
+    /// Count a buffer of `len` bytes made from data of this stream, e.g.
+    /// the result of a `process`, for the [`MemoryAccountant`] of the
+    /// session.
+    fn account_alloc(&self, len: usize) {
+        memory::record_alloc(self, len);
     }
 
-    fn size(&self) -> usize {
-        match self.get_state().max_pos {
-            Some(pos) => pos,
-            None => self.file_size as usize,
+    /// Report a non-fatal finding at the current position to the
+    /// diagnostics sink of the session.
+    fn report(&self, message: &str) {
+        if let Some(session) = self.session() {
+            session.report(Diagnostic {
+                offset: self.pos(),
+                message: message.to_string(),
+            });
         }
     }
 
-    fn read_bytes(&self, len: usize) -> KResult<Vec<u8>> {
-        // handle read beyond end of file
-        let num_bytes_available = self.size().saturating_sub(self.pos());
-        if len > num_bytes_available {
-            return Err(KError::Eof {
-                requested: len,
-                available: num_bytes_available,
-            });
+    /// Capture the position and pending bit state of the stream.
+    fn snapshot(&self) -> StreamSnapshot {
+        StreamSnapshot {
+            pos: self.pos(),
+            bits: self.bit_state(),
+            source: self.source_id(),
         }
-        self.sync_pos()?;
-        // let state = self.state.borrow_mut();
-        // state.buf.resize(len, 0);
-        let mut buf = vec![0; len];
-        self.buf.borrow_mut().read_exact(&mut buf[..])?;
-        self.get_state_mut().pos += len;
-        Ok(buf)
     }
 
-    fn read_bytes_full(&self) -> KResult<Vec<u8>> {
-        if self.get_state().max_pos.is_some() {
-            return self.read_bytes(self.size().saturating_sub(self.pos()));
+    /// Return to the state captured by [`snapshot`](KStream::snapshot). The
+    /// snapshot may come from a clone of this stream, but not from a stream
+    /// over a different data source or another part of it, such as a
+    /// substream, nor from one whose [`source_id`](KStream::source_id) is
+    /// unknown.
+    fn restore(&self, snap: &StreamSnapshot) -> KResult<()> {
+        if snap.source != self.source_id() {
+            return Err(KError::SnapshotMismatch);
         }
+        self.set_pos(snap.pos);
+        self.set_bit_state(snap.bits);
+        Ok(())
+    }
 
-        self.sync_pos()?;
-        //let state = self.state.borrow_mut();
-        let mut buf = Vec::new();
-        let readed = self.buf.borrow_mut().read_to_end(&mut buf)?;
-        self.get_state_mut().pos += readed;
-        Ok(buf)
+    /// Save the position and bit state, to be restored when the returned
+    /// guard is dropped, e.g. around a `pos` instance. Also on early
+    /// returns with `?`, unless the guard is
+    /// [`commit`](PosGuard::commit)ted.
+    fn save_pos(&self) -> PosGuard<'_, Self>
+    where
+        Self: Sized,
+    {
+        PosGuard::new(self)
     }
-}
 
-/// Return a byte array that is sized to exclude all trailing instances of the
-/// padding character.
-pub fn bytes_strip_right(bytes: &Vec<u8>, pad: u8) -> Vec<u8> {
-    if let Some(last_non_pad_index) = bytes.iter().rposition(|&c| c != pad) {
-        bytes[..=last_non_pad_index].to_vec()
-    } else {
-        vec![]
+    fn align_to_byte(&self) -> KResult<()> {
+        self.set_bit_state(BitState::default());
+        Ok(())
     }
-}
 
-/// Return a byte array that contains all bytes up until the
-/// termination byte. Can optionally include the termination byte as well.
-pub fn bytes_terminate(bytes: &Vec<u8>, term: u8, include_term: bool) -> Vec<u8> {
-    if let Some(term_index) = bytes.iter().position(|&c| c == term) {
-        &bytes[..term_index + if include_term { 1 } else { 0 }]
-    } else {
-        bytes
+    /// Number of bits read from the stream but not yet consumed by a bit read.
+    fn bits_remaining_in_buffer(&self) -> u8 {
+        self.bit_state().bits_left
     }
-    .to_vec()
-}
 
-pub fn bytes_to_str(bytes: &Vec<u8>, label: &str) -> KResult<String> {
-    if let Some(enc) = encoding_from_whatwg_label(label) {
-        return Ok(enc
-            .decode(bytes.as_slice(), DecoderTrap::Replace)
-            .expect("this should never fail because we use DecoderTrap::Replace"));
+    /// Number of bits still available: the pending buffer plus all unread bytes.
+    fn total_bits_remaining(&self) -> u64 {
+        let bytes_left = unread_bytes(self) as u64;
+        u64::from(self.bits_remaining_in_buffer()) + bytes_left * 8
     }
 
-    if label.eq_ignore_ascii_case("cp437") || label.eq_ignore_ascii_case("ibm437") {
-        use std::io::BufReader;
-        let reader = BufReader::new(bytes.as_slice());
-        let mut buffer = reader.bytes();
-        let mut r = cp437::Reader::new(&mut buffer);
-        return Ok(r.consume(bytes.len()));
+    /// Position in bits: bits of the current byte consumed by bit reads
+    /// count, the pending ones do not.
+    fn pos_bits(&self) -> u64 {
+        (self.pos() as u64 * 8).saturating_sub(self.bit_state().bits_left.into())
     }
 
-    Err(KError::UnknownEncoding {
-        name: label.to_string(),
-    })
-}
+    /// Move to bit `bit_pos` of the stream, so that the next
+    /// [`read_bits_int_be`](Self::read_bits_int_be) starts there, e.g. for
+    /// an instance at a bit offset. Within a byte the bit state is set up
+    /// from a read of that byte, which fails with [`KError::Eof`] past the
+    /// end; the position is unchanged then. A byte-aligned `bit_pos` is a
+    /// [`seek`](Self::seek) that also drops pending bits.
+    fn seek_bits(&self, bit_pos: u64) -> KResult<()> {
+        seek_bits(self, bit_pos, false)
+    }
 
-pub fn process_xor_one(bytes: &Vec<u8>, key: u8) -> Vec<u8> {
-    let mut res = bytes.to_vec();
-    for i in &mut res {
-        *i ^= key;
+    /// [`seek_bits`](Self::seek_bits) for
+    /// [`read_bits_int_le`](Self::read_bits_int_le), which takes the bits
+    /// of a byte in the opposite order.
+    fn seek_bits_le(&self, bit_pos: u64) -> KResult<()> {
+        seek_bits(self, bit_pos, true)
     }
-    res
-}
 
-pub fn process_xor_many(bytes: &Vec<u8>, key: &[u8]) -> Vec<u8> {
-    let mut res = bytes.to_vec();
-    let mut ki = 0;
-    for i in &mut res {
-        *i ^= key[ki];
-        ki += 1;
-        if ki >= key.len() {
-            ki = 0;
-        }
+    /// Returns true if not a single bit can be read anymore.
+    fn is_eof_bits(&self) -> bool {
+        self.total_bits_remaining() == 0
+    }
+
+    fn read_bits_int_be(&self, n: usize) -> KResult<u64> {
+        read_bits_int(self, n, false)
+    }
+
+    fn read_bits_int_le(&self, n: usize) -> KResult<u64> {
+        read_bits_int(self, n, true)
+    }
+
+    /// A big-endian bit field of `n` bits, which may be more than
+    /// [`MAX_BITS_READ`], as a big-endian number in `n.div_ceil(8)` bytes,
+    /// padded with zero bits at the start. Bit reads continue after it as
+    /// after [`read_bits_int_be`](Self::read_bits_int_be).
+    fn read_bits_bytes_be(&self, n: usize) -> KResult<Vec<u8>> {
+        read_bits_bytes(self, n, false)
+    }
+
+    /// [`read_bits_bytes_be`](Self::read_bits_bytes_be) for the bit order
+    /// of [`read_bits_int_le`](Self::read_bits_int_le). The result is a
+    /// big-endian number as well.
+    fn read_bits_bytes_le(&self, n: usize) -> KResult<Vec<u8>> {
+        read_bits_bytes(self, n, true)
+    }
+
+    /// [`read_bits_int_be`](Self::read_bits_int_be) of a two's complement
+    /// field, see [`sign_extend`].
+    fn read_bits_int_be_signed(&self, n: usize) -> KResult<i64> {
+        Ok(sign_extend(read_bits_int(self, n, false)?, n))
+    }
+
+    /// [`read_bits_int_le`](Self::read_bits_int_le) of a two's complement
+    /// field, see [`sign_extend`].
+    fn read_bits_int_le_signed(&self, n: usize) -> KResult<i64> {
+        Ok(sign_extend(read_bits_int(self, n, true)?, n))
+    }
+
+    /// Read `count` values of `width` bits each, packed as by
+    /// [`read_bits_int_be`](Self::read_bits_int_be), with a single read of
+    /// the underlying bytes. Fails before reading anything if fewer than
+    /// `width * count` bits are left, and with [`KError::InvalidInput`] for
+    /// a `width` of 0 and a `count` other than 0.
+    fn read_bits_array_be(&self, width: usize, count: usize) -> KResult<Vec<u64>> {
+        read_bits_array(self, width, count, false)
+    }
+
+    /// [`read_bits_array_be`](Self::read_bits_array_be) in the bit order of
+    /// [`read_bits_int_le`](Self::read_bits_int_le).
+    fn read_bits_array_le(&self, width: usize, count: usize) -> KResult<Vec<u64>> {
+        read_bits_array(self, width, count, true)
+    }
+
+    /// [`substream`](Self::substream) failing with [`KError::Eof`] if the
+    /// declared `len` is longer than what is left of this stream.
+    fn try_substream(&self, len: usize) -> KResult<BytesReader> {
+        self.check_len(len)?;
+        Ok(self.substream(len))
+    }
+
+    fn substream(&self, len: usize) -> BytesReader {
+        let mut reader = self.clone();
+
+        let limit = reader.pos().saturating_add(len);
+        if reader.max_pos.is_none_or(|max_pos| limit < max_pos) {
+            reader.max_pos = Some(limit);
+            reader.region_size = len;
+        }
+        reader.name = reader.range_name(reader.pos(), reader.size());
+
+        reader
+    }
+
+    /// The next `len` bytes as a stream of their own, without copying them,
+    /// e.g. for a `size`-bounded field. Position 0 of the new stream is the
+    /// current position of this one and its size is `len`; this stream
+    /// moves past the bytes.
+    ///
+    /// Unlike with [`substream`](Self::substream), positions are relative
+    /// to the start of the new stream, and it has a position and bit state
+    /// of its own. Fails with [`KError::Eof`] if fewer than `len` bytes are
+    /// left.
+    fn read_substream(&self, len: usize) -> KResult<BytesReader> {
+        let start = self.pos();
+        self.skip(len)?;
+        Ok(self.clone().window(start, len))
+    }
+
+    /// Read a length of type `len_kind`, then parse a `T` from exactly that
+    /// many following bytes, as a stream of their own like with
+    /// [`read_substream`](Self::read_substream). Whatever happens, this
+    /// stream moves past the region.
+    ///
+    /// A `T` that needs more bytes than the region has fails with
+    /// [`KError::Eof`]; bytes it leaves over are handled as `leftover`
+    /// says, where the offset of a [`KError::TrailingBytes`] or a reported
+    /// diagnostic is relative to the region.
+    fn read_len_prefixed_struct<T: KStruct + Default + Any>(
+        &self,
+        len_kind: LenKind,
+        leftover: Leftover,
+        root: Option<SharedType<T::Root>>,
+        parent: Option<SharedType<T::Parent>>,
+    ) -> KResult<OptRc<T>>
+    where
+        Self: Sized,
+    {
+        let len = usize_from_u64(len_kind.read(self)?, "length prefix")?;
+        let region = self.read_substream(len)?;
+        let res = T::read_into::<_, T>(&region, root, parent)?;
+        match leftover {
+            Leftover::Error => region.expect_fully_consumed()?,
+            Leftover::Skip => {}
+            Leftover::Report => {
+                let unused = region.remaining();
+                if unused > 0 {
+                    region.report(&format!("{} of {} bytes of the region unused", unused, len));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Parse the `len` bytes at `start` as a `T` of their own, e.g. for an
+    /// overlay whose type is only known after more of the data was read.
+    /// The region may be parsed any number of times, as different types;
+    /// the position and bit state of this stream are not touched. Fails
+    /// with [`KError::Eof`] if the region extends past the end.
+    fn reparse_region_as<T: KStruct + Any>(
+        &self,
+        start: usize,
+        len: usize,
+        root: Option<SharedType<T::Root>>,
+        parent: Option<SharedType<T::Parent>>,
+    ) -> KResult<OptRc<T>>
+    where
+        Self: Sized,
+    {
+        let available = self.size().saturating_sub(start);
+        if len > available {
+            return Err(KError::eof(len, available));
+        }
+        let view = self.clone().window(start, len);
+        T::read_into::<_, T>(&view, root, parent)
+    }
+
+    fn read_bytes(&self, len: usize) -> KResult<Vec<u8>>;
+
+    /// Read up to the end of the stream; [`stream_end`](Self::stream_end)
+    /// tells whether that is the end of a substream or of the input.
+    fn read_bytes_full(&self) -> KResult<Vec<u8>>;
+
+    /// What ends this stream, see [`StreamEnd`].
+    fn stream_end(&self) -> StreamEnd {
+        StreamEnd::EndOfInput
+    }
+
+    /// Fill `buf` with the next `buf.len()` bytes of the stream.
+    fn read_bytes_into(&self, buf: &mut [u8]) -> KResult<()> {
+        byte_aligned(self)?;
+        buf.copy_from_slice(&self.read_bytes(buf.len())?);
+        Ok(())
+    }
+
+    /// Read exactly `N` bytes into a stack array.
+    fn read_bytes_array<const N: usize>(&self) -> KResult<[u8; N]>
+    where
+        Self: Sized,
+    {
+        let mut buf = [0; N];
+        self.read_bytes_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read exactly `N` bytes starting at `pos`; the current position is left untouched.
+    fn read_bytes_array_at<const N: usize>(&self, pos: usize) -> KResult<[u8; N]>
+    where
+        Self: Sized,
+    {
+        let old_pos = self.pos();
+        self.seek(pos)?;
+        let res = self.read_bytes_array();
+        self.seek(old_pos)?;
+        res
+    }
+
+    /// Fail with [`KError::TrailingBytes`] unless the position is at the
+    /// end, e.g. after parsing a root struct that should cover the whole
+    /// input. Bits left of a partly read byte do not count.
+    fn expect_fully_consumed(&self) -> KResult<()> {
+        let offset = self.pos();
+        let len = self.size().saturating_sub(offset);
+        if len == 0 {
+            return Ok(());
+        }
+        Err(KError::trailing_bytes(
+            offset,
+            len,
+            self.peek_bytes(len.min(TRAILING_PREVIEW))?,
+        ))
+    }
+
+    /// The bytes from the position to the end, e.g. to keep data after the
+    /// parsed structure for inspection.
+    fn take_trailing(&self) -> KResult<Vec<u8>> {
+        self.read_bytes_full()
+    }
+
+    /// The `len` bytes at `offset`, for a positioned instance whose offset
+    /// comes from the data, without moving the position or disturbing a
+    /// bit-sized read in progress.
+    ///
+    /// The bytes must lie within `bounds`, a `start..end` region such as
+    /// the data area of an archive, or within the stream if `None`; if not,
+    /// this fails with [`KError::PointerOutOfBounds`] before anything is
+    /// read.
+    fn read_at_checked(
+        &self,
+        offset: u64,
+        len: usize,
+        bounds: Option<(u64, u64)>,
+    ) -> KResult<Vec<u8>> {
+        let (start, end) = bounds.unwrap_or((0, self.size() as u64));
+        let out_of_bounds = KError::PointerOutOfBounds {
+            offset,
+            len,
+            start,
+            end,
+        };
+        let inside = offset >= start
+            && (len as u64)
+                .checked_add(offset)
+                .is_some_and(|last| last <= end);
+        if !inside {
+            return Err(out_of_bounds);
+        }
+        let pos = usize::try_from(offset).map_err(|_| out_of_bounds)?;
+        let _guard = PosGuard::new(self);
+        self.seek(pos)?;
+        read_bytes_unaligned(self, len)
+    }
+
+    /// [`read_at_checked`](Self::read_at_checked) with typed offsets, the
+    /// bounds being the start and length of a region. Like all [`AbsOffset`]
+    /// methods, this fails with [`KError::InvalidInput`] for processed data,
+    /// whose positions have no place in the root stream.
+    fn read_at_abs(
+        &self,
+        offset: AbsOffset,
+        len: Len,
+        bounds: Option<(AbsOffset, Len)>,
+    ) -> KResult<Vec<u8>> {
+        let base = abs_base(self)?;
+        let len = usize_from_u64(len.0, "length")?;
+        let bounds = bounds.map(|(start, len)| {
+            let end = start.0.saturating_add(len.0);
+            (start.0.saturating_sub(base), end.saturating_sub(base))
+        });
+        // an offset before this stream is outside any bounds within it
+        let offset = offset.0.checked_sub(base).unwrap_or(u64::MAX);
+        self.read_at_checked(offset, len, bounds)
+    }
+
+    /// The position in the root stream, which differs from
+    /// [`pos`](Self::pos) in substreams and regions.
+    fn pos_abs(&self) -> KResult<AbsOffset> {
+        Ok(AbsOffset(abs_base(self)? + self.pos() as u64))
+    }
+
+    /// [`seek`](Self::seek) to an [`AbsOffset`], failing with
+    /// [`KError::ValueOutOfRange`] if it is before this stream.
+    fn seek_abs(&self, offset: AbsOffset) -> KResult<()> {
+        self.seek(abs_to_pos(self, offset)?)
+    }
+
+    /// The `len` bytes at `start` as a stream of their own, whose positions
+    /// are [`RelOffset`]s from `start`, as with
+    /// [`read_substream`](Self::read_substream); this stream does not move.
+    /// Fails with [`KError::Eof`] if the region extends past the end.
+    fn region_at(&self, start: AbsOffset, len: Len) -> KResult<BytesReader> {
+        let start = abs_to_pos(self, start)?;
+        let len = usize_from_u64(len.0, "length")?;
+        let available = self.size().saturating_sub(start);
+        if len > available {
+            return Err(KError::eof(len, available));
+        }
+        Ok(self.clone().window(start, len))
+    }
+
+    /// The next `len` bytes, without moving the position or disturbing a
+    /// bit-sized read in progress. Fails with [`KError::Eof`] if fewer are
+    /// left.
+    fn peek_bytes(&self, len: usize) -> KResult<Vec<u8>> {
+        peeking(self, len, || read_bytes_unaligned(self, len))
+    }
+
+    fn peek_u1(&self) -> KResult<u8> {
+        Ok(peek_fixed::<1, _>(self)?[0])
+    }
+    fn peek_u2be(&self) -> KResult<u16> {
+        Ok(u16::from_be_bytes(peek_fixed(self)?))
+    }
+    fn peek_u4be(&self) -> KResult<u32> {
+        Ok(u32::from_be_bytes(peek_fixed(self)?))
+    }
+    fn peek_u2le(&self) -> KResult<u16> {
+        Ok(u16::from_le_bytes(peek_fixed(self)?))
+    }
+    fn peek_u4le(&self) -> KResult<u32> {
+        Ok(u32::from_le_bytes(peek_fixed(self)?))
+    }
+
+    /// Position of the next occurrence of `pattern`, searching from the
+    /// current position, e.g. to resynchronize to a record marker after a
+    /// parse error. With `max_distance`, only matches starting at most that
+    /// many bytes ahead are found.
+    ///
+    /// The position and bit state are left as they are; seek to the result
+    /// to resume parsing there. The stream is read [`SCAN_CHUNK`] bytes at
+    /// a time.
+    fn scan_for(&self, pattern: &[u8], max_distance: Option<usize>) -> KResult<Option<usize>> {
+        let (pos, bits) = (self.pos(), self.bit_state());
+        let res = scan(self, pos, pattern, max_distance);
+        self.set_pos(pos);
+        self.set_bit_state(bits);
+        res
+    }
+
+    fn read_bytes_term(
+        &self,
+        term: u8,
+        include: bool,
+        consume: bool,
+        eos_error: bool,
+    ) -> KResult<Vec<u8>> {
+        byte_aligned(self)?;
+        let start = (self.pos(), self.bit_state());
+        let mut buf = vec![];
+        let limit = self.max_alloc();
+        // scan a chunk at a time instead of reading byte by byte
+        let mut chunk = [0; TERM_CHUNK];
+        loop {
+            let len = match unread_bytes(self).min(TERM_CHUNK) {
+                // a single byte read notices data appended to the source
+                0 => match self.read_u1() {
+                    Ok(c) => {
+                        chunk[0] = c;
+                        1
+                    }
+                    Err(KError::Eof { .. }) => return no_terminator(self, buf, eos_error),
+                    Err(e) => return Err(e),
+                },
+                n => {
+                    self.read_bytes_into(&mut chunk[..n])?;
+                    n
+                }
+            };
+            let chunk = &chunk[..len];
+            let found = chunk.iter().position(|&c| c == term);
+            let data_len = match found {
+                Some(i) if include => i + 1,
+                Some(i) => i,
+                None => len,
+            };
+            if let Some(limit) = limit.filter(|&l| buf.len() + data_len > l) {
+                return Err(term_too_long(self, start, buf.len() + data_len, limit));
+            }
+            buf.extend_from_slice(&chunk[..data_len]);
+            if let Some(i) = found {
+                // back to the byte after the terminator, or to the
+                // terminator itself
+                let after = if consume { i + 1 } else { i };
+                self.set_pos(self.pos() - (len - after));
+                memory::record_alloc(self, buf.len());
+                return Ok(buf);
+            }
+        }
+    }
+
+    /// [`read_bytes_term`](Self::read_bytes_term) with a terminator of
+    /// several bytes, e.g. `00 00` after UTF-16 strings or `\r\n` after
+    /// text records. `term` is found at any offset, as by [`bytes_find`].
+    /// Only a complete `term` ends the data: bytes of a partial match,
+    /// including one cut off by the end of the stream, are data. An empty
+    /// `term` matches at once.
+    fn read_bytes_term_multi(
+        &self,
+        term: &[u8],
+        include: bool,
+        consume: bool,
+        eos_error: bool,
+    ) -> KResult<Vec<u8>> {
+        byte_aligned(self)?;
+        let start = (self.pos(), self.bit_state());
+        let mut buf = vec![];
+        if term.is_empty() {
+            return Ok(buf);
+        }
+        let limit = self.max_alloc();
+        let mut chunk = [0; TERM_CHUNK];
+        loop {
+            let len = match unread_bytes(self).min(TERM_CHUNK) {
+                // a single byte read notices data appended to the source
+                0 => match self.read_u1() {
+                    Ok(c) => {
+                        chunk[0] = c;
+                        1
+                    }
+                    Err(KError::Eof { .. }) => return no_terminator(self, buf, eos_error),
+                    Err(e) => return Err(e),
+                },
+                n => {
+                    self.read_bytes_into(&mut chunk[..n])?;
+                    n
+                }
+            };
+            // a match may start in the bytes read before
+            let from = buf.len().saturating_sub(term.len() - 1);
+            buf.extend_from_slice(&chunk[..len]);
+            let found = bytes_find(&buf[from..], term).map(|i| from + i);
+            // the least number of bytes the result will have, the end of
+            // `buf` may be the start of the terminator
+            let data_len = match (found, include) {
+                (Some(i), true) => i + term.len(),
+                (Some(i), false) => i,
+                (None, true) => buf.len(),
+                (None, false) => buf.len().saturating_sub(term.len() - 1),
+            };
+            if let Some(limit) = limit.filter(|&l| data_len > l) {
+                return Err(term_too_long(self, start, data_len, limit));
+            }
+            if let Some(i) = found {
+                // back to the byte after the terminator, or to the
+                // terminator itself
+                let after = if consume { i + term.len() } else { i };
+                self.set_pos(self.pos() - (buf.len() - after));
+                buf.truncate(data_len);
+                memory::record_alloc(self, buf.len());
+                return Ok(buf);
+            }
+        }
+    }
+
+    /// Read a string of `u1` length prefix and that many bytes, decoded
+    /// from `encoding` as by [`bytes_to_str`]. The bytes are taken as they
+    /// are, NUL bytes included.
+    fn read_str_pascal_u1(&self, encoding: &str) -> KResult<String> {
+        let len = self.read_u1()?;
+        read_str_len(self, len.into(), encoding)
+    }
+
+    /// [`read_str_pascal_u1`](Self::read_str_pascal_u1) with a `u2le` length
+    /// prefix.
+    fn read_str_pascal_u2le(&self, encoding: &str) -> KResult<String> {
+        let len = self.read_u2le()?;
+        read_str_len(self, len.into(), encoding)
+    }
+
+    /// [`read_str_pascal_u1`](Self::read_str_pascal_u1) with a `u4le` length
+    /// prefix.
+    fn read_str_pascal_u4le(&self, encoding: &str) -> KResult<String> {
+        let len = self.read_u4le()?;
+        read_str_len(self, usize_from_u64(len.into(), "string length")?, encoding)
+    }
+
+    /// Read a NUL-terminated string, decoded from `encoding` as by
+    /// [`bytes_to_str`]. The terminator is consumed but not part of the
+    /// string. A missing terminator is an error, see
+    /// [`read_bytes_term`](Self::read_bytes_term) with `eos_error` set.
+    ///
+    /// The terminator is a single 0 byte, so this is not suitable for
+    /// UTF-16 and other encodings with multi-byte units, see
+    /// [`read_bytes_term_multi`](Self::read_bytes_term_multi) for those.
+    fn read_str_c(&self, encoding: &str) -> KResult<String> {
+        bytes_to_str(&self.read_bytes_term(0, false, true, true)?, encoding)
+    }
+}
+
+/// Where a stream ends, see [`KStream::stream_end`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+    /// The end of the data: a terminator search reaching it finds no
+    /// terminator in the whole input.
+    EndOfInput,
+    /// The limit of a substream at position `end`, with more data after
+    /// it. `size` is the length the substream was created with.
+    SubstreamLimit { end: usize, size: usize },
+}
+
+/// Position and pending bits of a stream. Implementors of [`KStream`] can
+/// keep one in a `RefCell` and forward the state methods of the trait to it.
+#[derive(Default, Debug, Clone)]
+pub struct ReaderState {
+    pos: usize,
+    bits: BitState,
+}
+
+impl ReaderState {
+    /// State at the start of a stream.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn set_pos(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    pub fn bit_state(&self) -> BitState {
+        self.bits
+    }
+
+    pub fn set_bit_state(&mut self, state: BitState) {
+        self.bits = state;
+    }
+
+    /// Number of pending bits, see [`BitState`].
+    pub fn bits_left(&self) -> u8 {
+        self.bits.bits_left
+    }
+
+    /// The pending bits, see [`BitState`].
+    pub fn bits(&self) -> u64 {
+        self.bits.bits
+    }
+}
+
+/// What a byte-sized read does while bits of a bit-sized read are
+/// pending, e.g. for a `u1` after a `b3` field, set with
+/// [`BytesReader::with_bit_alignment`]. Generated code aligns explicitly
+/// where the format says so; the other policies catch code that does not.
+///
+/// Reads that leave the position and bit state as they were, like
+/// [`KStream::peek_bytes`], [`KStream::read_at_checked`] and
+/// [`KStream::scan_for`], are not subject to the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitAlignment {
+    /// Read the next byte and keep the pending bits for the next bit-sized
+    /// read.
+    #[default]
+    Ignore,
+    /// Drop the pending bits first, as [`KStream::align_to_byte`] does.
+    AutoAlign,
+    /// Fail with [`KError::UnalignedBitRead`].
+    Error,
+}
+
+/// Integer type of a length prefix, see
+/// [`KStream::read_len_prefixed_struct`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LenKind {
+    U1,
+    U2le,
+    U2be,
+    U4le,
+    U4be,
+    U8le,
+    U8be,
+}
+
+impl LenKind {
+    /// Read a length of this type from `io`.
+    pub fn read<S: KStream + ?Sized>(self, io: &S) -> KResult<u64> {
+        Ok(match self {
+            LenKind::U1 => io.read_u1()?.into(),
+            LenKind::U2le => io.read_u2le()?.into(),
+            LenKind::U2be => io.read_u2be()?.into(),
+            LenKind::U4le => io.read_u4le()?.into(),
+            LenKind::U4be => io.read_u4be()?.into(),
+            LenKind::U8le => io.read_u8le()?,
+            LenKind::U8be => io.read_u8be()?,
+        })
+    }
+}
+
+/// What to do with bytes of a sized region the struct parsed from it did
+/// not consume, see [`KStream::read_len_prefixed_struct`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leftover {
+    /// Fail with [`KError::TrailingBytes`], the region must be consumed
+    /// exactly.
+    Error,
+    /// Skip them, as for a `size`-bounded field.
+    Skip,
+    /// Skip them and report a diagnostic to the session.
+    Report,
+}
+
+/// Bits left over from the last byte consumed by a bit-sized read.
+///
+/// After a [`read_bits_int_be`](KStream::read_bits_int_be) the pending
+/// bits are the low bits of that byte, the next one to read being the
+/// highest of them; after a [`read_bits_int_le`](KStream::read_bits_int_le)
+/// they are its high bits shifted down, the next one being bit 0.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BitState {
+    /// The pending bits, in the low `bits_left` bits.
+    pub bits: u64,
+    /// Number of pending bits, less than 8.
+    pub bits_left: u8,
+}
+
+/// Offset in the root stream, see [`KStream::translate_to_root`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RootOffset {
+    pub offset: u64,
+}
+
+// where the bytes of a reader come from
+#[derive(Debug, Clone, Copy)]
+enum Origin {
+    // position 0 is at this offset in the root stream
+    Root(u64),
+    // transformed data, positions do not map back
+    Processed,
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Origin::Root(0)
+    }
+}
+
+/// Identity of the data source behind a stream, see [`KStream::source_id`],
+/// and of the part of it the stream covers.
+#[derive(Debug, Clone)]
+pub struct SourceId {
+    source: Option<Weak<dyn Any>>,
+    // start and end in the source, `None` for up to its end
+    range: (usize, Option<usize>),
+}
+
+impl SourceId {
+    /// Identity of the data held by `source`.
+    pub fn new<T: Any>(source: &Rc<T>) -> Self {
+        SourceId {
+            source: Some(Rc::downgrade(source) as Weak<dyn Any>),
+            range: (0, None),
+        }
+    }
+
+    /// Identity of a source that is not known. It is not equal to any
+    /// identity, itself included.
+    pub fn unknown() -> Self {
+        SourceId {
+            source: None,
+            range: (0, None),
+        }
+    }
+
+    /// Identity of the bytes of the same source from `start` to `end`, for
+    /// a stream over part of it whose positions count from `start`.
+    pub fn range(self, start: usize, end: Option<usize>) -> Self {
+        SourceId {
+            range: (start, end),
+            ..self
+        }
+    }
+}
+
+impl PartialEq for SourceId {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.source, &other.source) {
+            (Some(a), Some(b)) => Weak::ptr_eq(a, b) && self.range == other.range,
+            _ => false,
+        }
+    }
+}
+
+/// Saved stream state, see [`KStream::snapshot`].
+#[derive(Debug, Clone)]
+pub struct StreamSnapshot {
+    pos: usize,
+    bits: BitState,
+    source: SourceId,
+}
+
+impl StreamSnapshot {
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+}
+
+/// Restores the position and bit state of a stream on drop, see
+/// [`KStream::save_pos`].
+#[must_use = "the position is restored as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct PosGuard<'a, S: KStream + ?Sized> {
+    io: &'a S,
+    // `None` once committed
+    saved: Option<(usize, BitState)>,
+}
+
+impl<'a, S: KStream + ?Sized> PosGuard<'a, S> {
+    pub fn new(io: &'a S) -> Self {
+        PosGuard {
+            io,
+            saved: Some((io.pos(), io.bit_state())),
+        }
+    }
+
+    /// The position to be restored.
+    pub fn saved_pos(&self) -> Option<usize> {
+        self.saved.map(|(pos, _)| pos)
+    }
+
+    /// Keep the current position instead of restoring the saved one.
+    pub fn commit(mut self) {
+        self.saved = None;
+    }
+}
+
+impl<S: KStream + ?Sized> Drop for PosGuard<'_, S> {
+    fn drop(&mut self) {
+        if let Some((pos, bits)) = self.saved.take() {
+            self.io.set_pos(pos);
+            self.io.set_bit_state(bits);
+        }
+    }
+}
+
+/// Backend of a [`BytesReader`].
+pub trait ReadSeek: Read + Seek {}
+
+impl<T> ReadSeek for T where T: Read + Seek {}
+
+impl fmt::Display for dyn ReadSeek {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReadSeek")
+    }
+}
+
+impl fmt::Debug for dyn ReadSeek {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReadSeek")
+    }
+}
+
+fn file_len(f: &mut std::fs::File) -> KResult<u64> {
+    match f.metadata() {
+        Ok(m) if m.is_file() => Ok(m.len()),
+        // devices and the like report no useful length
+        _ => Ok(f.seek(SeekFrom::End(0))?),
+    }
+}
+
+// exposes `len` bytes of `inner` starting at `start` as a standalone stream
+struct RangeReader<R> {
+    inner: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Seek> RangeReader<R> {
+    fn new(inner: R, start: u64, len: u64) -> KResult<Self> {
+        let mut inner = inner;
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(RangeReader {
+            inner,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for RangeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.len.saturating_sub(self.pos);
+        let max = std::cmp::min(buf.len() as u64, left) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// the position `pos` leads to from `current`, in a source of the size
+// `size` returns; seeking before the start or overflowing fails
+pub(crate) fn resolve_seek(
+    pos: SeekFrom,
+    current: u64,
+    size: impl FnOnce() -> std::io::Result<u64>,
+) -> std::io::Result<u64> {
+    match pos {
+        SeekFrom::Start(p) => Some(p),
+        SeekFrom::End(d) => size()?.checked_add_signed(d),
+        SeekFrom::Current(d) => current.checked_add_signed(d),
+    }
+    .ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+impl<R: Seek> Seek for RangeReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = resolve_seek(pos, self.pos, || Ok(self.len))?;
+        let inner_pos = self.start.checked_add(new_pos).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to an overflowing position",
+            )
+        })?;
+        self.inner.seek(SeekFrom::Start(inner_pos))?;
+        self.pos = new_pos;
+        Ok(new_pos)
+    }
+}
+
+/// Bounded [`Read`] on a [`BytesReader`], see [`BytesReader::take_read`].
+/// Stream errors are returned as [`std::io::Error`]s wrapping the
+/// [`KError`].
+#[derive(Debug)]
+pub struct TakeRead<'a> {
+    io: &'a BytesReader,
+    remaining: u64,
+}
+
+impl TakeRead<'_> {
+    /// Bytes left before the limit.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Move the reader to the limit. Fails with [`KError::Eof`] if the
+    /// limit is past the end of the stream.
+    pub fn skip_remaining(self) -> KResult<()> {
+        let n = usize_from_u64(self.remaining, "length")?;
+        self.io.skip(n)
+    }
+}
+
+impl Read for TakeRead<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.io.size().saturating_sub(self.io.pos()) as u64;
+        let n = buf.len().min(self.remaining.min(left) as usize);
+        self.io
+            .read_bytes_into(&mut buf[..n])
+            .map_err(std::io::Error::other)?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// stands in for a backend handed out by `BytesReader::with_inner`
+struct BusyBackend;
+
+impl BusyBackend {
+    fn error() -> std::io::Error {
+        std::io::Error::other("stream backend is in use, e.g. by with_inner")
+    }
+}
+
+impl Read for BusyBackend {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(Self::error())
+    }
+}
+
+impl Seek for BusyBackend {
+    fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+        Err(Self::error())
+    }
+}
+
+// takes the backend out of its slot, so that no borrow is held while user
+// code runs, and puts it back on drop (also when unwinding)
+struct DetachedBackend<'a> {
+    slot: &'a RefCell<Box<dyn ReadSeek>>,
+    inner: Option<Box<dyn ReadSeek>>,
+}
+
+impl<'a> DetachedBackend<'a> {
+    fn new(slot: &'a RefCell<Box<dyn ReadSeek>>) -> Self {
+        let inner = std::mem::replace(&mut *slot.borrow_mut(), Box::new(BusyBackend));
+        DetachedBackend {
+            slot,
+            inner: Some(inner),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn ReadSeek {
+        // only taken on drop
+        &mut **self.inner.as_mut().unwrap()
+    }
+}
+
+impl Drop for DetachedBackend<'_> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            *self.slot.borrow_mut() = inner;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BytesReader {
+    state: RefCell<ReaderState>,
+    // share same "instance" of data beetween all clones
+    // reposition before each read call
+    buf: OptRc<RefCell<Box<dyn ReadSeek>>>,
+    file_size: Cell<u64>,
+    // re-check the size of the backend when a read goes past it
+    auto_refresh: bool,
+    session: Option<ParseSession>,
+    origin: Origin,
+    // position 0 is at this position of the backend, see `read_substream`
+    base: usize,
+    // end of a substream
+    max_pos: Option<usize>,
+    // declared size of the substream that set `max_pos`
+    region_size: usize,
+    name: Option<Rc<str>>,
+    // the file the backend was opened from, see `checkpoint`
+    path: Option<Rc<Path>>,
+    // see `with_max_alloc`
+    max_alloc: Option<usize>,
+    // see `with_bit_alignment`
+    bit_alignment: BitAlignment,
+    // shared with clones, like the backend
+    access: Option<Rc<RefCell<AccessStats>>>,
+    // see `freeze`
+    frozen: bool,
+}
+
+/// A stream of length 0: every read fails with [`KError::Eof`], seeking to 0
+/// and zero-length reads succeed. Generated structs hold one of these until
+/// they are read from a real stream.
+impl Default for BytesReader {
+    fn default() -> Self {
+        let r: Box<dyn ReadSeek> = Box::new(std::io::empty());
+        BytesReader {
+            state: RefCell::new(ReaderState::default()),
+            file_size: Cell::new(0),
+            auto_refresh: false,
+            buf: OptRc::from(RefCell::new(r)),
+            session: None,
+            origin: Origin::default(),
+            base: 0,
+            max_pos: None,
+            region_size: 0,
+            name: None,
+            path: None,
+            max_alloc: None,
+            bit_alignment: BitAlignment::Ignore,
+            access: None,
+            frozen: false,
+        }
+    }
+}
+
+impl From<Vec<u8>> for BytesReader {
+    fn from(bytes: Vec<u8>) -> BytesReader {
+        BytesReader::from_buffer(bytes)
+    }
+}
+
+impl From<&[u8]> for BytesReader {
+    fn from(slice: &[u8]) -> BytesReader {
+        BytesReader::from_buffer(slice.to_vec())
+    }
+}
+
+impl TryFrom<Box<dyn ReadSeek>> for BytesReader {
+    type Error = KError;
+    fn try_from(reader: Box<dyn ReadSeek>) -> KResult<BytesReader> {
+        BytesReader::from_reader(reader)
+    }
+}
+
+impl BytesReader {
+    /// The position and bit state, as kept by
+    /// [`KStream::pos`] and [`KStream::bit_state`].
+    #[deprecated(note = "use `KStream::pos` and `KStream::bit_state`")]
+    pub fn get_state(&self) -> Ref<'_, ReaderState> {
+        self.state.borrow()
+    }
+
+    /// The position and bit state, to be changed in place. The borrow must
+    /// end before the next call on the reader.
+    #[deprecated(note = "use `KStream::set_pos` and `KStream::set_bit_state`")]
+    pub fn get_state_mut(&self) -> RefMut<'_, ReaderState> {
+        self.state.borrow_mut()
+    }
+
+    /// The reader is named after the path, see [`KStream::name`].
+    pub fn open<T: AsRef<Path>>(filename: T) -> KResult<Self> {
+        let path = filename.as_ref();
+        let name = path.display().to_string();
+        let mut reader = Self::from_file(std::fs::File::open(path)?)?.with_name(&name);
+        reader.path = Some(path.into());
+        Ok(reader)
+    }
+
+    /// Use an already opened file. Position 0 of the stream is the first
+    /// byte of the file, regardless of the current offset of `f`.
+    pub fn from_file(f: std::fs::File) -> KResult<Self> {
+        let mut f = f;
+        let file_size = file_len(&mut f)?;
+        usize_from_u64(file_size, "file size")?;
+        let r: Box<dyn ReadSeek> = Box::new(f);
+        Ok(BytesReader {
+            state: RefCell::new(ReaderState::default()),
+            file_size: Cell::new(file_size),
+            auto_refresh: false,
+            buf: OptRc::from(RefCell::new(r)),
+            session: None,
+            origin: Origin::default(),
+            base: 0,
+            max_pos: None,
+            region_size: 0,
+            name: None,
+            path: None,
+            max_alloc: None,
+            bit_alignment: BitAlignment::Ignore,
+            access: None,
+            frozen: false,
+        })
+    }
+
+    /// Use `len` bytes of an already opened file starting at `offset` as the
+    /// whole stream, i.e. position 0 of the stream is `offset` in the file.
+    pub fn from_file_range(f: std::fs::File, offset: u64, len: u64) -> KResult<Self> {
+        let mut f = f;
+        let available = file_len(&mut f)?.saturating_sub(offset);
+        if len > available {
+            return Err(KError::eof(
+                usize::try_from(len).unwrap_or(usize::MAX),
+                usize::try_from(available).unwrap_or(usize::MAX),
+            ));
+        }
+        usize_from_u64(len, "file size")?;
+        let r: Box<dyn ReadSeek> = Box::new(RangeReader::new(f, offset, len)?);
+        Ok(BytesReader {
+            state: RefCell::new(ReaderState::default()),
+            file_size: Cell::new(len),
+            auto_refresh: false,
+            buf: OptRc::from(RefCell::new(r)),
+            session: None,
+            origin: Origin::Root(offset),
+            base: 0,
+            max_pos: None,
+            region_size: 0,
+            name: None,
+            path: None,
+            max_alloc: None,
+            bit_alignment: BitAlignment::Ignore,
+            access: None,
+            frozen: false,
+        })
+    }
+
+    fn from_buffer(bytes: Vec<u8>) -> Self {
+        let file_size = bytes.len() as u64;
+        let r: Box<dyn ReadSeek> = Box::new(std::io::Cursor::new(bytes));
+        BytesReader {
+            state: RefCell::new(ReaderState::default()),
+            file_size: Cell::new(file_size),
+            auto_refresh: false,
+            buf: OptRc::from(RefCell::new(r)),
+            session: None,
+            origin: Origin::default(),
+            base: 0,
+            max_pos: None,
+            region_size: 0,
+            name: None,
+            path: None,
+            max_alloc: None,
+            bit_alignment: BitAlignment::Ignore,
+            access: None,
+            frozen: false,
+        }
+    }
+
+    /// Reader over any seekable source, e.g. a `Cursor` or a decrypting
+    /// adapter. Position 0 of the stream is the first byte of `io`,
+    /// regardless of its current position, which is left as it is. The size
+    /// is taken from seeking to the end; failures are
+    /// [`KError::IoError`]s.
+    pub fn from_io<R: Read + Seek + 'static>(io: R) -> KResult<Self> {
+        Self::from_reader(Box::new(io))
+    }
+
+    fn from_reader(reader: Box<dyn ReadSeek>) -> KResult<Self> {
+        let mut reader = reader;
+
+        let pos = reader.stream_position()?;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        usize_from_u64(file_size, "file size")?;
+        reader.seek(SeekFrom::Start(pos))?;
+
+        Ok(BytesReader {
+            state: RefCell::new(ReaderState::default()),
+            file_size: Cell::new(file_size),
+            auto_refresh: false,
+            buf: OptRc::from(RefCell::new(reader)),
+            session: None,
+            origin: Origin::default(),
+            base: 0,
+            max_pos: None,
+            region_size: 0,
+            name: None,
+            path: None,
+            max_alloc: None,
+            bit_alignment: BitAlignment::Ignore,
+            access: None,
+            frozen: false,
+        })
+    }
+
+    /// Attach `session` to this reader. Clones and substreams made afterwards
+    /// inherit it.
+    pub fn with_session(mut self, session: &ParseSession) -> Self {
+        self.session = Some(session.clone());
+        self
+    }
+
+    /// Limit the buffer a single read of this reader may allocate to `limit`
+    /// bytes, like [`ParseSessionBuilder::max_alloc`] does for a whole
+    /// session; the lower of the two limits applies. Clones and substreams
+    /// made afterwards inherit it.
+    pub fn with_max_alloc(mut self, limit: usize) -> Self {
+        self.max_alloc = Some(limit);
+        self
+    }
+
+    /// Set what byte-sized reads do while bits of a bit-sized read are
+    /// pending. Clones and substreams made afterwards inherit it.
+    pub fn with_bit_alignment(mut self, policy: BitAlignment) -> Self {
+        self.bit_alignment = policy;
+        self
+    }
+
+    /// Mark this reader as holding bytes read from `parent` starting at
+    /// `base`, so that its positions translate to the root of `parent`.
+    pub fn derived_from<S: KStream>(mut self, parent: &S, base: usize) -> Self {
+        self.origin = match parent.translate_to_root(base) {
+            Some(root) => Origin::Root(root.offset),
+            None => Origin::Processed,
+        };
+        if let Some(name) = parent.name() {
+            let end = base + self.size();
+            self.name = Some(format!("{}[{:#x}..{:#x}]", name, base, end).into());
+        }
+        self
+    }
+
+    /// Mark this reader as holding transformed (e.g. decompressed) data,
+    /// whose positions cannot be translated to the root stream.
+    pub fn processed(mut self) -> Self {
+        self.origin = Origin::Processed;
+        self
+    }
+
+    /// [`processed`](Self::processed), naming the transformation for
+    /// [`KStream::name`]: a reader named `a.bin[0x10..0x80]` becomes
+    /// `a.bin[0x10..0x80]/zlib`.
+    pub fn processed_with(mut self, process: &str) -> Self {
+        if let Some(name) = &self.name {
+            self.name = Some(format!("{}/{}", name, process).into());
+        }
+        self.processed()
+    }
+
+    /// Create a reader over bytes given as hex digits, e.g. `"89 50 4e_47"`.
+    /// Whitespace and `_` separators are ignored.
+    pub fn from_hex(s: &str) -> KResult<Self> {
+        Ok(Self::from_buffer(decode_hex(s)?))
+    }
+
+    /// Create a reader over bytes given in standard base64, ignoring whitespace.
+    pub fn from_base64(s: &str) -> KResult<Self> {
+        Ok(Self::from_buffer(decode_base64(s)?))
+    }
+
+    /// Run `f` on the underlying backend. The backend is positioned at
+    /// [`pos()`](KStream::pos) before the call, and `pos()` follows wherever
+    /// `f` left the backend afterwards. Pending bits of a bit read are kept.
+    ///
+    /// All clones and substreams of this reader share the same backend, so
+    /// moving it around affects them as well (they reposition before their
+    /// next read). While `f` runs, the backend is detached from the reader:
+    /// `f` may query `pos()`, `size()` etc., but reads through this reader or
+    /// its clones fail with [`KError::IoError`].
+    ///
+    /// Fails with [`KError::FrozenReader`] on a [frozen](Self::freeze) reader.
+    pub fn with_inner<R>(&self, f: impl FnOnce(&mut dyn ReadSeek) -> R) -> KResult<R> {
+        if self.frozen {
+            return Err(KError::FrozenReader {
+                operation: "with_inner".to_string(),
+            });
+        }
+        self.sync_pos()?;
+        let mut detached = DetachedBackend::new(&self.buf);
+        let inner = detached.inner();
+        let res = f(inner);
+        let pos = inner.stream_position()?;
+        drop(detached);
+        let pos = usize_from_u64(pos, "position")?;
+        let pos = pos.checked_sub(self.base).ok_or_else(|| {
+            KError::invalid_input(pos, "backend moved before the start of the stream")
+        })?;
+        self.set_pos(pos);
+        Ok(res)
+    }
+
+    /// [`Read`] of the next `limit` bytes at most, e.g. for a decoder
+    /// working on one field. Its reads move the position of this reader;
+    /// [`TakeRead::skip_remaining`] moves past what the consumer left, to
+    /// continue parsing after the field.
+    pub fn take_read(&self, limit: u64) -> TakeRead<'_> {
+        TakeRead {
+            io: self,
+            remaining: limit,
+        }
+    }
+
+    /// Handle on the same data that cannot affect this reader or its clones,
+    /// e.g. for code inspecting the data while a parse is in progress.
+    ///
+    /// The handle starts at the current position and moves independently.
+    /// Its reads leave the shared backend where they found it, are not
+    /// counted in the [`access_stats`](Self::access_stats) of this reader,
+    /// and diagnostics it reports are dropped; the limits of the session
+    /// still apply. Clones and substreams of the handle are frozen as well.
+    /// Operations that cannot be isolated, such as
+    /// [`with_inner`](Self::with_inner), fail with [`KError::FrozenReader`].
+    pub fn freeze(&self) -> BytesReader {
+        let mut frozen = Clone::clone(self);
+        frozen.frozen = true;
+        frozen.access = None;
+        frozen
+    }
+
+    // run `f`, which uses the backend; a frozen reader puts the backend
+    // back where it was
+    fn isolated<R>(&self, f: impl FnOnce() -> KResult<R>) -> KResult<R> {
+        if !self.frozen {
+            return f();
+        }
+        let before = self.with_backend(|b| b.stream_position())?;
+        let res = f();
+        self.with_backend(|b| b.seek(SeekFrom::Start(before)))?;
+        res
+    }
+
+    // run `f` on the backend detached from its slot, like `with_inner`
+    // does, so that a backend calling back into this reader or a clone
+    // gets an error rather than a `RefCell` borrow panic
+    fn with_backend<R>(
+        &self,
+        f: impl FnOnce(&mut dyn ReadSeek) -> std::io::Result<R>,
+    ) -> KResult<R> {
+        Ok(f(DetachedBackend::new(&self.buf).inner())?)
+    }
+
+    // the `len` bytes at `start` as a reader of their own
+    fn window(mut self, start: usize, len: usize) -> Self {
+        self.name = self.range_name(start, start + len);
+        self.base += start;
+        self.state = RefCell::new(ReaderState::default());
+        self.max_pos = Some(len);
+        self.region_size = len;
+        if let Origin::Root(offset) = self.origin {
+            self.origin = Origin::Root(offset + start as u64);
+        }
+        self
+    }
+
+    // name of the bytes from `start` to `end`
+    fn range_name(&self, start: usize, end: usize) -> Option<Rc<str>> {
+        let name = self.name.as_ref()?;
+        Some(format!("{}[{:#x}..{:#x}]", name, start, end).into())
+    }
+
+    /// Set the name returned by [`KStream::name`].
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    // position in the backend
+    fn backend_pos(&self) -> u64 {
+        (self.base + self.pos()) as u64
+    }
+
+    // sync stream pos with state.pos
+    fn sync_pos(&self) -> KResult<()> {
+        let cur_pos = self.with_backend(|b| b.stream_position())?;
+        let pos = self.backend_pos();
+        if pos != cur_pos {
+            self.record_seek(cur_pos, pos);
+            self.with_backend(|b| b.seek(SeekFrom::Start(pos)))?;
+        }
+        Ok(())
+    }
+
+    /// Query the current length of the data source and use it as the size
+    /// of this reader, e.g. after the underlying file has grown. Fails with
+    /// [`KError::SourceTruncated`] if the source is now shorter than
+    /// [`pos()`](KStream::pos); the new size is taken over nevertheless.
+    ///
+    /// Only this reader is updated, clones made before keep their size. The
+    /// size of a substream or of a file range never changes.
+    pub fn refresh_size(&self) -> KResult<u64> {
+        let len = self.isolated(|| self.with_backend(|b| b.seek(SeekFrom::End(0))))?;
+        usize_from_u64(len, "file size")?;
+        self.file_size.set(len);
+        if len < self.backend_pos() {
+            return Err(KError::SourceTruncated {
+                size: len,
+                pos: self.pos(),
+            });
+        }
+        Ok(len)
+    }
+
+    /// Call [`refresh_size`](Self::refresh_size) whenever a read goes past
+    /// the known size or the backend ends early, so that data appended to a
+    /// file while it is being parsed can be read. Off by default.
+    pub fn auto_refresh(mut self, on: bool) -> Self {
+        self.auto_refresh = on;
+        self
+    }
+
+    // handle read beyond end of file
+    fn check_available(&self, len: usize) -> KResult<()> {
+        if len > self.size().saturating_sub(self.pos()) && self.refreshes() {
+            self.refresh_size()?;
+        }
+        self.check_len(len)
+    }
+
+    fn refreshes(&self) -> bool {
+        self.auto_refresh && self.max_pos.is_none()
+    }
+}
+
+impl KStream for BytesReader {
+    fn clone(&self) -> Self {
+        Clone::clone(self)
+    }
+
+    fn pos(&self) -> usize {
+        self.state.borrow().pos()
+    }
+
+    fn set_pos(&self, pos: usize) {
+        self.state.borrow_mut().set_pos(pos);
+    }
+
+    fn bit_state(&self) -> BitState {
+        self.state.borrow().bit_state()
+    }
+
+    fn set_bit_state(&self, state: BitState) {
+        self.state.borrow_mut().set_bit_state(state);
+    }
+
+    fn session(&self) -> Option<&ParseSession> {
+        self.session.as_ref()
+    }
+
+    fn bit_alignment(&self) -> BitAlignment {
+        self.bit_alignment
+    }
+
+    fn max_alloc(&self) -> Option<usize> {
+        let session = self.session().and_then(ParseSession::max_alloc);
+        match (self.max_alloc, session) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn translate_to_root(&self, pos: usize) -> Option<RootOffset> {
+        match self.origin {
+            Origin::Root(base) => Some(RootOffset {
+                offset: base + pos as u64,
+            }),
+            Origin::Processed => None,
+        }
+    }
+
+    fn source_id(&self) -> SourceId {
+        match self.buf.get_value() {
+            Some(rc) => SourceId::new(rc).range(self.base, self.max_pos.map(|end| self.base + end)),
+            None => SourceId::unknown(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self.max_pos {
+            Some(pos) => pos,
+            None => self.file_size.get() as usize,
+        }
+    }
+
+    fn name(&self) -> Option<Rc<str>> {
+        self.name.clone()
+    }
+
+    fn stream_end(&self) -> StreamEnd {
+        match self.max_pos {
+            Some(end) if ((self.base + end) as u64) < self.file_size.get() => {
+                StreamEnd::SubstreamLimit {
+                    end,
+                    size: self.region_size,
+                }
+            }
+            _ => StreamEnd::EndOfInput,
+        }
+    }
+
+    fn read_bytes(&self, len: usize) -> KResult<Vec<u8>> {
+        byte_aligned(self)?;
+        // check before allocating
+        session::check_alloc(self, len)?;
+        self.check_available(len)?;
+        let mut buf = vec![0; len];
+        self.read_bytes_into(&mut buf)?;
+        memory::record_alloc(self, len);
+        Ok(buf)
+    }
+
+    fn read_bytes_into(&self, buf: &mut [u8]) -> KResult<()> {
+        byte_aligned(self)?;
+        self.isolated(|| self.read_backend(buf))
+    }
+
+    fn read_bytes_full(&self) -> KResult<Vec<u8>> {
+        byte_aligned(self)?;
+        session::check_alloc(self, self.size().saturating_sub(self.pos()))?;
+        if self.max_pos.is_some() {
+            return self.read_bytes(self.size().saturating_sub(self.pos()));
+        }
+
+        self.isolated(|| {
+            self.sync_pos()?;
+            let mut buf = Vec::new();
+            let readed = self.with_backend(|b| b.read_to_end(&mut buf))?;
+            self.record_read(readed);
+            self.advance(readed);
+            memory::record_alloc(self, readed);
+            Ok(buf)
+        })
+    }
+
+    fn report(&self, message: &str) {
+        match self.session() {
+            Some(session) if !self.frozen => session.report(Diagnostic {
+                offset: self.pos(),
+                message: message.to_string(),
+            }),
+            _ => {}
+        }
+    }
+}
+
+impl BytesReader {
+    fn read_backend(&self, buf: &mut [u8]) -> KResult<()> {
+        self.check_available(buf.len())?;
+        self.sync_pos()?;
+        let filled = self.with_backend(|b| {
+            // `read` may return fewer bytes than asked for
+            let mut filled = 0;
+            while filled < buf.len() {
+                match b.read(&mut buf[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(filled)
+        })?;
+        if filled < buf.len() {
+            // the source shrank since its size was last checked
+            if self.refreshes() {
+                self.refresh_size()?;
+                self.check_available(buf.len())?;
+            }
+            return Err(KError::incomplete(buf.len(), filled));
+        }
+        self.record_read(buf.len());
+        self.advance(buf.len());
+        Ok(())
+    }
+}
+
+/// Return a byte array that is sized to exclude all trailing instances of the
+/// padding character.
+pub fn bytes_strip_right(bytes: &[u8], pad: u8) -> Vec<u8> {
+    if let Some(last_non_pad_index) = bytes.iter().rposition(|&c| c != pad) {
+        bytes[..=last_non_pad_index].to_vec()
+    } else {
+        vec![]
+    }
+}
+
+/// Return a byte array that contains all bytes up until the
+/// termination byte. Can optionally include the termination byte as well.
+/// Without a termination byte, all of `bytes` is returned.
+pub fn bytes_terminate(bytes: &[u8], term: u8, include_term: bool) -> Vec<u8> {
+    if let Some(term_index) = bytes.iter().position(|&c| c == term) {
+        &bytes[..term_index + if include_term { 1 } else { 0 }]
+    } else {
+        bytes
+    }
+    .to_vec()
+}
+
+/// The bytes of a sized field with `terminator` and/or `pad-right`, as
+/// Kaitai Struct defines them: a found terminator ends the data, and
+/// padding is stripped only when there is no terminator in the data.
+pub fn bytes_strip_and_terminate(
+    bytes: &[u8],
+    pad: Option<u8>,
+    term: Option<u8>,
+    include_term: bool,
+) -> Vec<u8> {
+    if let Some(term) = term.filter(|t| bytes.contains(t)) {
+        return bytes_terminate(bytes, term, include_term);
+    }
+    match pad {
+        Some(pad) => bytes_strip_right(bytes, pad),
+        None => bytes.to_vec(),
+    }
+}
+
+/// [`bytes_terminate`] with a terminator of several bytes, the counterpart
+/// of [`KStream::read_bytes_term_multi`] for sized fields: `term` is found
+/// at any offset. An empty `term` matches at once.
+pub fn bytes_terminate_multi(bytes: &[u8], term: &[u8], include_term: bool) -> Vec<u8> {
+    match bytes_find(bytes, term) {
+        Some(i) => &bytes[..i + if include_term { term.len() } else { 0 }],
+        None => bytes,
+    }
+    .to_vec()
+}
+
+/// `a == b` taking the same time for all contents of the same length, for
+/// comparing secrets like authentication tags. The lengths are not treated
+/// as secret: different lengths compare unequal at once.
+pub fn bytes_eq_constant_time(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    // keep the optimizer from turning the fold into an early exit
+    std::hint::black_box(diff) == 0
+}
+
+/// Check `contents: [...]`-like data, failing with a
+/// [`ValidationKind::NotEqual`] error. With `ignore_ascii_case`, textual
+/// magics match in any case, see [`eq_ignore_ascii_case_bytes`].
+pub fn ensure_fixed_contents(
+    actual: &[u8],
+    expected: &[u8],
+    ignore_ascii_case: bool,
+    src_path: &str,
+) -> KResult<()> {
+    let eq = if ignore_ascii_case {
+        eq_ignore_ascii_case_bytes(actual, expected)
+    } else {
+        actual == expected
+    };
+    if eq {
+        Ok(())
+    } else {
+        Err(KError::validation_failed(
+            ValidationKind::NotEqual,
+            src_path,
+        ))
+    }
+}
+
+/// [`ensure_fixed_contents`] comparing with [`bytes_eq_constant_time`].
+/// With `redact`, the error is the same as that of
+/// [`ensure_fixed_contents`] and names `src_path` only, for secrets that
+/// must not end up in logs. Without it, the error is a
+/// [`ValidationKind::ContentsNotEqual`] holding `actual`, for debugging.
+pub fn ensure_fixed_contents_ct(
+    actual: &[u8],
+    expected: &[u8],
+    src_path: &str,
+    redact: bool,
+) -> KResult<()> {
+    if bytes_eq_constant_time(actual, expected) {
+        return Ok(());
+    }
+    let kind = if redact {
+        ValidationKind::NotEqual
+    } else {
+        ValidationKind::ContentsNotEqual {
+            actual: actual.to_vec(),
+        }
+    };
+    Err(KError::validation_failed(kind, src_path))
+}
+
+pub fn bytes_to_str(bytes: &[u8], label: &str) -> KResult<String> {
+    match lookup_encoding(label) {
+        Some(StrEncoding::Whatwg(enc)) => Ok(enc
+            .decode(bytes, DecoderTrap::Replace)
+            .expect("this should never fail because we use DecoderTrap::Replace")),
+        Some(StrEncoding::Cp437) => Ok(decode_cp437(bytes)),
+        None => Err(unknown_encoding(bytes, label)),
+    }
+}
+
+/// Decode `bytes` in the encoding named by `label`, failing on the first
+/// sequence the encoding cannot represent instead of substituting U+FFFD.
+pub fn decode_string(bytes: &[u8], label: &str) -> KResult<String> {
+    match lookup_encoding(label) {
+        Some(StrEncoding::Whatwg(enc)) => decode_whatwg(enc, bytes, &mut |_, offset, cause, _| {
+            Err(KError::encoding(
+                label,
+                cause.into_owned(),
+                bytes.len(),
+                Some(offset),
+            ))
+        }),
+        Some(StrEncoding::Cp437) => Ok(decode_cp437(bytes)),
+        None => Err(unknown_encoding(bytes, label)),
+    }
+}
+
+/// Result of [`decode_string_with_handler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedString {
+    pub text: String,
+    /// Number of undecodable bytes passed to the handler.
+    pub substitutions: usize,
+}
+
+/// Decode `bytes` in the encoding named by `label`, calling `handler` with
+/// every byte that cannot be decoded and its offset in `bytes`. The returned
+/// char is inserted in place of the byte; `None` drops the byte.
+pub fn decode_string_with_handler(
+    bytes: &[u8],
+    label: &str,
+    handler: &mut dyn FnMut(u8, usize) -> Option<char>,
+) -> KResult<DecodedString> {
+    let mut substitutions = 0;
+    let text = match lookup_encoding(label) {
+        Some(StrEncoding::Whatwg(enc)) => decode_whatwg(enc, bytes, &mut |bad, offset, _, res| {
+            for (i, &b) in bad.iter().enumerate() {
+                substitutions += 1;
+                if let Some(c) = handler(b, offset + i) {
+                    res.push(c);
+                }
+            }
+            Ok(())
+        })?,
+        Some(StrEncoding::Cp437) => decode_cp437(bytes),
+        None => return Err(unknown_encoding(bytes, label)),
+    };
+    Ok(DecodedString {
+        text,
+        substitutions,
+    })
+}
+
+type DecodeErrorHandler<'a> =
+    dyn FnMut(&[u8], usize, Cow<'static, str>, &mut String) -> KResult<()> + 'a;
+
+// same loop as `Encoding::decode_to`, but reports where each undecodable sequence starts
+fn decode_whatwg(
+    enc: EncodingRef,
+    bytes: &[u8],
+    on_error: &mut DecodeErrorHandler<'_>,
+) -> KResult<String> {
+    let mut decoder = enc.raw_decoder();
+    let mut res = String::new();
+    let mut remaining = 0;
+    loop {
+        let (offset, err) = decoder.raw_feed(&bytes[remaining..], &mut res);
+        let unprocessed = remaining + offset;
+        match err {
+            Some(err) => {
+                remaining = (remaining as isize + err.upto) as usize;
+                on_error(
+                    &bytes[unprocessed..remaining],
+                    unprocessed,
+                    err.cause,
+                    &mut res,
+                )?;
+            }
+            None => {
+                remaining = bytes.len();
+                if let Some(err) = decoder.raw_finish(&mut res) {
+                    remaining = (remaining as isize + err.upto) as usize;
+                    on_error(
+                        &bytes[unprocessed..remaining],
+                        unprocessed,
+                        err.cause,
+                        &mut res,
+                    )?;
+                }
+                if remaining >= bytes.len() {
+                    return Ok(res);
+                }
+            }
+        }
+    }
+}
+
+enum StrEncoding {
+    Whatwg(EncodingRef),
+    Cp437,
+}
+
+// single place resolving encoding labels, so every decoder accepts the same names
+fn lookup_encoding(label: &str) -> Option<StrEncoding> {
+    if let Some(enc) = encoding_from_whatwg_label(label) {
+        return Some(StrEncoding::Whatwg(enc));
+    }
+    if CP437_LABELS.iter().any(|l| label.eq_ignore_ascii_case(l)) {
+        return Some(StrEncoding::Cp437);
+    }
+    None
+}
+
+/// Where the decoding tables of an encoding come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EncodingSource {
+    /// WHATWG Encoding Standard implementation of the `encoding-next` crate.
+    Whatwg,
+    /// Code page table built into this runtime.
+    Codepage,
+}
+
+/// Description of an encoding accepted by [`decode_string`] and [`bytes_to_str`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodingInfo {
+    pub name: &'static str,
+    /// Other labels resolving to the same encoding.
+    pub aliases: Vec<&'static str>,
+    pub source: EncodingSource,
+}
+
+/// List all encodings the string decoders know about.
+pub fn supported_encodings() -> Vec<EncodingInfo> {
+    // keyed by the backend's own name, which is unique per encoding
+    let mut whatwg: Vec<(&str, EncodingInfo)> = Vec::new();
+    for &label in WHATWG_LABELS {
+        let enc = match lookup_encoding(label) {
+            Some(StrEncoding::Whatwg(enc)) => enc,
+            _ => continue,
+        };
+        match whatwg.iter_mut().find(|(key, _)| *key == enc.name()) {
+            Some((_, info)) => info.aliases.push(label),
+            None => whatwg.push((
+                enc.name(),
+                EncodingInfo {
+                    // some canonical names (e.g. "replacement") are not valid labels themselves
+                    name: enc
+                        .whatwg_name()
+                        .filter(|n| encoding_from_whatwg_label(n).is_some())
+                        .unwrap_or(label),
+                    aliases: vec![label],
+                    source: EncodingSource::Whatwg,
+                },
+            )),
+        }
+    }
+    let mut res: Vec<EncodingInfo> = whatwg.into_iter().map(|(_, info)| info).collect();
+    for info in &mut res {
+        let name = info.name;
+        info.aliases.retain(|a| *a != name);
+    }
+    res.push(EncodingInfo {
+        name: CP437_LABELS[0],
+        aliases: CP437_LABELS[1..].to_vec(),
+        source: EncodingSource::Codepage,
+    });
+    res
+}
+
+/// Returns true if `label` names an encoding the string decoders accept.
+pub fn is_encoding_supported(label: &str) -> bool {
+    lookup_encoding(label).is_some()
+}
+
+const CP437_LABELS: &[&str] = &["cp437", "ibm437"];
+
+// labels of the WHATWG Encoding Standard, as recognized by `encoding_from_whatwg_label`
+const WHATWG_LABELS: &[&str] = &[
+    "unicode-1-1-utf-8",
+    "utf-8",
+    "utf8",
+    "866",
+    "cp866",
+    "csibm866",
+    "ibm866",
+    "csisolatin2",
+    "iso-8859-2",
+    "iso-ir-101",
+    "iso8859-2",
+    "iso88592",
+    "iso_8859-2",
+    "iso_8859-2:1987",
+    "l2",
+    "latin2",
+    "csisolatin3",
+    "iso-8859-3",
+    "iso-ir-109",
+    "iso8859-3",
+    "iso88593",
+    "iso_8859-3",
+    "iso_8859-3:1988",
+    "l3",
+    "latin3",
+    "csisolatin4",
+    "iso-8859-4",
+    "iso-ir-110",
+    "iso8859-4",
+    "iso88594",
+    "iso_8859-4",
+    "iso_8859-4:1988",
+    "l4",
+    "latin4",
+    "csisolatincyrillic",
+    "cyrillic",
+    "iso-8859-5",
+    "iso-ir-144",
+    "iso8859-5",
+    "iso88595",
+    "iso_8859-5",
+    "iso_8859-5:1988",
+    "arabic",
+    "asmo-708",
+    "csiso88596e",
+    "csiso88596i",
+    "csisolatinarabic",
+    "ecma-114",
+    "iso-8859-6",
+    "iso-8859-6-e",
+    "iso-8859-6-i",
+    "iso-ir-127",
+    "iso8859-6",
+    "iso88596",
+    "iso_8859-6",
+    "iso_8859-6:1987",
+    "csisolatingreek",
+    "ecma-118",
+    "elot_928",
+    "greek",
+    "greek8",
+    "iso-8859-7",
+    "iso-ir-126",
+    "iso8859-7",
+    "iso88597",
+    "iso_8859-7",
+    "iso_8859-7:1987",
+    "sun_eu_greek",
+    "csiso88598e",
+    "csisolatinhebrew",
+    "hebrew",
+    "iso-8859-8",
+    "iso-8859-8-e",
+    "iso-ir-138",
+    "iso8859-8",
+    "iso88598",
+    "iso_8859-8",
+    "iso_8859-8:1988",
+    "visual",
+    "csiso88598i",
+    "iso-8859-8-i",
+    "logical",
+    "csisolatin6",
+    "iso-8859-10",
+    "iso-ir-157",
+    "iso8859-10",
+    "iso885910",
+    "l6",
+    "latin6",
+    "iso-8859-13",
+    "iso8859-13",
+    "iso885913",
+    "iso-8859-14",
+    "iso8859-14",
+    "iso885914",
+    "csisolatin9",
+    "iso-8859-15",
+    "iso8859-15",
+    "iso885915",
+    "iso_8859-15",
+    "l9",
+    "iso-8859-16",
+    "cskoi8r",
+    "koi",
+    "koi8",
+    "koi8-r",
+    "koi8_r",
+    "koi8-ru",
+    "koi8-u",
+    "csmacintosh",
+    "mac",
+    "macintosh",
+    "x-mac-roman",
+    "dos-874",
+    "iso-8859-11",
+    "iso8859-11",
+    "iso885911",
+    "tis-620",
+    "windows-874",
+    "cp1250",
+    "windows-1250",
+    "x-cp1250",
+    "cp1251",
+    "windows-1251",
+    "x-cp1251",
+    "ansi_x3.4-1968",
+    "ascii",
+    "cp1252",
+    "cp819",
+    "csisolatin1",
+    "ibm819",
+    "iso-8859-1",
+    "iso-ir-100",
+    "iso8859-1",
+    "iso88591",
+    "iso_8859-1",
+    "iso_8859-1:1987",
+    "l1",
+    "latin1",
+    "us-ascii",
+    "windows-1252",
+    "x-cp1252",
+    "cp1253",
+    "windows-1253",
+    "x-cp1253",
+    "cp1254",
+    "csisolatin5",
+    "iso-8859-9",
+    "iso-ir-148",
+    "iso8859-9",
+    "iso88599",
+    "iso_8859-9",
+    "iso_8859-9:1989",
+    "l5",
+    "latin5",
+    "windows-1254",
+    "x-cp1254",
+    "cp1255",
+    "windows-1255",
+    "x-cp1255",
+    "cp1256",
+    "windows-1256",
+    "x-cp1256",
+    "cp1257",
+    "windows-1257",
+    "x-cp1257",
+    "cp1258",
+    "windows-1258",
+    "x-cp1258",
+    "x-mac-cyrillic",
+    "x-mac-ukrainian",
+    "chinese",
+    "csgb2312",
+    "csiso58gb231280",
+    "gb2312",
+    "gb_2312",
+    "gb_2312-80",
+    "gbk",
+    "iso-ir-58",
+    "x-gbk",
+    "gb18030",
+    "big5",
+    "big5-hkscs",
+    "cn-big5",
+    "csbig5",
+    "x-x-big5",
+    "cseucpkdfmtjapanese",
+    "euc-jp",
+    "x-euc-jp",
+    "csiso2022jp",
+    "iso-2022-jp",
+    "csshiftjis",
+    "ms932",
+    "ms_kanji",
+    "shift-jis",
+    "shift_jis",
+    "sjis",
+    "windows-31j",
+    "x-sjis",
+    "cseuckr",
+    "csksc56011987",
+    "euc-kr",
+    "iso-ir-149",
+    "korean",
+    "ks_c_5601-1987",
+    "ks_c_5601-1989",
+    "ksc5601",
+    "ksc_5601",
+    "windows-949",
+    "csiso2022kr",
+    "hz-gb-2312",
+    "iso-2022-kr",
+    "iso-2022-cn",
+    "iso-2022-cn-ext",
+    "utf-16be",
+    "utf-16",
+    "utf-16le",
+    "x-user-defined",
+];
+
+fn unknown_encoding(bytes: &[u8], label: &str) -> KError {
+    KError::Encoding {
+        label: label.to_string(),
+        desc: "unknown encoding".to_string(),
+        input_len: bytes.len(),
+        bad_offset: None,
+    }
+}
+
+fn decode_cp437(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity(bytes.len());
+    for b in bytes {
+        match b {
+            // the `cp437` table has no entries for these control codes
+            0x00..=0x04 => res.push(char::from(*b)),
+            _ => res.push_str(cp437::convert_byte(b)),
+        }
+    }
+    res
+}
+
+pub fn process_xor_one(bytes: &[u8], key: u8) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    for i in &mut res {
+        *i ^= key;
+    }
+    res
+}
+
+/// XOR `bytes` with `key` repeated. An empty key leaves the bytes unchanged.
+pub fn process_xor_many(bytes: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    if key.is_empty() {
+        return res;
+    }
+    let mut ki = 0;
+    for i in &mut res {
+        *i ^= key[ki];
+        ki += 1;
+        if ki >= key.len() {
+            ki = 0;
+        }
+    }
+    res
+}
+
+pub fn process_rotate_left(bytes: &[u8], amount: u8) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    for i in &mut res {
+        *i = i.rotate_left(amount.into());
+    }
+    res
+}
+
+pub fn process_zlib(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut dec = ZlibDecoder::new(bytes);
+    let mut dec_bytes = Vec::new();
+    dec.read_to_end(&mut dec_bytes).map_err(|e| e.to_string())?;
+    Ok(dec_bytes)
+}
+
+pub fn reverse_string<S: AsRef<str>>(s: S) -> KResult<String> {
+    Ok(s.as_ref().graphemes(true).rev().collect())
+}
+
+/// Kaitai's `to_lower`. Only ASCII letters are changed, as in the other
+/// Kaitai runtimes: formats mean ASCII case, and the result must not depend
+/// on the Unicode tables. `s` is borrowed if it has no uppercase letters.
+pub fn ascii_lower(s: &str) -> Cow<'_, str> {
+    if s.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(s.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Kaitai's `to_upper`, ASCII only like [`ascii_lower`].
+pub fn ascii_upper(s: &str) -> Cow<'_, str> {
+    if s.bytes().any(|b| b.is_ascii_lowercase()) {
+        Cow::Owned(s.to_ascii_uppercase())
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// `a == b` ignoring the case of ASCII letters; other bytes must be equal.
+pub fn eq_ignore_ascii_case_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// The value of the IEEE 754 half-precision float `bits`, e.g. a `u2` read
+/// for a value instance. Every half-precision value is exact as `f32`,
+/// subnormals included; NaNs stay NaNs, keeping their payload.
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = u32::from(bits >> 15) << 31;
+    let exp = u32::from(bits >> 10) & 0x1f;
+    let man = u32::from(bits) & 0x3ff;
+    match exp {
+        // subnormal or zero: man * 2^-24, normal as f32
+        0 => {
+            let v = man as f32 / (1 << 24) as f32;
+            f32::from_bits(v.to_bits() | sign)
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | man << 13),
+        _ => f32::from_bits(sign | (exp + 127 - 15) << 23 | man << 13),
+    }
+}
+
+/// `v` as a length, e.g. of a `size` or `repeat-expr` expression. Fails with
+/// [`KError::ValueOutOfRange`] if it is negative or does not fit in `usize`.
+pub fn to_len(v: i64) -> KResult<usize> {
+    usize::try_from(v).map_err(|_| KError::value_out_of_range(v, "length"))
+}
+
+/// `v` as a stream position, e.g. of a `pos` expression, see [`to_len`].
+pub fn to_pos(v: i64) -> KResult<usize> {
+    usize::try_from(v).map_err(|_| KError::value_out_of_range(v, "position"))
+}
+
+/// `v` as an unsigned value, failing if it is negative.
+pub fn to_u64_nonneg(v: i64) -> KResult<u64> {
+    u64::try_from(v).map_err(|_| KError::value_out_of_range(v, "unsigned value"))
+}
+
+pub(crate) fn usize_from_u64(v: u64, context: &str) -> KResult<usize> {
+    usize::try_from(v).map_err(|_| KError::value_out_of_range(v, context))
+}
+
+/// `a - b` for sizes computed from the data, e.g. `header.len - 8`, failing
+/// with [`KError::SizeUnderflow`] instead of wrapping around.
+pub fn checked_sub_len(a: usize, b: usize) -> KResult<usize> {
+    a.checked_sub(b).ok_or(KError::SizeUnderflow {
+        minuend: a,
+        subtrahend: b,
+    })
+}
+
+/// Kaitai's `%`, whose result has the sign of `b`. Panics if `b` is 0, see
+/// [`try_modulo`].
+pub fn modulo(a: i64, b: i64) -> i64 {
+    a.rem_euclid(b)
+}
+
+/// [`modulo`] failing instead of panicking when `b` is 0.
+pub fn try_modulo(a: i64, b: i64) -> KResult<i64> {
+    match b {
+        0 => Err(KError::invalid_input(0, "modulo by zero")),
+        // the result is 0, but `i64::MIN.rem_euclid(-1)` overflows
+        -1 => Ok(0),
+        _ => Ok(a.rem_euclid(b)),
+    }
+}
+
+/// Parse `bytes` as a `T`, turning every failure into an error: this
+/// function does not panic for any input. The runtime reports its own
+/// failures as [`KError`]s, e.g. an empty [`OptRc`] through
+/// [`OptRc::try_get`]; `catch_unwind` is only a backstop for panics left
+/// in parsing code, reported as [`KError::Internal`] with the panic
+/// message. [`ParseSession::parse_bytes_no_panic`] does the same with the
+/// options of a session.
+pub fn parse_bytes_no_panic<T: KStruct + Any>(bytes: &[u8]) -> KResult<OptRc<T>> {
+    ParseSession::default().parse_bytes_no_panic(bytes)
+}
+
+/// Decode a string of hex digits, ignoring whitespace and `_` separators.
+/// Errors report the byte offset of the offending character in `s`.
+pub fn decode_hex(s: &str) -> KResult<Vec<u8>> {
+    let mut res = Vec::with_capacity(s.len() / 2);
+    let mut high: Option<(usize, u8)> = None;
+    for (offset, c) in s.char_indices() {
+        if c.is_whitespace() || c == '_' {
+            continue;
+        }
+        let nibble = c
+            .to_digit(16)
+            .ok_or_else(|| KError::invalid_input(offset, format!("{:?} is not a hex digit", c)))?
+            as u8;
+        match high.take() {
+            Some((_, h)) => res.push(h << 4 | nibble),
+            None => high = Some((offset, nibble)),
+        }
+    }
+    if let Some((offset, _)) = high {
+        return Err(KError::invalid_input(offset, "odd number of hex digits"));
+    }
+    Ok(res)
+}
+
+/// Decode standard (RFC 4648) base64, ignoring whitespace. Padding is
+/// optional, but if present must complete the last group. Errors report the
+/// byte offset of the offending character in `s`.
+pub fn decode_base64(s: &str) -> KResult<Vec<u8>> {
+    let mut res = Vec::with_capacity(s.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut n_digits = 0;
+    let mut n_pad = 0;
+    let mut last_offset = 0;
+    for (offset, c) in s.char_indices() {
+        if c.is_whitespace() {
+            continue;
+        }
+        last_offset = offset;
+        let bad = |msg: String| KError::invalid_input(offset, msg);
+        if c == '=' {
+            n_pad += 1;
+            // only a group of 2 or 3 digits can be padded, up to 4 characters
+            let group = n_digits % 4;
+            if group < 2 || group + n_pad > 4 {
+                return Err(bad("unexpected padding".to_string()));
+            }
+            continue;
+        }
+        if n_pad > 0 {
+            return Err(bad(format!("{:?} after padding", c)));
+        }
+        let v = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            _ => return Err(bad(format!("{:?} is not a base64 digit", c))),
+        };
+        acc = acc << 6 | v;
+        n_digits += 1;
+        if n_digits % 4 == 0 {
+            res.extend_from_slice(&acc.to_be_bytes()[1..]);
+            acc = 0;
+        }
+    }
+    let truncated = |msg: &str| KError::invalid_input(last_offset, msg);
+    match n_digits % 4 {
+        0 => {}
+        1 => return Err(truncated("truncated base64 group")),
+        2 => res.push((acc >> 4) as u8),
+        _ => res.extend_from_slice(&((acc >> 2) as u16).to_be_bytes()),
+    }
+    if n_pad > 0 && (n_digits + n_pad) % 4 != 0 {
+        return Err(truncated("incomplete padding"));
+    }
+    Ok(res)
+}
+
+/// Canonical rendering of a float value, used wherever the runtime shows
+/// floats to users so the output matches across platforms and runtimes.
+///
+/// Follows the ECMAScript `Number.prototype.toString` layout: the shortest
+/// digit string that round-trips, positional notation for decimal exponents
+/// in `-7 < e < 21` (e.g. `0.000001`, `100`) and `1.5e-7` / `1e+21` style
+/// otherwise. Special values are spelled `NaN`, `Infinity` and `-Infinity`.
+/// Unlike ECMAScript, negative zero keeps its sign (`-0`).
+pub fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        return "NaN".to_string();
+    }
+    layout_float(
+        v.is_sign_negative(),
+        v.is_infinite(),
+        &format!("{:e}", v.abs()),
+    )
+}
+
+/// Same as [`format_float`], using the shortest digits that round-trip as `f32`.
+pub fn format_float_f32(v: f32) -> String {
+    if v.is_nan() {
+        return "NaN".to_string();
+    }
+    layout_float(
+        v.is_sign_negative(),
+        v.is_infinite(),
+        &format!("{:e}", v.abs()),
+    )
+}
+
+// `exp_repr` is the `{:e}` rendering of the absolute value, e.g. "1.25e-7"
+fn layout_float(negative: bool, infinite: bool, exp_repr: &str) -> String {
+    let mut res = String::new();
+    if negative {
+        res.push('-');
+    }
+    if infinite {
+        res.push_str("Infinity");
+        return res;
+    }
+    let (mantissa, exp) = exp_repr.split_at(exp_repr.find('e').unwrap());
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+    if digits == "0" {
+        res.push('0');
+        return res;
+    }
+    let k = digits.len() as i32;
+    // position of the decimal point relative to the start of `digits`
+    let n = exp[1..].parse::<i32>().unwrap() + 1;
+    if k <= n && n <= 21 {
+        res.push_str(&digits);
+        res.push_str(&"0".repeat((n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        res.push_str(&digits[..n as usize]);
+        res.push('.');
+        res.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        res.push_str("0.");
+        res.push_str(&"0".repeat(-n as usize));
+        res.push_str(&digits);
+    } else {
+        res.push_str(&digits[..1]);
+        if k > 1 {
+            res.push('.');
+            res.push_str(&digits[1..]);
+        }
+        res.push_str(&format!(
+            "e{}{}",
+            if n > 0 { "+" } else { "-" },
+            (n - 1).abs()
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn basic_strip_right() {
+        let b = vec![1, 2, 3, 4, 5, 5, 5, 5];
+        let c = bytes_strip_right(&b, 5);
+
+        assert_eq!([1, 2, 3, 4], c[..]);
+    }
+
+    #[test]
+    fn basic_read_bytes() {
+        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
+        assert_eq!(reader.read_bytes(3).unwrap()[..], [5, 6, 7]);
+        assert_eq!(reader.read_bytes(4).unwrap_err(), KError::eof(4, 1));
+        assert_eq!(reader.read_bytes(1).unwrap()[..], [8]);
+    }
+
+    #[test]
+    fn read_bits_single() {
+        let b = vec![0x80];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn read_bits_multiple() {
+        // 0xA0
+        let b = vec![0b10100000];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 0);
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn read_bits_large() {
+        let b = vec![0b10100000];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 5);
+    }
+
+    #[test]
+    fn read_bits_span() {
+        let b = vec![0x01, 0x80];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(9).unwrap(), 3);
+    }
+
+    #[test]
+    fn read_bits_too_large() {
+        let b: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(
+            reader.read_bits_int_be(65).unwrap_err(),
+            KError::ReadBitsTooLarge {
+                requested: 65,
+                max: 64
+            }
+        );
+        assert_eq!(
+            reader.read_bits_int_le(70).unwrap_err(),
+            KError::ReadBitsTooLarge {
+                requested: 70,
+                max: 64
+            }
+        );
+    }
+
+    #[test]
+    fn read_bits_eof() {
+        let reader = BytesReader::from(vec![0xab, 0xcd]);
+        reader.read_u1().unwrap();
+        let expected = KError::eof_bits(13, 8, 1);
+        assert_eq!(reader.read_bits_int_be(13).unwrap_err(), expected);
+        assert_eq!(reader.read_bits_int_le(13).unwrap_err(), expected);
+        assert_eq!(
+            expected.to_string(),
+            "unexpected end of stream at byte 1: requested 13 bits, 8 available"
+        );
+
+        // the failed reads did not consume anything
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 0b110);
+        assert_eq!(
+            reader.read_bits_int_be(6).unwrap_err(),
+            KError::eof_bits(6, 5, 2)
+        );
+        assert_eq!(reader.read_bits_int_be(5).unwrap(), 0b01101);
+    }
+
+    #[test]
+    fn read_substream() {
+        let b: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let reader = BytesReader::from(b);
+        assert_eq!(reader.read_bytes(3).unwrap()[..], [1, 2, 3]);
+
+        let sub = reader.substream(4);
+        assert_eq!(sub.read_bytes(5).unwrap_err(), KError::eof(5, 4));
+        let sub = sub.substream(5);
+        assert_eq!(sub.read_bytes(5).unwrap_err(), KError::eof(5, 4));
+        assert_eq!(sub.read_bytes(4).unwrap()[..], [4, 5, 6, 7]);
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn read_bytes_term() {
+        let b = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(
+            reader.read_bytes_term(3, false, false, false).unwrap()[..],
+            [1, 2]
+        );
+        assert_eq!(
+            reader.read_bytes_term(3, true, false, true).unwrap()[..],
+            [3]
+        );
+        assert_eq!(
+            reader.read_bytes_term(3, false, true, true).unwrap()[..],
+            []
+        );
+        assert_eq!(
+            reader.read_bytes_term(5, true, true, true).unwrap()[..],
+            [4, 5]
+        );
+        assert_eq!(
+            reader.read_bytes_term(8, false, false, true).unwrap()[..],
+            [6, 7]
+        );
+        assert_eq!(
+            reader.read_bytes_term(11, false, true, true).unwrap_err(),
+            KError::NoTerminatorFound
+        );
+        // restore position
+        reader.seek(7).unwrap();
+        assert_eq!(
+            reader.read_bytes_term(9, true, true, false).unwrap()[..],
+            [8, 9]
+        );
+        assert_eq!(
+            reader.read_bytes_term(10, true, false, false).unwrap()[..],
+            [10]
+        );
+    }
+
+    #[test]
+    fn process_xor_one_test() {
+        let b = vec![0x66];
+        let reader = BytesReader::from(b);
+        let res = process_xor_one(&reader.read_bytes(1).unwrap(), 3);
+        assert_eq!(0x65, res[0]);
+    }
+
+    #[test]
+    fn process_xor_many_test() {
+        let b = vec![0x66, 0x6F];
+        let reader = BytesReader::from(b);
+        let key: Vec<u8> = vec![3, 3];
+        let res = process_xor_many(&reader.read_bytes(2).unwrap(), &key);
+        assert_eq!(vec![0x65, 0x6C], res);
+    }
+
+    #[test]
+    fn process_rotate_left_test() {
+        let b = vec![0x09, 0xAC];
+        let reader = BytesReader::from(b);
+        let res = process_rotate_left(&reader.read_bytes(2).unwrap(), 3);
+        let expected: Vec<u8> = vec![0x48, 0x65];
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn basic_seek() {
+        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
+        let pos = reader.pos();
+        reader.seek(1).unwrap();
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [2, 3, 4, 5]);
+        reader.seek(pos).unwrap();
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [5, 6, 7, 8]);
+        reader.seek(9).unwrap();
+    }
+
+    fn dump_and_open(bytes: &[u8]) -> BytesReader {
+        let tmp_dir = tempdir().unwrap();
+        let file_path = tmp_dir.path().join("test.txt");
+        {
+            let mut tmp_file = std::fs::File::create(file_path.clone()).unwrap();
+            tmp_file.write_all(bytes).unwrap();
+        }
+        BytesReader::open(file_path).unwrap()
+    }
+
+    #[test]
+    fn basic_read_bytes_file() {
+        let reader = dump_and_open(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
+        assert_eq!(reader.read_bytes(3).unwrap()[..], [5, 6, 7]);
+        assert_eq!(reader.read_bytes(4).unwrap_err(), KError::eof(4, 1));
+        assert_eq!(reader.read_bytes(1).unwrap()[..], [8]);
+    }
+
+    #[test]
+    fn basic_seek_file() {
+        let reader = dump_and_open(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
+        let pos = reader.pos();
+        reader.seek(1).unwrap();
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [2, 3, 4, 5]);
+        reader.seek(pos).unwrap();
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [5, 6, 7, 8]);
+        reader.seek(9).unwrap();
+    }
+
+    #[test]
+    fn decode_string_unknown_label() {
+        assert_eq!(
+            decode_string(&[0x41, 0x42], "no-such-encoding").unwrap_err(),
+            KError::Encoding {
+                label: "no-such-encoding".to_string(),
+                desc: "unknown encoding".to_string(),
+                input_len: 2,
+                bad_offset: None,
+            }
+        );
+        assert_eq!(
+            bytes_to_str(&[], "no-such-encoding").unwrap_err(),
+            KError::Encoding {
+                label: "no-such-encoding".to_string(),
+                desc: "unknown encoding".to_string(),
+                input_len: 0,
+                bad_offset: None,
+            }
+        );
+    }
+
+    #[test]
+    fn decode_string_invalid_sequence() {
+        let b = vec![0x61, 0x62, 0xff, 0x63];
+        match decode_string(&b, "UTF-8").unwrap_err() {
+            KError::Encoding {
+                label,
+                input_len,
+                bad_offset,
+                ..
+            } => {
+                assert_eq!(label, "UTF-8");
+                assert_eq!(input_len, 4);
+                assert_eq!(bad_offset, Some(2));
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // lenient decoding substitutes instead
+        assert_eq!(bytes_to_str(&b, "UTF-8").unwrap(), "ab\u{fffd}c");
+        // truncated multi-byte sequence at the end of input
+        let e = decode_string(&[0x61, 0xe2, 0x82], "UTF-8").unwrap_err();
+        assert_eq!(e.category(), ErrorCategory::Encoding);
+        assert_eq!(e.offset(), Some(1));
+    }
+
+    #[test]
+    fn decode_string_cp437() {
+        let b = vec![0x00, 0x01, 0x41, 0x80, 0xfe];
+        assert_eq!(decode_string(&b, "cp437").unwrap(), "\u{0}\u{1}AÇ■");
+        assert_eq!(bytes_to_str(&b, "IBM437").unwrap(), "\u{0}\u{1}AÇ■");
+    }
+
+    #[test]
+    fn encoding_error_display() {
+        let e = KError::Encoding {
+            label: "UTF-8".to_string(),
+            desc: "invalid sequence".to_string(),
+            input_len: 4,
+            bad_offset: Some(2),
+        };
+        assert_eq!(
+            e.to_string(),
+            "cannot decode 4 bytes as \"UTF-8\": invalid sequence (at byte 2)"
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_encoding_errors() {
+        let e = KError::UnknownEncoding {
+            name: "klingon".to_string(),
+        };
+        assert_eq!(e.to_string(), "unknown encoding \"klingon\"");
+        assert_eq!(e.category(), ErrorCategory::Encoding);
+        let e = KError::BytesDecodingError {
+            msg: "invalid sequence".to_string(),
+        };
+        assert_eq!(e.to_string(), "cannot decode bytes: invalid sequence");
+        assert_eq!(e.category(), ErrorCategory::Encoding);
+    }
+
+    #[test]
+    fn read_bytes_array() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4, 5]);
+
+        assert_eq!(reader.read_bytes_array::<0>().unwrap(), []);
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(reader.read_bytes_array::<2>().unwrap(), [1, 2]);
+        assert_eq!(
+            reader.read_bytes_array::<4>().unwrap_err(),
+            KError::eof(4, 3)
+        );
+        assert_eq!(reader.pos(), 2);
+        assert_eq!(reader.read_bytes_array::<3>().unwrap(), [3, 4, 5]);
+        assert_eq!(reader.read_bytes_array::<0>().unwrap(), []);
+    }
+
+    #[test]
+    fn read_bytes_array_at() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4, 5]);
+
+        assert_eq!(reader.read_bytes_array::<1>().unwrap(), [1]);
+        assert_eq!(reader.read_bytes_array_at::<2>(3).unwrap(), [4, 5]);
+        assert_eq!(reader.pos(), 1);
+        assert_eq!(
+            reader.read_bytes_array_at::<2>(4).unwrap_err(),
+            KError::eof(2, 1)
+        );
+        assert_eq!(reader.pos(), 1);
+        assert_eq!(reader.read_bytes_array::<2>().unwrap(), [2, 3]);
+    }
+
+    #[test]
+    fn bits_remaining_be() {
+        let reader = BytesReader::from(vec![0xab, 0xcd]);
+
+        assert_eq!(reader.bits_remaining_in_buffer(), 0);
+        assert_eq!(reader.total_bits_remaining(), 16);
+        assert_eq!(reader.read_bits_int_be(12).unwrap(), 0xabc);
+        assert_eq!(reader.bits_remaining_in_buffer(), 4);
+        assert_eq!(reader.total_bits_remaining(), 4);
+        assert!(!reader.is_eof_bits());
+        assert_eq!(reader.read_bits_int_be(4).unwrap(), 0xd);
+        assert_eq!(reader.bits_remaining_in_buffer(), 0);
+        assert_eq!(reader.total_bits_remaining(), 0);
+        assert!(reader.is_eof_bits());
+    }
+
+    #[test]
+    fn bits_remaining_le() {
+        let reader = BytesReader::from(vec![0xab, 0xcd]);
+
+        assert_eq!(reader.read_bits_int_le(12).unwrap(), 0xdab);
+        assert_eq!(reader.bits_remaining_in_buffer(), 4);
+        assert_eq!(reader.total_bits_remaining(), 4);
+        assert!(!reader.is_eof_bits());
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.bits_remaining_in_buffer(), 0);
+        assert_eq!(reader.total_bits_remaining(), 0);
+        assert!(reader.is_eof_bits());
+    }
+
+    #[test]
+    fn supported_encodings_list() {
+        let encodings = supported_encodings();
+
+        let utf8 = encodings.iter().find(|e| e.name == "utf-8").unwrap();
+        assert_eq!(utf8.source, EncodingSource::Whatwg);
+        assert!(utf8.aliases.contains(&"utf8"));
+        let cp437 = encodings.iter().find(|e| e.name == "cp437").unwrap();
+        assert_eq!(cp437.source, EncodingSource::Codepage);
+        assert_eq!(cp437.aliases, ["ibm437"]);
+
+        for e in &encodings {
+            assert!(is_encoding_supported(e.name), "{}", e.name);
+            for alias in &e.aliases {
+                assert!(is_encoding_supported(alias), "{}", alias);
+            }
+        }
+        let names: Vec<_> = encodings.iter().map(|e| e.name).collect();
+        let mut unique = names.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(names.len(), unique.len());
+        assert!(is_encoding_supported("Shift_JIS"));
+        assert!(is_encoding_supported("IBM437"));
+
+        // the label list covers every WHATWG encoding of the backend
+        for enc in encoding::all::encodings() {
+            if let Some(name) = enc.whatwg_name().filter(|n| is_encoding_supported(n)) {
+                let listed = encodings
+                    .iter()
+                    .any(|e| e.name == name || e.aliases.contains(&name));
+                assert!(listed, "{} missing from WHATWG_LABELS", name);
+            }
+        }
+        assert!(!is_encoding_supported("no-such-encoding"));
+    }
+
+    #[test]
+    fn decode_string_escape_handler() {
+        let b = vec![0x61, 0xff, 0x62, 0xfe];
+        let mut escaped = String::new();
+        let res = decode_string_with_handler(&b, "utf-8", &mut |byte, _| {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+            Some('?')
+        })
+        .unwrap();
+        assert_eq!(res.text, "a?b?");
+        assert_eq!(res.substitutions, 2);
+        assert_eq!(escaped, "\\xff\\xfe");
+    }
+
+    #[test]
+    fn decode_string_dropping_handler() {
+        let b = vec![0xff, 0x61, 0xc3, 0xa9, 0xff, 0xff];
+        let res = decode_string_with_handler(&b, "utf-8", &mut |_, _| None).unwrap();
+        assert_eq!(res.text, "aé");
+        assert_eq!(res.substitutions, 3);
+
+        let res = decode_string_with_handler(&[0x00, 0x80], "cp437", &mut |_, _| None).unwrap();
+        assert_eq!(res.text, "\u{0}Ç");
+        assert_eq!(res.substitutions, 0);
+    }
+
+    #[test]
+    fn decode_string_handler_offsets() {
+        // 0x82 0xa0 is a valid Shift_JIS sequence, 0xa0 alone is not
+        let b = vec![0x41, 0xa0, 0x82, 0xa0, 0x42, 0xa0];
+        let mut offsets = vec![];
+        let res = decode_string_with_handler(&b, "shift_jis", &mut |byte, offset| {
+            offsets.push((byte, offset));
+            Some('_')
+        })
+        .unwrap();
+        assert_eq!(res.text, "A_あB_");
+        assert_eq!(offsets, [(0xa0, 1), (0xa0, 5)]);
+    }
+
+    #[test]
+    fn format_float_table() {
+        let cases: &[(f64, &str)] = &[
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (1.0, "1"),
+            (-2.5, "-2.5"),
+            (0.1, "0.1"),
+            (123456.789, "123456.789"),
+            (0.000001, "0.000001"),
+            (1e-7, "1e-7"),
+            (1.5e-7, "1.5e-7"),
+            (1e20, "100000000000000000000"),
+            (1e21, "1e+21"),
+            (1.2345e22, "1.2345e+22"),
+            (f64::from(0.1f32), "0.10000000149011612"),
+            (f64::from(16777217.0f32), "16777216"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (5e-324, "5e-324"),
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+        ];
+        for (v, expected) in cases {
+            assert_eq!(format_float(*v), *expected);
+        }
+
+        assert_eq!(format_float_f32(0.1), "0.1");
+        assert_eq!(format_float_f32(-0.0), "-0");
+        assert_eq!(format_float_f32(1e-7), "1e-7");
+        assert_eq!(format_float_f32(f32::MAX), "3.4028235e+38");
+        assert_eq!(format_float_f32(f32::NAN), "NaN");
+    }
+
+    fn temp_file_with(bytes: &[u8]) -> std::fs::File {
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(bytes).unwrap();
+        f
+    }
+
+    #[test]
+    fn from_file_ignores_descriptor_offset() {
+        let mut f = temp_file_with(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        f.seek(SeekFrom::Start(5)).unwrap();
+        let reader = BytesReader::from_file(f).unwrap();
+
+        assert_eq!(reader.size(), 8);
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(reader.read_bytes(3).unwrap()[..], [1, 2, 3]);
+        reader.seek(6).unwrap();
+        assert_eq!(reader.read_bytes(2).unwrap()[..], [7, 8]);
+        assert!(reader.is_eof());
+    }
+
+    #[test]
+    fn from_file_range() {
+        let mut f = temp_file_with(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        f.seek(SeekFrom::Start(9)).unwrap();
+        let reader = BytesReader::from_file_range(f, 3, 5).unwrap();
+
+        assert_eq!(reader.size(), 5);
+        assert_eq!(reader.read_bytes(2).unwrap()[..], [4, 5]);
+        assert_eq!(reader.read_bytes(4).unwrap_err(), KError::eof(4, 3));
+        assert_eq!(reader.read_bytes_full().unwrap()[..], [6, 7, 8]);
+        assert!(reader.is_eof());
+        reader.seek(0).unwrap();
+        assert_eq!(reader.read_u1().unwrap(), 4);
+    }
+
+    #[test]
+    fn range_reader_seek_overflow() {
+        let mut r = RangeReader::new(std::io::Cursor::new(vec![0; 8]), 4, 4).unwrap();
+        let err = r.seek(SeekFrom::Start(u64::MAX - 1)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(r.stream_position().unwrap(), 0);
+    }
+
+    #[test]
+    fn from_file_range_out_of_bounds() {
+        let f = temp_file_with(&[1, 2, 3, 4]);
+        assert_eq!(
+            BytesReader::from_file_range(f, 2, 3).unwrap_err(),
+            KError::eof(3, 2)
+        );
+    }
+
+    #[test]
+    fn with_inner_reads() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(reader.read_u1().unwrap(), 1);
+
+        let res = reader
+            .with_inner(|r| {
+                let mut buf = [0; 2];
+                r.read_exact(&mut buf).unwrap();
+                buf
+            })
+            .unwrap();
+        assert_eq!(res, [2, 3]);
+        assert_eq!(reader.pos(), 3);
+        assert_eq!(reader.read_u1().unwrap(), 4);
+    }
+
+    #[test]
+    fn with_inner_seeks_back() {
+        let reader = dump_and_open(&[1, 2, 3, 4, 5, 6]);
+        reader.seek(5).unwrap();
+
+        reader
+            .with_inner(|r| r.seek(SeekFrom::Current(-4)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reader.pos(), 1);
+        assert_eq!(reader.read_bytes(2).unwrap()[..], [2, 3]);
+    }
+
+    #[test]
+    fn from_hex() {
+        let reader = BytesReader::from_hex("89 50 4e_47\n0d0A").unwrap();
+        let expected = BytesReader::from(vec![0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a]);
+        assert_eq!(reader.read_bytes_full(), expected.read_bytes_full());
+        assert_eq!(decode_hex("").unwrap(), []);
+        assert_eq!(decode_hex(" \t_ ").unwrap(), []);
+    }
+
+    #[test]
+    fn from_hex_errors() {
+        assert_eq!(
+            BytesReader::from_hex("00 1x").unwrap_err(),
+            KError::invalid_input(4, "'x' is not a hex digit")
+        );
+        assert_eq!(
+            BytesReader::from_hex("0011 2").unwrap_err(),
+            KError::invalid_input(5, "odd number of hex digits")
+        );
+        // offsets are byte offsets into the string
+        assert_eq!(decode_hex("é0").unwrap_err().offset(), Some(0));
+    }
+
+    #[test]
+    fn from_base64() {
+        let reader = BytesReader::from_base64("iVBO Rw0K\nGgo=").unwrap();
+        let expected = BytesReader::from_hex("89504e470d0a1a0a").unwrap();
+        assert_eq!(reader.read_bytes_full(), expected.read_bytes_full());
+
+        assert_eq!(decode_base64("").unwrap(), []);
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zm9v").unwrap(), b"foo");
+        assert_eq!(decode_base64("Zm9vYg").unwrap(), b"foob");
+        assert_eq!(decode_base64("+/+/").unwrap(), [0xfb, 0xff, 0xbf]);
+    }
+
+    #[test]
+    fn from_base64_errors() {
+        let offset_of = |s: &str| decode_base64(s).unwrap_err().offset().unwrap();
+        assert_eq!(offset_of("Zm9v!"), 4);
+        assert_eq!(offset_of("Zg==Zg=="), 4);
+        assert_eq!(offset_of("Z==="), 1);
+        assert_eq!(offset_of("Zm9vY"), 4);
+        assert_eq!(offset_of("Zg="), 2);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let reader = BytesReader::from(vec![0xa5, 0x3c, 0xff, 0x00]);
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 0b101);
+
+        let snap = reader.snapshot();
+        assert_eq!(snap.pos(), 1);
+        let first = (
+            reader.read_bits_int_be(7).unwrap(),
+            reader.read_u1().unwrap(),
+        );
+        reader.restore(&snap).unwrap();
+        assert_eq!(
+            (
+                reader.read_bits_int_be(7).unwrap(),
+                reader.read_u1().unwrap()
+            ),
+            first
+        );
+
+        // clones share the data source
+        let clone = KStream::clone(&reader);
+        clone.restore(&snap).unwrap();
+        assert_eq!(clone.read_bits_int_be(7).unwrap(), first.0);
+        assert_eq!(reader.pos(), 3);
+    }
+
+    #[test]
+    fn snapshot_foreign_stream() {
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        let other = BytesReader::from(vec![1, 2, 3]);
+        other.read_u1().unwrap();
+
+        assert_eq!(
+            reader.restore(&other.snapshot()).unwrap_err(),
+            KError::SnapshotMismatch
+        );
+        assert_eq!(reader.pos(), 0);
+    }
+
+    #[test]
+    fn snapshot_other_window() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4, 5, 6]);
+        reader.read_u1().unwrap();
+        let window = reader.read_substream(4).unwrap();
+        window.read_u1().unwrap();
+        let other = KStream::clone(&reader).read_substream(1).unwrap();
+        let sub = window.substream(2);
+
+        // positions of a window are not those of its parent
+        for snap in [window.snapshot(), other.snapshot(), sub.snapshot()] {
+            assert_eq!(reader.restore(&snap).unwrap_err(), KError::SnapshotMismatch);
+        }
+        assert_eq!(reader.pos(), 5);
+        for snap in [reader.snapshot(), other.snapshot(), sub.snapshot()] {
+            assert_eq!(window.restore(&snap).unwrap_err(), KError::SnapshotMismatch);
+        }
+        assert_eq!(window.pos(), 1);
+        let clone = KStream::clone(&window);
+        clone.restore(&window.snapshot()).unwrap();
+    }
+
+    // u2le length, that many bytes of payload, a NUL-terminated name and a
+    // 4-bit flag field padded to a byte
+    #[derive(Default, Debug)]
+    struct Record {
+        start: RefCell<usize>,
+        payload: RefCell<Vec<u8>>,
+        name: RefCell<Vec<u8>>,
+        flags: RefCell<u64>,
+    }
+
+    impl KStruct for Record {
+        type Root = Record;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            *self_rc.start.borrow_mut() = _io.pos();
+            let len = _io.read_u2le()?;
+            *self_rc.payload.borrow_mut() = _io.read_bytes(len.into())?;
+            *self_rc.name.borrow_mut() = _io.read_bytes_term(0, false, true, true)?;
+            *self_rc.flags.borrow_mut() = _io.read_bits_int_be(4)?;
+            _io.align_to_byte()?;
+            Ok(())
+        }
+    }
+
+    impl KStructSkip for Record {
+        fn skip<S: KStream>(io: &S) -> KResult<()> {
+            let len = io.read_u2le()?;
+            io.skip(len.into())?;
+            io.read_bytes_term(0, false, true, true)?;
+            io.skip(1)
+        }
+    }
+
+    #[test]
+    fn skip_matches_full_parse() {
+        let data = decode_hex("0300 aabbcc 6100 f0  0000 00 10  0100 ff 616200 20").unwrap();
+
+        let reader = BytesReader::from(data.clone());
+        let mut parsed = vec![];
+        while !reader.is_eof() {
+            let r: OptRc<Record> = Record::read_into(&reader, None, None).unwrap();
+            parsed.push(*r.start.borrow());
+        }
+
+        let reader = BytesReader::from(data);
+        let mut skipped = vec![];
+        while !reader.is_eof() {
+            skipped.push(read_skip::<_, Record>(&reader).unwrap());
+        }
+        assert_eq!(
+            skipped,
+            [
+                SkipInfo { offset: 0, len: 8 },
+                SkipInfo { offset: 8, len: 4 },
+                SkipInfo { offset: 12, len: 7 },
+            ]
+        );
+        assert_eq!(skipped.iter().map(|s| s.offset).collect::<Vec<_>>(), parsed);
+    }
+
+    struct Rewind;
+
+    impl KStructSkip for Rewind {
+        fn skip<S: KStream>(io: &S) -> KResult<()> {
+            io.seek(0)
+        }
+    }
+
+    #[test]
+    fn skip_backwards() {
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        reader.skip(2).unwrap();
+        let err = read_skip::<_, Rewind>(&reader).unwrap_err();
+        assert_eq!(
+            (err.category(), err.offset()),
+            (ErrorCategory::InvalidInput, Some(2))
+        );
+    }
+
+    #[test]
+    fn skip_bounds() {
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        reader.skip(2).unwrap();
+        assert_eq!(reader.skip(2).unwrap_err(), KError::eof(2, 1));
+        assert_eq!(reader.pos(), 2);
+        reader.skip(1).unwrap();
+        reader.skip(0).unwrap();
+        assert!(reader.is_eof());
+    }
+
+    thread_local! {
+        static RECORD_READS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    // `Record` which counts its parses
+    #[derive(Default, Debug)]
+    struct CountedRecord {
+        record: RefCell<OptRc<Record>>,
+    }
+
+    impl KStruct for CountedRecord {
+        type Root = CountedRecord;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            RECORD_READS.with(|c| c.set(c.get() + 1));
+            *self_rc.record.borrow_mut() = Record::read_into::<_, Record>(_io, None, None)?;
+            Ok(())
+        }
+    }
+
+    impl KStructSkip for CountedRecord {
+        fn skip<S: KStream>(io: &S) -> KResult<()> {
+            Record::skip(io)
+        }
+    }
+
+    #[test]
+    fn defer_read_parses_on_access() {
+        let reader = BytesReader::from_hex("0100 aa 6100 f0  7777").unwrap();
+        RECORD_READS.with(|c| c.set(0));
+
+        let lazy = defer_read::<_, CountedRecord>(&reader, None, None).unwrap();
+        assert_eq!(reader.pos(), 6);
+        assert_eq!(reader.read_u2le().unwrap(), 0x7777);
+        assert!(!lazy.is_parsed());
+        assert_eq!(RECORD_READS.with(|c| c.get()), 0);
+
+        let first = lazy.get().unwrap();
+        assert_eq!(*first.record.borrow().payload.borrow(), [0xaa]);
+        lazy.get().unwrap();
+        assert!(lazy.is_parsed());
+        assert_eq!(RECORD_READS.with(|c| c.get()), 1);
+        assert_eq!(reader.pos(), 8);
+    }
+
+    #[test]
+    fn defer_read_matches_eager() {
+        let data = decode_hex("0300 aabbcc 6100 f0").unwrap();
+        let eager: OptRc<Record> =
+            Record::read_into(&BytesReader::from(data.clone()), None, None).unwrap();
+
+        let reader = BytesReader::from(data);
+        let lazy = defer_read::<_, Record>(&reader, None, None).unwrap();
+        let lazy = lazy.get().unwrap();
+        assert_eq!(*lazy.payload.borrow(), *eager.payload.borrow());
+        assert_eq!(*lazy.name.borrow(), *eager.name.borrow());
+        assert_eq!(*lazy.flags.borrow(), *eager.flags.borrow());
+    }
+
+    #[test]
+    fn defer_read_sized_errors_on_access() {
+        // the region is too short for the payload length it declares
+        let reader = BytesReader::from_hex("0500 aabb 1234").unwrap();
+
+        let lazy = defer_read_sized::<_, Record>(&reader, 4, None, None).unwrap();
+        assert_eq!(reader.read_u2be().unwrap(), 0x1234);
+        assert_eq!(lazy.get().unwrap_err(), KError::eof(5, 2));
+        assert!(!lazy.is_parsed());
+    }
+
+    #[test]
+    fn with_inner_reentrant_access() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4]);
+        let clone = KStream::clone(&reader);
+        reader.read_u1().unwrap();
+
+        reader
+            .with_inner(|r| {
+                assert_eq!(reader.pos(), 1);
+                assert_eq!(reader.size(), 4);
+                assert!(!reader.is_eof());
+                let e = reader.read_u1().unwrap_err();
+                assert_eq!(e.category(), ErrorCategory::Io);
+                assert_eq!(clone.read_u1().unwrap_err(), e);
+                let mut buf = [0; 1];
+                r.read_exact(&mut buf).unwrap();
+            })
+            .unwrap();
+        assert_eq!(reader.read_u1().unwrap(), 3);
+        assert_eq!(clone.read_u1().unwrap(), 1);
+    }
+
+    // a backend calling back into the reader it is the backend of
+    struct Hooked {
+        data: std::io::Cursor<Vec<u8>>,
+        reader: Rc<RefCell<Option<BytesReader>>>,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl Read for Hooked {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if let Some(reader) = &*self.reader.borrow() {
+                self.calls.set(self.calls.get() + 1);
+                reader.pos();
+                reader.size();
+                reader.is_eof();
+                assert_eq!(reader.read_u1().unwrap_err().category(), ErrorCategory::Io);
+                assert!(reader.refresh_size().is_err());
+            }
+            self.data.read(buf)
+        }
+    }
+
+    impl Seek for Hooked {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    #[test]
+    fn backend_reentrant_access() {
+        let slot = Rc::new(RefCell::new(None));
+        let calls = Rc::new(Cell::new(0));
+        let backend: Box<dyn ReadSeek> = Box::new(Hooked {
+            data: std::io::Cursor::new(vec![1, 2, 3, 4]),
+            reader: slot.clone(),
+            calls: calls.clone(),
+        });
+        let reader = BytesReader::try_from(backend).unwrap();
+        *slot.borrow_mut() = Some(KStream::clone(&reader));
+
+        assert_eq!(reader.read_u2le().unwrap(), 0x0201);
+        reader.seek(1).unwrap();
+        assert_eq!(reader.read_bytes_full().unwrap(), [2, 3, 4]);
+        assert!(calls.get() >= 2);
+        // the reader is back to normal afterwards
+        *slot.borrow_mut() = None;
+        reader.seek(3).unwrap();
+        assert_eq!(reader.read_u1().unwrap(), 4);
+    }
+
+    #[test]
+    fn with_inner_panic_restores_backend() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4]);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            reader.with_inner(|_| panic!("boom")).unwrap();
+        }));
+        assert!(res.is_err());
+        assert_eq!(reader.read_u1().unwrap(), 1);
+    }
+
+    #[test]
+    fn lazy_struct_reentrant_access() {
+        let reader = BytesReader::from_hex("0100 aa 6100 f0").unwrap();
+        let lazy = defer_read::<_, Record>(&reader, None, None).unwrap();
+        // parsing a deferred struct must not hold borrows of the outer reader
+        assert_eq!(*lazy.get().unwrap().payload.borrow(), [0xaa]);
+        assert!(reader.is_eof());
+        assert_eq!(reader.size(), 6);
+    }
+
+    #[test]
+    fn error_accessors() {
+        let reader = BytesReader::from(vec![1, 2]);
+        let e = reader.read_u4be().unwrap_err();
+        assert_eq!(e, KError::eof(4, 2));
+        assert!(e.is_eof());
+        assert_eq!(e.offset(), None);
+        let e = reader.read_bits_int_le(17).unwrap_err();
+        assert!(e.is_eof());
+        assert_eq!(e.offset(), Some(0));
+
+        let e = KError::validation_failed(ValidationKind::NotEqual, "/seq/0");
+        assert_eq!(e.category(), ErrorCategory::Validation);
+        assert!(!e.is_eof());
+        assert_eq!(e.to_string(), "validation failed (NotEqual) at /seq/0");
+        assert_eq!(KError::MissingParent.category(), ErrorCategory::Structure);
+        assert_eq!(KError::NoTerminatorFound.category(), ErrorCategory::Format);
+        assert_eq!(
+            KError::invalid_input(3, "bad").category(),
+            ErrorCategory::InvalidInput
+        );
+    }
+
+    #[test]
+    fn opt_rc_identity() {
+        let a = OptRc::from(1u8);
+        let b = OptRc::from(1u8);
+        assert!(a.ptr_eq(&a.clone()));
+        assert!(!a.ptr_eq(&b));
+        assert_eq!(a.as_ptr(), &*a as *const u8);
+
+        let empty = OptRc::<u8>::default();
+        assert!(empty.as_ptr().is_null());
+        assert!(empty.ptr_eq(&OptRc::default()));
+        assert!(!empty.ptr_eq(&a));
+
+        let mut map = std::collections::HashMap::new();
+        map.insert(OptRcKey(a.clone()), "a");
+        map.insert(OptRcKey::from(b.clone()), "b");
+        map.insert(OptRcKey(empty), "none");
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&OptRcKey(a.clone())], "a");
+        assert_eq!(map[&OptRcKey(b)], "b");
+        assert_eq!(map[&OptRcKey(OptRc::default())], "none");
+        assert!(!map.contains_key(&OptRcKey(OptRc::from(1u8))));
+    }
+
+    #[test]
+    fn opt_rc_option_interop() {
+        let empty = OptRc::<u8>::default();
+        assert_eq!(empty.iter().next(), None);
+        assert_eq!(empty.as_deref(), None);
+        assert!(!empty.map_or(false, |_| true));
+        assert_eq!(*empty.unwrap_or_else(|| Rc::new(7)), 7);
+        assert_eq!(Option::<Rc<u8>>::from(empty), None);
+
+        let v = OptRc::<u8>::from(Some(Rc::new(5)));
+        assert_eq!(v.iter().collect::<Vec<_>>(), [&5]);
+        assert!(v.map_or(false, |x| *x == 5));
+        // no new allocation for a present value
+        assert!(Rc::ptr_eq(&v.unwrap_or_else(|| Rc::new(0)), &v.get()));
+        let rc: Option<Rc<u8>> = v.clone().into();
+        assert!(v.ptr_eq(&OptRc::from(rc)));
+    }
+
+    #[test]
+    fn translate_nested_to_file_offset() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let range = BytesReader::from_file_range(temp_file_with(&bytes), 8, 20).unwrap();
+        range.read_bytes(2).unwrap();
+        let sub = range.substream(10);
+        sub.read_bytes(4).unwrap();
+        let base = sub.pos();
+        let inner = BytesReader::from(sub.read_bytes(4).unwrap()).derived_from(&sub, base);
+        assert_eq!(inner.read_u2le().unwrap(), 0x0f0e);
+
+        let e = inner.read_u4le().unwrap_err();
+        let e = inner.enrich_error(e);
+        assert_eq!(e.offset(), Some(2));
+        assert_eq!(e.root_offset(), Some(RootOffset { offset: 16 }));
+        assert!(e.is_eof());
+        assert_eq!(
+            e.to_string(),
+            "unexpected end of stream: requested 4 bytes, 2 available \
+             (at byte 2, offset 16 in root stream)"
+        );
+        // enriching twice keeps the innermost position
+        assert_eq!(sub.enrich_error(e.clone()), e);
+    }
+
+    #[test]
+    fn translate_processed() {
+        use flate2::{write::ZlibEncoder, Compression};
+
+        let mut enc = ZlibEncoder::new(vec![], Compression::default());
+        enc.write_all(&[1, 2, 3]).unwrap();
+        let reader = BytesReader::from(enc.finish().unwrap());
+        let raw = reader.read_bytes_full().unwrap();
+        let unpacked = BytesReader::from(process_zlib(&raw).unwrap()).processed();
+        assert_eq!(unpacked.read_bytes(3).unwrap(), [1, 2, 3]);
+        assert_eq!(unpacked.translate_to_root(1), None);
+
+        let e = unpacked.enrich_error(unpacked.read_u1().unwrap_err());
+        assert_eq!(e.offset(), Some(3));
+        assert_eq!(e.root_offset(), None);
+        assert_eq!(
+            e.to_string(),
+            "unexpected end of stream: requested 1 bytes, 0 available (at byte 3)"
+        );
+        // streams derived from processed data stay untranslatable
+        let derived = BytesReader::from(vec![0]).derived_from(&unpacked, 1);
+        assert_eq!(derived.translate_to_root(0), None);
+    }
+
+    #[test]
+    fn stream_conformance() {
+        let mut gen = testkit::ScriptGen::new(1);
+        for len in [0, 1, 7, 64] {
+            let data = gen.data(len);
+            testkit::check_stream_conformance(&BytesReader::from(data.clone()), &data).unwrap();
+            testkit::check_stream_conformance(&dump_and_open(&data), &data).unwrap();
+        }
+    }
+
+    #[derive(Default, Debug)]
+    struct Unrooted;
+
+    impl KStruct for Unrooted {
+        type Root = KStructUnit;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            _self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn no_panic_entry_point() {
+        // not its own root and none given
+        assert_eq!(
+            parse_bytes_no_panic::<Unrooted>(&[1, 2]).unwrap_err(),
+            KError::MissingRoot
+        );
+        assert!(parse_bytes_no_panic::<KStructUnit>(&[]).is_ok());
+    }
+
+    #[derive(Default, Debug)]
+    struct Panicky;
+
+    impl KStruct for Panicky {
+        type Root = Panicky;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            _self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            let len = _io.read_u1()?;
+            panic!("bad length {}", len);
+        }
+    }
+
+    #[test]
+    fn no_panic_converts_panics() {
+        assert!(parse_bytes_no_panic::<Panicky>(&[]).unwrap_err().is_eof());
+        match parse_bytes_no_panic::<Panicky>(&[7]).unwrap_err() {
+            KError::Internal { msg } => assert_eq!(msg, "bad length 7"),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn empty_opt_rc_is_an_error() {
+        let empty = OptRc::<Panicky>::default();
+        assert_eq!(
+            empty.try_get().unwrap_err().category(),
+            ErrorCategory::Internal
+        );
+        assert!(empty.try_deref().is_err());
+        assert_eq!(*OptRc::from(3).try_deref().unwrap(), 3);
+        // setting a link to an empty value clears it
+        let link = SharedType::new(Rc::new(Panicky));
+        link.set(Ok(empty.clone()));
+        assert!(link.is_empty());
+
+        assert!(Panicky::downcast::<Panicky, KStructUnit>(None, empty, false).is_err());
+        let t = OptRc::from(Panicky);
+        assert!(
+            Panicky::downcast::<Panicky, KStructUnit>(None, t.clone(), false)
+                .unwrap()
+                .is_empty()
+        );
+        match Panicky::downcast::<Panicky, KStructUnit>(None, t, true).unwrap_err() {
+            KError::Internal { msg } => assert!(msg.contains("is not a"), "{}", msg),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn try_modulo_never_panics() {
+        assert_eq!(try_modulo(-7, 3).unwrap(), modulo(-7, 3));
+        assert_eq!(try_modulo(i64::MIN, -1).unwrap(), 0);
+        assert_eq!(
+            try_modulo(5, 0).unwrap_err().category(),
+            ErrorCategory::InvalidInput
+        );
+        assert_eq!(process_xor_many(&[1, 2], &[]), [1, 2]);
+    }
+
+    #[test]
+    fn checked_conversions() {
+        assert_eq!(to_len(0).unwrap(), 0);
+        assert_eq!(to_len(4096).unwrap(), 4096);
+        assert_eq!(to_pos(u32::MAX.into()).unwrap(), u32::MAX as usize);
+        assert_eq!(
+            to_len(-1).unwrap_err(),
+            KError::value_out_of_range(-1, "length")
+        );
+        assert_eq!(
+            to_pos(i64::MIN).unwrap_err().to_string(),
+            "-9223372036854775808 out of range for a position"
+        );
+        assert_eq!(
+            to_len(-1).unwrap_err().category(),
+            ErrorCategory::InvalidInput
+        );
+        // on 64-bit targets every non-negative i64 fits
+        let big = i64::from(u32::MAX) + 1;
+        assert_eq!(to_len(big).is_ok(), usize::BITS == 64);
+        assert_eq!(to_len(i64::MAX).is_ok(), usize::BITS == 64);
+        assert_eq!(to_u64_nonneg(i64::MAX).unwrap(), i64::MAX as u64);
+        assert!(to_u64_nonneg(-5).is_err());
+        assert_eq!(usize_from_u64(7, "size").unwrap(), 7);
+        assert_eq!(usize_from_u64(u64::MAX, "size").is_ok(), usize::BITS == 64);
+    }
+
+    #[test]
+    fn refresh_size_after_append() {
+        let f = temp_file_with(&[1, 2, 3, 4]);
+        let mut writer = f.try_clone().unwrap();
+        let reader = BytesReader::from_file(f).unwrap();
+        reader.read_bytes(4).unwrap();
+        writer.seek(SeekFrom::End(0)).unwrap();
+        writer.write_all(&[5, 6]).unwrap();
+        assert_eq!(reader.read_bytes(2).unwrap_err(), KError::eof(2, 0));
+        assert_eq!(reader.refresh_size().unwrap(), 6);
+        assert_eq!(reader.size(), 6);
+        assert_eq!(reader.read_bytes(2).unwrap(), [5, 6]);
+
+        // generic backends are sized the same way
+        let backend: Box<dyn ReadSeek> = Box::new(std::io::Cursor::new(vec![1, 2, 3]));
+        assert_eq!(BytesReader::try_from(backend).unwrap().size(), 3);
+
+        // with auto-refresh, the new bytes are picked up by the read itself
+        let reader = BytesReader::from_file(writer.try_clone().unwrap())
+            .unwrap()
+            .auto_refresh(true);
+        writer.write_all(&[7]).unwrap();
+        reader.seek(6).unwrap();
+        assert_eq!(reader.read_u1().unwrap(), 7);
+        assert_eq!(reader.read_u1().unwrap_err(), KError::eof(1, 0));
+    }
+
+    #[test]
+    fn refresh_size_after_truncation() {
+        let f = temp_file_with(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let writer = f.try_clone().unwrap();
+        let reader = BytesReader::from_file(f).unwrap();
+        reader.seek(6).unwrap();
+        writer.set_len(4).unwrap();
+        let err = reader.refresh_size().unwrap_err();
+        assert_eq!(err, KError::SourceTruncated { size: 4, pos: 6 });
+        assert_eq!(err.category(), ErrorCategory::Io);
+        assert_eq!(reader.size(), 4);
+        assert!(reader.read_u1().unwrap_err().is_eof());
+
+        // without a refresh, the short read is noticed by the backend
+        let reader = BytesReader::from_file(writer.try_clone().unwrap())
+            .unwrap()
+            .auto_refresh(true);
+        writer.set_len(2).unwrap();
+        reader.seek(1).unwrap();
+        assert_eq!(reader.read_bytes(3).unwrap_err(), KError::eof(3, 1));
+        reader.seek(3).unwrap();
+        assert_eq!(
+            reader.read_u1().unwrap_err(),
+            KError::SourceTruncated { size: 2, pos: 3 }
+        );
+    }
+
+    #[test]
+    fn default_reader_is_empty() {
+        let reader = BytesReader::default();
+        let eof = |res: KResult<()>| assert!(res.unwrap_err().is_eof());
+
+        assert_eq!(reader.size(), 0);
+        assert!(reader.is_eof());
+        assert!(reader.is_eof_bits());
+        assert_eq!(reader.pos(), 0);
+        reader.seek(0).unwrap();
+        reader.skip(0).unwrap();
+        eof(reader.skip(1));
+        reader.advance(0);
+        reader.set_pos(0);
+        assert_eq!(reader.bit_state(), BitState::default());
+        reader.set_bit_state(BitState::default());
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.bits_remaining_in_buffer(), 0);
+        assert_eq!(reader.total_bits_remaining(), 0);
+
+        eof(reader.read_s1().map(drop));
+        eof(reader.read_s2be().map(drop));
+        eof(reader.read_s4be().map(drop));
+        eof(reader.read_s8be().map(drop));
+        eof(reader.read_s2le().map(drop));
+        eof(reader.read_s4le().map(drop));
+        eof(reader.read_s8le().map(drop));
+        eof(reader.read_u1().map(drop));
+        eof(reader.read_u2be().map(drop));
+        eof(reader.read_u4be().map(drop));
+        eof(reader.read_u8be().map(drop));
+        eof(reader.read_u2le().map(drop));
+        eof(reader.read_u4le().map(drop));
+        eof(reader.read_u8le().map(drop));
+        eof(reader.read_f4be().map(drop));
+        eof(reader.read_f8be().map(drop));
+        eof(reader.read_f4le().map(drop));
+        eof(reader.read_f8le().map(drop));
+        eof(reader.read_f2be().map(drop));
+        eof(reader.read_f2le().map(drop));
+        eof(reader.read_u128be().map(drop));
+        eof(reader.read_u128le().map(drop));
+        eof(reader.read_s128be().map(drop));
+        eof(reader.read_s128le().map(drop));
+        eof(reader.read_int::<u16, BigEndian>().map(drop));
+        eof(reader.read_float::<f32, LittleEndian>().map(drop));
+        assert!(reader
+            .read_int_array::<u32, BigEndian>(0)
+            .unwrap()
+            .is_empty());
+        eof(reader.read_int_array::<u32, BigEndian>(1).map(drop));
+        assert!(reader
+            .read_float_array::<f64, LittleEndian>(0)
+            .unwrap()
+            .is_empty());
+        eof(reader.read_f4be_array(1).map(drop));
+        eof(reader.read_f8be_array(1).map(drop));
+        eof(reader.read_f4le_array(1).map(drop));
+        eof(reader.read_f8le_array(1).map(drop));
+        eof(reader.read_mac().map(drop));
+        eof(reader.read_ipv4().map(drop));
+        eof(reader.read_ipv6().map(drop));
+        assert_eq!(reader.read_bits_int_be(0).unwrap(), 0);
+        eof(reader.read_bits_int_be(1).map(drop));
+        eof(reader.read_bits_int_le(1).map(drop));
+
+        assert!(reader.read_bytes(0).unwrap().is_empty());
+        eof(reader.read_bytes(1).map(drop));
+        assert!(reader.read_bytes_full().unwrap().is_empty());
+        reader.read_bytes_into(&mut []).unwrap();
+        eof(reader.read_bytes_into(&mut [0]));
+        assert_eq!(reader.read_bytes_array::<0>().unwrap(), []);
+        eof(reader.read_bytes_array::<2>().map(drop));
+        eof(reader.read_bytes_array_at::<1>(0).map(drop));
+        assert!(reader
+            .read_bytes_term(0, false, true, false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            reader.read_bytes_term(0, false, true, true).unwrap_err(),
+            KError::NoTerminatorFound
+        );
+        assert_eq!(reader.pos(), 0);
+
+        let sub = reader.substream(0);
+        assert_eq!(sub.size(), 0);
+        eof(sub.read_u1().map(drop));
+        let clone = KStream::clone(&reader);
+        assert_eq!(clone.source_id(), reader.source_id());
+        assert_ne!(BytesReader::default().source_id(), reader.source_id());
+        assert_ne!(SourceId::unknown(), SourceId::unknown());
+        assert_ne!(SourceId::unknown(), reader.source_id());
+        assert_eq!(reader.translate_to_root(0), Some(RootOffset { offset: 0 }));
+        assert!(reader.session().is_none());
+        reader.report("nothing to see");
+        let snap = reader.snapshot();
+        reader.restore(&snap).unwrap();
+        let err = reader.enrich_error(KError::eof(1, 0));
+        assert_eq!(err.offset(), Some(0));
+        assert!(err.is_eof());
+    }
+
+    #[test]
+    fn frozen_reader_does_not_disturb_parse() {
+        let data: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let parse = |observe: bool| {
+            let reader = dump_and_open(&data).track_access();
+            reader.seek(16).unwrap();
+            let observer = reader.freeze();
+            let mut values = vec![];
+            for i in 0..512 {
+                values.push(reader.read_u4le().unwrap());
+                if observe {
+                    observer.seek(4095 - i * 7).unwrap();
+                    assert_eq!(observer.read_u1().unwrap(), data[4095 - i * 7]);
+                    assert_eq!(observer.read_bytes_full().unwrap(), data[4096 - i * 7..]);
+                    observer.refresh_size().unwrap();
+                }
+            }
+            (values, reader.access_stats().unwrap())
+        };
+        let (values, stats) = parse(true);
+        assert_eq!(values[0], u32::from_le_bytes([16, 17, 18, 19]));
+        assert_eq!((values, stats), parse(false));
+    }
+
+    #[test]
+    fn frozen_reader_restrictions() {
+        let diagnostics = Rc::new(RefCell::new(vec![]));
+        let session = ParseSession::builder()
+            .diagnostics(diagnostics.clone())
+            .max_alloc(2)
+            .build();
+        let reader = session.reader_from_bytes(vec![1, 2, 3, 4]);
+        reader.read_u1().unwrap();
+        let frozen = reader.freeze().substream(2);
+        assert_eq!(frozen.pos(), 1);
+        frozen.report("ignored");
+        reader.report("kept");
+        assert_eq!(diagnostics.borrow().len(), 1);
+        assert_eq!(
+            reader.freeze().read_bytes_full().unwrap_err().category(),
+            ErrorCategory::Limit
+        );
+        let err = KStream::clone(&frozen).with_inner(|_| ()).unwrap_err();
+        assert_eq!(
+            err,
+            KError::FrozenReader {
+                operation: "with_inner".to_string()
+            }
+        );
+        assert_eq!(err.category(), ErrorCategory::InvalidInput);
+        assert_eq!(frozen.read_bytes(2).unwrap(), [2, 3]);
+        assert_eq!(reader.read_u1().unwrap(), 2);
+        // a frozen reader can track its own accesses
+        let observer = reader.freeze().track_access();
+        observer.read_u1().unwrap();
+        assert_eq!(observer.access_stats().unwrap().reads, 1);
+    }
+
+    #[test]
+    fn read_bytes_term_substream_limit() {
+        let reader = BytesReader::from(b"abcdef\0xyz".to_vec());
+        reader.seek(1).unwrap();
+        let sub = reader.substream(4);
+        assert_eq!(
+            sub.stream_end(),
+            StreamEnd::SubstreamLimit { end: 5, size: 4 }
+        );
+        assert_eq!(reader.stream_end(), StreamEnd::EndOfInput);
+
+        // the limit ends the data unless eos_error is set
+        assert_eq!(sub.read_bytes_term(0, false, true, false).unwrap(), b"bcde");
+        sub.seek(1).unwrap();
+        let err = sub.read_bytes_term(0, false, true, true).unwrap_err();
+        assert_eq!(err, KError::NoTerminatorInSubstream { end: 5, size: 4 });
+        assert_eq!(err.offset(), Some(5));
+        assert_eq!(err.category(), ErrorCategory::Format);
+        assert_eq!(
+            err.to_string(),
+            "terminator not found before end of 4-byte substream at 5"
+        );
+        // unlike the real end of the input
+        reader.seek(7).unwrap();
+        assert_eq!(
+            reader.read_bytes_term(0, false, true, true).unwrap_err(),
+            KError::NoTerminatorFound
+        );
+
+        // a substream reaching up to or past the end of the input ends there
+        reader.seek(7).unwrap();
+        let tail = reader.substream(3);
+        assert_eq!(tail.stream_end(), StreamEnd::EndOfInput);
+        assert_eq!(
+            tail.read_bytes_term(0, false, true, true).unwrap_err(),
+            KError::NoTerminatorFound
+        );
+        // nested substreams report the one that limits
+        reader.seek(0).unwrap();
+        let outer = reader.substream(6);
+        outer.seek(2).unwrap();
+        assert_eq!(
+            outer.substream(10).stream_end(),
+            StreamEnd::SubstreamLimit { end: 6, size: 6 }
+        );
+        assert_eq!(
+            outer.substream(2).stream_end(),
+            StreamEnd::SubstreamLimit { end: 4, size: 2 }
+        );
+        outer.seek(4).unwrap();
+        assert_eq!(outer.read_bytes_full().unwrap(), b"ef");
+        assert_ne!(outer.stream_end(), StreamEnd::EndOfInput);
+    }
+
+    #[test]
+    fn strict_terminators() {
+        let session = ParseSession::builder().strict_terminators(true).build();
+        let reader = session.reader_from_bytes(b"abc\0de".to_vec());
+        let sub = reader.substream(2);
+        assert_eq!(
+            sub.read_bytes_term(0, false, true, false).unwrap_err(),
+            KError::NoTerminatorInSubstream { end: 2, size: 2 }
+        );
+        // the terminator inside the substream is found as before
+        assert_eq!(
+            reader
+                .substream(4)
+                .read_bytes_term(0, false, true, false)
+                .unwrap(),
+            b"abc"
+        );
+        // the end of the input still ends the data
+        reader.seek(4).unwrap();
+        assert_eq!(
+            reader.read_bytes_term(0, false, true, false).unwrap(),
+            b"de"
+        );
+    }
+
+    #[test]
+    fn read_substream_nested() {
+        // chunks of a u4be length followed by the body, nested three deep
+        let chunk = |body: &[u8]| [&(body.len() as u32).to_be_bytes()[..], body].concat();
+        let payload: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let inner = chunk(&[&[0xa5][..], &chunk(&payload)].concat());
+        let data = [&b"hdr"[..], &chunk(&inner), &b"end"[..]].concat();
+        let reader = dump_and_open(&data).track_access();
+
+        reader.seek(3).unwrap();
+        let len = reader.read_u4be().unwrap() as usize;
+        let level1 = reader.read_substream(len).unwrap();
+        assert_eq!(reader.pos(), data.len() - 3);
+        assert_eq!(level1.size(), inner.len());
+        assert_eq!(level1.pos(), 0);
+
+        let len = level1.read_u4be().unwrap() as usize;
+        let level2 = level1.read_substream(len).unwrap();
+        assert!(level1.is_eof());
+        assert_eq!(level2.read_bits_int_be(4).unwrap(), 0xa);
+        level2.align_to_byte().unwrap();
+        let len = level2.read_u4be().unwrap() as usize;
+        let level3 = level2.read_substream(len).unwrap();
+        assert!(level2.is_eof());
+        assert_eq!(level3.size(), payload.len());
+        assert_eq!(level3.translate_to_root(0).unwrap().offset, 16);
+        // nothing of the payload was read so far
+        assert_eq!(reader.access_stats().unwrap().bytes_read, 4 + 4 + 1 + 4);
+
+        // positions and bit state are independent of the parents
+        level3.seek(99_998).unwrap();
+        assert_eq!(level3.read_bytes_full().unwrap(), payload[99_998..]);
+        assert!(level3.is_eof());
+        assert_eq!(
+            level3.stream_end(),
+            StreamEnd::SubstreamLimit {
+                end: 100_000,
+                size: 100_000
+            }
+        );
+        level3.seek(1).unwrap();
+        assert_eq!(level3.read_bits_int_le(12).unwrap(), 0x201);
+        assert_eq!(level1.pos(), level1.size());
+        assert_eq!(reader.read_bytes(3).unwrap(), b"end");
+        assert_eq!(level3.read_u1().unwrap(), 3);
+
+        let err = level2.read_substream(1).unwrap_err();
+        assert_eq!(err, KError::eof(1, 0));
+        // the old style of substream works within a window
+        level3.seek(10).unwrap();
+        let sub = level3.substream(2);
+        assert_eq!(sub.read_bytes_full().unwrap(), [10, 11]);
+    }
+
+    #[test]
+    fn stream_names() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(&bytes).unwrap();
+        let path = f.path().display().to_string();
+        let file = BytesReader::open(f.path()).unwrap();
+        assert_eq!(file.name().as_deref(), Some(path.as_str()));
+        assert_eq!(BytesReader::from(vec![1]).name(), None);
+
+        file.seek(0x20).unwrap();
+        let sub = file.substream(0x40);
+        assert_eq!(
+            sub.name().unwrap().as_ref(),
+            format!("{}[0x20..0x60]", path)
+        );
+        let window = file.read_substream(0x80).unwrap();
+        window.seek(0x10).unwrap();
+        let nested = window.read_substream(8).unwrap();
+        assert_eq!(
+            nested.name().unwrap().as_ref(),
+            format!("{}[0x20..0xa0][0x10..0x18]", path)
+        );
+        // the parent keeps its name
+        assert_eq!(file.name().as_deref(), Some(path.as_str()));
+
+        let io = BytesReader::from(bytes).with_name("archive.bin");
+        io.seek(0x80).unwrap();
+        let base = io.pos();
+        let packed = BytesReader::from(io.read_bytes(0x20).unwrap()).derived_from(&io, base);
+        assert_eq!(packed.name().as_deref(), Some("archive.bin[0x80..0xa0]"));
+        let unpacked = BytesReader::from(vec![0; 4])
+            .derived_from(&io, base)
+            .processed_with("zlib");
+        assert_eq!(
+            unpacked.name().as_deref(),
+            Some("archive.bin[0x80..0x84]/zlib")
+        );
+        // unnamed parents give unnamed children
+        let unnamed = BytesReader::from(vec![0; 4]).processed_with("zlib");
+        assert_eq!(unnamed.name(), None);
+        assert_eq!(unnamed.substream(2).name(), None);
+    }
+
+    #[test]
+    fn stream_name_in_errors() {
+        let io = BytesReader::from(vec![0; 0x40]).with_name("archive.bin");
+        io.seek(0x10).unwrap();
+        let sub = io.read_substream(8).unwrap();
+        sub.read_u4le().unwrap();
+        let e = sub.enrich_error(sub.read_u8le().unwrap_err());
+        match &e {
+            KError::At { stream, .. } => {
+                assert_eq!(stream.as_deref(), Some("archive.bin[0x10..0x18]"))
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(
+            e.to_string(),
+            "unexpected end of stream: requested 8 bytes, 4 available \
+             (at byte 4 of archive.bin[0x10..0x18], offset 20 in root stream)"
+        );
+    }
+
+    // one byte per `read`, interrupted every other call; claims `size` bytes
+    struct Trickle {
+        data: Vec<u8>,
+        size: u64,
+        pos: u64,
+        interrupt: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            match self.data.get(self.pos as usize) {
+                Some(&b) if !buf.is_empty() => {
+                    buf[0] = b;
+                    self.pos += 1;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    impl Seek for Trickle {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(p) => p,
+                SeekFrom::End(d) => self.size.checked_add_signed(d).unwrap(),
+                SeekFrom::Current(d) => self.pos.checked_add_signed(d).unwrap(),
+            };
+            Ok(self.pos)
+        }
+    }
+
+    fn trickle(data: &[u8], size: u64) -> BytesReader {
+        let backend: Box<dyn ReadSeek> = Box::new(Trickle {
+            data: data.to_vec(),
+            size,
+            pos: 0,
+            interrupt: false,
+        });
+        BytesReader::try_from(backend).unwrap()
+    }
+
+    #[test]
+    fn short_reads_are_continued() {
+        let reader = trickle(&[1, 2, 3, 4, 5, 6, 7, 8, 9], 9);
+        assert_eq!(reader.read_u4be().unwrap(), 0x0102_0304);
+        assert_eq!(reader.read_bytes(3).unwrap(), [5, 6, 7]);
+        assert_eq!(reader.pos(), 7);
+        assert_eq!(reader.read_bytes_full().unwrap(), [8, 9]);
+
+        // the source holds fewer bytes than it claims
+        let reader = trickle(&[1, 2, 3], 8);
+        let err = reader.read_u8le().unwrap_err();
+        assert_eq!(err, KError::incomplete(8, 3));
+        assert_eq!(err.category(), ErrorCategory::Eof);
+        assert!(err.is_eof());
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(reader.read_u2be().unwrap(), 0x0102);
+    }
+
+    #[test]
+    fn pascal_strings() {
+        let data: &[&[u8]] = &[
+            b"\x05a\0b\0c",
+            b"\x03\0Hi\0",
+            b"\0\0",
+            b"\x02\0\0\0\xc3\xa9",
+            b"\x09abc",
+        ];
+        let reader = BytesReader::from(data.concat());
+        assert_eq!(reader.read_str_pascal_u1("ASCII").unwrap(), "a\0b\0c");
+        assert_eq!(reader.read_str_pascal_u2le("UTF-8").unwrap(), "Hi\0");
+        assert_eq!(reader.read_str_pascal_u2le("UTF-8").unwrap(), "");
+        assert_eq!(reader.read_str_pascal_u4le("UTF-8").unwrap(), "é");
+        // the prefix promises more than is left
+        assert_eq!(
+            reader.read_str_pascal_u1("ASCII").unwrap_err(),
+            KError::eof(9, 3)
+        );
+
+        let session = ParseSession::builder().max_alloc(4).build();
+        let reader = session.reader_from_bytes(b"\xff\xff\xff\x7f".to_vec());
+        assert_eq!(
+            reader.read_str_pascal_u4le("UTF-8").unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: 0x7fff_ffff,
+                limit: 4
+            }
+        );
+    }
+
+    #[test]
+    fn c_strings() {
+        let reader = BytesReader::from(b"abc\0\0de".to_vec());
+        assert_eq!(reader.read_str_c("ASCII").unwrap(), "abc");
+        assert_eq!(reader.read_str_c("ASCII").unwrap(), "");
+        assert_eq!(reader.pos(), 5);
+        assert_eq!(
+            reader.read_str_c("ASCII").unwrap_err(),
+            KError::NoTerminatorFound
+        );
+    }
+
+    #[test]
+    fn read_bits_array() {
+        let data: Vec<u8> = (0..128u32).map(|i| (i * 37 + 11) as u8).collect();
+        for width in [1, 7, 12, 33] {
+            for le in [false, true] {
+                let single = BytesReader::from(data.clone());
+                let bulk = BytesReader::from(data.clone());
+                let read = |io: &BytesReader, n| {
+                    if le {
+                        io.read_bits_int_le(n)
+                    } else {
+                        io.read_bits_int_be(n)
+                    }
+                };
+                // not byte-aligned at the start
+                read(&single, 3).unwrap();
+                read(&bulk, 3).unwrap();
+
+                let count = 30;
+                let expected: Vec<u64> =
+                    (0..count).map(|_| read(&single, width).unwrap()).collect();
+                let values = if le {
+                    bulk.read_bits_array_le(width, count).unwrap()
+                } else {
+                    bulk.read_bits_array_be(width, count).unwrap()
+                };
+                assert_eq!(values, expected, "width {} le {}", width, le);
+                assert_eq!(bulk.pos(), single.pos());
+                assert_eq!(bulk.bit_state(), single.bit_state());
+                assert_eq!(read(&bulk, 5).unwrap(), read(&single, 5).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn read_bits_array_errors() {
+        let reader = BytesReader::from(vec![0xff; 4]);
+        reader.read_bits_int_be(3).unwrap();
+        let state = reader.bit_state();
+        assert_eq!(
+            reader.read_bits_array_be(10, 3).unwrap_err(),
+            KError::eof_bits(30, 29, 1)
+        );
+        assert_eq!(reader.bit_state(), state);
+        assert_eq!(reader.pos(), 1);
+        assert_eq!(
+            reader.read_bits_array_le(65, 1).unwrap_err(),
+            KError::ReadBitsTooLarge {
+                requested: 65,
+                max: MAX_BITS_READ
+            }
+        );
+        assert_eq!(reader.read_bits_array_be(20, 0).unwrap(), []);
+        let err = reader.read_bits_array_be(0, usize::MAX).unwrap_err();
+        assert_eq!(
+            (err.category(), err.offset()),
+            (ErrorCategory::InvalidInput, Some(1))
+        );
+        assert_eq!(reader.bit_state(), state);
+        assert_eq!(reader.read_bits_array_be(4, 7).unwrap(), [0xf; 7]);
+        assert_eq!(reader.bit_state().bits_left, 1);
+
+        // the values take 8 bytes each, whatever their width
+        let session = ParseSession::builder().max_alloc(64).build();
+        let reader = session.reader_from_bytes(vec![0xff; 4]);
+        assert_eq!(
+            reader.read_bits_array_le(1, 9).unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: 72,
+                limit: 64
+            }
+        );
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(reader.read_bits_array_le(1, 8).unwrap(), [1; 8]);
+    }
+
+    #[test]
+    fn peek() {
+        let reader = BytesReader::from(vec![0x89, b'P', b'N', b'G', 0xab, 0xcd]);
+        assert_eq!(reader.peek_u4le().unwrap(), 0x474e_5089);
+        assert_eq!(reader.peek_u4be().unwrap(), 0x8950_4e47);
+        assert_eq!(reader.peek_bytes(4).unwrap(), b"\x89PNG");
+        assert_eq!(reader.pos(), 0);
+
+        // a bit read in progress is not disturbed
+        assert_eq!(reader.read_bits_int_be(12).unwrap(), 0x895);
+        let state = reader.bit_state();
+        assert_eq!(reader.peek_u4le().unwrap(), 0xcdab_474e);
+        assert_eq!(reader.peek_u2be().unwrap(), 0x4e47);
+        assert_eq!(reader.peek_u1().unwrap(), 0x4e);
+        assert_eq!(reader.bit_state(), state);
+        assert_eq!(reader.read_bits_int_be(12).unwrap(), 0x04e);
+
+        assert_eq!(reader.peek_u2le().unwrap(), 0xab47);
+        assert_eq!(reader.peek_u4be().unwrap_err(), KError::eof(4, 3));
+        assert_eq!(reader.peek_bytes(3).unwrap(), [0x47, 0xab, 0xcd]);
+        assert_eq!(reader.pos(), 3);
+        assert_eq!(reader.bit_state(), BitState::default());
+    }
+
+    #[test]
+    fn scan_for() {
+        let mut data = vec![0; 3 * SCAN_CHUNK];
+        // straddles the first two chunks
+        data[SCAN_CHUNK - 2..SCAN_CHUNK + 2].copy_from_slice(b"SYNC");
+        data[2 * SCAN_CHUNK + 100..2 * SCAN_CHUNK + 104].copy_from_slice(b"SYNC");
+        let reader = BytesReader::from(data);
+        reader.read_bits_int_be(3).unwrap();
+        let state = reader.bit_state();
+
+        assert_eq!(
+            reader.scan_for(b"SYNC", None).unwrap(),
+            Some(SCAN_CHUNK - 2)
+        );
+        assert_eq!(reader.pos(), 1);
+        assert_eq!(reader.bit_state(), state);
+
+        // the match must start within the bound
+        assert_eq!(
+            reader.scan_for(b"SYNC", Some(SCAN_CHUNK - 4)).unwrap(),
+            None
+        );
+        assert_eq!(
+            reader.scan_for(b"SYNC", Some(SCAN_CHUNK - 3)).unwrap(),
+            Some(SCAN_CHUNK - 2)
+        );
+        assert_eq!(reader.scan_for(b"SYNX", None).unwrap(), None);
+
+        // at the current position
+        reader.seek(SCAN_CHUNK - 2).unwrap();
+        assert_eq!(
+            reader.scan_for(b"SYNC", Some(0)).unwrap(),
+            Some(SCAN_CHUNK - 2)
+        );
+        reader.skip(1).unwrap();
+        assert_eq!(
+            reader.scan_for(b"SYNC", None).unwrap(),
+            Some(2 * SCAN_CHUNK + 100)
+        );
+        assert_eq!(reader.scan_for(b"", None).unwrap(), Some(SCAN_CHUNK - 1));
+
+        // small chunks under an allocation limit
+        let session = ParseSession::builder().max_alloc(16).build();
+        let reader = session.reader_from_bytes(b"..............abc..xyz".to_vec());
+        assert_eq!(reader.scan_for(b"xyz", None).unwrap(), Some(19));
+        assert_eq!(reader.scan_for(b"abc", Some(13)).unwrap(), None);
+        assert_eq!(reader.scan_for(b"abc", Some(14)).unwrap(), Some(14));
+    }
+
+    #[test]
+    fn size_guards() {
+        assert_eq!(checked_sub_len(20, 8).unwrap(), 12);
+        assert_eq!(checked_sub_len(8, 8).unwrap(), 0);
+        let err = checked_sub_len(4, 8).unwrap_err();
+        assert_eq!(
+            err,
+            KError::SizeUnderflow {
+                minuend: 4,
+                subtrahend: 8
+            }
+        );
+        assert_eq!(err.to_string(), "size 4 - 8 is negative: 8 exceeds 4");
+        assert_eq!(err.category(), ErrorCategory::Format);
+
+        let reader = BytesReader::from(vec![0; 10]);
+        reader.skip(4).unwrap();
+        let sub = reader.try_substream(6).unwrap();
+        assert_eq!(sub.size(), 10);
+        assert_eq!(reader.pos(), 4);
+        // an oversized substream fails like a read of its size
+        let err = reader.try_substream(7).unwrap_err();
+        assert_eq!(err, KError::eof(7, 6));
+        assert_eq!(
+            err.to_string(),
+            "unexpected end of stream: requested 7 bytes, 6 available"
+        );
+        assert_eq!(reader.read_bytes(7).unwrap_err(), err);
+        assert_eq!(reader.skip(7).unwrap_err(), err);
+        assert_eq!(reader.read_substream(7).unwrap_err(), err);
+        // checked against the parent's limit, not the whole input
+        let inner = reader.substream(3);
+        assert_eq!(inner.try_substream(4).unwrap_err(), KError::eof(4, 3));
+    }
+
+    // can't tell its size
+    struct Unsized(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Unsized {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for Unsized {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            match pos {
+                SeekFrom::End(_) => Err(std::io::Error::other("no size")),
+                pos => self.0.seek(pos),
+            }
+        }
+    }
+
+    #[test]
+    fn from_io() {
+        let mut cursor = std::io::Cursor::new(vec![1, 2, 3, 4, 5]);
+        cursor.set_position(3);
+        let reader = BytesReader::from_io(cursor).unwrap();
+        assert_eq!(reader.size(), 5);
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(reader.read_u2be().unwrap(), 0x0102);
+        reader.seek(4).unwrap();
+        assert_eq!(reader.read_bytes_full().unwrap(), [5]);
+
+        let err = BytesReader::from_io(Unsized(std::io::Cursor::new(vec![1]))).unwrap_err();
+        assert_eq!(err, KError::io("no size"));
+        assert_eq!(err.category(), ErrorCategory::Io);
+
+        // a custom source reading one byte per call
+        let reader = BytesReader::from_io(Trickle {
+            data: vec![9, 8, 7],
+            size: 3,
+            pos: 0,
+            interrupt: false,
+        })
+        .unwrap();
+        assert_eq!(reader.read_bytes(3).unwrap(), [9, 8, 7]);
+    }
+
+    // the same 4 bytes as a pair of u2be
+    #[derive(Default, Debug)]
+    struct Pair {
+        a: RefCell<u16>,
+        b: RefCell<u16>,
+    }
+
+    impl KStruct for Pair {
+        type Root = Pair;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            *self_rc.a.borrow_mut() = _io.read_u2be()?;
+            *self_rc.b.borrow_mut() = _io.read_u2be()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reparse_region_as() {
+        let reader = BytesReader::from_hex("ff 0200 aabb 6100 f0 ee").unwrap();
+        reader.read_u1().unwrap();
+        reader.read_bits_int_be(3).unwrap();
+        let (pos, bits) = (reader.pos(), reader.bit_state());
+
+        let record: OptRc<Record> = reader.reparse_region_as(1, 7, None, None).unwrap();
+        assert_eq!(*record.payload.borrow(), [0xaa, 0xbb]);
+        assert_eq!(*record.name.borrow(), b"a");
+        assert_eq!(*record.flags.borrow(), 0xf);
+        // offsets within the region
+        assert_eq!(*record.start.borrow(), 0);
+
+        let pair: OptRc<Pair> = reader.reparse_region_as(1, 4, None, None).unwrap();
+        assert_eq!((*pair.a.borrow(), *pair.b.borrow()), (0x0200, 0xaabb));
+        assert_eq!((reader.pos(), reader.bit_state()), (pos, bits));
+        assert_eq!(reader.read_u1().unwrap(), 0x00);
+
+        // bounded by the region, not the stream
+        let err = reader
+            .reparse_region_as::<Pair>(1, 3, None, None)
+            .unwrap_err();
+        assert!(err.is_eof());
+        let err = reader
+            .reparse_region_as::<Pair>(6, 4, None, None)
+            .unwrap_err();
+        assert_eq!(err, KError::eof(4, 3));
+    }
+
+    #[test]
+    fn error_chain() {
+        let inner = BytesReader::from(vec![0; 6]).with_name("a.bin");
+        inner.seek(5).unwrap();
+        let eof = inner.read_u2le().unwrap_err();
+        let err = KError::at(
+            9,
+            Some(RootOffset { offset: 9 }),
+            None,
+            KError::RepeatUntilEof {
+                parsed: 2,
+                source: Box::new(inner.enrich_error(eof.clone())),
+            },
+        );
+        assert_eq!(err.root_cause(), &eof);
+        assert_eq!(eof.root_cause(), &eof);
+        assert_eq!(err.chain().count(), 4);
+        assert!(matches!(
+            err.chain().nth(1),
+            Some(KError::RepeatUntilEof { parsed: 2, .. })
+        ));
+
+        assert_eq!(
+            err.to_string(),
+            "unexpected end of stream: requested 2 bytes, 1 available \
+             (at byte 5 of a.bin, offset 5 in root stream)"
+        );
+        assert_eq!(
+            format!("{:#}", err),
+            "at byte 9, offset 9 in root stream\n\
+             caused by: repeat-until condition not met after 2 elements\n\
+             caused by: at byte 5 of a.bin, offset 5 in root stream\n\
+             caused by: unexpected end of stream: requested 2 bytes, 1 available"
+        );
+        // unwrapped errors look the same either way
+        assert_eq!(format!("{:#}", eof), eof.to_string());
+    }
+
+    #[test]
+    fn seek_relative() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4, 5]);
+        reader.seek_relative(0).unwrap();
+        assert_eq!(reader.seek_relative(-1).unwrap_err(), KError::eof(1, 0));
+        reader.seek_relative(3).unwrap();
+        assert_eq!(reader.read_u1().unwrap(), 4);
+        reader.seek_relative(-4).unwrap();
+        assert_eq!(reader.pos(), 0);
+
+        // up to the end, but not past it
+        reader.seek_relative(5).unwrap();
+        assert!(reader.is_eof());
+        assert_eq!(reader.seek_relative(1).unwrap_err(), KError::eof(1, 0));
+        assert_eq!(
+            reader.seek_relative(i64::MIN).unwrap_err(),
+            KError::eof(
+                usize::try_from(i64::MIN.unsigned_abs()).unwrap_or(usize::MAX),
+                5
+            )
+        );
+        assert_eq!(reader.pos(), 5);
+
+        // the bit state is kept, as with `seek`
+        reader.seek(1).unwrap();
+        reader.read_bits_int_be(3).unwrap();
+        let bits = reader.bit_state();
+        reader.seek_relative(-2).unwrap();
+        assert_eq!(reader.bit_state(), bits);
+        assert_eq!(reader.pos(), 0);
+    }
+
+    #[test]
+    fn pos_guard() {
+        let reader = BytesReader::from(vec![0xa5, 2, 3, 4, 5]);
+        reader.read_bits_int_be(3).unwrap();
+        let bits = reader.bit_state();
+
+        // an instance at offset 3 whose read fails
+        let instance = |io: &BytesReader| -> KResult<u32> {
+            let _guard = io.save_pos();
+            io.seek(3)?;
+            io.read_u4le()
+        };
+        assert!(instance(&reader).unwrap_err().is_eof());
+        assert_eq!((reader.pos(), reader.bit_state()), (1, bits));
+
+        {
+            let guard = reader.save_pos();
+            assert_eq!(guard.saved_pos(), Some(1));
+            reader.seek(3).unwrap();
+            assert_eq!(reader.read_u2be().unwrap(), 0x0405);
+        }
+        assert_eq!((reader.pos(), reader.bit_state()), (1, bits));
+
+        let guard = reader.save_pos();
+        reader.seek(4).unwrap();
+        guard.commit();
+        assert_eq!(reader.pos(), 4);
+
+        // through a trait object
+        let io: &dyn KStream = &reader;
+        drop(PosGuard::new(io));
+        let guard = PosGuard::new(io);
+        io.seek(0).unwrap();
+        drop(guard);
+        assert_eq!(io.pos(), 4);
+    }
+
+    #[test]
+    fn read_bytes_term_multi() {
+        let reader = BytesReader::from(b"\r\nab\r\ncd\r".to_vec());
+        // terminator at position 0
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, false, true)
+                .unwrap(),
+            b""
+        );
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", true, true, true)
+                .unwrap(),
+            b"\r\n"
+        );
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, true, true)
+                .unwrap(),
+            b"ab"
+        );
+        assert_eq!(reader.pos(), 6);
+        // the terminator is cut off by the end of the stream
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, true, true)
+                .unwrap_err(),
+            KError::NoTerminatorFound
+        );
+        reader.seek(6).unwrap();
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", true, true, false)
+                .unwrap(),
+            b"cd\r"
+        );
+        assert_eq!(reader.pos(), 9);
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, true, false)
+                .unwrap(),
+            b""
+        );
+
+        // at odd offsets and overlapping partial matches
+        let reader = BytesReader::from(b"abc\r\nd".to_vec());
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, true, true)
+                .unwrap(),
+            b"abc"
+        );
+        assert_eq!(reader.pos(), 5);
+        let reader = BytesReader::from(vec![0xaa, 0xaa, 0xab, 1]);
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(&[0xaa, 0xab], false, false, true)
+                .unwrap(),
+            [0xaa]
+        );
+        assert_eq!(reader.pos(), 1);
+        let reader = BytesReader::from(vec![0, 1, 0, 1, 0, 1, 1, 9]);
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(&[0, 1, 0, 1, 1], true, true, true)
+                .unwrap(),
+            [0, 1, 0, 1, 0, 1, 1]
+        );
+        assert_eq!(reader.pos(), 7);
+        // a partial match at the end is data
+        let reader = BytesReader::from(vec![0x41, 0]);
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(&[0, 0], false, true, false)
+                .unwrap(),
+            [0x41, 0]
+        );
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(&[], false, true, true)
+                .unwrap(),
+            []
+        );
+
+        // substream limits and allocation limits apply as for single bytes
+        let session = ParseSession::builder().max_alloc(4).build();
+        let reader = session.reader_from_bytes(b"abcd\r\nabcdef\r\n".to_vec());
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, true, true)
+                .unwrap(),
+            b"abcd"
+        );
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"\r\n", false, true, true)
+                .unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: 6,
+                limit: 4
+            }
+        );
+        assert_eq!(reader.pos(), 6);
+        let reader = BytesReader::from(b"ab\r\n".to_vec());
+        let err = reader
+            .substream(3)
+            .read_bytes_term_multi(b"\r\n", false, true, true)
+            .unwrap_err();
+        assert_eq!(
+            (err.category(), err.offset()),
+            (ErrorCategory::Format, Some(3))
+        );
+    }
+
+    #[test]
+    fn constant_time_eq() {
+        assert!(bytes_eq_constant_time(b"", b""));
+        assert!(bytes_eq_constant_time(b"tag", b"tag"));
+        assert!(!bytes_eq_constant_time(b"tag", b"tab"));
+        assert!(!bytes_eq_constant_time(b"\x00tag", b"\x01tag"));
+        assert!(!bytes_eq_constant_time(b"tag", b"tags"));
+        assert!(!bytes_eq_constant_time(b"", b"\x00"));
+
+        let reader = BytesReader::from(b"\x8a\x3f\x11\x02".to_vec());
+        let tag = reader.read_bytes(4).unwrap();
+        for redact in [true, false] {
+            assert_eq!(
+                ensure_fixed_contents_ct(&tag, b"\x8a\x3f\x11\x02", "/seq/3", redact),
+                Ok(())
+            );
+            assert!(ensure_fixed_contents_ct(&tag, b"", "/seq/3", redact).is_err());
+        }
+        let err = ensure_fixed_contents_ct(&tag, b"\x8a\x3f\x11\x03", "/seq/3", true).unwrap_err();
+        assert_eq!(
+            err,
+            KError::validation_failed(ValidationKind::NotEqual, "/seq/3")
+        );
+        // the data does not show up in the error
+        let shown = format!("{} {:?}", err, err);
+        assert!(!shown.contains("8a") && !shown.contains("138"), "{}", shown);
+
+        // unless asked for
+        let err = ensure_fixed_contents_ct(&tag, b"\x8a\x3f\x11\x03", "/seq/3", false).unwrap_err();
+        assert_eq!(
+            err,
+            KError::validation_failed(
+                ValidationKind::ContentsNotEqual {
+                    actual: tag.clone()
+                },
+                "/seq/3"
+            )
+        );
+        assert_eq!(err.category(), ErrorCategory::Validation);
+        assert_eq!(
+            err.to_string(),
+            "validation failed (NotEqual) at /seq/3: found [8a 3f 11 02]"
+        );
+    }
+
+    #[test]
+    fn bytes_terminate_bounds() {
+        for include in [false, true] {
+            assert_eq!(bytes_terminate(&[], 0, include), []);
+            assert_eq!(bytes_terminate(&[1, 2, 3], 0, include), [1, 2, 3]);
+        }
+        assert_eq!(bytes_terminate(&[0, 1, 0], 0, false), []);
+        assert_eq!(bytes_terminate(&[0, 1, 0], 0, true), [0]);
+        assert_eq!(bytes_terminate(&[1, 2, 0], 0, false), [1, 2]);
+        assert_eq!(bytes_terminate(&[1, 2, 0], 0, true), [1, 2, 0]);
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        reader.read_u2be().unwrap();
+        reader.read_u1().unwrap();
+        assert_eq!(reader.expect_fully_consumed(), Ok(()));
+        assert_eq!(reader.take_trailing().unwrap(), []);
+
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        reader.read_u2be().unwrap();
+        let err = reader.expect_fully_consumed().unwrap_err();
+        assert_eq!(err, KError::trailing_bytes(2, 1, vec![3]));
+        assert_eq!(err.to_string(), "1 trailing bytes at offset 2: 03");
+        assert_eq!(err.category(), ErrorCategory::Format);
+        assert_eq!(err.offset(), Some(2));
+        assert_eq!(reader.pos(), 2);
+        assert_eq!(reader.take_trailing().unwrap(), [3]);
+        assert_eq!(reader.expect_fully_consumed(), Ok(()));
+
+        let reader = BytesReader::from(vec![0xee; 100]);
+        let err = reader.expect_fully_consumed().unwrap_err();
+        assert!(matches!(
+            &err,
+            KError::TrailingBytes { len: 100, preview, .. } if preview.len() == TRAILING_PREVIEW
+        ));
+        assert!(err.to_string().ends_with("ee ee …"), "{}", err);
+    }
+
+    #[test]
+    fn bytes_terminate_multi_bounds() {
+        for include in [false, true] {
+            assert_eq!(bytes_terminate_multi(&[], &[0, 0], include), []);
+            assert_eq!(
+                bytes_terminate_multi(&[1, 0, 3], &[0, 0], include),
+                [1, 0, 3]
+            );
+            assert_eq!(bytes_terminate_multi(&[0], &[0, 0, 0], include), [0]);
+            assert_eq!(bytes_terminate_multi(&[1, 2], &[], include), []);
+        }
+        assert_eq!(bytes_terminate_multi(&[0, 0, 1, 0, 0], &[0, 0], false), []);
+        assert_eq!(
+            bytes_terminate_multi(&[0, 0, 1, 0, 0], &[0, 0], true),
+            [0, 0]
+        );
+        assert_eq!(bytes_terminate_multi(b"ab\r\n", b"\r\n", false), b"ab");
+        assert_eq!(bytes_terminate_multi(b"ab\r\n", b"\r\n", true), b"ab\r\n");
+        // at odd offsets and overlapping partial matches
+        assert_eq!(bytes_terminate_multi(b"abc\r\nd", b"\r\n", false), b"abc");
+        assert_eq!(
+            bytes_terminate_multi(b"abc\r\nd", b"\r\n", true),
+            b"abc\r\n"
+        );
+        assert_eq!(
+            bytes_terminate_multi(&[0xaa, 0xaa, 0xab, 1], &[0xaa, 0xab], true),
+            [0xaa, 0xaa, 0xab]
+        );
+        assert_eq!(
+            bytes_terminate_multi(&[0xaa, 0xaa, 0xab, 1], &[0xaa, 0xab], false),
+            [0xaa]
+        );
+        // the same result as reading the bytes
+        let data = [0x41, 0, 0, 1, 0, 0];
+        let reader = BytesReader::from(data.to_vec());
+        assert_eq!(
+            bytes_terminate_multi(&data, &[0, 0], false),
+            reader
+                .read_bytes_term_multi(&[0, 0], false, true, true)
+                .unwrap()
+        );
+        // single bytes as with bytes_terminate
+        assert_eq!(
+            bytes_terminate_multi(&[1, 2, 0, 3], &[0], true),
+            bytes_terminate(&[1, 2, 0, 3], 0, true)
+        );
+    }
+
+    #[test]
+    fn take_read() {
+        let reader = BytesReader::from((0..20).collect::<Vec<u8>>());
+        reader.read_u2be().unwrap();
+
+        // the consumer reads half of the field
+        let mut field = reader.take_read(8);
+        let mut half = [0; 4];
+        field.read_exact(&mut half).unwrap();
+        assert_eq!(half, [2, 3, 4, 5]);
+        assert_eq!(reader.pos(), 6);
+        assert_eq!(field.remaining(), 4);
+        field.skip_remaining().unwrap();
+        assert_eq!(reader.pos(), 10);
+
+        // and all of it
+        let mut field = reader.take_read(4);
+        let mut all = vec![];
+        assert_eq!(field.read_to_end(&mut all).unwrap(), 4);
+        assert_eq!(all, [10, 11, 12, 13]);
+        assert_eq!(field.read(&mut [0; 4]).unwrap(), 0);
+        field.skip_remaining().unwrap();
+        assert_eq!(reader.pos(), 14);
+        assert_eq!(reader.read_u1().unwrap(), 14);
+
+        // a limit past the end stops there
+        let mut field = reader.take_read(100);
+        let mut rest = vec![];
+        assert_eq!(field.read_to_end(&mut rest).unwrap(), 5);
+        assert!(field.skip_remaining().unwrap_err().is_eof());
+        assert_eq!(reader.pos(), 20);
+    }
+
+    #[test]
+    fn read_bytes_term_chunked() {
+        let mut data = vec![];
+        let lens = [0, 1, TERM_CHUNK - 1, TERM_CHUNK, 700, 3];
+        for (i, &len) in lens.iter().enumerate() {
+            data.extend(std::iter::repeat_n(b'a' + i as u8, len));
+            data.push(0);
+        }
+        data.extend_from_slice(b"tail");
+        let reader = dump_and_open(&data).track_access();
+        for (i, &len) in lens.iter().enumerate() {
+            let start = reader.pos();
+            let s = reader.read_bytes_term(0, false, false, true).unwrap();
+            assert_eq!(s, vec![b'a' + i as u8; len]);
+            assert_eq!(reader.pos(), start + len);
+            assert_eq!(reader.read_bytes_term(0, true, true, true).unwrap(), [0]);
+        }
+        assert_eq!(
+            reader.read_bytes_term(0, false, true, false).unwrap(),
+            b"tail"
+        );
+        assert!(reader.is_eof());
+        // a few reads per string rather than one per byte
+        assert!(reader.access_stats().unwrap().reads < 30);
+    }
+
+    #[test]
+    fn read_bytes_term_alloc_limit() {
+        // the limit is passed in the second chunk
+        let mut data = vec![0xf0];
+        data.extend(vec![b'a'; TERM_CHUNK + 10]);
+        data.push(0);
+        let session = ParseSession::builder().max_alloc(TERM_CHUNK + 5).build();
+        let reader = session.reader_from_bytes(data);
+        reader.read_bits_int_be(4).unwrap();
+        let state = reader.bit_state();
+        assert_eq!(
+            reader.read_bytes_term(0, false, true, true).unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: TERM_CHUNK + 10,
+                limit: TERM_CHUNK + 5
+            }
+        );
+        // back where the read started
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
+        assert_eq!(
+            reader
+                .read_bytes_term_multi(b"a\0", false, true, true)
+                .unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: TERM_CHUNK + 9,
+                limit: TERM_CHUNK + 5
+            }
+        );
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
+        assert_eq!(reader.read_u1().unwrap(), b'a');
     }
-    res
-}
 
-pub fn process_rotate_left(bytes: &Vec<u8>, amount: u8) -> Vec<u8> {
-    let mut res = bytes.to_vec();
-    for i in &mut res {
-        *i = i.rotate_left(amount.into());
+    #[test]
+    fn read_bytes_term_multi_repetitive() {
+        // quadratic for a naive search, as in `search::tests::repetitive`
+        let mut term = vec![b'a'; 1 << 12];
+        term.push(b'b');
+        let mut data = vec![b'a'; 1 << 16];
+        data.extend_from_slice(&term);
+        data.push(1);
+
+        assert_eq!(bytes_terminate_multi(&data, &term, false), [b'a'; 1 << 16]);
+        let reader = BytesReader::from(data);
+        let s = reader
+            .read_bytes_term_multi(&term, true, true, true)
+            .unwrap();
+        assert_eq!(s.len(), (1 << 16) + term.len());
+        assert_eq!(reader.read_u1().unwrap(), 1);
     }
-    res
-}
 
-pub fn process_zlib(bytes: &Vec<u8>) -> Result<Vec<u8>, String> {
-    let mut dec = ZlibDecoder::new(bytes.as_slice());
-    let mut dec_bytes = Vec::new();
-    dec.read_to_end(&mut dec_bytes).map_err(|e| e.to_string())?;
-    Ok(dec_bytes)
-}
+    #[test]
+    fn strip_and_terminate() {
+        // the fields of the `str_pad_term` test format, 20 bytes each
+        let check = |bytes: &[u8], pad, term, include, want: &[u8]| {
+            assert_eq!(
+                bytes_strip_and_terminate(bytes, pad, term, include),
+                want,
+                "{:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        };
+        check(b"str1@@@@@@@@@@@@@@@@", Some(b'@'), None, false, b"str1");
+        check(b"str2foo@++++++++++++", None, Some(b'@'), false, b"str2foo");
+        check(
+            b"str+++3bar+++@++++++",
+            Some(b'+'),
+            Some(b'@'),
+            false,
+            b"str+++3bar+++",
+        );
+        check(b"str4baz@++++++++++++", None, Some(b'@'), true, b"str4baz@");
 
-pub fn reverse_string<S: AsRef<str>>(s: S) -> KResult<String> {
-    Ok(s.as_ref().graphemes(true).rev().collect())
-}
+        // no terminator in the data: the padding is stripped
+        assert_eq!(
+            bytes_strip_and_terminate(b"ab\0\0", Some(0), Some(b'@'), true),
+            b"ab"
+        );
+        // a terminator that is also the padding
+        assert_eq!(
+            bytes_strip_and_terminate(b"a\0b\0\0", Some(0), Some(0), false),
+            b"a"
+        );
+        assert_eq!(
+            bytes_strip_and_terminate(b"ab@@", None, None, false),
+            b"ab@@"
+        );
+        assert_eq!(bytes_strip_and_terminate(b"", Some(0), Some(0), true), b"");
+    }
+    // claims a terabyte, serves zeros
+    struct Huge(u64);
 
-pub fn modulo(a: i64, b: i64) -> i64 {
-    a.rem_euclid(b)
-}
+    impl Read for Huge {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            buf.fill(0);
+            Ok(buf.len())
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::tempdir;
+    impl Seek for Huge {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0 = match pos {
+                SeekFrom::End(_) => 1 << 40,
+                SeekFrom::Start(p) => p,
+                SeekFrom::Current(_) => self.0,
+            };
+            Ok(self.0)
+        }
+    }
 
     #[test]
-    fn basic_strip_right() {
-        let b = vec![1, 2, 3, 4, 5, 5, 5, 5];
-        let c = bytes_strip_right(&b, 5);
+    fn reader_alloc_limit() {
+        // allocating what the bogus lengths ask for would abort the test
+        let reader = BytesReader::from_io(Huge(0)).unwrap().with_max_alloc(1024);
+        assert_eq!(reader.size(), 1 << 40);
+        let limit_error = KError::AllocationLimitExceeded {
+            requested: 0xffff_ffff,
+            limit: 1024,
+        };
+        assert_eq!(reader.read_bytes(0xffff_ffff).unwrap_err(), limit_error);
+        assert!(matches!(
+            reader.read_bytes_full(),
+            Err(KError::AllocationLimitExceeded { limit: 1024, .. })
+        ));
+        let err = reader
+            .substream(1 << 20)
+            .read_bytes_term(1, false, true, false)
+            .unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::Limit);
+        assert_eq!(reader.read_bytes(1024).unwrap().len(), 1024);
+        assert_eq!(KStream::clone(&reader).max_alloc(), Some(1024));
 
-        assert_eq!([1, 2, 3, 4], c[..]);
+        // the lower of the reader and session limits applies
+        let session = ParseSession::builder().max_alloc(16).build();
+        let reader = BytesReader::from(vec![0; 64]).with_max_alloc(32);
+        assert_eq!(reader.max_alloc(), Some(32));
+        let reader = reader.with_session(&session);
+        assert_eq!(reader.max_alloc(), Some(16));
+        assert!(reader.read_bytes(17).is_err());
+        let reader = session.reader_from_bytes(vec![0; 64]).with_max_alloc(8);
+        assert_eq!(reader.max_alloc(), Some(8));
+        assert_eq!(BytesReader::from(vec![0; 64]).max_alloc(), None);
     }
 
     #[test]
-    fn basic_read_bytes() {
-        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
-        let reader = BytesReader::from(b);
+    fn ascii_case() {
+        assert!(matches!(ascii_lower("riff"), Cow::Borrowed("riff")));
+        assert!(matches!(ascii_upper("RIFF 1.0"), Cow::Borrowed("RIFF 1.0")));
+        assert_eq!(ascii_lower("RiFf"), "riff");
+        assert_eq!(ascii_upper("RiFf"), "RIFF");
+        // non-ASCII letters are left as they are
+        assert_eq!(ascii_lower("ÄÖ-Ab"), "ÄÖ-ab");
+        assert_eq!(ascii_upper("straße"), "STRAßE");
+        assert!(matches!(ascii_upper("ÄÖ"), Cow::Borrowed(_)));
 
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
-        assert_eq!(reader.read_bytes(3).unwrap()[..], [5, 6, 7]);
+        assert!(eq_ignore_ascii_case_bytes(b"RIFF", b"riff"));
+        assert!(eq_ignore_ascii_case_bytes(b"", b""));
+        assert!(!eq_ignore_ascii_case_bytes(b"RIFF", b"rif"));
+        assert!(!eq_ignore_ascii_case_bytes(b"\xc4", b"\xe4"));
+        assert!(!eq_ignore_ascii_case_bytes(b"@", b"`"));
+
+        let reader = BytesReader::from(b"rIfF".to_vec());
+        let magic = reader.read_bytes(4).unwrap();
         assert_eq!(
-            reader.read_bytes(4).unwrap_err(),
-            KError::Eof {
-                requested: 4,
-                available: 1
-            }
+            ensure_fixed_contents(&magic, b"RIFF", true, "/seq/0"),
+            Ok(())
         );
-        assert_eq!(reader.read_bytes(1).unwrap()[..], [8]);
+        assert_eq!(
+            ensure_fixed_contents(&magic, b"RIFF", false, "/seq/0"),
+            Err(KError::validation_failed(
+                ValidationKind::NotEqual,
+                "/seq/0"
+            ))
+        );
+        assert_eq!(
+            ensure_fixed_contents(&magic, b"rIfF", false, "/seq/0"),
+            Ok(())
+        );
+        assert!(ensure_fixed_contents(&magic, b"RIFX", true, "/seq/0").is_err());
     }
 
     #[test]
-    fn read_bits_single() {
-        let b = vec![0x80];
-        let reader = BytesReader::from(b);
+    fn refresh_size_open_and_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("growing.log");
+        std::fs::write(&path, b"head").unwrap();
+        let reader = BytesReader::open(&path).unwrap().auto_refresh(true);
+        assert_eq!(reader.read_bytes(4).unwrap(), b"head");
+        assert!(reader.is_eof());
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        log.write_all(b"more").unwrap();
+        assert_eq!(reader.read_bytes(4).unwrap(), b"more");
+        assert_eq!(reader.size(), 8);
 
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+        // nothing changes for memory-backed readers
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        reader.seek(2).unwrap();
+        assert_eq!(reader.refresh_size().unwrap(), 3);
+        assert_eq!((reader.size(), reader.pos()), (3, 2));
+        let reader = reader.auto_refresh(true);
+        assert_eq!(reader.read_bytes(2).unwrap_err(), KError::eof(2, 1));
+        assert_eq!(reader.read_u1().unwrap(), 3);
     }
 
     #[test]
-    fn read_bits_multiple() {
-        // 0xA0
-        let b = vec![0b10100000];
-        let reader = BytesReader::from(b);
+    fn seek_past_end() {
+        for reader in [
+            BytesReader::from(vec![1, 2, 3, 4]),
+            dump_and_open(&[1, 2, 3, 4]),
+        ] {
+            reader.seek(4).unwrap();
+            assert!(reader.is_eof());
+            assert_eq!(reader.read_bytes(0).unwrap(), []);
+            assert_eq!(reader.read_bytes_full().unwrap(), []);
 
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 0);
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+            reader.seek(10).unwrap();
+            assert_eq!(reader.pos(), 10);
+            assert!(reader.is_eof());
+            assert_eq!(reader.read_bytes(2).unwrap_err(), KError::eof(2, 0));
+            assert!(reader.read_u1().unwrap_err().is_eof());
+            assert_eq!(reader.pos(), 10);
+
+            reader.seek(3).unwrap();
+            assert_eq!(reader.read_u1().unwrap(), 4);
+        }
     }
 
     #[test]
-    fn read_bits_large() {
-        let b = vec![0b10100000];
-        let reader = BytesReader::from(b);
+    fn read_at_checked() {
+        // a directory entry points at a record, which points at its data
+        // within the data area 8..14
+        let data = vec![4, 2, 0, 0, 10, 3, 0, 0, 0xff, 0xff, 0xab, 0xcd, 0xef, 0xff];
+        let reader = BytesReader::from(data);
+        reader.read_bits_int_be(3).unwrap();
+        let area = Some((8, 14));
+        let dir = reader.read_at_checked(0, 2, None).unwrap();
+        let rec = reader
+            .read_at_checked(dir[0].into(), dir[1].into(), None)
+            .unwrap();
+        let blob = reader
+            .read_at_checked(rec[0].into(), rec[1].into(), area)
+            .unwrap();
+        assert_eq!(blob, [0xab, 0xcd, 0xef]);
+        assert_eq!(reader.pos(), 1);
+        assert_eq!(reader.read_bits_int_be(5).unwrap(), 0b00100);
 
-        assert_eq!(reader.read_bits_int_be(3).unwrap(), 5);
+        // an offset before or after the region
+        let err = reader.read_at_checked(4, 1, area).unwrap_err();
+        assert_eq!(
+            err,
+            KError::PointerOutOfBounds {
+                offset: 4,
+                len: 1,
+                start: 8,
+                end: 14
+            }
+        );
+        assert_eq!(err.category(), ErrorCategory::Format);
+        assert_eq!(err.offset(), Some(4));
+        assert!(reader.read_at_checked(20, 0, None).is_err());
+        // a length running past the end of the region or the stream
+        assert!(reader.read_at_checked(12, 3, area).is_err());
+        let err = reader.read_at_checked(12, 3, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "3 bytes at offset 12 are outside of the region 0..14"
+        );
+        assert!(reader.read_at_checked(u64::MAX, 2, None).is_err());
+        assert_eq!(reader.read_at_checked(14, 0, None).unwrap(), []);
+        assert_eq!(reader.pos(), 1);
     }
 
     #[test]
-    fn read_bits_span() {
-        let b = vec![0x01, 0x80];
-        let reader = BytesReader::from(b);
+    fn remaining() {
+        let reader = BytesReader::from(vec![1, 2, 3, 4, 5]);
+        assert_eq!(reader.remaining(), 5);
+        reader.ensure_remaining(5).unwrap();
+        let err = reader.ensure_remaining(6).unwrap_err();
+        assert_eq!(err, KError::incomplete(6, 5));
+        assert!(err.is_eof());
+        assert!(err.to_string().ends_with("1 short"), "{}", err);
 
-        assert_eq!(reader.read_bits_int_be(9).unwrap(), 3);
+        // the byte of pending bits counts until they are all read, so that
+        // nothing remains exactly at eof
+        reader.read_bits_int_be(3).unwrap();
+        assert_eq!(reader.remaining(), 5);
+        reader.read_bits_int_be(33).unwrap();
+        assert_eq!(reader.remaining(), 1);
+        assert!(!reader.is_eof());
+        reader.ensure_remaining(1).unwrap();
+        assert_eq!(
+            reader.ensure_remaining(2).unwrap_err(),
+            KError::incomplete(2, 1)
+        );
+        reader.read_bits_int_be(4).unwrap();
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.is_eof());
+
+        // past the end nothing remains
+        reader.seek(8).unwrap();
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.ensure_remaining(1).is_err());
+
+        // a substream counts up to its own end
+        reader.seek(1).unwrap();
+        let sub = reader.substream(2);
+        assert_eq!(sub.remaining(), 2);
+        sub.read_u1().unwrap();
+        assert_eq!(sub.remaining(), 1);
+        assert!(sub.ensure_remaining(2).is_err());
+        // the parent stays where the substream started
+        assert_eq!(reader.remaining(), 4);
     }
 
     #[test]
-    fn read_bits_too_large() {
-        let b: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        let reader = BytesReader::from(b);
+    fn read_bits_int_signed() {
+        // b1, b12 across a byte boundary and b3, big-endian:
+        // 1 | 1000_0000_0001 | 010
+        let reader = BytesReader::from(vec![0b1100_0000, 0b0000_1010]);
+        assert_eq!(reader.read_bits_int_be_signed(1).unwrap(), -1);
+        assert_eq!(reader.read_bits_int_be_signed(12).unwrap(), -2047);
+        assert_eq!(reader.read_bits_int_be_signed(3).unwrap(), 2);
 
+        // the same widths little-endian, from the least significant bit:
+        // 0 | 1111_1111_1110 | 011
+        let reader = BytesReader::from(vec![0b1111_1100, 0b0111_1111]);
+        assert_eq!(reader.read_bits_int_le_signed(1).unwrap(), 0);
+        assert_eq!(reader.read_bits_int_le_signed(12).unwrap(), -2);
+        assert_eq!(reader.read_bits_int_le_signed(3).unwrap(), 3);
+
+        let reader = BytesReader::from(vec![0x80, 0, 0, 0, 0, 0, 0, 0, 0xff]);
+        assert_eq!(reader.read_bits_int_be_signed(64).unwrap(), i64::MIN);
+        assert_eq!(reader.read_bits_int_le_signed(8).unwrap(), -1);
         assert_eq!(
-            reader.read_bits_int_be(65).unwrap_err(),
-            KError::ReadBitsTooLarge { requested: 65 }
-        )
+            reader.read_bits_int_be_signed(65).unwrap_err(),
+            KError::ReadBitsTooLarge {
+                requested: 65,
+                max: 64
+            }
+        );
+        let reader = BytesReader::from(vec![0xff; 8]);
+        assert_eq!(reader.read_bits_int_le_signed(64).unwrap(), -1);
     }
 
     #[test]
-    fn read_substream() {
-        let b: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        let reader = BytesReader::from(b);
-        assert_eq!(reader.read_bytes(3).unwrap()[..], [1, 2, 3]);
+    fn read_bits_bytes() {
+        // b3, b72 and b4 big-endian
+        let mut data = vec![0; 10];
+        insert_bits_be(&mut data, 0, 3, 0b101).unwrap();
+        insert_bits_be(&mut data, 3, 8, 0xab).unwrap();
+        insert_bits_be(&mut data, 11, 64, 0x0123_4567_89ab_cdef).unwrap();
+        insert_bits_be(&mut data, 75, 4, 0b1001).unwrap();
+        let reader = BytesReader::from(data.clone());
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 0b101);
+        assert_eq!(
+            reader.read_bits_bytes_be(72).unwrap(),
+            [0xab, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
+        );
+        assert_eq!(reader.read_bits_int_be(4).unwrap(), 0b1001);
+        assert_eq!(reader.pos(), 10);
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 0);
 
-        let sub = reader.substream(4);
+        // the same in little-endian bit order: the low bits come first
+        let mut data = vec![0; 10];
+        insert_bits_le(&mut data, 0, 3, 0b101).unwrap();
+        insert_bits_le(&mut data, 3, 64, 0x0123_4567_89ab_cdef).unwrap();
+        insert_bits_le(&mut data, 67, 8, 0xab).unwrap();
+        insert_bits_le(&mut data, 75, 4, 0b1001).unwrap();
+        let reader = BytesReader::from(data);
+        assert_eq!(reader.read_bits_int_le(3).unwrap(), 0b101);
         assert_eq!(
-            sub.read_bytes(5).unwrap_err(),
-            KError::Eof {
-                requested: 5,
-                available: 4
-            }
+            reader.read_bits_bytes_le(72).unwrap(),
+            [0xab, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
         );
-        let sub = sub.substream(5);
+        assert_eq!(reader.read_bits_int_le(4).unwrap(), 0b1001);
+        assert_eq!(reader.pos(), 10);
+
+        // widths within 64 bits agree with `read_bits_int_*`, left-padded
+        let reader = BytesReader::from(vec![0xf3, 0x5a]);
+        assert_eq!(reader.read_bits_bytes_be(12).unwrap(), [0x0f, 0x35]);
+        let reader = BytesReader::from(vec![0xf3, 0x5a]);
+        assert_eq!(reader.read_bits_bytes_le(12).unwrap(), [0x0a, 0xf3]);
+        assert_eq!(reader.read_bits_int_le(4).unwrap(), 0x5);
+        assert!(reader.read_bits_bytes_be(0).unwrap().is_empty());
+
+        // too few bits fail without consuming any
+        let reader = BytesReader::from(vec![0xf3, 0x5a]);
+        reader.read_bits_int_be(1).unwrap();
         assert_eq!(
-            sub.read_bytes(5).unwrap_err(),
-            KError::Eof {
-                requested: 5,
-                available: 4
-            }
+            reader.read_bits_bytes_be(16).unwrap_err(),
+            KError::eof_bits(16, 15, 1)
         );
-        assert_eq!(sub.read_bytes(4).unwrap()[..], [4, 5, 6, 7]);
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [4, 5, 6, 7]);
+        assert_eq!(reader.read_bits_int_be(15).unwrap(), 0x735a);
     }
 
     #[test]
-    fn read_bytes_term() {
-        let b = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        let reader = BytesReader::from(b);
+    fn seek_bits() {
+        let data: Vec<u8> = (0..16u8).map(|i| i.wrapping_mul(0x9d) ^ 0x5a).collect();
+        for bit_pos in [0, 5, 37, 64, 121] {
+            for le in [false, true] {
+                let read = |io: &BytesReader, n| match le {
+                    true => io.read_bits_int_le(n).unwrap(),
+                    false => io.read_bits_int_be(n).unwrap(),
+                };
+                // reading up to `bit_pos` sequentially
+                let sequential = BytesReader::from(data.clone());
+                if bit_pos > 0 {
+                    read(&sequential, bit_pos as usize % 64);
+                    for _ in 0..bit_pos / 64 {
+                        read(&sequential, 64);
+                    }
+                }
+                assert_eq!(sequential.pos_bits(), bit_pos);
+
+                let reader = BytesReader::from(data.clone());
+                reader.read_bits_int_be(3).unwrap();
+                match le {
+                    true => reader.seek_bits_le(bit_pos).unwrap(),
+                    false => reader.seek_bits(bit_pos).unwrap(),
+                }
+                assert_eq!(reader.pos_bits(), bit_pos);
+                assert_eq!(reader.bit_state(), sequential.bit_state());
+                let n = (128 - bit_pos as usize).min(7);
+                assert_eq!(read(&reader, n), read(&sequential, n));
+                assert_eq!(reader.pos_bits(), bit_pos + n as u64);
+            }
+        }
+
+        // past the end only a byte-aligned position is allowed
+        let reader = BytesReader::from(vec![0xff, 0x0f]);
+        reader.read_bits_int_be(2).unwrap();
+        reader.seek_bits(16).unwrap();
+        assert!(reader.is_eof());
+        reader.seek_bits(4).unwrap();
+        assert!(reader.seek_bits(17).unwrap_err().is_eof());
+        assert_eq!(reader.pos_bits(), 4);
+        assert_eq!(reader.read_bits_int_be(8).unwrap(), 0xf0);
+        reader.seek_bits(8).unwrap();
+        assert_eq!(reader.bit_state(), BitState::default());
+        assert_eq!(reader.read_u1().unwrap(), 0x0f);
+    }
+
+    #[test]
+    fn read_len_prefixed_struct() {
+        let read = |hex: &str, kind, leftover| {
+            let reader = BytesReader::from_hex(hex).unwrap();
+            let res = reader.read_len_prefixed_struct::<Pair>(kind, leftover, None, None);
+            (res.map(|p| (*p.a.borrow(), *p.b.borrow())), reader.pos())
+        };
+
+        // exact consumption, with every policy
+        for leftover in [Leftover::Error, Leftover::Skip, Leftover::Report] {
+            let (res, pos) = read("04000000 0102 0304 ff", LenKind::U4le, leftover);
+            assert_eq!(res.unwrap(), (0x102, 0x304));
+            assert_eq!(pos, 8);
+        }
+        let (res, pos) = read("0004 0102 0304", LenKind::U2be, Leftover::Error);
+        assert_eq!(res.unwrap(), (0x102, 0x304));
+        assert_eq!(pos, 6);
 
+        // under-consumption
+        let (res, pos) = read("06 0102 0304 aabb ff", LenKind::U1, Leftover::Error);
         assert_eq!(
-            reader.read_bytes_term(3, false, false, false).unwrap()[..],
-            [1, 2]
+            res.unwrap_err(),
+            KError::trailing_bytes(4, 2, vec![0xaa, 0xbb])
         );
+        assert_eq!(pos, 7);
+        let (res, pos) = read("06 0102 0304 aabb ff", LenKind::U1, Leftover::Skip);
+        assert_eq!(res.unwrap(), (0x102, 0x304));
+        assert_eq!(pos, 7);
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let session = ParseSession::builder().diagnostics(log.clone()).build();
+        let reader =
+            session.reader_from_bytes(vec![0xee, 6, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 0xaa, 0xbb]);
+        reader.read_u1().unwrap();
+        let pair: OptRc<Pair> = reader
+            .read_len_prefixed_struct(LenKind::U8le, Leftover::Report, None, None)
+            .unwrap();
+        assert_eq!(*pair.b.borrow(), 0x304);
+        assert!(reader.is_eof());
         assert_eq!(
-            reader.read_bytes_term(3, true, false, true).unwrap()[..],
-            [3]
+            *log.borrow(),
+            [Diagnostic {
+                offset: 4,
+                message: "2 of 6 bytes of the region unused".to_string()
+            }]
         );
+        // not from a frozen reader
+        reader.seek(1).unwrap();
+        let pair: OptRc<Pair> = reader
+            .freeze()
+            .read_len_prefixed_struct(LenKind::U8le, Leftover::Report, None, None)
+            .unwrap();
+        assert_eq!(*pair.a.borrow(), 0x102);
+        assert_eq!(log.borrow().len(), 1);
+
+        // over-consumption fails within the region, which is skipped anyway
+        for leftover in [Leftover::Error, Leftover::Skip, Leftover::Report] {
+            let (res, pos) = read("03 0102 03 04", LenKind::U1, leftover);
+            assert!(res.unwrap_err().is_eof());
+            assert_eq!(pos, 4);
+        }
+        let (res, pos) = read("0000 0102 0304", LenKind::U2le, Leftover::Skip);
+        assert!(res.unwrap_err().is_eof());
+        assert_eq!(pos, 2);
+
+        // a prefix longer than the stream
+        let (res, pos) = read("00000009 0102 0304", LenKind::U4be, Leftover::Skip);
+        assert!(res.unwrap_err().is_eof());
+        assert_eq!(pos, 4);
+    }
+
+    #[test]
+    fn bit_state_layout() {
+        let reader = BytesReader::from(vec![0b1011_0110, 0xff]);
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 0b101);
         assert_eq!(
-            reader.read_bytes_term(3, false, true, true).unwrap()[..],
-            []
+            reader.bit_state(),
+            BitState {
+                bits: 0b1_0110,
+                bits_left: 5
+            }
         );
+        assert_eq!(reader.pos(), 1);
+
+        let reader = BytesReader::from(vec![0b1011_0110, 0xff]);
+        assert_eq!(reader.read_bits_int_le(3).unwrap(), 0b110);
         assert_eq!(
-            reader.read_bytes_term(5, true, true, true).unwrap()[..],
-            [4, 5]
+            reader.bit_state(),
+            BitState {
+                bits: 0b1_0110,
+                bits_left: 5
+            }
         );
+        assert_eq!(reader.read_bits_int_le(5).unwrap(), 0b1_0110);
+        assert_eq!(reader.bit_state(), BitState::default());
+
+        // a custom reader keeping its state in a `ReaderState`
+        let mut state = ReaderState::new();
+        state.set_bit_state(BitState {
+            bits: 0b11,
+            bits_left: 2,
+        });
+        assert_eq!((state.bits(), state.bits_left()), (0b11, 2));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_state_accessors() {
+        let reader = BytesReader::from(vec![1, 2, 3]);
+        reader.read_u1().unwrap();
+        assert_eq!(reader.get_state().pos(), 1);
+        reader.get_state_mut().set_pos(2);
+        assert_eq!(reader.read_u1().unwrap(), 3);
+    }
+
+    #[test]
+    fn typed_offsets() {
+        // a header with the section start, the section with the offset of
+        // an entry relative to it and the entry's length
+        let data = vec![4, 0, 0, 0, 0xee, 4, 2, 0xaa, 0xbb, 0xcc, 0xdd];
+        let reader = BytesReader::from(data);
+        let section = AbsOffset::try_from(i64::from(reader.read_u1().unwrap())).unwrap();
+        reader
+            .seek_abs(section.checked_add(Len(1)).unwrap())
+            .unwrap();
+        let rel = RelOffset(reader.read_u1().unwrap().into());
+        let len = Len(reader.read_u1().unwrap().into());
+        assert_eq!(reader.pos_abs().unwrap(), AbsOffset(7));
+
+        let entry = section.checked_add_rel(rel).unwrap();
+        assert_eq!(reader.read_at_abs(entry, len, None).unwrap(), [0xbb, 0xcc]);
+        let bounds = Some((section, Len(7)));
         assert_eq!(
-            reader.read_bytes_term(8, false, false, true).unwrap()[..],
-            [6, 7]
+            reader.read_at_abs(entry, len, bounds).unwrap(),
+            [0xbb, 0xcc]
         );
+        let err = reader
+            .read_at_abs(entry, Len(3), Some((section, Len(6))))
+            .unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::Format);
+        assert_eq!(reader.pos_abs().unwrap(), AbsOffset(7));
+
+        // positions within a region are relative to its start
+        let region = reader.region_at(section, Len(7)).unwrap();
+        region.seek(rel.0 as usize).unwrap();
+        assert_eq!(region.read_bytes(2).unwrap(), [0xbb, 0xcc]);
+        assert_eq!(region.pos(), 6);
+        assert_eq!(region.pos_abs().unwrap(), AbsOffset(10));
+        assert!(reader.region_at(section, Len(8)).unwrap_err().is_eof());
+        assert_eq!(reader.pos(), 7);
+
+        // the typed offsets of a region stay those of the root stream
+        region.seek_abs(entry).unwrap();
+        assert_eq!(region.pos(), 4);
+        assert_eq!(region.read_at_abs(entry, len, None).unwrap(), [0xbb, 0xcc]);
+        let err = region.read_at_abs(AbsOffset(3), Len(1), None).unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::Format);
+        let err = region.seek_abs(AbsOffset(3)).unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::InvalidInput);
+        let inner = region.region_at(entry, Len(2)).unwrap();
+        assert_eq!(inner.read_u1().unwrap(), 0xbb);
+        assert_eq!(inner.pos_abs().unwrap(), AbsOffset(9));
+        let processed = BytesReader::from(vec![0; 4]).processed();
         assert_eq!(
-            reader.read_bytes_term(11, false, true, true).unwrap_err(),
-            KError::NoTerminatorFound
+            processed.pos_abs().unwrap_err().category(),
+            ErrorCategory::InvalidInput
         );
-        // restore position
-        reader.seek(7).unwrap();
+    }
+
+    #[test]
+    fn bits_le_64_after_partial_byte() {
+        let data = vec![0xff, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xfe];
+
+        // the 5 pending bits, 7 whole bytes and the low 3 bits of the last
+        let reader = BytesReader::from(data.clone());
+        assert_eq!(reader.read_bits_int_le(3).unwrap(), 0b111);
+        assert_eq!(reader.read_bits_int_le(64).unwrap(), 0xc0e0_c0a0_8060_403f);
+        assert_eq!(reader.read_bits_int_le(5).unwrap(), 0b1_1111);
+        assert!(reader.is_eof());
+
+        // 63 bits take only the low 2 bits of the last byte
+        let reader = BytesReader::from(data.clone());
+        reader.read_bits_int_le(3).unwrap();
+        assert_eq!(reader.read_bits_int_le(63).unwrap(), 0x40e0_c0a0_8060_403f);
+        assert_eq!(reader.read_bits_int_le(6).unwrap(), 0b11_1111);
+
+        // aligned, the 8 bytes as a u8le
+        let reader = BytesReader::from(data);
+        assert_eq!(reader.read_bits_int_le(64).unwrap(), 0x0706_0504_0302_01ff);
         assert_eq!(
-            reader.read_bytes_term(9, true, true, false).unwrap()[..],
-            [8, 9]
+            reader.bit_state(),
+            BitState {
+                bits: 0,
+                bits_left: 0
+            }
         );
+    }
+
+    #[test]
+    fn bit_alignment() {
+        let data = vec![0b1010_0110, 0x34, 0x12, 0b1100_0000];
+        let reader = |policy| BytesReader::from(data.clone()).with_bit_alignment(policy);
+
+        // b3, u2le, b2
+        let ignore = reader(BitAlignment::Ignore);
+        assert_eq!(ignore.read_bits_int_be(3).unwrap(), 0b101);
+        assert_eq!(ignore.read_u2le().unwrap(), 0x1234);
+        // the pending bits of the first byte
+        assert_eq!(ignore.read_bits_int_be(2).unwrap(), 0b00);
+
+        let auto = reader(BitAlignment::AutoAlign);
+        auto.read_bits_int_be(3).unwrap();
+        assert_eq!(auto.read_u2le().unwrap(), 0x1234);
+        assert_eq!(auto.read_bits_int_be(2).unwrap(), 0b11);
+
+        let strict = reader(BitAlignment::Error);
+        strict.read_bits_int_be(3).unwrap();
+        let err = strict.read_u2le().unwrap_err();
+        assert_eq!(err, KError::unaligned_bit_read(1, 5));
+        assert_eq!(err.offset(), Some(1));
+        assert!(strict.read_bytes(1).is_err());
+        assert!(strict.read_bytes_term(0x12, false, true, false).is_err());
+        // nothing was consumed, and bit reads across bytes still work
+        assert_eq!(strict.pos(), 1);
+        assert_eq!(strict.read_bits_int_be(13).unwrap(), 0b0_0110_0011_0100);
+        strict.align_to_byte().unwrap();
+        assert_eq!(strict.read_u1().unwrap(), 0x12);
+
+        // inherited by substreams
+        let strict = reader(BitAlignment::Error);
+        let sub = strict.read_substream(3).unwrap();
+        assert_eq!(sub.bit_alignment(), BitAlignment::Error);
         assert_eq!(
-            reader.read_bytes_term(10, true, false, false).unwrap()[..],
-            [10]
+            BytesReader::from(vec![]).bit_alignment(),
+            BitAlignment::Ignore
         );
     }
 
     #[test]
-    fn process_xor_one_test() {
-        let b = vec![0x66];
-        let reader = BytesReader::from(b);
-        let res = process_xor_one(&reader.read_bytes(1).unwrap(), 3);
-        assert_eq!(0x65, res[0]);
+    fn bit_alignment_fixed_arrays() {
+        let data = vec![0b1010_0110, 1, 2, 3, 4, 5, 6, 7, 8];
+        let reader = |policy| {
+            let reader = BytesReader::from(data.clone()).with_bit_alignment(policy);
+            reader.read_bits_int_be(3).unwrap();
+            reader
+        };
+
+        let ignore = reader(BitAlignment::Ignore);
+        assert_eq!(ignore.read_bytes_array::<2>().unwrap(), [1, 2]);
+        assert_eq!(ignore.read_ipv4().unwrap().0, [3, 4, 5, 6]);
+        assert_eq!(ignore.bit_state().bits_left, 5);
+
+        let auto = reader(BitAlignment::AutoAlign);
+        assert_eq!(auto.read_bytes_array::<2>().unwrap(), [1, 2]);
+        assert_eq!(auto.bit_state(), BitState::default());
+        auto.seek(1).unwrap();
+        auto.read_bits_int_be(3).unwrap();
+        assert_eq!(auto.read_ipv4().unwrap().0, [2, 3, 4, 5]);
+        assert_eq!(auto.bit_state(), BitState::default());
+
+        let strict = reader(BitAlignment::Error);
+        let unaligned = KError::unaligned_bit_read(1, 5);
+        assert_eq!(strict.read_bytes_array::<2>().unwrap_err(), unaligned);
+        assert_eq!(strict.read_ipv4().unwrap_err(), unaligned);
+        assert_eq!((strict.pos(), strict.bit_state().bits_left), (1, 5));
+    }
+
+    // under `BitAlignment::Error` after `b3`, with the bit state checked to
+    // be kept
+    fn strict_after_bits(data: &[u8]) -> (BytesReader, BitState) {
+        let reader = BytesReader::from(data.to_vec()).with_bit_alignment(BitAlignment::Error);
+        reader.read_bits_int_be(3).unwrap();
+        let state = reader.bit_state();
+        (reader, state)
     }
 
     #[test]
-    fn process_xor_many_test() {
-        let b = vec![0x66, 0x6F];
-        let reader = BytesReader::from(b);
-        let key: Vec<u8> = vec![3, 3];
-        let res = process_xor_many(&reader.read_bytes(2).unwrap(), &key);
-        assert_eq!(vec![0x65, 0x6C], res);
+    fn bit_alignment_read_at_checked() {
+        let (reader, state) = strict_after_bits(&[0xa6, 1, 2, 3]);
+        assert_eq!(reader.read_at_checked(2, 2, None).unwrap(), [2, 3]);
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
     }
 
     #[test]
-    fn process_rotate_left_test() {
-        let b = vec![0x09, 0xAC];
-        let reader = BytesReader::from(b);
-        let res = process_rotate_left(&reader.read_bytes(2).unwrap(), 3);
-        let expected: Vec<u8> = vec![0x48, 0x65];
-        assert_eq!(expected, res);
+    fn bit_alignment_peek_bytes() {
+        let (reader, state) = strict_after_bits(&[0xa6, 1, 2, 3]);
+        assert_eq!(reader.peek_bytes(2).unwrap(), [1, 2]);
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
     }
 
     #[test]
-    fn basic_seek() {
-        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
-        let reader = BytesReader::from(b);
+    fn bit_alignment_peek_u1() {
+        let (reader, state) = strict_after_bits(&[0xa6, 1, 2, 3]);
+        assert_eq!(reader.peek_u1().unwrap(), 1);
+        assert_eq!(reader.peek_u2le().unwrap(), 0x0201);
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
+    }
 
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
-        let pos = reader.pos();
-        reader.seek(1).unwrap();
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [2, 3, 4, 5]);
-        reader.seek(pos).unwrap();
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [5, 6, 7, 8]);
-        reader.seek(9).unwrap();
+    #[test]
+    fn bit_alignment_checkpoint() {
+        let (reader, state) = strict_after_bits(&[0xa6, 1, 2, 3]);
+        let checkpoint = reader.checkpoint().unwrap();
+        assert_eq!(checkpoint.bit_state(), state);
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
     }
 
-    fn dump_and_open(bytes: &[u8]) -> BytesReader {
-        let tmp_dir = tempdir().unwrap();
-        let file_path = tmp_dir.path().join("test.txt");
-        {
-            let mut tmp_file = std::fs::File::create(file_path.clone()).unwrap();
-            tmp_file.write_all(bytes).unwrap();
-        }
-        BytesReader::open(file_path).unwrap()
+    #[test]
+    fn bit_alignment_expect_fully_consumed() {
+        let (reader, state) = strict_after_bits(&[0xa6, 1]);
+        assert_eq!(
+            reader.expect_fully_consumed().unwrap_err(),
+            KError::trailing_bytes(1, 1, vec![1])
+        );
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
+        let (reader, _) = strict_after_bits(&[0xa6]);
+        reader.expect_fully_consumed().unwrap();
     }
 
     #[test]
-    fn basic_read_bytes_file() {
-        let reader = dump_and_open(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    fn bit_alignment_scan_for() {
+        let (reader, state) = strict_after_bits(&[0xa6, 1, 2, 3]);
+        assert_eq!(reader.scan_for(&[2, 3], None).unwrap(), Some(2));
+        assert_eq!((reader.pos(), reader.bit_state()), (1, state));
+    }
 
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
-        assert_eq!(reader.read_bytes(3).unwrap()[..], [5, 6, 7]);
-        assert_eq!(
-            reader.read_bytes(4).unwrap_err(),
-            KError::Eof {
-                requested: 4,
-                available: 1
+    #[test]
+    fn half_floats() {
+        let min_subnormal = 2f32.powi(-24);
+        let cases: &[(u16, f32)] = &[
+            (0x3c00, 1.0),
+            (0xbc00, -1.0),
+            (0x3555, 0.333_251_95),
+            (0x7bff, 65504.0),
+            (0x0400, 2f32.powi(-14)),
+            (0x03ff, 1023.0 * min_subnormal),
+            (0x0001, min_subnormal),
+            (0x8001, -min_subnormal),
+            (0x7c00, f32::INFINITY),
+            (0xfc00, f32::NEG_INFINITY),
+        ];
+        for &(bits, expected) in cases {
+            assert_eq!(f16_to_f32(bits), expected, "{:#06x}", bits);
+        }
+        // the sign of zero is kept
+        assert_eq!(f16_to_f32(0x0000).to_bits(), 0);
+        assert_eq!(f16_to_f32(0x8000).to_bits(), 0x8000_0000);
+        for bits in [0x7e00, 0x7c01, 0xfe00, 0x7fff] {
+            assert!(f16_to_f32(bits).is_nan(), "{:#06x}", bits);
+        }
+        // every finite value against the definition of the format
+        for bits in 0..=u16::MAX {
+            let v = f16_to_f32(bits);
+            if v.is_finite() && v != 0.0 {
+                let exp = ((bits >> 10) & 0x1f) as i32;
+                let man = (bits & 0x3ff) as f64;
+                let expected = if exp == 0 {
+                    man * 2f64.powi(-24)
+                } else {
+                    (1.0 + man / 1024.0) * 2f64.powi(exp - 15)
+                };
+                assert_eq!(f64::from(v.abs()), expected, "{:#06x}", bits);
             }
-        );
-        assert_eq!(reader.read_bytes(1).unwrap()[..], [8]);
+        }
+
+        let reader = BytesReader::from(vec![0x3c, 0x00, 0x00, 0x3c, 0xfc, 0x00]);
+        assert_eq!(reader.read_f2be().unwrap(), 1.0);
+        assert_eq!(reader.read_f2le().unwrap(), 1.0);
+        assert_eq!(reader.read_f2be().unwrap(), f32::NEG_INFINITY);
+        assert!(reader.read_f2le().unwrap_err().is_eof());
     }
 
     #[test]
-    fn basic_seek_file() {
-        let reader = dump_and_open(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    fn read_128() {
+        let mut data = vec![0xff; 16];
+        data.extend([0x80].iter().chain(&[0; 15]));
+        data.extend((1..=16).collect::<Vec<u8>>());
+        let reader = BytesReader::from(data);
+        assert_eq!(reader.read_u128be().unwrap(), u128::MAX);
+        assert_eq!(reader.read_s128be().unwrap(), i128::MIN);
+        reader.seek(0).unwrap();
+        assert_eq!(reader.read_s128le().unwrap(), -1);
+        assert_eq!(reader.read_u128le().unwrap(), 0x80);
 
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
-        let pos = reader.pos();
-        reader.seek(1).unwrap();
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [2, 3, 4, 5]);
-        reader.seek(pos).unwrap();
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [5, 6, 7, 8]);
-        reader.seek(9).unwrap();
+        let be = reader.read_u128be().unwrap();
+        assert_eq!(be, 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10);
+        reader.seek(32).unwrap();
+        assert_eq!(reader.read_u128le().unwrap(), be.swap_bytes());
+        reader.seek(32).unwrap();
+        assert_eq!(reader.read_s128le().unwrap(), be.swap_bytes() as i128);
+        // the halves as two 8-byte reads
+        reader.seek(32).unwrap();
+        let (hi, lo) = (reader.read_u8be().unwrap(), reader.read_u8be().unwrap());
+        assert_eq!(u128::from(hi) << 64 | u128::from(lo), be);
+
+        // a short read fails like the other fixed-width ones
+        let reader = BytesReader::from(vec![0; 15]);
+        assert_eq!(reader.read_u128le().unwrap_err(), KError::eof(16, 15));
+        assert_eq!(reader.pos(), 0);
     }
 }
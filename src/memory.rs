@@ -0,0 +1,306 @@
+use crate::KStream;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+/// Allocations of one struct type, see [`MemoryAccountant::report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub type_name: String,
+    /// Bytes allocated by reads of the type's own fields, over all
+    /// instances.
+    pub bytes: usize,
+    /// Most bytes allocated while a single instance was being parsed,
+    /// nested structs included: an estimate of the memory one instance
+    /// keeps alive.
+    pub peak: usize,
+    pub instances: usize,
+}
+
+/// Attributes the buffers allocated by stream reads to the struct being
+/// parsed, to find out which part of a format a file makes large.
+///
+/// Set on a session with
+/// [`ParseSessionBuilder::memory_accountant`](crate::ParseSessionBuilder::memory_accountant);
+/// parsing code opens a [`KStream::memory_scope`] per struct. Each buffer
+/// is counted once, by the read that fills it: reads of
+/// [`read_bytes`](KStream::read_bytes), which arrays and strings are made
+/// from, terminated bytes and bit fields, as well as buffers passed to
+/// [`KStream::account_alloc`]. Allocations outside of any scope are
+/// attributed to [`UNSCOPED`](Self::UNSCOPED).
+#[derive(Debug, Default)]
+pub struct MemoryAccountant {
+    state: RefCell<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // open scopes, innermost last, with the bytes allocated in each so far
+    stack: Vec<(String, usize)>,
+    usage: HashMap<String, MemoryUsage>,
+}
+
+impl State {
+    fn usage(&mut self, type_name: &str) -> &mut MemoryUsage {
+        self.usage
+            .entry(type_name.to_string())
+            .or_insert_with(|| MemoryUsage {
+                type_name: type_name.to_string(),
+                bytes: 0,
+                peak: 0,
+                instances: 0,
+            })
+    }
+}
+
+impl MemoryAccountant {
+    /// Type name of allocations made outside of any scope.
+    pub const UNSCOPED: &'static str = "<unscoped>";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `len` allocated bytes for the innermost open scope.
+    pub fn record(&self, len: usize) {
+        let mut state = self.state.borrow_mut();
+        for (_, total) in &mut state.stack {
+            *total += len;
+        }
+        let type_name = match state.stack.last() {
+            Some((name, _)) => name.clone(),
+            None => Self::UNSCOPED.to_string(),
+        };
+        state.usage(&type_name).bytes += len;
+    }
+
+    fn enter(&self, type_name: &str) {
+        let mut state = self.state.borrow_mut();
+        state.usage(type_name).instances += 1;
+        state.stack.push((type_name.to_string(), 0));
+    }
+
+    fn exit(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some((type_name, total)) = state.stack.pop() {
+            let usage = state.usage(&type_name);
+            usage.peak = usage.peak.max(total);
+        }
+    }
+
+    /// Usage of every type seen, largest [`bytes`](MemoryUsage::bytes)
+    /// first.
+    pub fn report(&self) -> Vec<MemoryUsage> {
+        let mut res: Vec<_> = self.state.borrow().usage.values().cloned().collect();
+        res.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.type_name.cmp(&b.type_name))
+        });
+        res
+    }
+
+    /// The first `n` entries of the [`report`](Self::report).
+    pub fn top(&self, n: usize) -> Vec<MemoryUsage> {
+        let mut res = self.report();
+        res.truncate(n);
+        res
+    }
+}
+
+/// Open scope of a [`MemoryAccountant`], closed when dropped, see
+/// [`KStream::memory_scope`].
+#[must_use]
+#[derive(Debug)]
+pub struct MemoryScope {
+    accountant: Rc<MemoryAccountant>,
+}
+
+impl MemoryScope {
+    pub fn new(accountant: Rc<MemoryAccountant>, type_name: &str) -> Self {
+        accountant.enter(type_name);
+        MemoryScope { accountant }
+    }
+}
+
+impl Drop for MemoryScope {
+    fn drop(&mut self) {
+        self.accountant.exit();
+    }
+}
+
+// count `len` bytes for the accountant of the session of `io`, if any
+pub(crate) fn record_alloc<S: KStream + ?Sized>(io: &S, len: usize) {
+    if let Some(accountant) = io.session().and_then(|s| s.memory_accountant()) {
+        accountant.record(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BytesReader, KResult, KStruct, KStructUnit, OptRc, ParseSession, SharedType};
+
+    #[derive(Default)]
+    struct Archive {
+        header: RefCell<OptRc<Header>>,
+        payload: RefCell<OptRc<Payload>>,
+    }
+
+    #[derive(Default)]
+    struct Header {
+        name: RefCell<String>,
+    }
+
+    #[derive(Default)]
+    struct Payload {
+        blob: RefCell<Vec<u8>>,
+    }
+
+    impl KStruct for Archive {
+        type Root = Archive;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            let _scope = _io.memory_scope("Archive");
+            let header = Header::read_into::<_, Header>(_io, Some(_root.clone()), None)?;
+            *self_rc.header.borrow_mut() = header;
+            let payload = Payload::read_into::<_, Payload>(_io, Some(_root.clone()), None)?;
+            *self_rc.payload.borrow_mut() = payload;
+            _io.read_bytes(4)?;
+            Ok(())
+        }
+    }
+
+    impl KStruct for Header {
+        type Root = Archive;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            let _scope = _io.memory_scope("Header");
+            *self_rc.name.borrow_mut() = _io.read_str_pascal_u1("ASCII")?;
+            Ok(())
+        }
+    }
+
+    impl KStruct for Payload {
+        type Root = Archive;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            let _scope = _io.memory_scope("Payload");
+            let len = _io.read_u4le()?;
+            *self_rc.blob.borrow_mut() = _io.read_bytes(len as usize)?;
+            Ok(())
+        }
+    }
+
+    fn archive(blob_len: u32) -> Vec<u8> {
+        let mut data = vec![3];
+        data.extend_from_slice(b"abc");
+        data.extend_from_slice(&blob_len.to_le_bytes());
+        data.resize(data.len() + blob_len as usize, 0xaa);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn blob_attributed_to_its_struct() {
+        let accountant = Rc::new(MemoryAccountant::new());
+        let session = ParseSession::builder()
+            .memory_accountant(accountant.clone())
+            .build();
+        let reader = session.reader_from_bytes(archive(1 << 20));
+        Archive::read_into::<_, Archive>(&reader, None, None).unwrap();
+
+        let report = accountant.report();
+        let usage = |name: &str| report.iter().find(|u| u.type_name == name).unwrap();
+        assert_eq!(report[0].type_name, "Payload");
+        assert_eq!(usage("Payload").bytes, 1 << 20);
+        assert_eq!(usage("Payload").peak, 1 << 20);
+        // the string counts as its bytes
+        assert_eq!(usage("Header").bytes, 3);
+        assert_eq!(usage("Archive").bytes, 4);
+        assert_eq!(usage("Archive").peak, (1 << 20) + 7);
+        assert_eq!(usage("Archive").instances, 1);
+        assert_eq!(accountant.top(1), report[..1]);
+
+        reader.seek(0).unwrap();
+        reader.read_bytes(2).unwrap();
+        assert_eq!(accountant.top(4)[3].type_name, MemoryAccountant::UNSCOPED);
+    }
+
+    #[test]
+    fn each_read_counted_once() {
+        let accountant = Rc::new(MemoryAccountant::new());
+        let session = ParseSession::builder()
+            .memory_accountant(accountant.clone())
+            .build();
+        let counted = |read: &dyn Fn(&BytesReader)| {
+            let reader = session.reader_from_bytes(b"\x03abc\0def\r\nghij".to_vec());
+            let before = accountant.report().iter().map(|u| u.bytes).sum::<usize>();
+            read(&reader);
+            accountant.report().iter().map(|u| u.bytes).sum::<usize>() - before
+        };
+        assert_eq!(counted(&|io| drop(io.read_bytes(4).unwrap())), 4);
+        // scalars are read into the stack, whatever the method
+        let u2le = |io: &BytesReader| {
+            io.read_u2le().unwrap();
+        };
+        assert_eq!(counted(&u2le), 0);
+        let int = |io: &BytesReader| {
+            io.read_int::<u16, crate::LittleEndian>().unwrap();
+        };
+        assert_eq!(counted(&int), 0);
+        let float = |io: &BytesReader| {
+            io.read_float::<f32, crate::BigEndian>().unwrap();
+        };
+        assert_eq!(counted(&float), 0);
+        assert_eq!(
+            counted(&|io| drop(io.read_int_array::<u16, crate::LittleEndian>(3).unwrap())),
+            6
+        );
+        assert_eq!(
+            counted(&|io| drop(io.read_str_pascal_u1("ASCII").unwrap())),
+            3
+        );
+        let strz = |io: &BytesReader| {
+            io.skip(1).unwrap();
+            drop(io.read_str_c("ASCII").unwrap())
+        };
+        assert_eq!(counted(&strz), 3);
+        let multi = |io: &BytesReader| {
+            io.skip(4).unwrap();
+            drop(
+                io.read_bytes_term_multi(b"\r\n", false, true, true)
+                    .unwrap(),
+            )
+        };
+        assert_eq!(counted(&multi), 4);
+        assert_eq!(counted(&|io| drop(io.read_bits_bytes_be(20).unwrap())), 3);
+        assert_eq!(
+            counted(&|io| drop(io.read_bits_array_be(4, 5).unwrap())),
+            40
+        );
+    }
+
+    #[test]
+    fn without_accountant() {
+        let reader = BytesReader::from(archive(16));
+        assert!(reader.memory_scope("Archive").is_none());
+        Archive::read_into::<_, Archive>(&reader, None, None).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{BytesReader, KResult, ReadSeek};
+use memmap2::Mmap;
+use std::{convert::TryFrom, fs::File, io::Cursor, path::Path};
+
+impl BytesReader {
+    /// Open a file mapped into memory, so that reads are plain copies out
+    /// of the mapping instead of system calls. Named after the path like
+    /// [`open`](Self::open). The size is fixed when the file is mapped.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or
+    /// another one, while the reader or one of its clones is alive. Reads
+    /// of changed contents are undefined behavior, and on most systems
+    /// touching a page past a new end kills the process.
+    pub unsafe fn open_mmap<P: AsRef<Path>>(path: P) -> KResult<BytesReader> {
+        // SAFETY: the caller keeps the file as it is while it is mapped
+        let map = unsafe { Mmap::map(&File::open(&path)?)? };
+        let backend: Box<dyn ReadSeek> = Box::new(Cursor::new(map));
+        let name = path.as_ref().display().to_string();
+        Ok(BytesReader::try_from(backend)?.with_name(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testkit::{verify_fixture, Expectation, FixtureBuilder},
+        KError, KStream,
+    };
+
+    fn fixture(data: &[u8]) -> tempfile::NamedTempFile {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut f, data).unwrap();
+        f
+    }
+
+    // runs of terminated bytes and bit fields between whole-byte reads
+    fn fixture_data() -> (Vec<u8>, Vec<Expectation>) {
+        let mut builder = FixtureBuilder::new();
+        for i in 0..100u32 {
+            let bytes: Vec<u8> = (0..i % 40).map(|j| (i * j) as u8 | 1).collect();
+            builder = builder
+                .u4be(i.wrapping_mul(2_654_435_761))
+                .bytes_term(&bytes, 0)
+                .bits_be(13, u64::from(i) * 61)
+                .bits_le(7, u64::from(i))
+                .bytes(&bytes);
+        }
+        builder.build()
+    }
+
+    // reads running into the end of the stream
+    fn read_past_end(io: &BytesReader) -> (KResult<u32>, KResult<Vec<u8>>, KResult<()>) {
+        io.seek(io.size() - 3).unwrap();
+        let int = io.read_u4le();
+        let term = io.read_bytes_term(7, false, true, true);
+        (int, term, io.seek(io.size() + 1))
+    }
+
+    #[test]
+    fn same_results_as_files() {
+        let (data, expectations) = fixture_data();
+        let f = fixture(&data);
+        // SAFETY: the temporary file is not changed
+        let io = unsafe { BytesReader::open_mmap(f.path()) }.unwrap();
+        assert_eq!(io.size(), data.len());
+        verify_fixture(&io, &expectations).unwrap();
+        let file = BytesReader::open(f.path()).unwrap();
+        assert_eq!(io.name(), file.name());
+        assert_eq!(read_past_end(&io), read_past_end(&file));
+        assert_eq!(read_past_end(&io).0.unwrap_err(), KError::eof(4, 3));
+    }
+
+    #[test]
+    fn empty_file() {
+        let f = fixture(&[]);
+        // SAFETY: the temporary file is not changed
+        let io = unsafe { BytesReader::open_mmap(f.path()) }.unwrap();
+        assert_eq!(io.size(), 0);
+        assert!(io.is_eof());
+        assert_eq!(io.read_u1().unwrap_err(), KError::eof(1, 0));
+        assert_eq!(io.read_bytes_full().unwrap(), []);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+/// Colon-separated lowercase hex, e.g. `00:1b:63:84:45:e6`.
+pub fn format_mac(bytes: &[u8; 6]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Dotted decimal, from either a `u32` (as read big-endian) or 4 bytes.
+pub fn ipv4_to_string(addr: impl Into<Ipv4Addr>) -> String {
+    addr.into().to_string()
+}
+
+/// Canonical text form as per RFC 5952: lowercase, no leading zeros, the
+/// longest run of two or more zero groups compressed to `::` (the first
+/// one on ties), and IPv4-mapped addresses in mixed notation.
+pub fn ipv6_to_string(bytes: [u8; 16]) -> String {
+    Ipv6Addr::from(bytes).to_string()
+}
+
+macro_rules! address_type {
+    ($name:ident, $len:expr, $fmt:expr) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+        pub struct $name(pub [u8; $len]);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&$fmt(self.0))
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                other == $fmt(self.0)
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                *other == $fmt(self.0)
+            }
+        }
+    };
+}
+
+address_type!(MacAddress, 6, |b| format_mac(&b));
+address_type!(Ipv4Address, 4, ipv4_to_string);
+address_type!(Ipv6Address, 16, ipv6_to_string);
+
+impl From<Ipv4Address> for Ipv4Addr {
+    fn from(a: Ipv4Address) -> Self {
+        a.0.into()
+    }
+}
+
+impl From<Ipv6Address> for Ipv6Addr {
+    fn from(a: Ipv6Address) -> Self {
+        a.0.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BytesReader, KStream};
+    use std::convert::TryInto;
+
+    #[test]
+    fn mac() {
+        assert_eq!(
+            format_mac(&[0x00, 0x1b, 0x63, 0x84, 0x45, 0xe6]),
+            "00:1b:63:84:45:e6"
+        );
+        assert_eq!(format_mac(&[0xff; 6]), "ff:ff:ff:ff:ff:ff");
+    }
+
+    #[test]
+    fn ipv4() {
+        assert_eq!(ipv4_to_string(0xc0a80001u32), "192.168.0.1");
+        assert_eq!(ipv4_to_string([10, 0, 0, 255]), "10.0.0.255");
+        assert_eq!(ipv4_to_string(0u32), "0.0.0.0");
+    }
+
+    #[test]
+    fn ipv6_rfc5952() {
+        let cases: &[(&str, &str)] = &[
+            ("00000000000000000000000000000000", "::"),
+            ("00000000000000000000000000000001", "::1"),
+            ("20010db8000000000000000000000001", "2001:db8::1"),
+            // a single zero group is not compressed
+            ("20010db8000000010001000100010001", "2001:db8:0:1:1:1:1:1"),
+            // the longest run wins, the first one on ties
+            ("20010db8000000000001000000000001", "2001:db8::1:0:0:1"),
+            ("20010000000000010000000000010001", "2001::1:0:0:1:1"),
+            ("20010db80000000100000000000000ab", "2001:db8:0:1::ab"),
+            (
+                "FE800000000000000202B3FFFE1E8329",
+                "fe80::202:b3ff:fe1e:8329",
+            ),
+            ("00000000000000000000ffffc0000280", "::ffff:192.0.2.128"),
+        ];
+        for (hex, expected) in cases {
+            let bytes: [u8; 16] = crate::decode_hex(hex).unwrap().try_into().unwrap();
+            assert_eq!(ipv6_to_string(bytes), *expected);
+        }
+    }
+
+    #[test]
+    fn read_addresses() {
+        let reader =
+            BytesReader::from_hex("001b638445e6 c0a80001 20010db8000000000000000000000001")
+                .unwrap();
+
+        let mac = reader.read_mac().unwrap();
+        assert_eq!(mac, "00:1b:63:84:45:e6");
+        assert_eq!(mac, MacAddress([0x00, 0x1b, 0x63, 0x84, 0x45, 0xe6]));
+        let ip = reader.read_ipv4().unwrap();
+        assert_eq!(ip, "192.168.0.1");
+        assert_eq!(Ipv4Addr::from(ip), Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(reader.read_ipv6().unwrap().to_string(), "2001:db8::1");
+        assert!(reader.read_ipv4().is_err());
+    }
+}
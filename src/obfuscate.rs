@@ -0,0 +1,158 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Transformation undone by [`process_xor_many`](crate::process_xor_many)
+/// or [`process_rotate_left`](crate::process_rotate_left) when reading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Obfuscation {
+    /// XOR with the key repeated from offset 0. An empty key changes
+    /// nothing.
+    Xor(Vec<u8>),
+    /// Bytes to be rotated left by this amount when read, so they are
+    /// rotated right when written.
+    RotateLeft(u8),
+}
+
+impl Obfuscation {
+    fn apply(&self, offset: u64, b: u8) -> u8 {
+        match self {
+            Obfuscation::Xor(key) if key.is_empty() => b,
+            Obfuscation::Xor(key) => b ^ key[(offset % key.len() as u64) as usize],
+            Obfuscation::RotateLeft(amount) => b.rotate_right((*amount).into()),
+        }
+    }
+}
+
+/// Writer that obfuscates the data on the way to `inner`, keyed to the
+/// offset of every byte, so the output needs no post-processing. Seeking
+/// back to patch a field, e.g. a length written once the body is known,
+/// applies the key at the patched offset.
+///
+/// Offsets are those of `inner`, so it should be wrapped while at
+/// position 0.
+#[derive(Debug)]
+pub struct ObfuscatingWriter<W> {
+    inner: W,
+    obfuscation: Obfuscation,
+    pos: u64,
+}
+
+impl<W> ObfuscatingWriter<W> {
+    pub fn new(inner: W, obfuscation: Obfuscation) -> Self {
+        ObfuscatingWriter {
+            inner,
+            obfuscation,
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ObfuscatingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let encoded: Vec<u8> = (self.pos..)
+            .zip(buf)
+            .map(|(offset, &b)| self.obfuscation.apply(offset, b))
+            .collect();
+        // only what `inner` took advances the keystream
+        let n = self.inner.write(&encoded)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for ObfuscatingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{process_rotate_left, process_xor_many};
+    use std::io::Cursor;
+
+    // a u4le length field patched after the body is written
+    fn write_record<W: Write + Seek>(w: &mut W, body: &[u8]) -> io::Result<()> {
+        w.write_all(b"HDR")?;
+        let len_pos = w.stream_position()?;
+        w.write_all(&[0; 4])?;
+        w.write_all(body)?;
+        let end = w.stream_position()?;
+        w.seek(SeekFrom::Start(len_pos))?;
+        w.write_all(&(body.len() as u32).to_le_bytes())?;
+        w.seek(SeekFrom::Start(end))?;
+        w.write_all(b"END")
+    }
+
+    fn plain(body: &[u8]) -> Vec<u8> {
+        let mut out = Cursor::new(vec![]);
+        write_record(&mut out, body).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn xor() {
+        let body: Vec<u8> = (0..100).collect();
+        for key in [
+            &b"k"[..],
+            b"\x12\x34\x56",
+            b"a longer key than the header",
+            b"",
+        ] {
+            let mut w = ObfuscatingWriter::new(Cursor::new(vec![]), Obfuscation::Xor(key.to_vec()));
+            write_record(&mut w, &body).unwrap();
+            let written = w.into_inner().into_inner();
+            if !key.is_empty() {
+                assert_ne!(written, plain(&body));
+            }
+            assert_eq!(
+                process_xor_many(&written, key),
+                plain(&body),
+                "key {:?}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn rotate() {
+        let body = b"rotated body";
+        let mut w = ObfuscatingWriter::new(Cursor::new(vec![]), Obfuscation::RotateLeft(3));
+        write_record(&mut w, body).unwrap();
+        let written = w.into_inner().into_inner();
+        assert_eq!(written[0], b'H'.rotate_right(3));
+        assert_eq!(process_rotate_left(&written, 3), plain(body));
+    }
+
+    // takes at most 2 bytes per call
+    struct Slow(Vec<u8>);
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(2);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes() {
+        let mut w = ObfuscatingWriter::new(Slow(vec![]), Obfuscation::Xor(b"xyz".to_vec()));
+        w.write_all(b"partial writes").unwrap();
+        let written = w.into_inner().0;
+        assert_eq!(process_xor_many(&written, b"xyz"), b"partial writes");
+    }
+}
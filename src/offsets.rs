@@ -0,0 +1,140 @@
+//! Offsets and lengths that cannot be mixed up: [`AbsOffset`] is a position
+//! in the root stream, [`RelOffset`] one from the start of a region of it,
+//! e.g. a section, and [`Len`] a number of bytes. Only meaningful
+//! arithmetic is offered, as checked methods returning `None` on overflow,
+//! so that adding two positions or a length to a relative offset does not
+//! compile:
+//!
+//! ```compile_fail
+//! # use kaitai::*;
+//! let _ = AbsOffset(4).checked_add(AbsOffset(8));
+//! ```
+//!
+//! ```compile_fail
+//! # use kaitai::*;
+//! let _ = Len(8).checked_add(RelOffset(4));
+//! ```
+//!
+//! The wrappers are for hand-written code; generated code keeps using
+//! plain integers.
+
+use crate::{KError, KResult};
+use std::convert::TryFrom;
+
+/// Position in the root stream, counted from its start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct AbsOffset(pub u64);
+
+/// Position counted from the start of a region of a stream, turned into an
+/// [`AbsOffset`] by adding it to the region's start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct RelOffset(pub u64);
+
+/// Number of bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Len(pub u64);
+
+impl AbsOffset {
+    /// `self + len`, `None` on overflow.
+    pub fn checked_add(self, len: Len) -> Option<AbsOffset> {
+        self.0.checked_add(len.0).map(AbsOffset)
+    }
+
+    /// The absolute position of `rel` within the region at `self`, `None`
+    /// on overflow.
+    pub fn checked_add_rel(self, rel: RelOffset) -> Option<AbsOffset> {
+        self.0.checked_add(rel.0).map(AbsOffset)
+    }
+
+    /// Bytes from `start` to `self`, `None` if `start` is after `self`.
+    pub fn checked_distance_from(self, start: AbsOffset) -> Option<Len> {
+        self.0.checked_sub(start.0).map(Len)
+    }
+
+    /// `self` as an offset within the region starting at `base`, `None`
+    /// if it is before the region.
+    pub fn relative_to(self, base: AbsOffset) -> Option<RelOffset> {
+        self.0.checked_sub(base.0).map(RelOffset)
+    }
+}
+
+impl Len {
+    pub fn checked_add(self, other: Len) -> Option<Len> {
+        self.0.checked_add(other.0).map(Len)
+    }
+
+    /// `self - other`, `None` if `other` is longer.
+    pub fn checked_sub(self, other: Len) -> Option<Len> {
+        self.0.checked_sub(other.0).map(Len)
+    }
+}
+
+/// Conversions of expression results, failing with
+/// [`KError::ValueOutOfRange`] if negative, like [`to_pos`](crate::to_pos)
+/// and [`to_len`](crate::to_len).
+macro_rules! from_expr {
+    ($($t:ident: $context:expr;)*) => {
+        $(
+            impl TryFrom<i64> for $t {
+                type Error = KError;
+
+                fn try_from(v: i64) -> KResult<Self> {
+                    u64::try_from(v)
+                        .map($t)
+                        .map_err(|_| KError::value_out_of_range(v, $context))
+                }
+            }
+
+            impl From<$t> for u64 {
+                fn from(v: $t) -> u64 {
+                    v.0
+                }
+            }
+        )*
+    };
+}
+
+from_expr! {
+    AbsOffset: "position";
+    RelOffset: "relative offset";
+    Len: "length";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let section = AbsOffset(0x100);
+        let entry = section.checked_add_rel(RelOffset(0x20)).unwrap();
+        assert_eq!(entry, AbsOffset(0x120));
+        assert_eq!(entry.checked_add(Len(8)), Some(AbsOffset(0x128)));
+        assert_eq!(entry.relative_to(section), Some(RelOffset(0x20)));
+        assert_eq!(section.relative_to(entry), None);
+        assert_eq!(entry.checked_distance_from(section), Some(Len(0x20)));
+        assert_eq!(section.checked_distance_from(entry), None);
+
+        assert_eq!(Len(5).checked_add(Len(3)), Some(Len(8)));
+        assert_eq!(Len(5).checked_sub(Len(3)), Some(Len(2)));
+        assert_eq!(Len(3).checked_sub(Len(5)), None);
+        assert_eq!(AbsOffset(u64::MAX).checked_add(Len(1)), None);
+        assert_eq!(AbsOffset(u64::MAX).checked_add_rel(RelOffset(1)), None);
+        assert_eq!(Len(u64::MAX).checked_add(Len(1)), None);
+    }
+
+    #[test]
+    fn from_expressions() {
+        assert_eq!(AbsOffset::try_from(12i64).unwrap(), AbsOffset(12));
+        assert_eq!(Len::try_from(0i64).unwrap(), Len(0));
+        assert_eq!(
+            RelOffset::try_from(-4i64).unwrap_err(),
+            KError::ValueOutOfRange {
+                value: -4,
+                context: "relative offset".to_string()
+            }
+        );
+        assert!(Len::try_from(i64::MIN).is_err());
+        assert_eq!(u64::from(Len(7)), 7);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::OptRc;
+use std::{collections::HashMap, hash::Hash};
+
+/// What [`KOrderedMap`] does when a key is inserted again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    KeepFirst,
+    KeepLast,
+    KeepAll,
+}
+
+/// Map which iterates in insertion order, meant for TLV-style blocks where
+/// both the order and the handling of repeated tags matter. Keys are
+/// ordered by their first insertion, also under [`DuplicatePolicy::KeepLast`].
+#[derive(Debug, Clone)]
+pub struct KOrderedMap<K, V> {
+    policy: DuplicatePolicy,
+    index: HashMap<K, usize>,
+    entries: Vec<(K, Vec<V>)>,
+}
+
+impl<K: Eq + Hash + Clone, V> KOrderedMap<K, V> {
+    pub fn new(policy: DuplicatePolicy) -> Self {
+        KOrderedMap {
+            policy,
+            index: HashMap::new(),
+            entries: vec![],
+        }
+    }
+
+    pub fn policy(&self) -> DuplicatePolicy {
+        self.policy
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        match self.index.get(&key) {
+            Some(&i) => {
+                let values = &mut self.entries[i].1;
+                match self.policy {
+                    DuplicatePolicy::KeepFirst => {}
+                    DuplicatePolicy::KeepLast => values[0] = value,
+                    DuplicatePolicy::KeepAll => values.push(value),
+                }
+            }
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, vec![value]));
+            }
+        }
+    }
+
+    /// The value kept for `key`; the first one under [`DuplicatePolicy::KeepAll`].
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.get_all(key).first()
+    }
+
+    /// All values kept for `key`, in insertion order.
+    pub fn get_all(&self, key: &K) -> &[V] {
+        match self.index.get(key) {
+            Some(&i) => &self.entries[i].1,
+            None => &[],
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Number of distinct keys.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    /// Keys with the value [`get`](Self::get) would return.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, v)| (k, &v[0]))
+    }
+
+    /// Keys with all their values.
+    pub fn iter_all(&self) -> impl Iterator<Item = (&K, &[V])> {
+        self.entries.iter().map(|(k, v)| (k, v.as_slice()))
+    }
+}
+
+/// Build a [`KOrderedMap`] from parsed items, keyed by `key_fn`.
+pub fn collect_by_key<K, T>(
+    items: &[OptRc<T>],
+    policy: DuplicatePolicy,
+    key_fn: impl Fn(&T) -> K,
+) -> KOrderedMap<K, OptRc<T>>
+where
+    K: Eq + Hash + Clone,
+{
+    let mut res = KOrderedMap::new(policy);
+    for item in items {
+        res.insert(key_fn(item), item.clone());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::cell::RefCell;
+
+    #[derive(Default, Debug)]
+    struct Tlv {
+        tag: RefCell<u8>,
+        value: RefCell<Vec<u8>>,
+    }
+
+    impl KStruct for Tlv {
+        type Root = Tlv;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            *self_rc.tag.borrow_mut() = _io.read_u1()?;
+            let len = _io.read_u1()?;
+            *self_rc.value.borrow_mut() = _io.read_bytes(len.into())?;
+            Ok(())
+        }
+    }
+
+    fn parse_tlvs() -> Vec<OptRc<Tlv>> {
+        // tags 3, 1, 3, 2, 3
+        let reader = BytesReader::from_hex("0301aa 0100 0302bbcc 0201dd 0301ee").unwrap();
+        let mut res = vec![];
+        while !reader.is_eof() {
+            res.push(Tlv::read_into::<_, Tlv>(&reader, None, None).unwrap());
+        }
+        res
+    }
+
+    fn value_of(v: &OptRc<Tlv>) -> Vec<u8> {
+        v.value.borrow().clone()
+    }
+
+    #[test]
+    fn keep_first() {
+        let map = collect_by_key(&parse_tlvs(), DuplicatePolicy::KeepFirst, |t| {
+            *t.tag.borrow()
+        });
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), [3, 1, 2]);
+        assert_eq!(value_of(map.get(&3).unwrap()), [0xaa]);
+        assert_eq!(map.get_all(&3).len(), 1);
+        assert!(map.get(&4).is_none());
+        assert!(map.get_all(&4).is_empty());
+    }
+
+    #[test]
+    fn keep_last() {
+        let map = collect_by_key(&parse_tlvs(), DuplicatePolicy::KeepLast, |t| {
+            *t.tag.borrow()
+        });
+        let values: Vec<_> = map.iter().map(|(k, v)| (*k, value_of(v))).collect();
+        assert_eq!(values, [(3, vec![0xee]), (1, vec![]), (2, vec![0xdd])]);
+    }
+
+    #[test]
+    fn keep_all() {
+        let map = collect_by_key(&parse_tlvs(), DuplicatePolicy::KeepAll, |t| *t.tag.borrow());
+        assert!(map.contains_key(&2));
+        assert_eq!(value_of(map.get(&3).unwrap()), [0xaa]);
+        let all: Vec<_> = map.get_all(&3).iter().map(value_of).collect();
+        assert_eq!(all, [vec![0xaa], vec![0xbb, 0xcc], vec![0xee]]);
+        let counts: Vec<_> = map.iter_all().map(|(k, v)| (*k, v.len())).collect();
+        assert_eq!(counts, [(3, 3), (1, 1), (2, 1)]);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{resolve_seek, BytesReader, KResult, ReadSeek};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::JoinHandle,
+};
+
+#[cfg(test)]
+static LIVE_WORKERS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+impl BytesReader {
+    /// Open a file for a mostly sequential parse: while the current block of
+    /// `block_size` bytes is parsed, the next one is read on a helper thread.
+    ///
+    /// Reading before the current block, e.g. after seeking back to a
+    /// header, stops the read-ahead for good and the file is read block by
+    /// block on demand. The helper thread ends when the reader and all its
+    /// clones are dropped.
+    pub fn open_with_prefetch<P: AsRef<Path>>(path: P, block_size: usize) -> KResult<BytesReader> {
+        let backend = Prefetch::new(File::open(&path)?, File::open(&path)?, block_size)?;
+        let backend: Box<dyn ReadSeek> = Box::new(backend);
+        let name = path.as_ref().display().to_string();
+        Ok(BytesReader::try_from(backend)?.with_name(&name))
+    }
+}
+
+struct Prefetch {
+    file: File,
+    size: u64,
+    pos: u64,
+    block_size: usize,
+    // the block containing the last read
+    start: u64,
+    data: Vec<u8>,
+    ahead: Option<Worker>,
+}
+
+impl Prefetch {
+    // `ahead` is a second handle of `file` for the helper thread, so that
+    // they don't share a file position
+    fn new(file: File, ahead: File, block_size: usize) -> io::Result<Prefetch> {
+        let mut file = file;
+        let block_size = block_size.max(1);
+        Ok(Prefetch {
+            size: file.seek(SeekFrom::End(0))?,
+            file,
+            pos: 0,
+            block_size,
+            start: 0,
+            data: vec![],
+            ahead: Some(Worker::spawn(ahead, block_size)?),
+        })
+    }
+
+    fn load(&mut self, start: u64) -> io::Result<()> {
+        let fetched = self.ahead.as_mut().and_then(|w| w.take(start));
+        self.data = match fetched {
+            Some(res) => res?,
+            None => read_block(&mut self.file, start, self.block_size)?,
+        };
+        self.start = start;
+        let next = start + self.block_size as u64;
+        if let Some(w) = &mut self.ahead {
+            if next < self.size {
+                w.request(next);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Prefetch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = self.start + self.data.len() as u64;
+        if self.pos < self.start || self.pos >= end {
+            let start = self.pos - self.pos % self.block_size as u64;
+            if start < self.start {
+                // not a sequential parse, stop reading ahead
+                self.ahead = None;
+            }
+            self.load(start)?;
+        }
+        let skip = (self.pos - self.start) as usize;
+        if skip >= self.data.len() {
+            return Ok(0);
+        }
+        let n = std::cmp::min(buf.len(), self.data.len() - skip);
+        buf[..n].copy_from_slice(&self.data[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Prefetch {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = resolve_seek(pos, self.pos, || {
+            self.size = self.file.seek(SeekFrom::End(0))?;
+            Ok(self.size)
+        })?;
+        Ok(self.pos)
+    }
+}
+
+fn read_block(file: &mut File, start: u64, len: usize) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// the helper thread, with at most one block requested at a time
+struct Worker {
+    requests: Option<Sender<u64>>,
+    blocks: Receiver<io::Result<Vec<u8>>>,
+    pending: Option<u64>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(file: File, block_size: usize) -> io::Result<Worker> {
+        let (requests, rx) = mpsc::channel::<u64>();
+        let (tx, blocks) = mpsc::channel();
+        #[cfg(test)]
+        LIVE_WORKERS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let thread = std::thread::Builder::new()
+            .name("kaitai-prefetch".to_string())
+            .spawn(move || {
+                let mut file = file;
+                // ends when the reader drops its sender
+                for start in rx {
+                    if tx.send(read_block(&mut file, start, block_size)).is_err() {
+                        break;
+                    }
+                }
+                #[cfg(test)]
+                LIVE_WORKERS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            })?;
+        Ok(Worker {
+            requests: Some(requests),
+            blocks,
+            pending: None,
+            thread: Some(thread),
+        })
+    }
+
+    fn request(&mut self, start: u64) {
+        if let Some(requests) = &self.requests {
+            if requests.send(start).is_ok() {
+                self.pending = Some(start);
+            }
+        }
+    }
+
+    // the block at `start` if it was requested; a different pending block
+    // is discarded
+    fn take(&mut self, start: u64) -> Option<io::Result<Vec<u8>>> {
+        let pending = self.pending.take()?;
+        let block = self.blocks.recv().ok()?;
+        if pending == start {
+            Some(block)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.requests = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testkit::{verify_fixture, Expectation, FixtureBuilder},
+        KError, KStream,
+    };
+    use std::sync::{atomic::Ordering, Mutex};
+
+    // tests that count the helper threads must not overlap
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn live_workers() -> usize {
+        LIVE_WORKERS.load(Ordering::SeqCst)
+    }
+
+    // length-prefixed runs with bit fields and padding, some 100 kB
+    fn fixture_data() -> (Vec<u8>, Vec<Expectation>) {
+        let mut builder = FixtureBuilder::new();
+        for i in 0..700u32 {
+            let len = (i.wrapping_mul(2_654_435_761) >> 26) as u8;
+            let bytes: Vec<u8> = (0..len).map(|j| (i * 7) as u8 ^ j).collect();
+            builder = builder
+                .u1(len)
+                .bytes(&bytes)
+                .u4le(i.wrapping_mul(40_503))
+                .bits_be(13, u64::from(i))
+                .pad(usize::from(len) * 3, i as u8)
+                .f8be(f64::from(i) / 3.0);
+        }
+        builder.build()
+    }
+
+    fn fixture() -> tempfile::NamedTempFile {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut f, &fixture_data().0).unwrap();
+        f
+    }
+
+    #[test]
+    fn same_results() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let f = fixture();
+        let (data, expectations) = fixture_data();
+        assert!(data.len() > 90_000);
+        for block_size in [0, 1, 7, 4096, 1 << 20] {
+            let io = BytesReader::open_with_prefetch(f.path(), block_size).unwrap();
+            assert_eq!(io.size(), data.len());
+            let res = verify_fixture(&io, &expectations);
+            assert_eq!(res, Ok(()), "block size {}", block_size);
+            // and again after jumping back
+            let res = verify_fixture(&io, &expectations);
+            assert_eq!(res, Ok(()), "block size {}", block_size);
+            io.seek(data.len() - 10).unwrap();
+            assert_eq!(io.read_bytes(20), Err(KError::eof(20, 10)));
+            assert_eq!(io.read_bytes_full().unwrap(), data[data.len() - 10..]);
+        }
+    }
+
+    #[test]
+    fn threads_are_shut_down() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let f = fixture();
+        for _ in 0..50 {
+            let io = BytesReader::open_with_prefetch(f.path(), 1024).unwrap();
+            let clone = KStream::clone(&io);
+            io.read_bytes(3000).unwrap();
+            drop(io);
+            // the clone keeps the helper thread
+            assert_eq!(live_workers(), 1);
+            assert_eq!(clone.read_bytes(3000).unwrap().len(), 3000);
+        }
+        assert_eq!(live_workers(), 0);
+
+        // reading before the current block stops the read-ahead
+        let io = BytesReader::open_with_prefetch(f.path(), 1024).unwrap();
+        io.seek(5000).unwrap();
+        io.read_u1().unwrap();
+        assert_eq!(live_workers(), 1);
+        io.seek(1000).unwrap();
+        io.read_u1().unwrap();
+        assert_eq!(live_workers(), 0);
+        io.seek(90_000).unwrap();
+        io.read_u1().unwrap();
+        assert_eq!(live_workers(), 0);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{KError, KResult, KStream};
+
+/// Order of the bits within each byte of a bit field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    /// From the most significant bit, as read by
+    /// [`KStream::read_bits_int_be`].
+    Be,
+    /// From the least significant bit, as read by
+    /// [`KStream::read_bits_int_le`].
+    Le,
+}
+
+/// Bitmap telling which of a number of optional fields follow it, bit `i`
+/// for field `i`, counted in the order the bit readers return the bits.
+/// Generated `if` expressions can test the fields with
+/// [`is_set`](Self::is_set), and consumers get the same answers from the
+/// parsed bitmap.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PresenceBitmap {
+    // bit `i` is bit `i % 8` of byte `i / 8`
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl PresenceBitmap {
+    /// The first `n_bits` bits of `bytes` in `order`. Fails with
+    /// [`KError::EofBits`] if `bytes` is shorter.
+    pub fn from_bytes(bytes: &[u8], n_bits: usize, order: BitOrder) -> KResult<Self> {
+        if n_bits as u64 > bytes.len() as u64 * 8 {
+            return Err(KError::eof_bits(n_bits, bytes.len() as u64 * 8, 0));
+        }
+        let mut bits: Vec<u8> = bytes[..n_bits.div_ceil(8)]
+            .iter()
+            .map(|&b| match order {
+                BitOrder::Be => b.reverse_bits(),
+                BitOrder::Le => b,
+            })
+            .collect();
+        if let Some(last) = bits.last_mut().filter(|_| !n_bits.is_multiple_of(8)) {
+            *last &= (1 << (n_bits % 8)) - 1;
+        }
+        Ok(PresenceBitmap { bits, len: n_bits })
+    }
+
+    /// Read `n_bits` bits in `order` with a single read of the bytes they
+    /// cover, so that bit reads continue after the bitmap as after the bit
+    /// reader for `order`.
+    pub fn read<S: KStream + ?Sized>(io: &S, n_bits: usize, order: BitOrder) -> KResult<Self> {
+        // the bits as a big-endian number, whose least significant bit is
+        // the last one read in big-endian order and the first one in
+        // little-endian order
+        let number = match order {
+            BitOrder::Be => io.read_bits_bytes_be(n_bits)?,
+            BitOrder::Le => io.read_bits_bytes_le(n_bits)?,
+        };
+        let mut bits = vec![0; n_bits.div_ceil(8)];
+        for i in 0..n_bits {
+            let k = match order {
+                BitOrder::Be => n_bits - 1 - i,
+                BitOrder::Le => i,
+            };
+            bits[i / 8] |= (number[number.len() - 1 - k / 8] >> (k % 8) & 1) << (i % 8);
+        }
+        Ok(PresenceBitmap { bits, len: n_bits })
+    }
+
+    /// Number of bits, set or not.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether field `index` is present. Fails with
+    /// [`KError::ValueOutOfRange`] if the bitmap has no such bit.
+    pub fn is_set(&self, index: usize) -> KResult<bool> {
+        if index >= self.len {
+            return Err(KError::value_out_of_range(
+                index as u64,
+                format!("index into a presence bitmap of {} bits", self.len),
+            ));
+        }
+        Ok(self.bits[index / 8] & 1 << (index % 8) != 0)
+    }
+
+    pub fn count_set(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Indices of the set bits, in increasing order.
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(move |&i| self.bits[i / 8] & 1 << (i % 8) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BytesReader;
+
+    #[test]
+    fn bit_orders() {
+        let bytes = [0b1010_0001, 0b0000_0110];
+        let be = PresenceBitmap::from_bytes(&bytes, 16, BitOrder::Be).unwrap();
+        assert_eq!(be.iter_set().collect::<Vec<_>>(), [0, 2, 7, 13, 14]);
+        let le = PresenceBitmap::from_bytes(&bytes, 16, BitOrder::Le).unwrap();
+        assert_eq!(le.iter_set().collect::<Vec<_>>(), [0, 5, 7, 9, 10]);
+        assert_eq!(be.count_set(), 5);
+        assert_eq!(le.count_set(), 5);
+        assert!(be.is_set(2).unwrap());
+        assert!(!le.is_set(2).unwrap());
+
+        // bits past `n_bits` do not count
+        let part = PresenceBitmap::from_bytes(&bytes, 10, BitOrder::Le).unwrap();
+        assert_eq!(part.len(), 10);
+        assert_eq!(part.iter_set().collect::<Vec<_>>(), [0, 5, 7, 9]);
+        assert_eq!(part.count_set(), 4);
+        let part = PresenceBitmap::from_bytes(&bytes, 3, BitOrder::Be).unwrap();
+        assert_eq!(part.iter_set().collect::<Vec<_>>(), [0, 2]);
+
+        let empty = PresenceBitmap::from_bytes(&[], 0, BitOrder::Be).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.count_set(), 0);
+        assert!(PresenceBitmap::from_bytes(&bytes, 17, BitOrder::Be)
+            .unwrap_err()
+            .is_eof());
+    }
+
+    #[test]
+    fn out_of_range() {
+        let bitmap = PresenceBitmap::from_bytes(&[0xff], 5, BitOrder::Le).unwrap();
+        assert!(bitmap.is_set(4).unwrap());
+        assert_eq!(
+            bitmap.is_set(5).unwrap_err(),
+            KError::ValueOutOfRange {
+                value: 5,
+                context: "index into a presence bitmap of 5 bits".to_string()
+            }
+        );
+        assert!(PresenceBitmap::default().is_set(0).is_err());
+    }
+
+    #[test]
+    fn read_like_bit_readers() {
+        let data = [0x5c, 0xe3, 0x19, 0x80];
+        for order in [BitOrder::Be, BitOrder::Le] {
+            for n in [0, 1, 7, 8, 13, 29] {
+                let io = BytesReader::from(data.to_vec());
+                // after a partial byte
+                io.read_bits_int_be(3).unwrap();
+                let bitmap = PresenceBitmap::read(&io, n, order).unwrap();
+                let reference = BytesReader::from(data.to_vec());
+                reference.read_bits_int_be(3).unwrap();
+                for i in 0..n {
+                    let bit = match order {
+                        BitOrder::Be => reference.read_bits_int_be(1),
+                        BitOrder::Le => reference.read_bits_int_le(1),
+                    };
+                    assert_eq!(bitmap.is_set(i).unwrap(), bit.unwrap() == 1);
+                }
+                assert_eq!(io.pos(), reference.pos());
+                assert_eq!(io.bit_state(), reference.bit_state());
+            }
+        }
+
+        // the same bitmap from the bytes
+        for order in [BitOrder::Be, BitOrder::Le] {
+            let io = BytesReader::from(data.to_vec());
+            assert_eq!(
+                PresenceBitmap::read(&io, 27, order).unwrap(),
+                PresenceBitmap::from_bytes(&data, 27, order).unwrap()
+            );
+        }
+        // wider than a single bit read
+        let wide: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(37)).collect();
+        for order in [BitOrder::Be, BitOrder::Le] {
+            let io = BytesReader::from(wide.clone());
+            assert_eq!(
+                PresenceBitmap::read(&io, 300, order).unwrap(),
+                PresenceBitmap::from_bytes(&wide, 300, order).unwrap()
+            );
+            assert_eq!(io.pos(), 38);
+        }
+        let io = BytesReader::from(vec![0xff]);
+        assert!(PresenceBitmap::read(&io, 9, BitOrder::Le)
+            .unwrap_err()
+            .is_eof());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{resolve_seek, BytesReader, KResult, ReadSeek};
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// Backend with positioned reads, for data sources where every access is
+/// expensive, e.g. files on a server. Used through
+/// [`BytesReader::from_read_at`].
+pub trait ReadAt {
+    /// Total length of the data.
+    fn size(&mut self) -> io::Result<u64>;
+
+    /// Read into `buf` starting at `offset`, returning the number of bytes
+    /// read. Returns 0 only at the end of the data.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl BytesReader {
+    pub fn from_read_at<R: ReadAt + 'static>(backend: R) -> KResult<BytesReader> {
+        let mut inner = backend;
+        let size = inner.size()?;
+        let cursor: Box<dyn ReadSeek> = Box::new(ReadAtCursor {
+            inner,
+            size,
+            pos: 0,
+        });
+        BytesReader::try_from(cursor)
+    }
+}
+
+// `Read` + `Seek` on top of `ReadAt`, seeking is free
+struct ReadAtCursor<R> {
+    inner: R,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: ReadAt> Read for ReadAtCursor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.size.saturating_sub(self.pos);
+        let max = std::cmp::min(buf.len() as u64, left) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read_at(self.pos, &mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for ReadAtCursor<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = resolve_seek(pos, self.pos, || Ok(self.size))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KStream;
+    use std::{cell::Cell, rc::Rc};
+
+    // serves at most 3 bytes per call, counting the calls
+    struct Chunky {
+        data: Vec<u8>,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl ReadAt for Chunky {
+        fn size(&mut self) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls.set(self.calls.get() + 1);
+            let src = &self.data[offset as usize..];
+            let n = buf.len().min(src.len()).min(3);
+            buf[..n].copy_from_slice(&src[..n]);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn positioned_backend() {
+        let calls = Rc::new(Cell::new(0));
+        let reader = BytesReader::from_read_at(Chunky {
+            data: (0..20).collect(),
+            calls: calls.clone(),
+        })
+        .unwrap();
+        assert_eq!(reader.size(), 20);
+        assert_eq!(calls.get(), 0);
+        reader.seek(10).unwrap();
+        assert_eq!(reader.read_bytes(5).unwrap(), [10, 11, 12, 13, 14]);
+        assert_eq!(calls.get(), 2);
+        assert!(reader.read_bytes(6).unwrap_err().is_eof());
+        assert_eq!(reader.read_bytes_full().unwrap(), [15, 16, 17, 18, 19]);
+    }
+}
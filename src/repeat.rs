@@ -0,0 +1,335 @@
+//! The `repeat` modes of Kaitai Struct. `parse` is called with the stream
+//! and the index of the element to read (`_index` in expressions).
+
+use crate::{KError, KResult, KStream, KStruct, OptRc, SharedType};
+use std::any::Any;
+
+/// `repeat: expr`, exactly `count` elements.
+pub fn read_repeat_expr<T, S: KStream>(
+    io: &S,
+    count: usize,
+    mut parse: impl FnMut(&S, usize) -> KResult<OptRc<T>>,
+) -> KResult<Vec<OptRc<T>>> {
+    let mut res = Vec::with_capacity(capacity(io, count));
+    for i in 0..count {
+        res.push(parse(io, i)?);
+    }
+    Ok(res)
+}
+
+/// `repeat: eos`, elements up to the end of the stream. An element that
+/// consumes nothing, neither bytes nor bits, would repeat forever and is
+/// rejected with [`KError::InvalidInput`].
+pub fn read_repeat_eos<T, S: KStream>(
+    io: &S,
+    mut parse: impl FnMut(&S, usize) -> KResult<OptRc<T>>,
+) -> KResult<Vec<OptRc<T>>> {
+    let mut res = vec![];
+    while !io.is_eof() {
+        let start = (io.pos(), io.bit_state());
+        res.push(parse(io, res.len())?);
+        if (io.pos(), io.bit_state()) == start {
+            return Err(KError::invalid_input(
+                start.0,
+                format!("repeated element {} consumed nothing", res.len() - 1),
+            ));
+        }
+    }
+    Ok(res)
+}
+
+/// `repeat: until`: elements up to and including the first one for which
+/// `done(_, _index)` holds. At least one element is read, the condition is
+/// only checked after an element has been parsed. If the stream ends first,
+/// the error is a [`KError::RepeatUntilEof`] with the number of elements
+/// read so far.
+pub fn read_repeat_until<T, S: KStream>(
+    io: &S,
+    mut parse: impl FnMut(&S, usize) -> KResult<OptRc<T>>,
+    mut done: impl FnMut(&T, usize) -> KResult<bool>,
+) -> KResult<Vec<OptRc<T>>> {
+    let mut res = vec![];
+    loop {
+        let i = res.len();
+        let el = parse(io, i).map_err(|e| match e {
+            e if e.is_eof() => KError::RepeatUntilEof {
+                parsed: i,
+                source: Box::new(e),
+            },
+            e => e,
+        })?;
+        let stop = done(&el, i)?;
+        res.push(el);
+        if stop {
+            return Ok(res);
+        }
+    }
+}
+
+/// `repeat: eos` without collecting: the elements are parsed one per call
+/// of `next`, so only those the caller keeps stay in memory.
+///
+/// A failing element is reported and the next call continues after whatever
+/// it consumed; it is up to the caller whether to go on. Iteration ends at
+/// the end of the stream, or after an element that consumed nothing, neither
+/// bytes nor bits, as repeating it could not make progress.
+///
+/// Since the elements are not collected, nothing links them to `parent`:
+/// instances that refer to sibling elements (`_parent.entries[i]`) cannot be
+/// evaluated on streamed elements.
+pub fn iter_repeat_eos<'a, T, S>(
+    io: &'a S,
+    root: Option<SharedType<T::Root>>,
+    parent: Option<SharedType<T::Parent>>,
+) -> RepeatEos<'a, T, S>
+where
+    T: KStruct + Default + Any,
+    S: KStream,
+{
+    RepeatEos {
+        io,
+        root,
+        parent,
+        done: false,
+    }
+}
+
+/// Iterator returned by [`iter_repeat_eos`].
+pub struct RepeatEos<'a, T: KStruct, S> {
+    io: &'a S,
+    root: Option<SharedType<T::Root>>,
+    parent: Option<SharedType<T::Parent>>,
+    done: bool,
+}
+
+impl<T, S> Iterator for RepeatEos<'_, T, S>
+where
+    T: KStruct + Default + Any,
+    S: KStream,
+{
+    type Item = KResult<OptRc<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.io.is_eof() {
+            return None;
+        }
+        let start = (self.io.pos(), self.io.bit_state());
+        let res = T::read_into::<S, T>(self.io, self.root.clone(), self.parent.clone());
+        self.done = (self.io.pos(), self.io.bit_state()) == start;
+        Some(res)
+    }
+}
+
+// a corrupt count must not allocate upfront: every element takes at least
+// a byte, except for the rare zero-sized ones which grow the vector as needed
+fn capacity<S: KStream>(io: &S, count: usize) -> usize {
+    count.min(io.remaining())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BytesReader;
+
+    #[derive(Debug)]
+    struct Rec {
+        len: u8,
+        body: Vec<u8>,
+    }
+
+    fn rec(io: &BytesReader, _index: usize) -> KResult<OptRc<Rec>> {
+        let len = io.read_u1()?;
+        let body = io.read_bytes(len.into())?;
+        Ok(OptRc::from(Rec { len, body }))
+    }
+
+    fn lens(v: &[OptRc<Rec>]) -> Vec<u8> {
+        v.iter().map(|r| r.len).collect()
+    }
+
+    #[test]
+    fn until_reads_at_least_one() {
+        let io = BytesReader::from(vec![1, 9, 0, 2, 7, 7]);
+        let res = read_repeat_until(&io, rec, |_, _| Ok(true)).unwrap();
+        assert_eq!(lens(&res), [1]);
+        assert_eq!(res[0].body, [9]);
+        assert_eq!(io.pos(), 2);
+
+        // `_` is the element just read, `_index` its index
+        let mut seen = vec![];
+        let res = read_repeat_until(&io, rec, |r, i| {
+            seen.push(i);
+            Ok(r.len == 2)
+        })
+        .unwrap();
+        assert_eq!(lens(&res), [0, 2]);
+        assert_eq!(seen, [0, 1]);
+        assert!(io.is_eof());
+    }
+
+    #[test]
+    fn until_eof() {
+        // the stream ends between elements
+        let io = BytesReader::from(vec![1, 9, 0]);
+        match read_repeat_until(&io, rec, |r, _| Ok(r.len == 5)).unwrap_err() {
+            KError::RepeatUntilEof { parsed, source } => {
+                assert_eq!(parsed, 2);
+                assert_eq!(*source, KError::eof(1, 0));
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // the stream ends within an element
+        let io = BytesReader::from(vec![0, 4, 1, 2]);
+        let err = read_repeat_until(&io, rec, |_, _| Ok(false)).unwrap_err();
+        assert!(err.is_eof());
+        assert!(matches!(err, KError::RepeatUntilEof { parsed: 1, .. }));
+
+        // other errors of the element or the condition are passed on
+        let err = read_repeat_until(&io, rec, |_, _| Err(KError::CastError));
+        assert_eq!(err.unwrap_err(), KError::CastError);
+    }
+
+    #[test]
+    fn eos() {
+        let io = BytesReader::from(vec![1, 9, 0, 2, 7, 7]);
+        assert_eq!(lens(&read_repeat_eos(&io, rec).unwrap()), [1, 0, 2]);
+        assert!(read_repeat_eos(&io, rec).unwrap().is_empty());
+
+        let io = BytesReader::from(vec![1, 9, 3]);
+        assert!(read_repeat_eos(&io, rec).unwrap_err().is_eof());
+
+        let io = BytesReader::from(vec![1]);
+        let stuck = |_: &BytesReader, _| {
+            Ok(OptRc::from(Rec {
+                len: 0,
+                body: vec![],
+            }))
+        };
+        assert_eq!(
+            read_repeat_eos(&io, stuck).unwrap_err().category(),
+            crate::ErrorCategory::InvalidInput
+        );
+        // also with bits of a byte left to read
+        io.read_bits_int_be(3).unwrap();
+        assert_eq!(
+            read_repeat_eos(&io, stuck).unwrap_err().category(),
+            crate::ErrorCategory::InvalidInput
+        );
+
+        // elements of bits make progress within a byte
+        let io = BytesReader::from(vec![0b1011_0000]);
+        let nibble = |io: &BytesReader, _| {
+            let len = io.read_bits_int_be(4)? as u8;
+            Ok(OptRc::from(Rec { len, body: vec![] }))
+        };
+        assert_eq!(lens(&read_repeat_eos(&io, nibble).unwrap()), [0b1011, 0]);
+    }
+
+    #[test]
+    fn expr() {
+        let io = BytesReader::from(vec![1, 9, 0, 2, 7, 7]);
+        let mut indices = vec![];
+        let res = read_repeat_expr(&io, 2, |io, i| {
+            indices.push(i);
+            rec(io, i)
+        })
+        .unwrap();
+        assert_eq!(lens(&res), [1, 0]);
+        assert_eq!(indices, [0, 1]);
+        assert!(read_repeat_expr(&io, 0, rec).unwrap().is_empty());
+
+        // a huge count fails at the end of the data, without allocating first
+        let io = BytesReader::from(vec![0; 3]);
+        assert!(read_repeat_expr(&io, usize::MAX, rec).unwrap_err().is_eof());
+    }
+
+    #[derive(Default, Debug)]
+    struct Item {
+        value: std::cell::RefCell<u16>,
+    }
+
+    impl KStruct for Item {
+        type Root = Item;
+        type Parent = crate::KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            let value = _io.read_u2le()?;
+            if value == 0xffff {
+                return Err(KError::validation_failed(
+                    crate::ValidationKind::NotEqual,
+                    "/seq/0",
+                ));
+            }
+            *self_rc.value.borrow_mut() = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn iter_eos_streams() {
+        let n = 500_000u32;
+        let data: Vec<u8> = (0..n)
+            .flat_map(|i| (i as u16 % 1000).to_le_bytes())
+            .collect();
+        let io = BytesReader::from(data);
+        let mut first = None;
+        let mut sum = 0u64;
+        for item in iter_repeat_eos::<Item, _>(&io, None, None) {
+            let item = item.unwrap();
+            first.get_or_insert_with(|| std::rc::Rc::downgrade(&item.get()));
+            sum += u64::from(*item.value.borrow());
+        }
+        let expected: u64 = (0..n).map(|i| u64::from(i as u16 % 1000)).sum();
+        assert_eq!(sum, expected);
+        // elements are not kept alive by the iteration
+        assert!(first.unwrap().upgrade().is_none());
+    }
+
+    #[test]
+    fn iter_eos_errors() {
+        let io = BytesReader::from(vec![1, 0, 0xff, 0xff, 3, 0, 4]);
+        let res: Vec<_> = iter_repeat_eos::<Item, _>(&io, None, None)
+            .map(|r| r.map(|item| *item.value.borrow()))
+            .collect();
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[0], Ok(1));
+        assert!(matches!(res[1], Err(KError::ValidationFailed(_))));
+        assert_eq!(res[2], Ok(3));
+        assert!(res[3].as_ref().unwrap_err().is_eof());
+        assert_eq!(io.pos(), 6);
+    }
+
+    #[derive(Default, Debug)]
+    struct Nothing;
+
+    impl KStruct for Nothing {
+        type Root = Nothing;
+        type Parent = crate::KStructUnit;
+
+        fn read<S: KStream>(
+            _self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn iter_eos_stuck() {
+        // with bits of a byte left to read, so not at the end either
+        let io = BytesReader::from(vec![0xff, 0]);
+        io.read_bits_int_be(3).unwrap();
+        let res: Vec<_> = iter_repeat_eos::<Nothing, _>(&io, None, None).collect();
+        assert_eq!(res.len(), 1);
+        assert!(res[0].is_ok());
+        assert_eq!((io.pos(), io.bit_state().bits_left), (1, 5));
+    }
+}
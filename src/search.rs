@@ -0,0 +1,190 @@
+/// Whether `needle` occurs in `haystack`, see [`bytes_find`].
+pub fn bytes_contains(haystack: &[u8], needle: &[u8]) -> bool {
+    bytes_find(haystack, needle).is_some()
+}
+
+/// Index of the first occurrence of `needle` in `haystack`. An empty
+/// `needle` is found at 0.
+///
+/// The search is the two-way algorithm, linear in the length of both
+/// whatever their contents, so that e.g. looking for `aaa…ab` in a run of
+/// `a`s does not take quadratic time.
+///
+/// ```
+/// # use kaitai::*;
+/// assert_eq!(bytes_find(b"\x00\x00PK\x03\x04", b"PK\x03\x04"), Some(2));
+/// assert_eq!(bytes_find(b"PK\x03", b"PK\x03\x04"), None);
+/// ```
+pub fn bytes_find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    match needle.len() {
+        0 => Some(0),
+        n if n > haystack.len() => None,
+        1 => haystack.iter().position(|&b| b == needle[0]),
+        _ => two_way(haystack, needle),
+    }
+}
+
+pub fn bytes_starts_with(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.starts_with(prefix)
+}
+
+pub fn bytes_ends_with(bytes: &[u8], suffix: &[u8]) -> bool {
+    bytes.ends_with(suffix)
+}
+
+// Crochemore and Perrin, "Two-way string-matching": `needle` is split at a
+// critical position, the right part is compared left to right, then the
+// left part right to left, and on a mismatch the needle moves by what the
+// comparison proved cannot match.
+fn two_way(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let n = needle.len();
+    let (crit_lt, period_lt) = maximal_suffix(needle, false);
+    let (crit_gt, period_gt) = maximal_suffix(needle, true);
+    let (crit, period) = if crit_lt > crit_gt {
+        (crit_lt, period_lt)
+    } else {
+        (crit_gt, period_gt)
+    };
+
+    // a needle periodic with `period` keeps what is known to match after a
+    // shift by the period in `memory`; otherwise no shift is shorter than
+    // the longer part
+    let periodic = crit + period <= n && needle[..crit] == needle[period..period + crit];
+    let period = if periodic {
+        period
+    } else {
+        crit.max(n - crit) + 1
+    };
+    let mut memory = 0;
+    let mut pos = 0;
+    while pos + n <= haystack.len() {
+        let window = &haystack[pos..pos + n];
+        let start = crit.max(memory);
+        if let Some(i) = (start..n).find(|&i| needle[i] != window[i]) {
+            pos += i - crit + 1;
+            memory = 0;
+            continue;
+        }
+        let end = if periodic { memory } else { 0 };
+        if (end..crit).rev().all(|i| needle[i] == window[i]) {
+            return Some(pos);
+        }
+        pos += period;
+        if periodic {
+            memory = n - period;
+        }
+    }
+    None
+}
+
+// start and period of the maximal suffix of `needle`, in lexicographic
+// order, or the reverse order if `reversed`
+fn maximal_suffix(needle: &[u8], reversed: bool) -> (usize, usize) {
+    let mut left = 0;
+    let mut right = 1;
+    let mut offset = 0;
+    let mut period = 1;
+    while right + offset < needle.len() {
+        let a = needle[right + offset];
+        let b = needle[left + offset];
+        if (a < b && !reversed) || (a > b && reversed) {
+            // the suffix at `right` is smaller, the period is all so far
+            right += offset + 1;
+            offset = 0;
+            period = right - left;
+        } else if a == b {
+            if offset + 1 == period {
+                right += offset + 1;
+                offset = 0;
+            } else {
+                offset += 1;
+            }
+        } else {
+            // the suffix at `right` is larger, start over from it
+            left = right;
+            right += 1;
+            offset = 0;
+            period = 1;
+        }
+    }
+    (left, period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        if needle.is_empty() {
+            return Some(0);
+        }
+        haystack.windows(needle.len()).position(|w| w == needle)
+    }
+
+    #[test]
+    fn find() {
+        assert_eq!(bytes_find(b"", b""), Some(0));
+        assert_eq!(bytes_find(b"abc", b""), Some(0));
+        assert_eq!(bytes_find(b"", b"a"), None);
+        assert_eq!(bytes_find(b"ab", b"abc"), None);
+        assert_eq!(bytes_find(b"abc", b"abc"), Some(0));
+        assert_eq!(bytes_find(b"xxabc", b"abc"), Some(2));
+        assert_eq!(bytes_find(b"abxabcab", b"abc"), Some(3));
+        assert_eq!(bytes_find(b"aabaabaaab", b"aaab"), Some(6));
+        assert_eq!(bytes_find(b"abababac", b"ababac"), Some(2));
+        assert_eq!(bytes_find(b"\r\n\r\r\n\r\n", b"\r\n\r\n"), Some(3));
+        assert!(bytes_contains(b"\x00MZ\x90\x00", b"MZ\x90"));
+        assert!(!bytes_contains(b"\x00MZ\x91\x00", b"MZ\x90"));
+
+        assert!(bytes_starts_with(b"PK\x03\x04", b"PK"));
+        assert!(bytes_starts_with(b"PK", b""));
+        assert!(!bytes_starts_with(b"P", b"PK"));
+        assert!(bytes_ends_with(b"data\r\n", b"\r\n"));
+        assert!(!bytes_ends_with(b"\n", b"\r\n"));
+    }
+
+    #[test]
+    fn against_naive() {
+        // every needle of up to 5 bytes over a two letter alphabet, in
+        // haystacks built from the same letters
+        let haystacks: Vec<Vec<u8>> = (0..1u32 << 12)
+            .step_by(7)
+            .map(|h| (0..12).map(|i| b'a' + (h >> i & 1) as u8).collect())
+            .collect();
+        for len in 1..=5 {
+            for bits in 0..1u32 << len {
+                let needle: Vec<u8> = (0..len).map(|i| b'a' + (bits >> i & 1) as u8).collect();
+                for haystack in &haystacks {
+                    assert_eq!(
+                        bytes_find(haystack, &needle),
+                        naive(haystack, &needle),
+                        "{:?} in {:?}",
+                        String::from_utf8_lossy(&needle),
+                        String::from_utf8_lossy(haystack)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn repetitive() {
+        // quadratic for a naive search: each position matches all but the
+        // last byte of the needle
+        let haystack = vec![b'a'; 1 << 20];
+        let mut needle = vec![b'a'; 1 << 16];
+        needle.push(b'b');
+        assert_eq!(bytes_find(&haystack, &needle), None);
+
+        let mut haystack = haystack;
+        haystack.extend_from_slice(&needle);
+        assert_eq!(
+            bytes_find(&haystack, &needle),
+            Some(haystack.len() - needle.len())
+        );
+        // the same for a needle with a periodic left part
+        let needle: Vec<u8> = b"ab".repeat(1 << 15).into_iter().chain(*b"c").collect();
+        let haystack = b"ab".repeat(1 << 19);
+        assert!(!bytes_contains(&haystack, &needle));
+    }
+}
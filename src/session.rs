@@ -0,0 +1,405 @@
+use crate::{BytesReader, KError, KResult, KStream, KStruct, MemoryAccountant, OptRc};
+use std::{any::Any, cell::RefCell, fmt, path::Path, rc::Rc};
+
+/// Non-fatal finding reported while parsing, see [`DiagnosticSink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Stream position at the time of the report.
+    pub offset: usize,
+    pub message: String,
+}
+
+/// Receives the [`Diagnostic`]s of a [`ParseSession`], in the order the
+/// parse reports them.
+pub trait DiagnosticSink {
+    fn report(&self, d: Diagnostic);
+}
+
+/// Collects diagnostics in order of reporting.
+impl DiagnosticSink for RefCell<Vec<Diagnostic>> {
+    fn report(&self, d: Diagnostic) {
+        self.borrow_mut().push(d);
+    }
+}
+
+/// [`Diagnostic`] numbered in the order it was reported, see
+/// [`DiagnosticLog`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequencedDiagnostic {
+    /// Number of diagnostics reported before this one.
+    pub seq: u64,
+    pub diagnostic: Diagnostic,
+}
+
+/// Sink numbering the diagnostics it receives, so that runs can be compared
+/// in a fixed order: the same parse of the same data gives the same
+/// sequence.
+#[derive(Debug, Default)]
+pub struct DiagnosticLog {
+    entries: RefCell<Vec<SequencedDiagnostic>>,
+}
+
+impl DiagnosticLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The diagnostics in the order they were reported.
+    pub fn in_parse_order(&self) -> Vec<SequencedDiagnostic> {
+        self.entries.borrow().clone()
+    }
+
+    /// The diagnostics by stream offset, those at the same offset in the
+    /// order they were reported.
+    pub fn sorted_by_offset(&self) -> Vec<SequencedDiagnostic> {
+        let mut res = self.in_parse_order();
+        res.sort_by_key(|d| (d.diagnostic.offset, d.seq));
+        res
+    }
+}
+
+impl DiagnosticSink for DiagnosticLog {
+    fn report(&self, d: Diagnostic) {
+        let mut entries = self.entries.borrow_mut();
+        let seq = entries.len() as u64;
+        entries.push(SequencedDiagnostic { seq, diagnostic: d });
+    }
+}
+
+#[derive(Default)]
+struct SessionOptions {
+    max_alloc: Option<usize>,
+    strict_bits: bool,
+    strict_terminators: bool,
+    strict_consumption: bool,
+    diagnostics: Option<Rc<dyn DiagnosticSink>>,
+    memory_accountant: Option<Rc<MemoryAccountant>>,
+}
+
+/// Settings shared by all readers of one parse. Readers created through the
+/// session carry it along, and so do their clones and substreams, so that a
+/// limit configured once applies to the whole tree.
+///
+/// ```
+/// # use kaitai::*;
+/// let session = ParseSession::builder().max_alloc(16).build();
+/// let reader = session.reader_from_bytes(vec![0; 64]);
+/// let sub = reader.substream(32);
+/// assert!(matches!(
+///     sub.read_bytes(32),
+///     Err(KError::AllocationLimitExceeded { .. })
+/// ));
+/// ```
+#[derive(Clone, Default)]
+pub struct ParseSession(Rc<SessionOptions>);
+
+impl ParseSession {
+    pub fn builder() -> ParseSessionBuilder {
+        ParseSessionBuilder::default()
+    }
+
+    /// Largest buffer a single read may allocate, `None` if unlimited.
+    pub fn max_alloc(&self) -> Option<usize> {
+        self.0.max_alloc
+    }
+
+    /// Whether zero-width bit reads are rejected.
+    pub fn strict_bits(&self) -> bool {
+        self.0.strict_bits
+    }
+
+    /// Whether a terminator search failing at the limit of a substream is an
+    /// error even where the end of the stream is allowed to end the data.
+    pub fn strict_terminators(&self) -> bool {
+        self.0.strict_terminators
+    }
+
+    /// Whether the parse functions of the session fail if the root struct
+    /// leaves input unread.
+    pub fn strict_consumption(&self) -> bool {
+        self.0.strict_consumption
+    }
+
+    /// Pass `d` to the diagnostics sink, if there is one.
+    pub fn report(&self, d: Diagnostic) {
+        if let Some(sink) = &self.0.diagnostics {
+            sink.report(d);
+        }
+    }
+
+    /// Accountant of the allocations made by reads, if there is one.
+    pub fn memory_accountant(&self) -> Option<&Rc<MemoryAccountant>> {
+        self.0.memory_accountant.as_ref()
+    }
+
+    pub fn reader_from_file<T: AsRef<Path>>(&self, path: T) -> KResult<BytesReader> {
+        Ok(BytesReader::open(path)?.with_session(self))
+    }
+
+    pub fn reader_from_bytes(&self, bytes: impl Into<Vec<u8>>) -> BytesReader {
+        BytesReader::from(bytes.into()).with_session(self)
+    }
+
+    /// Parse `bytes` as a root `T`. With
+    /// [`strict_consumption`](ParseSessionBuilder::strict_consumption), bytes
+    /// left over fail the parse with [`KError::TrailingBytes`].
+    pub fn parse_bytes<T: KStruct + Any>(&self, bytes: impl Into<Vec<u8>>) -> KResult<OptRc<T>> {
+        self.parse_root(&self.reader_from_bytes(bytes))
+    }
+
+    /// [`parse_bytes`](Self::parse_bytes) for the contents of the file at
+    /// `path`.
+    pub fn parse_file<T: KStruct + Any>(&self, path: impl AsRef<Path>) -> KResult<OptRc<T>> {
+        self.parse_root(&self.reader_from_file(path)?)
+    }
+
+    /// [`parse_bytes`](Self::parse_bytes) that does not panic for any input,
+    /// see [`parse_bytes_no_panic`](crate::parse_bytes_no_panic).
+    pub fn parse_bytes_no_panic<T: KStruct + Any>(&self, bytes: &[u8]) -> KResult<OptRc<T>> {
+        // only a backstop: the runtime reports its own failures as errors
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.parse_bytes::<T>(bytes)
+        }));
+        res.unwrap_or_else(|payload| {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "unknown panic".to_string(),
+                },
+            };
+            Err(KError::Internal { msg })
+        })
+    }
+
+    fn parse_root<T: KStruct + Any>(&self, io: &BytesReader) -> KResult<OptRc<T>> {
+        let t = T::read_into::<_, T>(io, None, None)?;
+        if self.strict_consumption() {
+            io.expect_fully_consumed()?;
+        }
+        Ok(t)
+    }
+}
+
+impl fmt::Debug for ParseSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParseSession")
+            .field("max_alloc", &self.0.max_alloc)
+            .field("strict_bits", &self.0.strict_bits)
+            .field("strict_terminators", &self.0.strict_terminators)
+            .field("strict_consumption", &self.0.strict_consumption)
+            .field("diagnostics", &self.0.diagnostics.is_some())
+            .field("memory_accountant", &self.0.memory_accountant.is_some())
+            .finish()
+    }
+}
+
+/// Builder for [`ParseSession`].
+#[derive(Default)]
+pub struct ParseSessionBuilder {
+    opts: SessionOptions,
+}
+
+impl ParseSessionBuilder {
+    pub fn max_alloc(mut self, limit: usize) -> Self {
+        self.opts.max_alloc = Some(limit);
+        self
+    }
+
+    pub fn strict_bits(mut self, strict: bool) -> Self {
+        self.opts.strict_bits = strict;
+        self
+    }
+
+    /// Make [`KStream::read_bytes_term`] fail with
+    /// [`KError::NoTerminatorInSubstream`] when it reaches the limit of a
+    /// substream, even with `eos_error` false. Off by default: the limit
+    /// ends the data like the end of the input does.
+    pub fn strict_terminators(mut self, strict: bool) -> Self {
+        self.opts.strict_terminators = strict;
+        self
+    }
+
+    /// Make the parse functions of the session, like
+    /// [`ParseSession::parse_bytes`], check with
+    /// [`KStream::expect_fully_consumed`] that the root struct read all of
+    /// the input. Off by default.
+    pub fn strict_consumption(mut self, strict: bool) -> Self {
+        self.opts.strict_consumption = strict;
+        self
+    }
+
+    pub fn diagnostics(mut self, sink: Rc<dyn DiagnosticSink>) -> Self {
+        self.opts.diagnostics = Some(sink);
+        self
+    }
+
+    pub fn memory_accountant(mut self, accountant: Rc<MemoryAccountant>) -> Self {
+        self.opts.memory_accountant = Some(accountant);
+        self
+    }
+
+    pub fn build(self) -> ParseSession {
+        ParseSession(Rc::new(self.opts))
+    }
+}
+
+// enforce the allocation limit of `io`, if any
+pub(crate) fn check_alloc<S: KStream + ?Sized>(io: &S, len: usize) -> KResult<()> {
+    match io.max_alloc() {
+        Some(limit) if len > limit => Err(KError::AllocationLimitExceeded {
+            requested: len,
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KStream;
+
+    #[test]
+    fn limit_in_nested_substream() {
+        let session = ParseSession::builder().max_alloc(8).build();
+        let reader = session.reader_from_bytes(vec![0; 100]);
+        let sub = reader.substream(50).substream(40).substream(30);
+        assert_eq!(sub.session().unwrap().max_alloc(), Some(8));
+        assert_eq!(sub.read_bytes(8).unwrap().len(), 8);
+        assert_eq!(
+            sub.read_bytes(9).unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: 9,
+                limit: 8
+            }
+        );
+        assert!(sub.read_bytes_full().is_err());
+        assert!(KStream::clone(&sub)
+            .read_bytes_term(1, false, true, false)
+            .is_err());
+        // the terminator itself does not count when it is not included
+        let exact = session.reader_from_bytes([vec![0; 8], vec![7]].concat());
+        assert_eq!(exact.read_bytes_term(7, false, true, true).unwrap(), [0; 8]);
+
+        // without a session nothing is limited
+        let plain = BytesReader::from(vec![0; 100]);
+        assert!(plain.session().is_none());
+        assert_eq!(plain.read_bytes_full().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn strict_bits() {
+        let session = ParseSession::builder().strict_bits(true).build();
+        let reader = session.reader_from_bytes(vec![0xff]);
+        assert!(reader.read_bits_int_be(0).is_err());
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+        assert_eq!(BytesReader::from(vec![]).read_bits_int_le(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let sink = Rc::new(RefCell::new(vec![]));
+        let session = ParseSession::builder().diagnostics(sink.clone()).build();
+        let reader = session.reader_from_bytes(vec![1, 2, 3]);
+        reader.read_u2le().unwrap();
+        reader.substream(1).report("odd padding");
+        assert_eq!(
+            *sink.borrow(),
+            [Diagnostic {
+                offset: 2,
+                message: "odd padding".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn diagnostic_order() {
+        let parse = || {
+            let log = Rc::new(DiagnosticLog::new());
+            let session = ParseSession::builder().diagnostics(log.clone()).build();
+            let reader = session.reader_from_bytes(vec![0; 16]);
+            reader.seek(8).unwrap();
+            reader.report("late field");
+            let header = KStream::clone(&reader);
+            header.seek(2).unwrap();
+            header.report("header");
+            reader.report("late field again");
+            reader.substream(4).report("in substream");
+            log
+        };
+        let (a, b) = (parse(), parse());
+        assert_eq!(a.in_parse_order(), b.in_parse_order());
+        assert_eq!(a.sorted_by_offset(), b.sorted_by_offset());
+
+        let messages = |ds: Vec<SequencedDiagnostic>| -> Vec<(u64, usize, String)> {
+            ds.into_iter()
+                .map(|d| (d.seq, d.diagnostic.offset, d.diagnostic.message))
+                .collect()
+        };
+        assert_eq!(
+            messages(a.in_parse_order()),
+            [
+                (0, 8, "late field".to_string()),
+                (1, 2, "header".to_string()),
+                (2, 8, "late field again".to_string()),
+                (3, 8, "in substream".to_string()),
+            ]
+        );
+        let by_offset: Vec<_> = messages(a.sorted_by_offset())
+            .into_iter()
+            .map(|(seq, ..)| seq)
+            .collect();
+        // ties in the order of reporting
+        assert_eq!(by_offset, [1, 0, 2, 3]);
+    }
+
+    #[derive(Debug, Default)]
+    struct Byte {
+        value: std::cell::Cell<u8>,
+    }
+
+    impl KStruct for Byte {
+        type Root = Byte;
+        type Parent = crate::KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            io: &S,
+            _root: crate::SharedType<Self::Root>,
+            _parent: crate::SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            self_rc.value.set(io.read_u1()?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn strict_consumption() {
+        let session = ParseSession::builder().strict_consumption(true).build();
+        assert_eq!(session.parse_bytes::<Byte>(vec![7]).unwrap().value.get(), 7);
+        let trailing = KError::trailing_bytes(1, 1, vec![8]);
+        assert_eq!(
+            session.parse_bytes::<Byte>(vec![7, 8]).unwrap_err(),
+            trailing
+        );
+        assert_eq!(
+            session.parse_bytes_no_panic::<Byte>(&[7, 8]).unwrap_err(),
+            trailing
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("byte.bin");
+        std::fs::write(&path, [7, 8]).unwrap();
+        assert_eq!(session.parse_file::<Byte>(&path).unwrap_err(), trailing);
+
+        // off by default
+        let lenient = ParseSession::default();
+        assert!(!lenient.strict_consumption());
+        assert_eq!(
+            lenient.parse_bytes::<Byte>(vec![7, 8]).unwrap().value.get(),
+            7
+        );
+        assert_eq!(lenient.parse_file::<Byte>(&path).unwrap().value.get(), 7);
+        assert!(crate::parse_bytes_no_panic::<Byte>(&[7, 8]).is_ok());
+    }
+}
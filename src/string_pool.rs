@@ -0,0 +1,129 @@
+use crate::{bytes_to_str, KError, KResult, KStream};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+/// Region of terminated strings referenced by their offset, e.g. the string
+/// table of an ELF section. Strings are decoded on first access and cached.
+///
+/// An offset may point into the middle of a string, in which case the
+/// string from there up to the terminator is returned: formats use this to
+/// share suffixes (`"name"` stored as part of `"filename"`).
+#[derive(Debug)]
+pub struct StringPool {
+    data: Vec<u8>,
+    encoding: String,
+    terminator: u8,
+    cache: RefCell<HashMap<u32, Rc<str>>>,
+}
+
+impl StringPool {
+    /// Read the `len` bytes at `offset` of `io` as a pool. The position of
+    /// `io` is left unchanged.
+    pub fn from_stream<S: KStream>(
+        io: &S,
+        offset: usize,
+        len: usize,
+        encoding: &str,
+        terminator: u8,
+    ) -> KResult<StringPool> {
+        // fail early on an unknown encoding
+        bytes_to_str(&[], encoding)?;
+        let _guard = io.save_pos();
+        io.seek(offset)?;
+        let data = io.read_bytes(len)?;
+        Ok(StringPool::new(data, encoding, terminator))
+    }
+
+    pub fn new(data: Vec<u8>, encoding: &str, terminator: u8) -> StringPool {
+        StringPool {
+            data,
+            encoding: encoding.to_string(),
+            terminator,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The string starting at `off`. Fails with [`KError::InvalidInput`] if
+    /// `off` is outside the pool and with [`KError::NoTerminatorFound`] if
+    /// the string runs to the end of the pool.
+    pub fn get(&self, off: u32) -> KResult<Rc<str>> {
+        if let Some(s) = self.cache.borrow().get(&off) {
+            return Ok(s.clone());
+        }
+        let start = off as usize;
+        if start >= self.data.len() {
+            return Err(KError::invalid_input(
+                start,
+                format!("string offset outside pool of {} bytes", self.data.len()),
+            ));
+        }
+        let tail = &self.data[start..];
+        let end = tail
+            .iter()
+            .position(|&b| b == self.terminator)
+            .ok_or(KError::NoTerminatorFound)?;
+        let s: Rc<str> = bytes_to_str(&tail[..end], &self.encoding)?.into();
+        self.cache.borrow_mut().insert(off, s.clone());
+        Ok(s)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BytesReader;
+
+    fn pool() -> (BytesReader, StringPool) {
+        let reader = BytesReader::from(b"hdr\0.text\0filename\0xyz".to_vec());
+        reader.seek(2).unwrap();
+        let pool = StringPool::from_stream(&reader, 3, 16, "ASCII", 0).unwrap();
+        (reader, pool)
+    }
+
+    #[test]
+    fn lookups_are_cached() {
+        let (reader, pool) = pool();
+        assert_eq!(reader.pos(), 2);
+        assert_eq!(pool.len(), 16);
+        let a = pool.get(7).unwrap();
+        assert_eq!(&*a, "filename");
+        assert!(Rc::ptr_eq(&a, &pool.get(7).unwrap()));
+        assert_eq!(&*pool.get(0).unwrap(), "");
+        assert_eq!(&*pool.get(1).unwrap(), ".text");
+    }
+
+    #[test]
+    fn suffix_offset() {
+        let (_, pool) = pool();
+        assert_eq!(&*pool.get(11).unwrap(), "name");
+        assert_eq!(&*pool.get(15).unwrap(), "");
+    }
+
+    #[test]
+    fn invalid_offsets() {
+        let (_, pool) = pool();
+        assert_eq!(
+            pool.get(16).unwrap_err().category(),
+            crate::ErrorCategory::InvalidInput
+        );
+        assert_eq!(pool.get(16).unwrap_err().offset(), Some(16));
+        assert!(pool.get(u32::MAX).is_err());
+
+        let unterminated = StringPool::new(b"abc\0de".to_vec(), "ASCII", 0);
+        assert_eq!(unterminated.get(4).unwrap_err(), KError::NoTerminatorFound);
+
+        let reader = BytesReader::from(vec![0; 4]);
+        assert!(StringPool::from_stream(&reader, 2, 4, "ASCII", 0)
+            .unwrap_err()
+            .is_eof());
+        assert_eq!(reader.pos(), 0);
+        assert!(StringPool::from_stream(&reader, 0, 4, "bogus", 0).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{
+    archive::{corrupt, corrupt_if_eof},
+    BytesReader, KError, KResult, KStream,
+};
+use std::{convert::TryFrom, fs::File, path::Path};
+
+const BLOCK: usize = 512;
+
+const CHECKSUM: std::ops::Range<usize> = 148..156;
+const USTAR_MAGIC: &[u8] = b"ustar\0";
+
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+const PAX_HEADER: u8 = b'x';
+const PAX_GLOBAL_HEADER: u8 = b'g';
+
+impl BytesReader {
+    /// Open the regular file `member_path` of the tar archive at `path` as a
+    /// stream over its bytes within the archive file, nothing is extracted.
+    /// ustar prefixes, GNU long names and pax `path` records are supported.
+    /// The stream is named `<path>/<member_path>`.
+    ///
+    /// Fails with [`KError::ArchiveMemberNotFound`] if there is no such
+    /// member, with [`KError::ArchiveChecksumMismatch`] if a header before
+    /// it is damaged and with [`KError::CorruptArchive`] if the archive is
+    /// truncated or malformed otherwise.
+    ///
+    /// The archive must not be compressed: a `.tar.gz` has to be
+    /// decompressed to a plain tar file first.
+    pub fn open_tar_member<P: AsRef<Path>>(path: P, member_path: &str) -> KResult<BytesReader> {
+        let archive = BytesReader::open(&path)?;
+        let (offset, len) = find_member(&archive, member_path)?;
+        let name = format!("{}/{}", path.as_ref().display(), member_path);
+        Ok(
+            BytesReader::from_file_range(File::open(&path)?, offset as u64, len as u64)
+                .map_err(|e| corrupt_if_eof(e, offset as u64))?
+                .with_name(&name),
+        )
+    }
+}
+
+// offset and length of the member's data
+fn find_member(io: &BytesReader, name: &str) -> KResult<(usize, usize)> {
+    let wanted = strip_dot(name.as_bytes());
+    let mut pos = 0;
+    // name for the next header from a GNU long name or pax header
+    let mut next_name = None;
+    loop {
+        // a missing end-of-archive marker is tolerated, missing padding is not
+        if pos >= io.size() {
+            if pos > io.size() {
+                return Err(corrupt(pos as u64, "unexpected end of archive"));
+            }
+            break;
+        }
+        io.seek(pos)?;
+        let header = io
+            .read_bytes(BLOCK)
+            .map_err(|e| corrupt_if_eof(e, pos as u64))?;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        check_checksum(&header, pos)?;
+        let len = number(&header[124..136])
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or_else(|| corrupt(pos as u64, "invalid size field"))?;
+        let data = pos + BLOCK;
+        if len > io.size() - data {
+            return Err(corrupt(pos as u64, "unexpected end of archive"));
+        }
+        match header[156] {
+            GNU_LONG_NAME => {
+                let mut long_name = io.read_bytes(len)?;
+                long_name.truncate(c_str(&long_name).len());
+                next_name = Some(long_name);
+            }
+            PAX_HEADER => {
+                if let Some(path) = pax_path(&io.read_bytes(len)?) {
+                    next_name = Some(path);
+                }
+            }
+            GNU_LONG_LINK | PAX_GLOBAL_HEADER => {}
+            typ => {
+                let entry_name = next_name.take().unwrap_or_else(|| header_name(&header));
+                let regular = matches!(typ, b'0' | b'\0' | b'7');
+                if regular && strip_dot(&entry_name) == wanted {
+                    return Ok((data, len));
+                }
+            }
+        }
+        // data is padded to whole blocks
+        pos = data.saturating_add(len).saturating_add(BLOCK - 1) / BLOCK * BLOCK;
+    }
+    Err(KError::ArchiveMemberNotFound {
+        name: name.to_string(),
+    })
+}
+
+fn check_checksum(header: &[u8], pos: usize) -> KResult<()> {
+    let stored =
+        number(&header[CHECKSUM]).ok_or_else(|| corrupt(pos as u64, "invalid checksum field"))?;
+    // the sum with the checksum field taken as spaces; some old
+    // implementations summed signed bytes
+    let spaces = u64::from(b' ') * CHECKSUM.len() as u64;
+    let (unsigned, signed) = header
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !CHECKSUM.contains(i))
+        .fold((spaces, spaces as i64), |(u, s), (_, &b)| {
+            (u + u64::from(b), s + i64::from(b as i8))
+        });
+    if stored != unsigned && i64::try_from(stored).ok() != Some(signed) {
+        return Err(KError::ArchiveChecksumMismatch {
+            offset: pos as u64,
+            stored,
+            computed: unsigned,
+        });
+    }
+    Ok(())
+}
+
+// octal, or base-256 for large values (a GNU extension)
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        if field[0] & 0x40 != 0 {
+            // negative
+            return None;
+        }
+        return std::iter::once(field[0] & 0x7f)
+            .chain(field[1..].iter().copied())
+            .try_fold(0u64, |n, b| n.checked_mul(256)?.checked_add(b.into()));
+    }
+    field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ')
+        .try_fold(0u64, |n, &b| match b {
+            b'0'..=b'7' => n.checked_mul(8)?.checked_add(u64::from(b - b'0')),
+            _ => None,
+        })
+}
+
+fn c_str(field: &[u8]) -> &[u8] {
+    field.split(|&b| b == 0).next().unwrap_or(&[])
+}
+
+fn header_name(header: &[u8]) -> Vec<u8> {
+    let name = c_str(&header[..100]);
+    let prefix = c_str(&header[345..500]);
+    // GNU headers use the prefix field for other data
+    if &header[257..263] != USTAR_MAGIC || prefix.is_empty() {
+        return name.to_vec();
+    }
+    [prefix, b"/", name].concat()
+}
+
+fn strip_dot(name: &[u8]) -> &[u8] {
+    name.strip_prefix(b"./").unwrap_or(name)
+}
+
+// the `path` record of a pax extended header; records are
+// "<record length> <key>=<value>\n"
+fn pax_path(mut records: &[u8]) -> Option<Vec<u8>> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|&b| b == b' ')?;
+        let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        let record = records.get(space + 1..len)?.strip_suffix(b"\n")?;
+        let eq = record.iter().position(|&b| b == b'=')?;
+        if &record[..eq] == b"path" {
+            path = Some(record[eq + 1..].to_vec());
+        }
+        records = &records[len..];
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/members.tar");
+    const LONG_NAME: &str = "very/very/very/very/very/very/very/very/very/very/very/very/\
+                             deeply/nested/member_with_a_name_longer_than_one_hundred_bytes.txt";
+
+    #[test]
+    fn members() {
+        let r = BytesReader::open_tar_member(FIXTURE, "dir/counting.bin").unwrap();
+        assert_eq!(r.size(), 768);
+        r.seek(0x110).unwrap();
+        assert_eq!(r.read_u4be().unwrap(), 0x1011_1213);
+        // the data starts after the directory's and the member's headers
+        assert_eq!(r.translate_to_root(0).unwrap().offset, 1024);
+
+        let r = BytesReader::open_tar_member(FIXTURE, LONG_NAME).unwrap();
+        assert_eq!(r.read_bytes_full().unwrap(), b"long names work\n");
+
+        let r = BytesReader::open_tar_member(FIXTURE, "./empty").unwrap();
+        assert_eq!(r.size(), 0);
+        assert_eq!(r.name().unwrap().as_ref(), format!("{}/./empty", FIXTURE));
+    }
+
+    #[test]
+    fn missing_member() {
+        for name in ["nope", "dir", "dir/", &LONG_NAME[..100]] {
+            assert_eq!(
+                BytesReader::open_tar_member(FIXTURE, name).unwrap_err(),
+                KError::ArchiveMemberNotFound {
+                    name: name.to_string()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn damaged_archives() {
+        let data = std::fs::read(FIXTURE).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // a flipped byte in the name of the second header
+        let mut broken = data.clone();
+        broken[BLOCK + 1] ^= 0x20;
+        let path = dir.path().join("checksum.tar");
+        std::fs::write(&path, &broken).unwrap();
+        let err = BytesReader::open_tar_member(&path, "dir/counting.bin").unwrap_err();
+        match err {
+            KError::ArchiveChecksumMismatch {
+                offset,
+                stored,
+                computed,
+            } => {
+                assert_eq!(offset, BLOCK as u64);
+                assert_eq!(stored, computed + 0x20);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // members before the damage can still be opened
+        broken[BLOCK + 1] ^= 0x20;
+        broken[4 * BLOCK + 1] ^= 0x20;
+        std::fs::write(&path, &broken).unwrap();
+        assert!(BytesReader::open_tar_member(&path, "dir/counting.bin").is_ok());
+
+        // cut within the data of a member
+        let path = dir.path().join("cut.tar");
+        std::fs::write(&path, &data[..1024 + 700]).unwrap();
+        for name in ["dir/counting.bin", "empty"] {
+            let err = BytesReader::open_tar_member(&path, name).unwrap_err();
+            assert!(
+                matches!(err, KError::CorruptArchive { offset: 512, .. }),
+                "{:?}",
+                err
+            );
+        }
+        // cut within the padding and within a header
+        for len in [1024 + 768 + 100, 2048 + 100] {
+            std::fs::write(&path, &data[..len]).unwrap();
+            let err = BytesReader::open_tar_member(&path, "empty").unwrap_err();
+            assert!(
+                matches!(err, KError::CorruptArchive { offset: 2048, .. }),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn header_fields() {
+        assert_eq!(number(b"00001411\0"), Some(0o1411));
+        assert_eq!(number(b"  1411 \0"), Some(0o1411));
+        assert_eq!(number(b"\0\0\0\0"), Some(0));
+        assert_eq!(number(b"0009\0"), None);
+        assert_eq!(number(&[0x80, 0, 0, 1, 0]), Some(256));
+        assert_eq!(number(&[0xff, 0xff]), None);
+
+        let records = b"30 mtime=1600000000.123456789\n18 path=a/b/c.txt\n";
+        assert_eq!(pax_path(records), Some(b"a/b/c.txt".to_vec()));
+        assert_eq!(pax_path(b"99 path=x\n"), None);
+    }
+}
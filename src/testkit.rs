@@ -0,0 +1,1845 @@
+//! Conformance checks for [`KStream`] implementations, enabled by the
+//! `test-support` feature.
+//!
+//! The checks run scripts of reads against a stream and against slow,
+//! obviously correct reference implementations working on the expected
+//! bytes, and report the first difference.
+//!
+//! [`FixtureBuilder`] writes a stream of fields of every kind together
+//! with what reading them must produce, for [`verify_fixture`].
+//!
+//! [`assert_parses_to`] compares the parse of a fixture file with a
+//! checked-in dump, for repositories of format specs.
+
+use crate::{
+    bytes_terminate, bytes_to_str, f16_to_f32, insert_bits_be, insert_bits_le, process_rotate_left,
+    process_xor_many, process_zlib, try_modulo, BytesReader, ErrorCategory, KError, KResult,
+    KStream, KStruct, KStructUnit, KVisit, OptRc, SharedType,
+};
+use crate::{
+    diff::diff_trees,
+    visit::{KValue, Node},
+    DiffOptions,
+};
+use crate::{BigEndian, LittleEndian};
+use encoding::{label::encoding_from_whatwg_label, EncoderTrap};
+use std::{any::Any, cell::RefCell, fs, path::Path};
+
+/// Single step of a read script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadOp {
+    Bytes(usize),
+    U1,
+    U2le,
+    U2be,
+    U4le,
+    U4be,
+    U8le,
+    U8be,
+    BitsBe(usize),
+    BitsLe(usize),
+    AlignToByte,
+    Seek(usize),
+    Term {
+        term: u8,
+        include: bool,
+        consume: bool,
+        eos_error: bool,
+    },
+}
+
+/// Value produced by a [`ReadOp`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(u64),
+    Bytes(Vec<u8>),
+    Unit,
+}
+
+/// Result of a [`ReadOp`] together with the position after it.
+pub type Outcome = (Result<Value, ErrorCategory>, usize);
+
+/// Read `n` bits starting at bit `bit_pos`, most significant bit first.
+pub fn reference_bits_be(data: &[u8], bit_pos: usize, n: usize) -> Option<u64> {
+    if n > 64 || bit_pos.checked_add(n)? > data.len() * 8 {
+        return None;
+    }
+    let mut res = 0u64;
+    for k in bit_pos..bit_pos + n {
+        let bit = (data[k / 8] >> (7 - k % 8)) & 1;
+        res = res << 1 | u64::from(bit);
+    }
+    Some(res)
+}
+
+/// Read `n` bits starting at bit `bit_pos`, least significant bit first.
+/// Bits are numbered from the least significant bit of each byte.
+pub fn reference_bits_le(data: &[u8], bit_pos: usize, n: usize) -> Option<u64> {
+    if n > 64 || bit_pos.checked_add(n)? > data.len() * 8 {
+        return None;
+    }
+    let mut res = 0u64;
+    for (i, k) in (bit_pos..bit_pos + n).enumerate() {
+        let bit = (data[k / 8] >> (k % 8)) & 1;
+        res |= u64::from(bit) << i;
+    }
+    Some(res)
+}
+
+/// Reference for [`KStream::read_bytes_term`] starting at `pos`. Returns the
+/// result and the position afterwards.
+pub fn reference_bytes_term(
+    data: &[u8],
+    pos: usize,
+    term: u8,
+    include: bool,
+    consume: bool,
+    eos_error: bool,
+) -> (Result<Vec<u8>, ErrorCategory>, usize) {
+    let rest = data.get(pos..).unwrap_or_default();
+    match rest.iter().position(|&b| b == term) {
+        Some(i) => {
+            let end = if include { i + 1 } else { i };
+            let new_pos = if consume { pos + i + 1 } else { pos + i };
+            (Ok(rest[..end].to_vec()), new_pos)
+        }
+        None if eos_error => (Err(ErrorCategory::Format), pos.max(data.len())),
+        None => (Ok(rest.to_vec()), pos.max(data.len())),
+    }
+}
+
+/// Model of a stream over `data`, built on the reference functions.
+#[derive(Debug, Clone)]
+pub struct ReferenceStream<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits_left: usize,
+}
+
+impl<'a> ReferenceStream<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ReferenceStream {
+            data,
+            pos: 0,
+            bits_left: 0,
+        }
+    }
+
+    pub fn apply(&mut self, op: &ReadOp) -> Outcome {
+        let res = match *op {
+            ReadOp::Bytes(n) => self.bytes(n).map(Value::Bytes),
+            ReadOp::U1 => self.int(1, false),
+            ReadOp::U2le => self.int(2, false),
+            ReadOp::U2be => self.int(2, true),
+            ReadOp::U4le => self.int(4, false),
+            ReadOp::U4be => self.int(4, true),
+            ReadOp::U8le => self.int(8, false),
+            ReadOp::U8be => self.int(8, true),
+            ReadOp::BitsBe(n) => self.bits(n, reference_bits_be),
+            ReadOp::BitsLe(n) => self.bits(n, reference_bits_le),
+            ReadOp::AlignToByte => {
+                self.bits_left = 0;
+                Ok(Value::Unit)
+            }
+            ReadOp::Seek(pos) => {
+                self.pos = pos;
+                Ok(Value::Unit)
+            }
+            ReadOp::Term {
+                term,
+                include,
+                consume,
+                eos_error,
+            } => {
+                let (res, pos) =
+                    reference_bytes_term(self.data, self.pos, term, include, consume, eos_error);
+                self.pos = pos;
+                res.map(Value::Bytes)
+            }
+        };
+        (res, self.pos)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<Vec<u8>, ErrorCategory> {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        if n > rest.len() {
+            return Err(ErrorCategory::Eof);
+        }
+        self.pos += n;
+        Ok(rest[..n].to_vec())
+    }
+
+    fn int(&mut self, size: usize, be: bool) -> Result<Value, ErrorCategory> {
+        let mut b = self.bytes(size)?;
+        if !be {
+            b.reverse();
+        }
+        Ok(Value::Int(
+            b.iter().fold(0, |acc, &x| acc << 8 | u64::from(x)),
+        ))
+    }
+
+    fn bits(
+        &mut self,
+        n: usize,
+        read: fn(&[u8], usize, usize) -> Option<u64>,
+    ) -> Result<Value, ErrorCategory> {
+        if n > 64 {
+            return Err(ErrorCategory::InvalidInput);
+        }
+        // reading nothing always works, even past the end
+        if n == 0 {
+            return Ok(Value::Int(0));
+        }
+        let bit_pos = self.pos * 8 - self.bits_left;
+        let v = read(self.data, bit_pos, n).ok_or(ErrorCategory::Eof)?;
+        let end = bit_pos + n;
+        // reads served from the pending bits alone do not move the position
+        self.pos = self.pos.max(end.div_ceil(8));
+        self.bits_left = self.pos * 8 - end;
+        Ok(Value::Int(v))
+    }
+}
+
+/// Apply `op` to `io`, in the same terms as [`ReferenceStream::apply`].
+pub fn apply<S: KStream>(io: &S, op: &ReadOp) -> Outcome {
+    let int = |r: crate::KResult<u64>| r.map(Value::Int);
+    let res = match *op {
+        ReadOp::Bytes(n) => io.read_bytes(n).map(Value::Bytes),
+        ReadOp::U1 => int(io.read_u1().map(u64::from)),
+        ReadOp::U2le => int(io.read_u2le().map(u64::from)),
+        ReadOp::U2be => int(io.read_u2be().map(u64::from)),
+        ReadOp::U4le => int(io.read_u4le().map(u64::from)),
+        ReadOp::U4be => int(io.read_u4be().map(u64::from)),
+        ReadOp::U8le => int(io.read_u8le()),
+        ReadOp::U8be => int(io.read_u8be()),
+        ReadOp::BitsBe(n) => int(io.read_bits_int_be(n)),
+        ReadOp::BitsLe(n) => int(io.read_bits_int_le(n)),
+        ReadOp::AlignToByte => io.align_to_byte().map(|_| Value::Unit),
+        ReadOp::Seek(pos) => io.seek(pos).map(|_| Value::Unit),
+        ReadOp::Term {
+            term,
+            include,
+            consume,
+            eos_error,
+        } => io
+            .read_bytes_term(term, include, consume, eos_error)
+            .map(Value::Bytes),
+    };
+    (res.map_err(|e| e.category()), io.pos())
+}
+
+/// Deterministic generator of random read scripts (xorshift64*).
+#[derive(Debug, Clone)]
+pub struct ScriptGen {
+    state: u64,
+}
+
+impl ScriptGen {
+    pub fn new(seed: u64) -> Self {
+        ScriptGen {
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Script of `steps` reads for a stream of `data_len` bytes. Bit reads
+    /// of one endianness are grouped and followed by an alignment before any
+    /// other read, as generated parsers do.
+    pub fn script(&mut self, data_len: usize, steps: usize) -> Vec<ReadOp> {
+        let mut res = vec![];
+        let mut bit_mode: Option<bool> = None;
+        while res.len() < steps {
+            let op = match self.below(12) {
+                0 => ReadOp::Bytes(self.below(data_len + 2)),
+                1 => ReadOp::U1,
+                2 => [ReadOp::U2le, ReadOp::U2be][self.below(2)].clone(),
+                3 => [ReadOp::U4le, ReadOp::U4be][self.below(2)].clone(),
+                4 => [ReadOp::U8le, ReadOp::U8be][self.below(2)].clone(),
+                5 | 6 => ReadOp::BitsBe(self.below(65)),
+                7 | 8 => ReadOp::BitsLe(self.below(65)),
+                9 => ReadOp::Seek(self.below(data_len + 2)),
+                10 => ReadOp::AlignToByte,
+                _ => ReadOp::Term {
+                    term: self.below(4) as u8,
+                    include: self.below(2) == 0,
+                    consume: self.below(2) == 0,
+                    eos_error: self.below(2) == 0,
+                },
+            };
+            let op_mode = match op {
+                ReadOp::BitsBe(_) => Some(true),
+                ReadOp::BitsLe(_) => Some(false),
+                _ => None,
+            };
+            if bit_mode.is_some() && bit_mode != op_mode && op != ReadOp::AlignToByte {
+                res.push(ReadOp::AlignToByte);
+            }
+            bit_mode = op_mode;
+            res.push(op);
+        }
+        res
+    }
+
+    /// Random bytes, mostly small values so that terminator searches hit.
+    pub fn data(&mut self, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| match self.below(3) {
+                0 => self.below(4) as u8,
+                _ => self.next_u64() as u8,
+            })
+            .collect()
+    }
+}
+
+/// Run `script` from the start of `io`, comparing every step with the
+/// reference. Returns a description of the first difference.
+pub fn check_script<S: KStream>(io: &S, expected: &[u8], script: &[ReadOp]) -> Result<(), String> {
+    io.seek(0).map_err(|e| format!("seek(0) failed: {}", e))?;
+    io.align_to_byte()
+        .map_err(|e| format!("align_to_byte failed: {}", e))?;
+    let mut reference = ReferenceStream::new(expected);
+    for (i, op) in script.iter().enumerate() {
+        let want = reference.apply(op);
+        let got = apply(io, op);
+        if got != want {
+            return Err(format!(
+                "step {} ({:?}): expected {:?}, got {:?}; script: {:?}",
+                i,
+                op,
+                want,
+                got,
+                &script[..=i]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Exercise reads, seeks, bit reads and terminator searches on `reader`,
+/// which must contain exactly `expected`, against the reference
+/// implementations. The position of `reader` is changed.
+pub fn check_stream_conformance<S: KStream>(reader: &S, expected: &[u8]) -> Result<(), String> {
+    if reader.size() != expected.len() {
+        return Err(format!(
+            "size: expected {}, got {}",
+            expected.len(),
+            reader.size()
+        ));
+    }
+    let len = expected.len();
+    let fixed = [
+        vec![ReadOp::Bytes(len), ReadOp::U1],
+        vec![ReadOp::Seek(len), ReadOp::Bytes(0), ReadOp::BitsBe(1)],
+        vec![ReadOp::BitsBe(3), ReadOp::BitsBe(13), ReadOp::BitsBe(64)],
+        vec![ReadOp::BitsLe(5), ReadOp::BitsLe(64), ReadOp::BitsLe(0)],
+        vec![ReadOp::BitsBe(65)],
+        vec![
+            ReadOp::Term {
+                term: 0,
+                include: false,
+                consume: false,
+                eos_error: true,
+            },
+            ReadOp::U1,
+        ],
+    ];
+    for script in &fixed {
+        check_script(reader, expected, script)?;
+    }
+    let mut gen = ScriptGen::new(len as u64);
+    for _ in 0..64 {
+        check_script(reader, expected, &gen.script(len, 16))?;
+    }
+    Ok(())
+}
+
+/// Kind of read described by an [`Expectation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixtureKind {
+    /// Integer of `width` bytes, `read_u*`/`read_s*`, up to `read_u128*`.
+    Int {
+        width: usize,
+        signed: bool,
+        le: bool,
+    },
+    /// Float of `width` bytes, `read_f2*`/`read_f4*`/`read_f8*`.
+    Float {
+        width: usize,
+        le: bool,
+    },
+    BitsBe(usize),
+    BitsLe(usize),
+    /// `read_bits_int_be_signed`.
+    BitsBeSigned(usize),
+    /// `read_bits_int_le_signed`.
+    BitsLeSigned(usize),
+    /// `read_bits_bytes_be`, a bit field of any width.
+    BitsBytesBe(usize),
+    /// `read_bits_bytes_le`.
+    BitsBytesLe(usize),
+    /// `count` integers read with one `read_int_array`.
+    IntArray {
+        width: usize,
+        signed: bool,
+        le: bool,
+        count: usize,
+    },
+    /// `count` bit fields of `width` bits, `read_bits_array_be`/`_le`.
+    BitsArray {
+        width: usize,
+        count: usize,
+        le: bool,
+    },
+    Bytes(usize),
+    /// `read_bytes_term` without the terminator, consuming it.
+    BytesTerm {
+        term: u8,
+    },
+    Str {
+        len: usize,
+        encoding: String,
+    },
+    /// Terminated string, read like [`BytesTerm`](Self::BytesTerm).
+    StrTerm {
+        term: u8,
+        encoding: String,
+    },
+    /// String after a length of `prefix` bytes, `read_str_pascal_*`.
+    StrPascal {
+        prefix: usize,
+        encoding: String,
+    },
+    /// Padding, read as bytes.
+    Pad(usize),
+}
+
+impl FixtureKind {
+    // the bit order of a bit field, `true` for that of `read_bits_int_le`
+    fn bit_order(&self) -> Option<bool> {
+        match *self {
+            FixtureKind::BitsBe(_) | FixtureKind::BitsBeSigned(_) | FixtureKind::BitsBytesBe(_) => {
+                Some(false)
+            }
+            FixtureKind::BitsLe(_) | FixtureKind::BitsLeSigned(_) | FixtureKind::BitsBytesLe(_) => {
+                Some(true)
+            }
+            FixtureKind::BitsArray { le, .. } => Some(le),
+            _ => None,
+        }
+    }
+}
+
+/// Value of an [`Expectation`]. Floats compare by their bit pattern.
+#[derive(Debug, Clone)]
+pub enum FixtureValue {
+    Int(i128),
+    /// An unsigned 16-byte integer, which may not fit [`Int`](Self::Int).
+    U128(u128),
+    Ints(Vec<i128>),
+    Float(f64),
+    Bytes(Vec<u8>),
+    Str(String),
+}
+
+impl PartialEq for FixtureValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FixtureValue::Int(a), FixtureValue::Int(b)) => a == b,
+            (FixtureValue::U128(a), FixtureValue::U128(b)) => a == b,
+            (FixtureValue::Ints(a), FixtureValue::Ints(b)) => a == b,
+            (FixtureValue::Float(a), FixtureValue::Float(b)) => a.to_bits() == b.to_bits(),
+            (FixtureValue::Bytes(a), FixtureValue::Bytes(b)) => a == b,
+            (FixtureValue::Str(a), FixtureValue::Str(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// A field written by a [`FixtureBuilder`]: its byte offset, which for a
+/// bit field is that of the byte holding its first bit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    pub offset: usize,
+    pub kind: FixtureKind,
+    pub value: FixtureValue,
+}
+
+macro_rules! fixture_ints {
+    ($($name:ident: $t:ty, $width:expr, $signed:expr, $le:expr;)*) => {
+        $(
+            pub fn $name(self, value: $t) -> Self {
+                self.int($width, $signed, $le, i128::from(value))
+            }
+        )*
+    };
+}
+
+/// Builds a stream of fields together with the [`Expectation`]s to check
+/// a [`KStream`] against with [`verify_fixture`]:
+///
+/// ```
+/// # use kaitai::{testkit::*, BytesReader};
+/// let (data, expectations) = FixtureBuilder::new()
+///     .u4le(0xdeadbeef)
+///     .bits_be(3, 0b101)
+///     .str_term("hello", 0, "UTF-8")
+///     .pad(4, 0)
+///     .build();
+/// verify_fixture(&BytesReader::from(data), &expectations).unwrap();
+/// ```
+///
+/// Bit fields of the same bit order are packed; any other field starts at
+/// the next byte, as after `align_to_byte`.
+#[derive(Debug, Clone, Default)]
+pub struct FixtureBuilder {
+    data: Vec<u8>,
+    // bit order and bit position of a run of bit fields
+    bits: Option<(bool, usize)>,
+    expectations: Vec<Expectation>,
+}
+
+impl FixtureBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fixture_ints! {
+        u1: u8, 1, false, false;
+        u2le: u16, 2, false, true;
+        u2be: u16, 2, false, false;
+        u4le: u32, 4, false, true;
+        u4be: u32, 4, false, false;
+        u8le: u64, 8, false, true;
+        u8be: u64, 8, false, false;
+        s1: i8, 1, true, false;
+        s2le: i16, 2, true, true;
+        s2be: i16, 2, true, false;
+        s4le: i32, 4, true, true;
+        s4be: i32, 4, true, false;
+        s8le: i64, 8, true, true;
+        s8be: i64, 8, true, false;
+        s128le: i128, 16, true, true;
+        s128be: i128, 16, true, false;
+    }
+
+    pub fn u128le(self, value: u128) -> Self {
+        self.u128(value.to_le_bytes(), true, value)
+    }
+    pub fn u128be(self, value: u128) -> Self {
+        self.u128(value.to_be_bytes(), false, value)
+    }
+
+    /// The half-precision float of bit pattern `bits`, see [`f16_to_f32`].
+    pub fn f2le(self, bits: u16) -> Self {
+        self.float(
+            bits.to_le_bytes().to_vec(),
+            2,
+            true,
+            f16_to_f32(bits).into(),
+        )
+    }
+    pub fn f2be(self, bits: u16) -> Self {
+        self.float(
+            bits.to_be_bytes().to_vec(),
+            2,
+            false,
+            f16_to_f32(bits).into(),
+        )
+    }
+
+    pub fn f4le(self, value: f32) -> Self {
+        self.float(value.to_le_bytes().to_vec(), 4, true, value.into())
+    }
+    pub fn f4be(self, value: f32) -> Self {
+        self.float(value.to_be_bytes().to_vec(), 4, false, value.into())
+    }
+    pub fn f8le(self, value: f64) -> Self {
+        self.float(value.to_le_bytes().to_vec(), 8, true, value)
+    }
+    pub fn f8be(self, value: f64) -> Self {
+        self.float(value.to_be_bytes().to_vec(), 8, false, value)
+    }
+
+    /// `width` bits of `value`, most significant bit first. Panics if
+    /// `value` does not fit.
+    pub fn bits_be(self, width: usize, value: u64) -> Self {
+        self.bits(width, value, false)
+    }
+
+    /// `width` bits of `value`, least significant bit first.
+    pub fn bits_le(self, width: usize, value: u64) -> Self {
+        self.bits(width, value, true)
+    }
+
+    /// `value` as a two's complement field of `width` bits, read back with
+    /// `read_bits_int_be_signed`. Panics if `value` does not fit.
+    pub fn bits_be_signed(self, width: usize, value: i64) -> Self {
+        let bits = signed_bits(width, value);
+        let kind = FixtureKind::BitsBeSigned(width);
+        self.bit_field(
+            kind,
+            false,
+            &[(width, bits)],
+            FixtureValue::Int(value.into()),
+        )
+    }
+
+    /// [`bits_be_signed`](Self::bits_be_signed) in the bit order of
+    /// [`bits_le`](Self::bits_le).
+    pub fn bits_le_signed(self, width: usize, value: i64) -> Self {
+        let bits = signed_bits(width, value);
+        let kind = FixtureKind::BitsLeSigned(width);
+        self.bit_field(
+            kind,
+            true,
+            &[(width, bits)],
+            FixtureValue::Int(value.into()),
+        )
+    }
+
+    /// A field of `width` bits, which may be more than 64, holding the
+    /// big-endian number `bytes` of `width.div_ceil(8)` bytes, read back
+    /// with `read_bits_bytes_be`. Panics if the number has more bits.
+    pub fn bits_bytes_be(self, width: usize, bytes: &[u8]) -> Self {
+        // most significant bit first
+        let bits: Vec<_> = (0..width)
+            .map(|i| (1, number_bit(width, bytes, width - 1 - i)))
+            .collect();
+        let value = FixtureValue::Bytes(bytes.into());
+        self.bit_field(FixtureKind::BitsBytesBe(width), false, &bits, value)
+    }
+
+    /// [`bits_bytes_be`](Self::bits_bytes_be) in the bit order of
+    /// [`bits_le`](Self::bits_le); `bytes` is big-endian all the same.
+    pub fn bits_bytes_le(self, width: usize, bytes: &[u8]) -> Self {
+        let bits: Vec<_> = (0..width)
+            .map(|i| (1, number_bit(width, bytes, i)))
+            .collect();
+        let value = FixtureValue::Bytes(bytes.into());
+        self.bit_field(FixtureKind::BitsBytesLe(width), true, &bits, value)
+    }
+
+    /// `values` as consecutive bit fields of `width` bits, read back with
+    /// one `read_bits_array_be`.
+    pub fn bits_array_be(self, width: usize, values: &[u64]) -> Self {
+        self.bits_array(width, values, false)
+    }
+
+    /// [`bits_array_be`](Self::bits_array_be) in the bit order of
+    /// [`bits_le`](Self::bits_le).
+    pub fn bits_array_le(self, width: usize, values: &[u64]) -> Self {
+        self.bits_array(width, values, true)
+    }
+
+    /// `values` as integers of `width` bytes, read back with one
+    /// `read_int_array`. Panics if a value does not fit or `width` is not
+    /// that of a [`KaitaiInt`](crate::KaitaiInt).
+    pub fn int_array(self, width: usize, signed: bool, le: bool, values: &[i128]) -> Self {
+        assert!(
+            [1, 2, 4, 8].contains(&width),
+            "no integer array of {} bytes",
+            width
+        );
+        let (min, max) = match signed {
+            true => (-(1i128 << (width * 8 - 1)), (1i128 << (width * 8 - 1)) - 1),
+            false => (0, (1i128 << (width * 8)) - 1),
+        };
+        let mut data = vec![];
+        for &value in values {
+            assert!(
+                (min..=max).contains(&value),
+                "{} does not fit {} bytes",
+                value,
+                width
+            );
+            match le {
+                true => data.extend_from_slice(&value.to_le_bytes()[..width]),
+                false => data.extend_from_slice(&value.to_be_bytes()[16 - width..]),
+            }
+        }
+        let kind = FixtureKind::IntArray {
+            width,
+            signed,
+            le,
+            count: values.len(),
+        };
+        self.push(kind, &data, FixtureValue::Ints(values.into()))
+    }
+
+    pub fn bytes(self, bytes: &[u8]) -> Self {
+        self.push(
+            FixtureKind::Bytes(bytes.len()),
+            bytes,
+            FixtureValue::Bytes(bytes.into()),
+        )
+    }
+
+    /// `bytes` followed by `term`, which they must not contain.
+    pub fn bytes_term(self, bytes: &[u8], term: u8) -> Self {
+        assert!(!bytes.contains(&term), "terminator within the bytes");
+        let mut data = bytes.to_vec();
+        data.push(term);
+        self.push(
+            FixtureKind::BytesTerm { term },
+            &data,
+            FixtureValue::Bytes(bytes.into()),
+        )
+    }
+
+    /// `s` in `encoding`, a WHATWG label. Panics if it cannot be encoded.
+    pub fn str(self, s: &str, encoding: &str) -> Self {
+        let data = encode(s, encoding);
+        let kind = FixtureKind::Str {
+            len: data.len(),
+            encoding: encoding.to_string(),
+        };
+        self.push(kind, &data, FixtureValue::Str(s.into()))
+    }
+
+    /// `s` in `encoding` followed by `term`.
+    pub fn str_term(self, s: &str, term: u8, encoding: &str) -> Self {
+        let mut data = encode(s, encoding);
+        assert!(!data.contains(&term), "terminator within the string");
+        data.push(term);
+        let kind = FixtureKind::StrTerm {
+            term,
+            encoding: encoding.to_string(),
+        };
+        self.push(kind, &data, FixtureValue::Str(s.into()))
+    }
+
+    /// `s` in `encoding` after its length as a `u1`.
+    pub fn str_pascal_u1(self, s: &str, encoding: &str) -> Self {
+        self.str_pascal(s, 1, encoding)
+    }
+
+    /// `s` in `encoding` after its length as a `u2le`.
+    pub fn str_pascal_u2le(self, s: &str, encoding: &str) -> Self {
+        self.str_pascal(s, 2, encoding)
+    }
+
+    /// `s` in `encoding` after its length as a `u4le`.
+    pub fn str_pascal_u4le(self, s: &str, encoding: &str) -> Self {
+        self.str_pascal(s, 4, encoding)
+    }
+
+    /// `len` bytes of `byte`.
+    pub fn pad(self, len: usize, byte: u8) -> Self {
+        let data = vec![byte; len];
+        self.push(
+            FixtureKind::Pad(len),
+            &data,
+            FixtureValue::Bytes(data.clone()),
+        )
+    }
+
+    /// The stream and what reading it must produce, in order.
+    pub fn build(self) -> (Vec<u8>, Vec<Expectation>) {
+        (self.data, self.expectations)
+    }
+
+    fn int(self, width: usize, signed: bool, le: bool, value: i128) -> Self {
+        // two's complement, so the low bytes are those of the value
+        let data = match le {
+            true => value.to_le_bytes()[..width].to_vec(),
+            false => value.to_be_bytes()[16 - width..].to_vec(),
+        };
+        let kind = FixtureKind::Int { width, signed, le };
+        self.push(kind, &data, FixtureValue::Int(value))
+    }
+
+    fn float(self, data: Vec<u8>, width: usize, le: bool, value: f64) -> Self {
+        let kind = FixtureKind::Float { width, le };
+        self.push(kind, &data, FixtureValue::Float(value))
+    }
+
+    fn push(mut self, kind: FixtureKind, data: &[u8], value: FixtureValue) -> Self {
+        self.bits = None;
+        self.expectations.push(Expectation {
+            offset: self.data.len(),
+            kind,
+            value,
+        });
+        self.data.extend_from_slice(data);
+        self
+    }
+
+    fn u128(self, data: [u8; 16], le: bool, value: u128) -> Self {
+        let kind = FixtureKind::Int {
+            width: 16,
+            signed: false,
+            le,
+        };
+        self.push(kind, &data, FixtureValue::U128(value))
+    }
+
+    fn str_pascal(self, s: &str, prefix: usize, encoding: &str) -> Self {
+        let encoded = encode(s, encoding);
+        assert!(
+            (encoded.len() as u64) < 1 << (prefix * 8),
+            "string of {} bytes with a length of {} bytes",
+            encoded.len(),
+            prefix
+        );
+        let mut data = (encoded.len() as u64).to_le_bytes()[..prefix].to_vec();
+        data.extend_from_slice(&encoded);
+        let kind = FixtureKind::StrPascal {
+            prefix,
+            encoding: encoding.to_string(),
+        };
+        self.push(kind, &data, FixtureValue::Str(s.into()))
+    }
+
+    fn bits(self, width: usize, value: u64, le: bool) -> Self {
+        let kind = match le {
+            true => FixtureKind::BitsLe(width),
+            false => FixtureKind::BitsBe(width),
+        };
+        self.bit_field(kind, le, &[(width, value)], FixtureValue::Int(value.into()))
+    }
+
+    fn bits_array(self, width: usize, values: &[u64], le: bool) -> Self {
+        let chunks: Vec<_> = values.iter().map(|&v| (width, v)).collect();
+        let kind = FixtureKind::BitsArray {
+            width,
+            count: values.len(),
+            le,
+        };
+        let value = FixtureValue::Ints(values.iter().map(|&v| v.into()).collect());
+        self.bit_field(kind, le, &chunks, value)
+    }
+
+    // a bit field written as `chunks` of (width, value) in stream order
+    fn bit_field(
+        mut self,
+        kind: FixtureKind,
+        le: bool,
+        chunks: &[(usize, u64)],
+        value: FixtureValue,
+    ) -> Self {
+        let start = match self.bits {
+            Some((run_le, pos)) if run_le == le => pos,
+            _ => self.data.len() * 8,
+        };
+        let end = start + chunks.iter().map(|&(width, _)| width).sum::<usize>();
+        self.data.resize(end.div_ceil(8), 0);
+        let mut bit_pos = start;
+        for &(width, value) in chunks {
+            let res = match le {
+                true => insert_bits_le(&mut self.data, bit_pos, width, value),
+                false => insert_bits_be(&mut self.data, bit_pos, width, value),
+            };
+            if let Err(e) = res {
+                panic!("bit field of {} bits: {}", width, e);
+            }
+            bit_pos += width;
+        }
+        self.bits = Some((le, end));
+        self.expectations.push(Expectation {
+            offset: start / 8,
+            kind,
+            value,
+        });
+        self
+    }
+}
+
+// `value` as the bits of a two's complement field of `width` bits
+fn signed_bits(width: usize, value: i64) -> u64 {
+    assert!((1..=64).contains(&width), "signed field of {} bits", width);
+    let half = 1i128 << (width - 1);
+    assert!(
+        (-half..half).contains(&i128::from(value)),
+        "{} does not fit {} bits",
+        value,
+        width
+    );
+    value as u64 & (u64::MAX >> (64 - width))
+}
+
+// bit `k`, counted from the least significant one, of the big-endian
+// number `bytes` of a `width`-bit field
+fn number_bit(width: usize, bytes: &[u8], k: usize) -> u64 {
+    assert_eq!(
+        bytes.len(),
+        width.div_ceil(8),
+        "bytes of a field of {} bits",
+        width
+    );
+    assert!(
+        width.is_multiple_of(8) || bytes[0] >> (width % 8) == 0,
+        "number of more than {} bits",
+        width
+    );
+    u64::from(bytes[bytes.len() - 1 - k / 8] >> (k % 8) & 1)
+}
+
+fn encode(s: &str, encoding: &str) -> Vec<u8> {
+    let enc = encoding_from_whatwg_label(encoding)
+        .unwrap_or_else(|| panic!("unknown encoding {:?}", encoding));
+    enc.encode(s, EncoderTrap::Strict)
+        .unwrap_or_else(|e| panic!("{:?} cannot be encoded in {}: {}", s, encoding, e))
+}
+
+/// Read the fields of `expectations` from the start of `io` and compare
+/// them with the expected values and offsets. Returns a description of the
+/// first difference.
+pub fn verify_fixture<S: KStream>(io: &S, expectations: &[Expectation]) -> Result<(), String> {
+    io.seek(0).map_err(|e| format!("seek(0) failed: {}", e))?;
+    io.align_to_byte()
+        .map_err(|e| format!("align_to_byte failed: {}", e))?;
+    let mut bit_order = None;
+    for (i, exp) in expectations.iter().enumerate() {
+        let order = exp.kind.bit_order();
+        if bit_order.is_some() && bit_order != order {
+            io.align_to_byte()
+                .map_err(|e| format!("align_to_byte failed: {}", e))?;
+        }
+        bit_order = order;
+        let pos = io.pos() - usize::from(io.bit_state().bits_left > 0);
+        if pos != exp.offset {
+            return Err(format!(
+                "field {} ({:?}): expected at offset {}, got {}",
+                i, exp.kind, exp.offset, pos
+            ));
+        }
+        match read_fixture_field(io, &exp.kind) {
+            Ok(value) if value == exp.value => {}
+            Ok(value) => {
+                return Err(format!(
+                    "field {} ({:?}) at offset {}: expected {:?}, got {:?}",
+                    i, exp.kind, exp.offset, exp.value, value
+                ))
+            }
+            Err(e) => {
+                return Err(format!(
+                    "field {} ({:?}) at offset {}: {}",
+                    i, exp.kind, exp.offset, e
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_fixture_field<S: KStream>(io: &S, kind: &FixtureKind) -> KResult<FixtureValue> {
+    let int = |v: KResult<i128>| v.map(FixtureValue::Int);
+    macro_rules! ints {
+        ($t:ty, $e:ty, $count:expr) => {
+            io.read_int_array::<$t, $e>($count)
+                .map(|v| FixtureValue::Ints(v.into_iter().map(i128::from).collect()))
+        };
+    }
+    match *kind {
+        FixtureKind::Int {
+            width: 16,
+            signed: false,
+            le,
+        } => match le {
+            true => io.read_u128le(),
+            false => io.read_u128be(),
+        }
+        .map(FixtureValue::U128),
+        FixtureKind::Int { width, signed, le } => int(match (width, signed, le) {
+            (1, false, _) => io.read_u1().map(i128::from),
+            (2, false, true) => io.read_u2le().map(i128::from),
+            (2, false, false) => io.read_u2be().map(i128::from),
+            (4, false, true) => io.read_u4le().map(i128::from),
+            (4, false, false) => io.read_u4be().map(i128::from),
+            (8, false, true) => io.read_u8le().map(i128::from),
+            (8, false, false) => io.read_u8be().map(i128::from),
+            (1, true, _) => io.read_s1().map(i128::from),
+            (2, true, true) => io.read_s2le().map(i128::from),
+            (2, true, false) => io.read_s2be().map(i128::from),
+            (4, true, true) => io.read_s4le().map(i128::from),
+            (4, true, false) => io.read_s4be().map(i128::from),
+            (8, true, true) => io.read_s8le().map(i128::from),
+            (8, true, false) => io.read_s8be().map(i128::from),
+            (16, true, true) => io.read_s128le(),
+            (16, true, false) => io.read_s128be(),
+            _ => Err(KError::invalid_input(io.pos(), "unsupported integer width")),
+        }),
+        FixtureKind::Float { width, le } => match (width, le) {
+            (2, true) => io.read_f2le().map(f64::from),
+            (2, false) => io.read_f2be().map(f64::from),
+            (4, true) => io.read_f4le().map(f64::from),
+            (4, false) => io.read_f4be().map(f64::from),
+            (8, true) => io.read_f8le(),
+            (8, false) => io.read_f8be(),
+            _ => Err(KError::invalid_input(io.pos(), "unsupported float width")),
+        }
+        .map(FixtureValue::Float),
+        FixtureKind::BitsBe(n) => int(io.read_bits_int_be(n).map(i128::from)),
+        FixtureKind::BitsLe(n) => int(io.read_bits_int_le(n).map(i128::from)),
+        FixtureKind::BitsBeSigned(n) => int(io.read_bits_int_be_signed(n).map(i128::from)),
+        FixtureKind::BitsLeSigned(n) => int(io.read_bits_int_le_signed(n).map(i128::from)),
+        FixtureKind::BitsBytesBe(n) => io.read_bits_bytes_be(n).map(FixtureValue::Bytes),
+        FixtureKind::BitsBytesLe(n) => io.read_bits_bytes_le(n).map(FixtureValue::Bytes),
+        FixtureKind::IntArray {
+            width,
+            signed,
+            le,
+            count,
+        } => match (width, signed, le) {
+            (1, false, _) => ints!(u8, LittleEndian, count),
+            (2, false, true) => ints!(u16, LittleEndian, count),
+            (2, false, false) => ints!(u16, BigEndian, count),
+            (4, false, true) => ints!(u32, LittleEndian, count),
+            (4, false, false) => ints!(u32, BigEndian, count),
+            (8, false, true) => ints!(u64, LittleEndian, count),
+            (8, false, false) => ints!(u64, BigEndian, count),
+            (1, true, _) => ints!(i8, LittleEndian, count),
+            (2, true, true) => ints!(i16, LittleEndian, count),
+            (2, true, false) => ints!(i16, BigEndian, count),
+            (4, true, true) => ints!(i32, LittleEndian, count),
+            (4, true, false) => ints!(i32, BigEndian, count),
+            (8, true, true) => ints!(i64, LittleEndian, count),
+            (8, true, false) => ints!(i64, BigEndian, count),
+            _ => Err(KError::invalid_input(io.pos(), "unsupported integer width")),
+        },
+        FixtureKind::BitsArray { width, count, le } => match le {
+            true => io.read_bits_array_le(width, count),
+            false => io.read_bits_array_be(width, count),
+        }
+        .map(|v| FixtureValue::Ints(v.into_iter().map(i128::from).collect())),
+        FixtureKind::Bytes(len) | FixtureKind::Pad(len) => {
+            io.read_bytes(len).map(FixtureValue::Bytes)
+        }
+        FixtureKind::BytesTerm { term } => io
+            .read_bytes_term(term, false, true, true)
+            .map(FixtureValue::Bytes),
+        FixtureKind::Str { len, ref encoding } => {
+            bytes_to_str(&io.read_bytes(len)?, encoding).map(FixtureValue::Str)
+        }
+        FixtureKind::StrTerm { term, ref encoding } => {
+            bytes_to_str(&io.read_bytes_term(term, false, true, true)?, encoding)
+                .map(FixtureValue::Str)
+        }
+        FixtureKind::StrPascal {
+            prefix,
+            ref encoding,
+        } => match prefix {
+            1 => io.read_str_pascal_u1(encoding),
+            2 => io.read_str_pascal_u2le(encoding),
+            4 => io.read_str_pascal_u4le(encoding),
+            _ => Err(KError::invalid_input(io.pos(), "unsupported length width")),
+        }
+        .map(FixtureValue::Str),
+    }
+}
+
+/// Environment variable which makes [`check_parses_to`] write the expected
+/// dumps instead of comparing with them, e.g.
+/// `KAITAI_REGENERATE=1 cargo test`.
+pub const REGENERATE_VAR: &str = "KAITAI_REGENERATE";
+
+/// Parse `fixture` as a `T` and compare its pretty [`KDebug`](crate::KDebug)
+/// dump with the contents of `expected`. On mismatch the error lists the
+/// differing fields as [`deep_diff`](crate::deep_diff) finds them, with the
+/// expected and the actual value.
+///
+/// With [`REGENERATE_VAR`] set to a non-empty value `expected` is
+/// overwritten with the dump instead.
+pub fn check_parses_to<T: KStruct + KVisit + Any>(
+    fixture: &Path,
+    expected: &Path,
+) -> Result<(), String> {
+    let reader = BytesReader::open(fixture).map_err(|e| format!("{}: {}", fixture.display(), e))?;
+    let parsed = T::read_into::<_, T>(&reader, None, None)
+        .map_err(|e| format!("{}: {:#}", fixture.display(), e))?;
+    let dump = format!("{:#?}\n", parsed.kdebug());
+    let tree = Node::of(KValue::Struct(&*parsed));
+    let regenerate = std::env::var_os(REGENERATE_VAR).is_some_and(|v| !v.is_empty());
+    compare_dump(fixture, expected, &dump, &tree, regenerate)
+}
+
+fn compare_dump(
+    fixture: &Path,
+    expected: &Path,
+    dump: &str,
+    tree: &Node,
+    regenerate: bool,
+) -> Result<(), String> {
+    if regenerate {
+        return fs::write(expected, dump).map_err(|e| format!("{}: {}", expected.display(), e));
+    }
+    let want = fs::read_to_string(expected).map_err(|e| {
+        format!(
+            "{}: {} (set {} to create it)",
+            expected.display(),
+            e,
+            REGENERATE_VAR
+        )
+    })?;
+    if want == dump {
+        return Ok(());
+    }
+    let want = read_dump(&want)
+        .map(|node| typed_like(node, tree))
+        .map_err(|e| format!("{}: {}", expected.display(), e))?;
+    let mut msg = format!(
+        "{} does not parse to {}:\n",
+        fixture.display(),
+        expected.display()
+    );
+    let opts = DiffOptions::default();
+    for entry in diff_trees(&want, tree, &opts) {
+        let path = if entry.path.is_empty() {
+            "(root)"
+        } else {
+            &entry.path
+        };
+        let show = |v: Option<String>| v.unwrap_or_else(|| "nothing".to_string());
+        msg.push_str(&format!(
+            "  {}: expected {}, got {}",
+            path,
+            show(entry.left),
+            show(entry.right)
+        ));
+        if let Some(i) = entry.first_difference {
+            msg.push_str(&format!(" (first difference at byte {})", i));
+        }
+        msg.push('\n');
+    }
+    Err(msg)
+}
+
+/// Panicking version of [`check_parses_to`], for use in tests.
+#[track_caller]
+pub fn assert_parses_to<T: KStruct + KVisit + Any>(fixture: &Path, expected: &Path) {
+    if let Err(msg) = check_parses_to::<T>(fixture, expected) {
+        panic!("{}", msg);
+    }
+}
+
+// the tree of a pretty `KDebug` dump; scalars the dump does not tell apart,
+// like signed and unsigned integers, are fixed up by `typed_like`
+fn read_dump(dump: &str) -> Result<Node, String> {
+    let mut reader = DumpReader { dump, pos: 0 };
+    let node = reader.value()?;
+    reader.skip_space();
+    if reader.pos < dump.len() {
+        return Err(reader.error("text after the dump"));
+    }
+    Ok(node)
+}
+
+struct DumpReader<'a> {
+    dump: &'a str,
+    pos: usize,
+}
+
+impl DumpReader<'_> {
+    fn error(&self, msg: &str) -> String {
+        let line = self.dump[..self.pos].matches('\n').count() + 1;
+        format!("line {}: {}", line, msg)
+    }
+
+    fn rest(&self) -> &str {
+        &self.dump[self.pos..]
+    }
+
+    fn skip_space(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // skip `c` and the space before it, if it comes next
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        let found = self.rest().starts_with(c);
+        if found {
+            self.pos += c.len_utf8();
+        }
+        found
+    }
+
+    // items up to `close`, each followed by a comma in pretty dumps
+    fn items<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut res = vec![];
+        while !self.eat(close) {
+            res.push(item(self)?);
+            if !self.eat(',') && !self.rest().trim_start().starts_with(close) {
+                return Err(self.error("expected a comma"));
+            }
+        }
+        Ok(res)
+    }
+
+    fn value(&mut self) -> Result<Node, String> {
+        self.skip_space();
+        if self.eat('{') {
+            let fields = self.items('}', |r| {
+                r.skip_space();
+                let len = r
+                    .rest()
+                    .find(':')
+                    .ok_or_else(|| r.error("expected a field"))?;
+                let name = r.rest()[..len].to_string();
+                r.pos += len + 1;
+                Ok((name, r.value()?))
+            })?;
+            return Ok(Node::Struct(fields));
+        }
+        if self.eat('[') {
+            // byte arrays are on one line, lists of any items are not
+            let rest = self.rest();
+            if let Some(end) = rest
+                .find(']')
+                .filter(|&i| i > 0 && !rest[..i].contains('\n'))
+            {
+                let bytes =
+                    crate::decode_hex(&rest[..end]).map_err(|e| self.error(&e.to_string()))?;
+                self.pos += end + 1;
+                return Ok(Node::Bytes(bytes));
+            }
+            return self.items(']', Self::value).map(Node::List);
+        }
+        if self.rest().starts_with('"') {
+            return self.string().map(Node::Str);
+        }
+        let len = self
+            .rest()
+            .find(|c: char| c == ',' || c.is_whitespace())
+            .unwrap_or(self.rest().len());
+        let token = &self.rest()[..len];
+        let node = scalar(token).ok_or_else(|| self.error(&format!("unexpected {:?}", token)))?;
+        self.pos += len;
+        Ok(node)
+    }
+
+    // a string as written by `str`'s `Debug`
+    fn string(&mut self) -> Result<String, String> {
+        let mut res = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            let c = match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(res);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('u') => {
+                        let hex: String = chars
+                            .by_ref()
+                            .map(|(_, c)| c)
+                            .skip(1)
+                            .take_while(|&c| c != '}')
+                            .collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error("bad escape"))?
+                    }
+                    Some(c) => c,
+                    None => break,
+                },
+                c => c,
+            };
+            res.push(c);
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+fn scalar(token: &str) -> Option<Node> {
+    Some(match token {
+        "None" => Node::None,
+        "true" => Node::Bool(true),
+        "false" => Node::Bool(false),
+        // integers are never written with a sign
+        "-0" => Node::Float(-0.0),
+        _ if token.ends_with(')') => {
+            let (name, value) = token[..token.len() - 1].split_once('(')?;
+            Node::Enum {
+                name: Some(name.to_string()),
+                value: value.parse().ok()?,
+            }
+        }
+        _ if token.starts_with('-') => match token.parse() {
+            Ok(v) => Node::Int(v),
+            Err(_) => Node::Float(token.parse().ok()?),
+        },
+        _ => match token.parse() {
+            Ok(v) => Node::UInt(v),
+            Err(_) => Node::Float(token.parse().ok()?),
+        },
+    })
+}
+
+// `node` with its scalars of the kind of the values at the same places in
+// `like`, where the dump would have shown both the same
+fn typed_like(node: Node, like: &Node) -> Node {
+    match (node, like) {
+        (Node::UInt(v), Node::Int(_)) => Node::Int(v as i64),
+        (Node::UInt(v), Node::Float(_)) => Node::Float(v as f64),
+        (Node::Int(v), Node::Float(_)) => Node::Float(v as f64),
+        (Node::UInt(v), Node::Enum { name: None, .. }) => Node::Enum {
+            name: None,
+            value: v as i64,
+        },
+        (Node::Int(v), Node::Enum { name: None, .. }) => Node::Enum {
+            name: None,
+            value: v,
+        },
+        (Node::List(items), Node::Bytes(_)) if items.is_empty() => Node::Bytes(vec![]),
+        (Node::List(items), Node::List(like)) => Node::List(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| match like.get(i) {
+                    Some(l) => typed_like(item, l),
+                    None => item,
+                })
+                .collect(),
+        ),
+        (Node::Struct(fields), Node::Struct(like)) => Node::Struct(
+            fields
+                .into_iter()
+                .map(|(name, v)| {
+                    let v = match like.iter().find(|(n, _)| *n == name) {
+                        Some((_, l)) => typed_like(v, l),
+                        None => v,
+                    };
+                    (name, v)
+                })
+                .collect(),
+        ),
+        (node, _) => node,
+    }
+}
+
+/// Deliberately hostile format for fuzzing: every size, count, bit width,
+/// encoding and transformation is taken from the input.
+#[derive(Default, Debug)]
+pub struct FuzzSpec {
+    pub version: RefCell<u8>,
+    pub flags: RefCell<u64>,
+    pub name: RefCell<String>,
+    pub entries: RefCell<Vec<OptRc<FuzzEntry>>>,
+}
+
+impl KStruct for FuzzSpec {
+    type Root = FuzzSpec;
+    type Parent = KStructUnit;
+
+    fn read<S: KStream>(
+        self_rc: &OptRc<Self>,
+        _io: &S,
+        _root: SharedType<Self::Root>,
+        _parent: SharedType<Self::Parent>,
+    ) -> KResult<()> {
+        let version = _io.read_u1()?;
+        *self_rc.version.borrow_mut() = version;
+        *self_rc.flags.borrow_mut() = _io.read_bits_int_be(usize::from(version % 70))?;
+        _io.align_to_byte()?;
+        let label = ["UTF-8", "cp437", "UTF-16LE", "SHIFT_JIS", "bogus"][usize::from(version % 5)];
+        let raw = _io.read_bytes_term(0, false, true, version & 0x80 != 0)?;
+        *self_rc.name.borrow_mut() = bytes_to_str(&bytes_terminate(&raw, b';', false), label)?;
+
+        let count = _io.read_u1()?;
+        let sub = _io.substream(_io.read_u4le()? as usize);
+        for _ in 0..count {
+            let entry = FuzzEntry::read_into::<_, FuzzEntry>(
+                &sub,
+                Some(_root.clone()),
+                Some(SharedType::new(self_rc.try_get()?)),
+            )?;
+            self_rc.entries.borrow_mut().push(entry);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct FuzzEntry {
+    pub kind: RefCell<u8>,
+    pub body: RefCell<Vec<u8>>,
+    pub value: RefCell<i64>,
+}
+
+impl KStruct for FuzzEntry {
+    type Root = FuzzSpec;
+    type Parent = FuzzSpec;
+
+    fn read<S: KStream>(
+        self_rc: &OptRc<Self>,
+        _io: &S,
+        _root: SharedType<Self::Root>,
+        _parent: SharedType<Self::Parent>,
+    ) -> KResult<()> {
+        let kind = _io.read_u1()?;
+        *self_rc.kind.borrow_mut() = kind;
+        let len = _io.read_u2le()?;
+        let raw = _io.read_bytes(len.into())?;
+        let body = match kind % 4 {
+            0 => process_xor_many(&raw, &raw[..raw.len().min(usize::from(kind % 3))]),
+            1 => process_rotate_left(&raw, kind),
+            2 => process_zlib(&raw).map_err(KError::io)?,
+            _ => raw,
+        };
+        *self_rc.body.borrow_mut() = body;
+        let a = i64::from(_io.read_s4le()?);
+        let b = i64::from(_io.read_s1()?);
+        *self_rc.value.borrow_mut() = try_modulo(a, b)?;
+        let version = *_root.get()?.version.borrow();
+        if version & 1 != 0 {
+            _io.read_bits_int_le(usize::from(kind))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BitState, BytesReader, KResult, SourceId};
+
+    #[test]
+    fn reference_bits() {
+        let data = [0b1010_0000, 0xff];
+        assert_eq!(reference_bits_be(&data, 0, 3), Some(0b101));
+        assert_eq!(reference_bits_be(&data, 6, 4), Some(0b0011));
+        assert_eq!(reference_bits_le(&data, 5, 3), Some(0b101));
+        assert_eq!(reference_bits_le(&data, 7, 2), Some(0b11));
+        assert_eq!(reference_bits_be(&data, 10, 7), None);
+        assert_eq!(reference_bits_be(&[], 0, 0), Some(0));
+        assert_eq!(reference_bits_le(&[], usize::MAX, 1), None);
+        assert_eq!(reference_bits_be(&data, usize::MAX, 64), None);
+    }
+
+    #[test]
+    fn reference_term() {
+        let data = [1, 2, 0, 3];
+        assert_eq!(
+            reference_bytes_term(&data, 0, 0, false, true, true),
+            (Ok(vec![1, 2]), 3)
+        );
+        assert_eq!(
+            reference_bytes_term(&data, 0, 0, true, false, true),
+            (Ok(vec![1, 2, 0]), 2)
+        );
+        assert_eq!(
+            reference_bytes_term(&data, 3, 0, false, true, true),
+            (Err(ErrorCategory::Format), 4)
+        );
+    }
+
+    // corrupts every byte at position 5
+    struct Faulty(BytesReader);
+
+    impl KStream for Faulty {
+        fn clone(&self) -> BytesReader {
+            KStream::clone(&self.0)
+        }
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+        fn pos(&self) -> usize {
+            self.0.pos()
+        }
+        fn set_pos(&self, pos: usize) {
+            self.0.set_pos(pos)
+        }
+        fn bit_state(&self) -> BitState {
+            self.0.bit_state()
+        }
+        fn set_bit_state(&self, state: BitState) {
+            self.0.set_bit_state(state)
+        }
+        fn source_id(&self) -> SourceId {
+            self.0.source_id()
+        }
+        fn read_bytes(&self, len: usize) -> KResult<Vec<u8>> {
+            let start = self.pos();
+            let mut res = self.0.read_bytes(len)?;
+            if let Some(b) = 5usize.checked_sub(start).and_then(|i| res.get_mut(i)) {
+                *b ^= 1;
+            }
+            Ok(res)
+        }
+        fn read_bytes_full(&self) -> KResult<Vec<u8>> {
+            self.0.read_bytes_full()
+        }
+    }
+
+    // mostly well-formed input for FuzzSpec, with random contents
+    fn fuzz_input(gen: &mut ScriptGen) -> Vec<u8> {
+        let version = [0, 1, 2, 3, 0x80, 0x81, 0xc0, 0xc1][gen.below(8)];
+        let mut entries = vec![];
+        let count = gen.below(4);
+        for _ in 0..count {
+            let kind = [0, 1, 3, 4, 5, 7, 9, 13][gen.below(8)];
+            let body_len = gen.below(6);
+            let body = gen.data(body_len);
+            entries.push(kind);
+            entries.extend_from_slice(&(body.len() as u16).to_le_bytes());
+            entries.extend(body);
+            entries.extend_from_slice(&(gen.next_u64() as i32).to_le_bytes());
+            entries.push([0, 1, 0xff, 0x80, 7][gen.below(5)]);
+            entries.extend(gen.data(usize::from(kind) / 8 + 1));
+        }
+        let mut res = vec![version];
+        res.extend(gen.data(usize::from(version % 70) / 8 + 1));
+        res.extend(b"name;x\0");
+        res.push(count as u8);
+        let len = (entries.len() + gen.below(3)).saturating_sub(1);
+        res.extend_from_slice(&(len as u32).to_le_bytes());
+        res.extend(entries);
+        res
+    }
+
+    #[test]
+    fn fuzz_spec_never_panics() {
+        let mut gen = ScriptGen::new(27);
+        let mut parsed_entries = 0;
+        for i in 0..4000 {
+            let data = match i % 2 {
+                0 => gen.data(i % 97),
+                _ => fuzz_input(&mut gen),
+            };
+            match crate::parse_bytes_no_panic::<FuzzSpec>(&data) {
+                Ok(spec) => parsed_entries += spec.entries.borrow().len(),
+                Err(KError::Internal { msg }) => panic!("{:02x?}: {}", data, msg),
+                Err(_) => {}
+            }
+        }
+        // the structured half must get past the header often enough to
+        // exercise the entry parsing
+        assert!(parsed_entries > 300);
+    }
+
+    #[test]
+    fn detects_faulty_stream() {
+        let data: Vec<u8> = (0..16).collect();
+        assert!(check_stream_conformance(&Faulty(BytesReader::from(data.clone())), &data).is_err());
+        let err = check_stream_conformance(&BytesReader::from(data.clone()), &data[1..]);
+        assert_eq!(err.unwrap_err(), "size: expected 15, got 16");
+    }
+
+    const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/golden");
+
+    #[derive(Default)]
+    struct Record {
+        version: RefCell<u8>,
+        name: RefCell<String>,
+        values: RefCell<Vec<u16>>,
+    }
+
+    impl KStruct for Record {
+        type Root = Record;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            *self_rc.version.borrow_mut() = _io.read_u1()?;
+            *self_rc.name.borrow_mut() = _io.read_str_pascal_u1("ASCII")?;
+            for _ in 0.._io.read_u1()? {
+                self_rc.values.borrow_mut().push(_io.read_u2le()?);
+            }
+            Ok(())
+        }
+    }
+
+    impl KVisit for Record {
+        fn visit(&self, v: &mut dyn crate::KVisitor) {
+            v.field("version", (&*self.version.borrow()).into());
+            v.field("name", (&*self.name.borrow()).into());
+            v.field("values", crate::KValue::List(&*self.values.borrow()));
+        }
+    }
+
+    fn golden(name: &str) -> std::path::PathBuf {
+        Path::new(GOLDEN).join(name)
+    }
+
+    #[test]
+    fn golden_pass() {
+        assert_parses_to::<Record>(&golden("record.bin"), &golden("record.txt"));
+    }
+
+    #[test]
+    fn golden_mismatch() {
+        let err = check_parses_to::<Record>(&golden("record_changed.bin"), &golden("record.txt"))
+            .unwrap_err();
+        let diff = &err[err.find('\n').unwrap() + 1..];
+        assert_eq!(
+            diff,
+            concat!(
+                "  values[0]: expected 1, got 7\n",
+                "  values[3]: expected nothing, got 4\n",
+            )
+        );
+
+        let err =
+            check_parses_to::<Record>(&golden("record.bin"), &golden("missing.txt")).unwrap_err();
+        assert!(err.contains(REGENERATE_VAR), "{}", err);
+        let err =
+            check_parses_to::<Record>(&golden("record.txt"), &golden("record.txt")).unwrap_err();
+        assert!(err.starts_with(&golden("record.txt").display().to_string()));
+    }
+
+    #[test]
+    fn golden_regenerate() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = dir.path().join("record.txt");
+        let fixture = golden("record.bin");
+        let empty = Node::Struct(vec![]);
+        compare_dump(&fixture, &expected, "{}\n", &empty, true).unwrap();
+        assert!(compare_dump(&fixture, &expected, "{}\n", &empty, false).is_ok());
+        let tree = Node::Struct(vec![("x".to_string(), Node::UInt(1))]);
+        assert!(compare_dump(&fixture, &expected, "{x: 1}\n", &tree, false).is_err());
+
+        // a file that is no dump
+        fs::write(&expected, "{\n    x: 1\n    y: 2,\n}\n").unwrap();
+        let err = compare_dump(&fixture, &expected, "{}\n", &empty, false).unwrap_err();
+        assert!(err.ends_with("line 3: expected a comma"), "{}", err);
+    }
+
+    #[derive(Default)]
+    struct Mixed {
+        values: Vec<Node>,
+    }
+
+    impl KVisit for Mixed {
+        fn visit(&self, v: &mut dyn crate::KVisitor) {
+            for (i, node) in self.values.iter().enumerate() {
+                let value = match node {
+                    Node::None => KValue::None,
+                    Node::Bool(b) => KValue::Bool(*b),
+                    Node::Int(n) => KValue::Int(*n),
+                    Node::UInt(n) => KValue::UInt(*n),
+                    Node::Float(x) => KValue::Float(*x),
+                    Node::Bytes(b) => KValue::Bytes(b),
+                    Node::Str(s) => KValue::Str(s),
+                    Node::Enum { name, value } => KValue::Enum {
+                        name: name.as_deref(),
+                        value: *value,
+                    },
+                    _ => unreachable!(),
+                };
+                v.field(&format!("f{}", i), value);
+            }
+        }
+    }
+
+    #[test]
+    fn dumps_read_back() {
+        let mixed = Mixed {
+            values: vec![
+                Node::None,
+                Node::Bool(true),
+                Node::Int(-3),
+                Node::Int(3),
+                Node::UInt(u64::MAX),
+                Node::Float(-0.0),
+                Node::Float(100.0),
+                Node::Float(1.5e-7),
+                Node::Float(f64::NEG_INFINITY),
+                Node::Bytes(vec![]),
+                Node::Bytes(vec![0, 0xab]),
+                Node::Str("a \"quoted\"\n\u{7f}, [tab]\t".to_string()),
+                Node::Enum {
+                    name: Some("Kind::Audio".to_string()),
+                    value: -1,
+                },
+                Node::Enum {
+                    name: None,
+                    value: 7,
+                },
+            ],
+        };
+        let tree = Node::of(KValue::Struct(&mixed));
+        let dump = format!("{:#?}", mixed.kdebug());
+        let read = typed_like(read_dump(&dump).unwrap(), &tree);
+        assert_eq!(diff_trees(&read, &tree, &DiffOptions::default()), []);
+
+        assert_eq!(
+            read_dump("{\n    l: [\n        [],\n        {},\n    ],\n}").unwrap(),
+            Node::Struct(vec![(
+                "l".to_string(),
+                Node::List(vec![Node::List(vec![]), Node::Struct(vec![])])
+            )])
+        );
+        assert_eq!(
+            read_dump("{} x").unwrap_err(),
+            "line 1: text after the dump"
+        );
+        assert!(read_dump("{\n    s: \"open,\n}").is_err());
+    }
+
+    fn every_kind() -> FixtureBuilder {
+        FixtureBuilder::new()
+            .u1(0xfe)
+            .u2le(0x1234)
+            .u2be(0x1234)
+            .u4le(0xdead_beef)
+            .u4be(0xdead_beef)
+            .u8le(u64::MAX - 1)
+            .u8be(0x0102_0304_0506_0708)
+            .s1(-2)
+            .s2le(-300)
+            .s2be(-300)
+            .s4le(i32::MIN)
+            .s4be(-70_000)
+            .s8le(-1)
+            .s8be(i64::MIN + 1)
+            .f4le(1.5)
+            .f4be(-0.25)
+            .f8le(f64::MAX)
+            .f8be(std::f64::consts::PI)
+            .bits_be(3, 0b101)
+            .bits_be(12, 0xabc)
+            .bits_le(5, 0b10011)
+            .bits_le(64, u64::MAX)
+            .bytes(&[1, 2, 3])
+            .bytes_term(b"key", b'=')
+            .str("Grüße", "UTF-8")
+            .str_term("hello", 0, "UTF-8")
+            .str_term("naïve", 0, "windows-1252")
+            .pad(4, 0)
+            .u128le(u128::MAX)
+            .u128be(1 << 100)
+            .s128le(i128::MIN)
+            .s128be(-2)
+            .f2le(0x3c00)
+            .f2be(0xfc00)
+            .bits_be_signed(5, -16)
+            .bits_be_signed(64, i64::MIN)
+            .bits_le_signed(7, -1)
+            .bits_le_signed(3, 3)
+            .bits_bytes_be(70, &[0x3f, 1, 2, 3, 4, 5, 6, 7, 8])
+            .bits_bytes_le(12, &[0x0a, 0xbc])
+            .bits_array_be(3, &[1, 7, 0, 5])
+            .bits_array_le(10, &[1023, 2])
+            .int_array(2, false, true, &[1, 0xffff])
+            .int_array(4, true, false, &[-1, i32::MAX.into()])
+            .int_array(1, true, false, &[-128])
+            .str_pascal_u1("a\0b", "ASCII")
+            .str_pascal_u2le("", "UTF-8")
+            .str_pascal_u4le("é", "UTF-8")
+    }
+
+    #[test]
+    fn fixture_backends() {
+        let (data, expectations) = every_kind().build();
+        assert_eq!(expectations.len(), 48);
+        assert_eq!(data.len(), 243);
+        verify_fixture(&BytesReader::from(data.clone()), &expectations).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.bin");
+        fs::write(&path, &data).unwrap();
+        verify_fixture(&BytesReader::open(&path).unwrap(), &expectations).unwrap();
+
+        // a substream at a different offset of its parent
+        let mut framed = vec![0xaa; 5];
+        framed.extend_from_slice(&data);
+        framed.push(0xaa);
+        let parent = BytesReader::from(framed);
+        parent.seek(5).unwrap();
+        let sub = parent.read_substream(data.len()).unwrap();
+        verify_fixture(&sub, &expectations).unwrap();
+    }
+
+    #[test]
+    fn fixture_layout() {
+        let (data, expectations) = FixtureBuilder::new()
+            .u4le(0xdeadbeef)
+            .bits_be(3, 0b101)
+            .bits_be(6, 0b11_0011)
+            .bits_le(2, 0b01)
+            .str_term("hi", 0, "UTF-8")
+            .pad(2, 0xff)
+            .build();
+        assert_eq!(
+            data,
+            [
+                0xef,
+                0xbe,
+                0xad,
+                0xde,
+                0b1011_1001,
+                0b1000_0000,
+                0b01,
+                b'h',
+                b'i',
+                0,
+                0xff,
+                0xff
+            ]
+        );
+        let offsets: Vec<_> = expectations.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0, 4, 4, 6, 7, 10]);
+        assert_eq!(expectations[1].kind, FixtureKind::BitsBe(3));
+        assert_eq!(expectations[4].value, FixtureValue::Str("hi".into()));
+    }
+
+    #[test]
+    fn fixture_layout_wide() {
+        let (data, expectations) = FixtureBuilder::new()
+            .bits_be_signed(4, -2)
+            .bits_bytes_be(12, &[0x0a, 0xbc])
+            .bits_array_le(4, &[1, 2])
+            .str_pascal_u2le("ab", "ASCII")
+            .f2be(0x3c00)
+            .build();
+        assert_eq!(data, [0xea, 0xbc, 0x21, 2, 0, b'a', b'b', 0x3c, 0]);
+        let offsets: Vec<_> = expectations.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0, 0, 2, 3, 7]);
+        assert_eq!(expectations[0].value, FixtureValue::Int(-2));
+        assert_eq!(expectations[4].value, FixtureValue::Float(1.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit 4 bits")]
+    fn fixture_signed_range() {
+        FixtureBuilder::new().bits_be_signed(4, 8);
+    }
+
+    #[test]
+    fn fixture_detects_faults() {
+        let (data, expectations) = every_kind().build();
+        let err =
+            verify_fixture(&Faulty(BytesReader::from(data.clone())), &expectations).unwrap_err();
+        assert!(
+            err.starts_with("field 3 (Int { width: 4, signed: false, le: true })"),
+            "{}",
+            err
+        );
+
+        let err =
+            verify_fixture(&BytesReader::from(data[..110].to_vec()), &expectations).unwrap_err();
+        assert!(err.starts_with("field 25"), "{}", err);
+        assert!(err.contains("terminator"), "{}", err);
+
+        // a field found at the wrong offset is reported before its value
+        let mut shifted = expectations.clone();
+        shifted[2].offset += 1;
+        let err = verify_fixture(&BytesReader::from(data), &shifted).unwrap_err();
+        assert!(err.contains("expected at offset 4, got 3"), "{}", err);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{KDebug, OptRc};
+
+/// Value of a single field, as reported to a [`KVisitor`].
+#[derive(Clone, Copy)]
+#[non_exhaustive]
+pub enum KValue<'a> {
+    /// Absent value, e.g. a field skipped by its `if` condition.
+    None,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bytes(&'a [u8]),
+    Str(&'a str),
+    /// Enum value with the name of its variant, if it is a known one.
+    Enum {
+        name: Option<&'a str>,
+        value: i64,
+    },
+    Struct(&'a dyn KVisit),
+    List(&'a dyn KVisitList),
+}
+
+/// Reflection over the fields of a parsed struct. Implementations report
+/// every field in declaration order and must not report `_root`/`_parent`
+/// links, so that traversals terminate.
+pub trait KVisit {
+    fn visit(&self, v: &mut dyn KVisitor);
+
+    /// `Debug` rendering with configurable size limits, see [`KDebug`].
+    fn kdebug(&self) -> KDebug<'_, Self>
+    where
+        Self: Sized,
+    {
+        KDebug::new(self)
+    }
+}
+
+/// Receives the fields of a struct, see [`KVisit`].
+pub trait KVisitor {
+    fn field(&mut self, name: &str, value: KValue<'_>);
+}
+
+/// Repeated field, see [`KValue::List`].
+pub trait KVisitList {
+    fn len(&self) -> usize;
+    fn item(&self, i: usize) -> KValue<'_>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> KVisitList for Vec<T>
+where
+    for<'a> &'a T: Into<KValue<'a>>,
+{
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn item(&self, i: usize) -> KValue<'_> {
+        (&self[i]).into()
+    }
+}
+
+impl<T: KVisit> KVisit for OptRc<T> {
+    fn visit(&self, v: &mut dyn KVisitor) {
+        if let Some(rc) = self.get_value() {
+            rc.visit(v);
+        }
+    }
+}
+
+impl<'a, T: KVisit> From<&'a OptRc<T>> for KValue<'a> {
+    fn from(v: &'a OptRc<T>) -> Self {
+        match v.get_value() {
+            Some(rc) => KValue::Struct(&**rc),
+            None => KValue::None,
+        }
+    }
+}
+
+macro_rules! kvalue_from {
+    ($variant:ident, $target:ty, $($t:ty),+) => {$(
+        impl<'a> From<&'a $t> for KValue<'a> {
+            fn from(v: &'a $t) -> Self {
+                KValue::$variant(*v as $target)
+            }
+        }
+    )+};
+}
+
+kvalue_from!(Int, i64, i8, i16, i32, i64);
+kvalue_from!(UInt, u64, u8, u16, u32, u64, usize);
+kvalue_from!(Float, f64, f32, f64);
+kvalue_from!(Bool, bool, bool);
+
+impl<'a> From<&'a Vec<u8>> for KValue<'a> {
+    fn from(v: &'a Vec<u8>) -> Self {
+        KValue::Bytes(v)
+    }
+}
+
+impl<'a> From<&'a String> for KValue<'a> {
+    fn from(v: &'a String) -> Self {
+        KValue::Str(v)
+    }
+}
+
+/// Owned copy of a visited tree, convenient for algorithms that need to walk
+/// two trees side by side.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Node {
+    None,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    Str(String),
+    Enum { name: Option<String>, value: i64 },
+    Struct(Vec<(String, Node)>),
+    List(Vec<Node>),
+}
+
+impl Node {
+    pub(crate) fn of(value: KValue<'_>) -> Node {
+        match value {
+            KValue::None => Node::None,
+            KValue::Bool(v) => Node::Bool(v),
+            KValue::Int(v) => Node::Int(v),
+            KValue::UInt(v) => Node::UInt(v),
+            KValue::Float(v) => Node::Float(v),
+            KValue::Bytes(v) => Node::Bytes(v.to_vec()),
+            KValue::Str(v) => Node::Str(v.to_string()),
+            KValue::Enum { name, value } => Node::Enum {
+                name: name.map(str::to_string),
+                value,
+            },
+            KValue::Struct(s) => {
+                let mut fields = Fields(vec![]);
+                s.visit(&mut fields);
+                Node::Struct(fields.0)
+            }
+            KValue::List(l) => Node::List((0..l.len()).map(|i| Node::of(l.item(i))).collect()),
+        }
+    }
+}
+
+struct Fields(Vec<(String, Node)>);
+
+impl KVisitor for Fields {
+    fn field(&mut self, name: &str, value: KValue<'_>) {
+        self.0.push((name.to_string(), Node::of(value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Point {
+        x: i32,
+        y: u8,
+        tags: Vec<String>,
+    }
+
+    impl KVisit for Point {
+        fn visit(&self, v: &mut dyn KVisitor) {
+            v.field("x", (&self.x).into());
+            v.field("y", (&self.y).into());
+            v.field("tags", KValue::List(&self.tags));
+        }
+    }
+
+    #[test]
+    fn collect_nodes() {
+        let p = OptRc::from(Point {
+            x: -1,
+            y: 2,
+            tags: vec!["a".to_string()],
+        });
+        assert_eq!(
+            Node::of((&p).into()),
+            Node::Struct(vec![
+                ("x".to_string(), Node::Int(-1)),
+                ("y".to_string(), Node::UInt(2)),
+                (
+                    "tags".to_string(),
+                    Node::List(vec![Node::Str("a".to_string())])
+                ),
+            ])
+        );
+        assert_eq!(Node::of((&OptRc::<Point>::default()).into()), Node::None);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{
+    archive::{corrupt, corrupt_if_eof},
+    session::check_alloc,
+    BytesReader, KError, KResult, KStream, ParseSession,
+};
+use flate2::read::DeflateDecoder;
+use std::{convert::TryFrom, fs::File, io::Read, path::Path};
+
+const EOCD_SIG: u32 = 0x0605_4b50;
+const CENTRAL_SIG: u32 = 0x0201_4b50;
+const LOCAL_SIG: u32 = 0x0403_4b50;
+const EOCD_LEN: usize = 22;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+impl BytesReader {
+    /// Open the member `member_name` of the zip archive at `path` as a
+    /// stream. Stored members are read directly from the archive file,
+    /// deflated ones are decompressed into memory. The stream is named
+    /// `<path>/<member_name>`.
+    ///
+    /// Fails with [`KError::ArchiveMemberNotFound`] if there is no such
+    /// member and with [`KError::CorruptArchive`] if the archive cannot be
+    /// read, including deflated data longer than the size the archive
+    /// declares. Zip64 archives, encryption and compression methods other
+    /// than deflate are not supported.
+    pub fn open_zip_member<P: AsRef<Path>>(path: P, member_name: &str) -> KResult<BytesReader> {
+        open_member(path.as_ref(), member_name, None)
+    }
+}
+
+impl ParseSession {
+    /// [`BytesReader::open_zip_member`] with the session. A deflated member
+    /// is only decompressed if its declared size is within
+    /// [`max_alloc`](Self::max_alloc).
+    pub fn reader_from_zip_member<P: AsRef<Path>>(
+        &self,
+        path: P,
+        member_name: &str,
+    ) -> KResult<BytesReader> {
+        Ok(open_member(path.as_ref(), member_name, self.max_alloc())?.with_session(self))
+    }
+}
+
+fn open_member(path: &Path, member_name: &str, max_alloc: Option<usize>) -> KResult<BytesReader> {
+    let archive = BytesReader::open(path)?;
+    let entry = find_member(&archive, member_name)?;
+    let data_pos = data_offset(&archive, &entry)?;
+    let name = format!("{}/{}", path.display(), member_name);
+    let range = BytesReader::from_file_range(File::open(path)?, data_pos, entry.compressed_size)
+        .map_err(|e| corrupt_if_eof(e, data_pos))?
+        .with_name(&name);
+    match entry.method {
+        STORED => Ok(range),
+        DEFLATED => {
+            let range = match max_alloc {
+                Some(limit) => range.with_max_alloc(limit),
+                None => range,
+            };
+            check_alloc(&range, usize::try_from(entry.size).unwrap_or(usize::MAX))?;
+            // a byte more than declared tells a lying header from a
+            // member of the declared size
+            let mut buf = vec![];
+            range
+                .with_inner(|inner| {
+                    DeflateDecoder::new(inner)
+                        .take(entry.size + 1)
+                        .read_to_end(&mut buf)
+                })?
+                .map_err(|e| corrupt(data_pos, e.to_string()))?;
+            if buf.len() as u64 > entry.size {
+                return Err(corrupt(
+                    data_pos,
+                    format!(
+                        "deflated data longer than the declared {} bytes",
+                        entry.size
+                    ),
+                ));
+            }
+            if buf.len() as u64 != entry.size {
+                return Err(corrupt(data_pos, "wrong uncompressed size"));
+            }
+            Ok(BytesReader::from(buf).with_name(&name).processed())
+        }
+        m => Err(KError::invalid_input(
+            entry.header as usize,
+            format!("unsupported compression method {}", m),
+        )),
+    }
+}
+
+struct Entry {
+    method: u16,
+    compressed_size: u64,
+    size: u64,
+    // offset of the local header
+    header: u64,
+}
+
+fn find_eocd(io: &BytesReader) -> KResult<usize> {
+    let size = io.size();
+    // the record is followed by a comment of up to 64 KiB
+    let start = size.saturating_sub(EOCD_LEN + 0xffff);
+    io.seek(start)?;
+    let tail = io.read_bytes(size - start)?;
+    (0..tail.len().saturating_sub(EOCD_LEN - 1))
+        .rev()
+        .find(|&i| tail[i..i + 4] == EOCD_SIG.to_le_bytes())
+        .map(|i| start + i)
+        .ok_or_else(|| corrupt(size as u64, "end of central directory not found"))
+}
+
+fn find_member(io: &BytesReader, name: &str) -> KResult<Entry> {
+    let eocd = find_eocd(io)?;
+    io.seek(eocd + 10)?;
+    let count = io.read_u2le()?;
+    io.skip(4)?;
+    let cd_offset = io.read_u4le()?;
+    io.seek(cd_offset as usize)?;
+    for _ in 0..count {
+        let pos = io.pos() as u64;
+        let res = (|| {
+            if io.read_u4le()? != CENTRAL_SIG {
+                return Err(corrupt(pos, "bad central directory signature"));
+            }
+            io.skip(6)?;
+            let method = io.read_u2le()?;
+            io.skip(8)?;
+            let compressed_size = io.read_u4le()?.into();
+            let size = io.read_u4le()?.into();
+            let name_len = io.read_u2le()?.into();
+            let extra_len: usize = io.read_u2le()?.into();
+            let comment_len: usize = io.read_u2le()?.into();
+            io.skip(8)?;
+            let header = io.read_u4le()?.into();
+            let entry_name = io.read_bytes(name_len)?;
+            io.skip(extra_len + comment_len)?;
+            Ok((
+                entry_name,
+                Entry {
+                    method,
+                    compressed_size,
+                    size,
+                    header,
+                },
+            ))
+        })()
+        .map_err(|e| corrupt_if_eof(e, pos))?;
+        if res.0 == name.as_bytes() {
+            return Ok(res.1);
+        }
+    }
+    Err(KError::ArchiveMemberNotFound {
+        name: name.to_string(),
+    })
+}
+
+fn data_offset(io: &BytesReader, entry: &Entry) -> KResult<u64> {
+    let res = (|| {
+        io.seek(entry.header as usize)?;
+        if io.read_u4le()? != LOCAL_SIG {
+            return Err(corrupt(entry.header, "bad local header signature"));
+        }
+        io.skip(22)?;
+        let name_len = u64::from(io.read_u2le()?);
+        let extra_len = u64::from(io.read_u2le()?);
+        Ok(entry.header + 30 + name_len + extra_len)
+    })();
+    res.map_err(|e| corrupt_if_eof(e, entry.header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/members.zip");
+
+    #[test]
+    fn stored_member() {
+        let r = BytesReader::open_zip_member(FIXTURE, "stored.bin").unwrap();
+        assert_eq!(r.size(), 256);
+        assert!(r.translate_to_root(0).is_some());
+        r.seek(0x10).unwrap();
+        assert_eq!(r.read_u4be().unwrap(), 0x1011_1213);
+        assert_eq!(r.read_bytes_full().unwrap().len(), 236);
+    }
+
+    #[test]
+    fn deflated_member() {
+        let r = BytesReader::open_zip_member(FIXTURE, "dir/deflated.txt").unwrap();
+        let text = String::from_utf8(r.read_bytes_full().unwrap()).unwrap();
+        assert_eq!(text, "kaitai struct\n".repeat(100));
+        assert!(r.translate_to_root(0).is_none());
+        assert_eq!(
+            r.name().unwrap().as_ref(),
+            format!("{}/dir/deflated.txt", FIXTURE)
+        );
+    }
+
+    #[test]
+    fn missing_member() {
+        assert_eq!(
+            BytesReader::open_zip_member(FIXTURE, "nope").unwrap_err(),
+            KError::ArchiveMemberNotFound {
+                name: "nope".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn corrupt_archive() {
+        let data = std::fs::read(FIXTURE).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // cut off the end of central directory record
+        let path = dir.path().join("cut.zip");
+        std::fs::write(&path, &data[..data.len() - 30]).unwrap();
+        let err = BytesReader::open_zip_member(&path, "stored.bin").unwrap_err();
+        assert!(matches!(err, KError::CorruptArchive { .. }), "{:?}", err);
+
+        // break the deflate stream
+        let mut broken = data.clone();
+        let deflated = data
+            .windows(16)
+            .position(|w| w == b"dir/deflated.txt")
+            .unwrap();
+        for b in &mut broken[deflated + 16..deflated + 24] {
+            *b = 0xff;
+        }
+        let path = dir.path().join("broken.zip");
+        std::fs::write(&path, &broken).unwrap();
+        let err = BytesReader::open_zip_member(&path, "dir/deflated.txt").unwrap_err();
+        assert_eq!(err.category(), crate::ErrorCategory::Format);
+    }
+
+    #[test]
+    fn deflated_size_limits() {
+        let data = std::fs::read(FIXTURE).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let name = b"dir/deflated.txt";
+        let size = "kaitai struct\n".len() * 100;
+
+        // declared larger than the session allows
+        let session = ParseSession::builder().max_alloc(size - 1).build();
+        assert_eq!(
+            session
+                .reader_from_zip_member(FIXTURE, "dir/deflated.txt")
+                .unwrap_err(),
+            KError::AllocationLimitExceeded {
+                requested: size,
+                limit: size - 1
+            }
+        );
+        let session = ParseSession::builder().max_alloc(size).build();
+        let r = session
+            .reader_from_zip_member(FIXTURE, "dir/deflated.txt")
+            .unwrap();
+        assert_eq!(r.size(), size);
+        assert!(r.session().is_some());
+
+        // the central directory declares fewer bytes than the data holds
+        let mut lying = data.clone();
+        let central = data.windows(name.len()).rposition(|w| w == name).unwrap() - 46;
+        lying[central + 24..central + 28].copy_from_slice(&100u32.to_le_bytes());
+        let path = dir.path().join("lying.zip");
+        std::fs::write(&path, &lying).unwrap();
+        let err = BytesReader::open_zip_member(&path, "dir/deflated.txt").unwrap_err();
+        assert!(
+            matches!(&err, KError::CorruptArchive { msg, .. } if msg.contains("longer")),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Fixed-width reads must not allocate.
+
+use kaitai::*;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[test]
+fn million_u4le() {
+    const N: u32 = 1_000_000;
+    let data: Vec<u8> = (0..N).flat_map(u32::to_le_bytes).collect();
+    let reader = BytesReader::from(data);
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut sum = 0u64;
+    for _ in 0..N {
+        sum += u64::from(reader.read_u4le().unwrap());
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    assert_eq!(sum, u64::from(N) * u64::from(N - 1) / 2);
+    // the test harness may allocate a little concurrently
+    assert!(allocations < 100, "{} allocations", allocations);
+}
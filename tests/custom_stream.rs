@@ -0,0 +1,137 @@
+//! A stream implemented outside the crate, using only the public API.
+
+use kaitai::*;
+use std::{cell::RefCell, rc::Rc};
+
+struct SliceStream {
+    data: Rc<Vec<u8>>,
+    state: RefCell<ReaderState>,
+    identified: bool,
+}
+
+impl SliceStream {
+    fn new(data: Vec<u8>) -> Self {
+        SliceStream {
+            data: Rc::new(data),
+            state: RefCell::new(ReaderState::new()),
+            identified: true,
+        }
+    }
+
+    // a stream that keeps the default, unknown source id
+    fn anonymous(data: Vec<u8>) -> Self {
+        SliceStream {
+            identified: false,
+            ..SliceStream::new(data)
+        }
+    }
+}
+
+impl KStream for SliceStream {
+    fn clone(&self) -> BytesReader {
+        let reader = BytesReader::from(self.data.to_vec());
+        reader.set_pos(self.pos());
+        reader.set_bit_state(self.bit_state());
+        reader
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn pos(&self) -> usize {
+        self.state.borrow().pos()
+    }
+
+    fn set_pos(&self, pos: usize) {
+        self.state.borrow_mut().set_pos(pos);
+    }
+
+    fn bit_state(&self) -> BitState {
+        self.state.borrow().bit_state()
+    }
+
+    fn set_bit_state(&self, state: BitState) {
+        self.state.borrow_mut().set_bit_state(state);
+    }
+
+    fn source_id(&self) -> SourceId {
+        if self.identified {
+            SourceId::new(&self.data)
+        } else {
+            SourceId::unknown()
+        }
+    }
+
+    fn read_bytes(&self, len: usize) -> KResult<Vec<u8>> {
+        let available = self.size().saturating_sub(self.pos());
+        if len > available {
+            return Err(KError::eof(len, available));
+        }
+        let start = self.pos();
+        self.advance(len);
+        Ok(self.data[start..start + len].to_vec())
+    }
+
+    fn read_bytes_full(&self) -> KResult<Vec<u8>> {
+        self.read_bytes(self.size().saturating_sub(self.pos()))
+    }
+}
+
+#[test]
+fn primitives() {
+    let io = SliceStream::new(vec![0x12, 0x34, 0xab, 0xcd, 0x61, 0x62, 0x00, 0xff]);
+    assert_eq!(io.read_u2be().unwrap(), 0x1234);
+    assert_eq!(io.read_bits_int_be(4).unwrap(), 0xa);
+    assert_eq!(io.bits_remaining_in_buffer(), 4);
+    assert_eq!(io.read_bits_int_be(12).unwrap(), 0xbcd);
+    assert_eq!(io.read_bytes_term(0, false, true, true).unwrap(), b"ab");
+    assert!(!io.is_eof());
+    assert_eq!(io.read_u1().unwrap(), 0xff);
+    assert!(io.is_eof());
+    assert!(io.read_u1().unwrap_err().is_eof());
+}
+
+#[test]
+fn snapshots_and_clones() {
+    let io = SliceStream::new(vec![1, 2, 3, 4]);
+    io.read_bits_int_le(3).unwrap();
+    let snap = io.snapshot();
+    assert_eq!(io.read_u2le().unwrap(), 0x0302);
+    io.restore(&snap).unwrap();
+    assert_eq!(io.bits_remaining_in_buffer(), 5);
+
+    let clone = io.clone();
+    assert_eq!(clone.pos(), io.pos());
+    assert_eq!(clone.bit_state(), io.bit_state());
+    assert_eq!(io.substream(2).read_bytes_full().unwrap(), [2, 3]);
+    // a snapshot does not apply to a different source
+    assert_eq!(clone.restore(&snap), Err(KError::SnapshotMismatch));
+}
+
+#[test]
+fn snapshots_without_identity() {
+    let io = SliceStream::anonymous(vec![1, 2, 3, 4]);
+    let other = SliceStream::anonymous(vec![5, 6]);
+    other.read_u1().unwrap();
+    // neither stream can prove the snapshot is its own
+    assert_eq!(io.restore(&other.snapshot()), Err(KError::SnapshotMismatch));
+    assert_eq!(io.restore(&io.snapshot()), Err(KError::SnapshotMismatch));
+    assert_eq!(io.pos(), 0);
+}
+
+#[test]
+fn trait_objects() {
+    let readers: Vec<Box<dyn KStream>> = vec![
+        Box::new(SliceStream::new(vec![0x12, 0x34, 0x56, 0x78, 0xf0])),
+        Box::new(BytesReader::from(vec![0x12, 0x34, 0x56, 0x78, 0xf0])),
+    ];
+    for io in &readers {
+        assert_eq!(io.read_u2le().unwrap(), 0x3412);
+        assert_eq!(io.read_u2be().unwrap(), 0x5678);
+        assert_eq!(io.read_bits_int_be(4).unwrap(), 0xf);
+        assert_eq!(KStream::clone(io.as_ref()).pos(), 5);
+        io.seek(1).unwrap();
+        assert_eq!(io.substream(2).read_bytes_full().unwrap(), [0x34, 0x56]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Every public helper on empty input, zero lengths and zero counts. None
+//! of them may panic; the expected results below are their defined
+//! behavior.
+
+use kaitai::*;
+
+fn empty() -> BytesReader {
+    BytesReader::from(vec![])
+}
+
+fn eof<T: std::fmt::Debug>(res: KResult<T>) {
+    let err = res.unwrap_err();
+    assert!(err.is_eof(), "{:?}", err);
+}
+
+#[test]
+fn byte_helpers() {
+    assert_eq!(bytes_strip_right(&[], 0), b"");
+    assert_eq!(bytes_strip_right(&[0, 0], 0), b"");
+    assert_eq!(bytes_terminate(&[], 0, false), b"");
+    assert_eq!(bytes_terminate(&[], 0, true), b"");
+    assert_eq!(bytes_terminate(&[0], 0, true), [0]);
+    assert_eq!(process_xor_one(&[], 0xff), b"");
+    assert_eq!(process_xor_many(&[], &[]), b"");
+    assert_eq!(process_xor_many(&[1, 2], &[]), [1, 2]);
+    assert_eq!(process_rotate_left(&[], 3), b"");
+    // rotating by a multiple of 8 leaves bytes unchanged
+    assert_eq!(process_rotate_left(&[0x81], 0), [0x81]);
+    assert_eq!(process_rotate_left(&[0x81], 8), [0x81]);
+    assert_eq!(process_rotate_left(&[0x81], 255), [0xc0]);
+    // an empty zlib stream is truncated, not empty data
+    assert!(process_zlib(&[]).is_err());
+    assert_eq!(format_bytes_hex(&[], 0), "");
+    assert_eq!(format_bytes_escaped(&[], 0), "");
+    assert_eq!(HexDump::new(&[]).to_string(), "");
+}
+
+#[test]
+fn text_helpers() {
+    assert_eq!(bytes_to_str(&[], "UTF-8").unwrap(), "");
+    assert_eq!(bytes_to_str(&[], "cp437").unwrap(), "");
+    assert_eq!(decode_string(&[], "UTF-16LE").unwrap(), "");
+    let decoded = decode_string_with_handler(&[], "ASCII", &mut |_, _| None).unwrap();
+    assert_eq!((decoded.text.as_str(), decoded.substitutions), ("", 0));
+    // an unknown label is an error even without bytes to decode
+    assert!(bytes_to_str(&[], "").is_err());
+    assert!(decode_string(&[], "").is_err());
+    assert!(!is_encoding_supported(""));
+    assert_eq!(reverse_string("").unwrap(), "");
+    assert_eq!(decode_hex("").unwrap(), b"");
+    assert_eq!(decode_hex(" _ ").unwrap(), b"");
+    assert_eq!(decode_base64("").unwrap(), b"");
+    assert_eq!(format_float(0.0), "0");
+    assert_eq!(format_float_f32(-0.0), "-0");
+}
+
+#[test]
+fn numeric_helpers() {
+    assert_eq!(to_len(0).unwrap(), 0);
+    assert_eq!(to_pos(0).unwrap(), 0);
+    assert_eq!(to_u64_nonneg(0).unwrap(), 0);
+    assert_eq!(checked_sub_len(0, 0).unwrap(), 0);
+    assert!(checked_sub_len(0, 1).is_err());
+    // `modulo` panics on 0 by contract, `try_modulo` is the checked form
+    assert!(try_modulo(0, 0).is_err());
+    assert_eq!(histogram_bucket(0), 0);
+}
+
+#[test]
+fn zero_length_reads() {
+    let io = empty();
+    assert_eq!(io.size(), 0);
+    assert!(io.is_eof());
+    assert!(io.is_eof_bits());
+    assert_eq!(io.read_bytes(0).unwrap(), b"");
+    assert_eq!(io.read_bytes_full().unwrap(), b"");
+    io.read_bytes_into(&mut []).unwrap();
+    assert_eq!(io.read_bytes_array::<0>().unwrap(), []);
+    assert_eq!(io.read_bytes_array_at::<0>(0).unwrap(), []);
+    assert_eq!(io.peek_bytes(0).unwrap(), b"");
+    assert_eq!(io.read_bits_int_be(0).unwrap(), 0);
+    assert_eq!(io.read_bits_int_le(0).unwrap(), 0);
+    assert_eq!(io.read_bits_array_le(7, 0).unwrap(), []);
+    assert_eq!(io.read_int_array::<u32, BigEndian>(0).unwrap(), []);
+    assert_eq!(io.read_f8le_array(0).unwrap(), []);
+    io.skip(0).unwrap();
+    io.seek_relative(0).unwrap();
+    io.check_len(0).unwrap();
+    io.align_to_byte().unwrap();
+    assert_eq!(io.pos(), 0);
+
+    // the terminator is missing from empty data
+    assert_eq!(io.read_bytes_term(0, false, true, false).unwrap(), b"");
+    assert!(io.read_bytes_term(0, false, true, true).is_err());
+    assert!(io.read_str_c("ASCII").is_err());
+    assert_eq!(io.scan_for(b"", None).unwrap(), Some(0));
+    assert_eq!(io.scan_for(b"x", None).unwrap(), None);
+    assert_eq!(io.scan_for(b"x", Some(0)).unwrap(), None);
+
+    let sub = io.substream(0);
+    assert!(sub.is_eof());
+    assert_eq!(io.try_substream(0).unwrap().size(), 0);
+    assert_eq!(io.read_substream(0).unwrap().size(), 0);
+    assert_eq!(
+        BytesReader::from(vec![0])
+            .read_str_pascal_u1("ASCII")
+            .unwrap(),
+        ""
+    );
+}
+
+#[test]
+fn reads_past_the_end_fail() {
+    let io = empty();
+    eof(io.read_u1());
+    eof(io.read_s8le());
+    eof(io.read_f4be());
+    eof(io.read_bytes(1));
+    eof(io.read_bits_int_be(1));
+    eof(io.read_bits_array_le(1, 1));
+    eof(io.read_int_array::<u16, LittleEndian>(1));
+    eof(io.read_bytes_array::<1>());
+    eof(io.read_mac());
+    eof(io.try_substream(1));
+    eof(io.read_substream(1));
+    eof(io.skip(1));
+    eof(io.read_str_pascal_u1("ASCII"));
+    eof(io.read_str_pascal_u4le("ASCII"));
+    assert!(io.peek_u1().is_err());
+    assert!(io.seek_relative(1).is_err());
+    assert!(io.seek_relative(-1).is_err());
+    assert!(BytesReader::default().read_u1().unwrap_err().is_eof());
+    assert_eq!(io.pos(), 0);
+}
+
+#[test]
+fn repeats_and_collections() {
+    let io = empty();
+    let parse = |_: &BytesReader, _| -> KResult<OptRc<KStructUnit>> {
+        unreachable!("no elements to parse")
+    };
+    assert!(read_repeat_expr(&io, 0, parse).unwrap().is_empty());
+    assert!(read_repeat_eos(&io, parse).unwrap().is_empty());
+    assert_eq!(
+        iter_repeat_eos::<KStructUnit, _>(&io, None, None).count(),
+        0
+    );
+    let map = collect_by_key(&[], DuplicatePolicy::KeepFirst, |_: &KStructUnit| 0);
+    assert!(map.is_empty());
+}
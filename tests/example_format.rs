@@ -0,0 +1,513 @@
+//! A small format written by hand the way the compiler generates code for
+//! it, parsed from `fixtures/example/example.bin`. It goes through the
+//! runtime's plumbing for nested types, root and parent links, enums, bit
+//! fields, sized substreams, positioned instances and parameters:
+//!
+//! ```yaml
+//! meta:
+//!   id: example
+//!   endian: le
+//! seq:
+//!   - id: magic
+//!     contents: ["EXF", 1]
+//!   - id: header
+//!     type: header
+//!   - id: body_len
+//!     type: u2
+//!   - id: body
+//!     type: body
+//!     size: body_len
+//!   - id: chunk
+//!     type: chunk(header.chunk_len)
+//! instances:
+//!   trailer:
+//!     pos: header.trailer_ofs
+//!     type: u4be
+//! types:
+//!   header:
+//!     seq:
+//!       - { id: kind, type: u1, enum: kind }
+//!       - { id: version, type: b4 }
+//!       - { id: flags, type: b3 }
+//!       - { id: compressed, type: b1 }
+//!       - { id: chunk_len, type: u1 }
+//!       - { id: trailer_ofs, type: u2 }
+//!   body:
+//!     seq:
+//!       - { id: name, type: strz, encoding: UTF-8 }
+//!       - { id: values, type: u2be, repeat: eos }
+//!   chunk:
+//!     params:
+//!       - { id: len, type: u1 }
+//!     seq:
+//!       - { id: data, size: len }
+//!     instances:
+//!       is_audio:
+//!         value: _parent.header.kind == kind::audio
+//! enums:
+//!   kind:
+//!     1: image
+//!     2: audio
+//! ```
+
+#![allow(non_camel_case_types)]
+
+use kaitai::*;
+use std::{
+    cell::{Cell, Ref, RefCell},
+    convert::{TryFrom, TryInto},
+    rc::Rc,
+};
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/example/example.bin"
+);
+
+#[derive(Default, Debug, Clone)]
+pub struct Example {
+    pub _root: SharedType<Example>,
+    pub _parent: SharedType<KStructUnit>,
+    pub _self: SharedType<Self>,
+    magic: RefCell<Vec<u8>>,
+    header: RefCell<OptRc<Example_Header>>,
+    body_len: RefCell<u16>,
+    body: RefCell<OptRc<Example_Body>>,
+    chunk: RefCell<OptRc<Example_Chunk>>,
+    _io: RefCell<BytesReader>,
+    f_trailer: Cell<bool>,
+    trailer: RefCell<u32>,
+}
+
+impl KStruct for Example {
+    type Root = Example;
+    type Parent = KStructUnit;
+
+    fn read<S: KStream>(
+        self_rc: &OptRc<Self>,
+        _io: &S,
+        _root: SharedType<Self::Root>,
+        _parent: SharedType<Self::Parent>,
+    ) -> KResult<()> {
+        *self_rc._io.borrow_mut() = _io.clone();
+        self_rc._root.set(_root.get());
+        self_rc._parent.set(_parent.get());
+        self_rc._self.set(Ok(self_rc.clone()));
+        let _rrc = self_rc._root.get_value().borrow().upgrade();
+        let _prc = self_rc._parent.get_value().borrow().upgrade();
+        let _r = _rrc.as_ref().unwrap();
+        *self_rc.magic.borrow_mut() = _io.read_bytes(4_usize)?;
+        if *self_rc.magic() != [0x45, 0x58, 0x46, 0x01] {
+            return Err(KError::ValidationFailed(ValidationFailedError {
+                kind: ValidationKind::NotEqual,
+                src_path: "/seq/0".to_string(),
+            }));
+        }
+        let t = Self::read_into::<_, Example_Header>(
+            _io,
+            Some(self_rc._root.clone()),
+            Some(self_rc._self.clone()),
+        )?;
+        *self_rc.header.borrow_mut() = t;
+        *self_rc.body_len.borrow_mut() = _io.read_u2le()?;
+        let _io_body = _io.read_substream(*self_rc.body_len() as usize)?;
+        let t = Self::read_into::<_, Example_Body>(
+            &_io_body,
+            Some(self_rc._root.clone()),
+            Some(self_rc._self.clone()),
+        )?;
+        *self_rc.body.borrow_mut() = t;
+        let chunk_len = *self_rc.header().chunk_len();
+        let f = |t: &mut Example_Chunk| {
+            t.set_params(chunk_len);
+            Ok(())
+        };
+        let t = Self::read_into_with_init::<_, Example_Chunk>(
+            _io,
+            Some(self_rc._root.clone()),
+            Some(self_rc._self.clone()),
+            &f,
+        )?;
+        *self_rc.chunk.borrow_mut() = t;
+        Ok(())
+    }
+}
+
+impl Example {
+    pub fn trailer(&self) -> KResult<Ref<'_, u32>> {
+        let _io = self._io.borrow();
+        let _rrc = self._root.get_value().borrow().upgrade();
+        let _prc = self._parent.get_value().borrow().upgrade();
+        let _r = _rrc.as_ref().unwrap();
+        if self.f_trailer.get() {
+            return Ok(self.trailer.borrow());
+        }
+        self.f_trailer.set(true);
+        let _pos = _io.pos();
+        _io.seek(*self.header().trailer_ofs() as usize)?;
+        *self.trailer.borrow_mut() = _io.read_u4be()?;
+        _io.seek(_pos)?;
+        Ok(self.trailer.borrow())
+    }
+}
+
+impl Example {
+    pub fn magic(&self) -> Ref<'_, Vec<u8>> {
+        self.magic.borrow()
+    }
+    pub fn header(&self) -> Ref<'_, OptRc<Example_Header>> {
+        self.header.borrow()
+    }
+    pub fn body_len(&self) -> Ref<'_, u16> {
+        self.body_len.borrow()
+    }
+    pub fn body(&self) -> Ref<'_, OptRc<Example_Body>> {
+        self.body.borrow()
+    }
+    pub fn chunk(&self) -> Ref<'_, OptRc<Example_Chunk>> {
+        self.chunk.borrow()
+    }
+    pub fn _io(&self) -> Ref<'_, BytesReader> {
+        self._io.borrow()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Example_Kind {
+    Image,
+    Audio,
+    Unknown(i64),
+}
+
+impl TryFrom<i64> for Example_Kind {
+    type Error = KError;
+    fn try_from(flag: i64) -> KResult<Example_Kind> {
+        match flag {
+            1 => Ok(Example_Kind::Image),
+            2 => Ok(Example_Kind::Audio),
+            _ => Ok(Example_Kind::Unknown(flag)),
+        }
+    }
+}
+
+impl From<&Example_Kind> for i64 {
+    fn from(v: &Example_Kind) -> Self {
+        match *v {
+            Example_Kind::Image => 1,
+            Example_Kind::Audio => 2,
+            Example_Kind::Unknown(v) => v,
+        }
+    }
+}
+
+impl Default for Example_Kind {
+    fn default() -> Self {
+        Example_Kind::Unknown(0)
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Example_Header {
+    pub _root: SharedType<Example>,
+    pub _parent: SharedType<Example>,
+    pub _self: SharedType<Self>,
+    kind: RefCell<Example_Kind>,
+    version: RefCell<u64>,
+    flags: RefCell<u64>,
+    compressed: RefCell<bool>,
+    chunk_len: RefCell<u8>,
+    trailer_ofs: RefCell<u16>,
+    _io: RefCell<BytesReader>,
+}
+
+impl KStruct for Example_Header {
+    type Root = Example;
+    type Parent = Example;
+
+    fn read<S: KStream>(
+        self_rc: &OptRc<Self>,
+        _io: &S,
+        _root: SharedType<Self::Root>,
+        _parent: SharedType<Self::Parent>,
+    ) -> KResult<()> {
+        *self_rc._io.borrow_mut() = _io.clone();
+        self_rc._root.set(_root.get());
+        self_rc._parent.set(_parent.get());
+        self_rc._self.set(Ok(self_rc.clone()));
+        let _rrc = self_rc._root.get_value().borrow().upgrade();
+        let _prc = self_rc._parent.get_value().borrow().upgrade();
+        let _r = _rrc.as_ref().unwrap();
+        *self_rc.kind.borrow_mut() = (_io.read_u1()? as i64).try_into()?;
+        *self_rc.version.borrow_mut() = _io.read_bits_int_be(4)?;
+        *self_rc.flags.borrow_mut() = _io.read_bits_int_be(3)?;
+        *self_rc.compressed.borrow_mut() = _io.read_bits_int_be(1)? != 0;
+        _io.align_to_byte()?;
+        *self_rc.chunk_len.borrow_mut() = _io.read_u1()?;
+        *self_rc.trailer_ofs.borrow_mut() = _io.read_u2le()?;
+        Ok(())
+    }
+}
+
+impl Example_Header {
+    pub fn kind(&self) -> Ref<'_, Example_Kind> {
+        self.kind.borrow()
+    }
+    pub fn version(&self) -> Ref<'_, u64> {
+        self.version.borrow()
+    }
+    pub fn flags(&self) -> Ref<'_, u64> {
+        self.flags.borrow()
+    }
+    pub fn compressed(&self) -> Ref<'_, bool> {
+        self.compressed.borrow()
+    }
+    pub fn chunk_len(&self) -> Ref<'_, u8> {
+        self.chunk_len.borrow()
+    }
+    pub fn trailer_ofs(&self) -> Ref<'_, u16> {
+        self.trailer_ofs.borrow()
+    }
+    pub fn _io(&self) -> Ref<'_, BytesReader> {
+        self._io.borrow()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Example_Body {
+    pub _root: SharedType<Example>,
+    pub _parent: SharedType<Example>,
+    pub _self: SharedType<Self>,
+    name: RefCell<String>,
+    values: RefCell<Vec<u16>>,
+    _io: RefCell<BytesReader>,
+}
+
+impl KStruct for Example_Body {
+    type Root = Example;
+    type Parent = Example;
+
+    fn read<S: KStream>(
+        self_rc: &OptRc<Self>,
+        _io: &S,
+        _root: SharedType<Self::Root>,
+        _parent: SharedType<Self::Parent>,
+    ) -> KResult<()> {
+        *self_rc._io.borrow_mut() = _io.clone();
+        self_rc._root.set(_root.get());
+        self_rc._parent.set(_parent.get());
+        self_rc._self.set(Ok(self_rc.clone()));
+        let _rrc = self_rc._root.get_value().borrow().upgrade();
+        let _prc = self_rc._parent.get_value().borrow().upgrade();
+        let _r = _rrc.as_ref().unwrap();
+        *self_rc.name.borrow_mut() =
+            bytes_to_str(&_io.read_bytes_term(0, false, true, true)?, "UTF-8")?;
+        *self_rc.values.borrow_mut() = Vec::new();
+        {
+            let mut _i = 0;
+            while !_io.is_eof() {
+                self_rc.values.borrow_mut().push(_io.read_u2be()?);
+                _i += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Example_Body {
+    pub fn name(&self) -> Ref<'_, String> {
+        self.name.borrow()
+    }
+    pub fn values(&self) -> Ref<'_, Vec<u16>> {
+        self.values.borrow()
+    }
+    pub fn _io(&self) -> Ref<'_, BytesReader> {
+        self._io.borrow()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Example_Chunk {
+    pub _root: SharedType<Example>,
+    pub _parent: SharedType<Example>,
+    pub _self: SharedType<Self>,
+    len: RefCell<u8>,
+    data: RefCell<Vec<u8>>,
+    _io: RefCell<BytesReader>,
+    f_is_audio: Cell<bool>,
+    is_audio: RefCell<bool>,
+}
+
+impl KStruct for Example_Chunk {
+    type Root = Example;
+    type Parent = Example;
+
+    fn read<S: KStream>(
+        self_rc: &OptRc<Self>,
+        _io: &S,
+        _root: SharedType<Self::Root>,
+        _parent: SharedType<Self::Parent>,
+    ) -> KResult<()> {
+        *self_rc._io.borrow_mut() = _io.clone();
+        self_rc._root.set(_root.get());
+        self_rc._parent.set(_parent.get());
+        self_rc._self.set(Ok(self_rc.clone()));
+        let _rrc = self_rc._root.get_value().borrow().upgrade();
+        let _prc = self_rc._parent.get_value().borrow().upgrade();
+        let _r = _rrc.as_ref().unwrap();
+        *self_rc.data.borrow_mut() = _io.read_bytes(*self_rc.len() as usize)?;
+        Ok(())
+    }
+}
+
+impl Example_Chunk {
+    pub fn len(&self) -> Ref<'_, u8> {
+        self.len.borrow()
+    }
+}
+
+impl Example_Chunk {
+    pub fn set_params(&mut self, len: u8) {
+        *self.len.borrow_mut() = len;
+    }
+}
+
+impl Example_Chunk {
+    pub fn is_audio(&self) -> KResult<Ref<'_, bool>> {
+        let _io = self._io.borrow();
+        let _rrc = self._root.get_value().borrow().upgrade();
+        let _prc = self._parent.get_value().borrow().upgrade();
+        let _r = _rrc.as_ref().unwrap();
+        if self.f_is_audio.get() {
+            return Ok(self.is_audio.borrow());
+        }
+        self.f_is_audio.set(true);
+        *self.is_audio.borrow_mut() =
+            *_prc.as_ref().unwrap().header().kind() == Example_Kind::Audio;
+        Ok(self.is_audio.borrow())
+    }
+}
+
+impl Example_Chunk {
+    pub fn data(&self) -> Ref<'_, Vec<u8>> {
+        self.data.borrow()
+    }
+    pub fn _io(&self) -> Ref<'_, BytesReader> {
+        self._io.borrow()
+    }
+}
+
+fn parse(data: Vec<u8>) -> KResult<OptRc<Example>> {
+    let io = BytesReader::from(data);
+    Example::read_into::<_, Example>(&io, None, None)
+}
+
+#[test]
+fn fields() {
+    let io = BytesReader::open(FIXTURE).unwrap();
+    let example = Example::read_into::<_, Example>(&io, None, None).unwrap();
+    assert_eq!(*example.magic(), b"EXF\x01");
+
+    let header = example.header();
+    assert_eq!(*header.kind(), Example_Kind::Audio);
+    assert_eq!(i64::from(&*header.kind()), 2);
+    assert_eq!(*header.version(), 3);
+    assert_eq!(*header.flags(), 5);
+    assert!(*header.compressed());
+    assert_eq!(*header.chunk_len(), 3);
+    assert_eq!(*header.trailer_ofs(), 29);
+
+    assert_eq!(*example.body_len(), 10);
+    let body = example.body();
+    assert_eq!(*body.name(), "wav");
+    assert_eq!(*body.values(), [0x0102, 0x0304, 0x0506]);
+    // the body has a stream of its own, positioned from 0
+    assert_eq!(body._io().size(), 10);
+    assert_eq!(body._io().read_bytes(3).unwrap(), b"wav");
+
+    let chunk = example.chunk();
+    assert_eq!(*chunk.len(), 3);
+    assert_eq!(*chunk.data(), [0xaa, 0xbb, 0xcc]);
+    assert!(*chunk.is_audio().unwrap());
+
+    // reading the instance leaves the stream where it was
+    assert_eq!(io.pos(), 24);
+    let pos = example._io().pos();
+    assert_eq!(*example.trailer().unwrap(), 0xdeadbeef);
+    assert_eq!(example._io().pos(), pos);
+    assert_eq!(*example.trailer().unwrap(), 0xdeadbeef);
+}
+
+#[test]
+fn links() {
+    let example = parse(std::fs::read(FIXTURE).unwrap()).unwrap();
+    let root = example.get();
+    // the root is its own root and has no parent
+    assert!(Rc::ptr_eq(&example._root.get().unwrap().get(), &root));
+    assert!(example._parent.is_empty());
+    assert!(Rc::ptr_eq(&example._self.get().unwrap().get(), &root));
+
+    let header = example.header();
+    assert!(Rc::ptr_eq(&header._root.get().unwrap().get(), &root));
+    assert!(Rc::ptr_eq(&header._parent.get().unwrap().get(), &root));
+    assert!(Rc::ptr_eq(
+        &header._self.get().unwrap().get(),
+        &header.get()
+    ));
+    let body = example.body();
+    assert!(Rc::ptr_eq(&body._root.get().unwrap().get(), &root));
+    assert!(Rc::ptr_eq(&body._parent.get().unwrap().get(), &root));
+    let chunk = OptRc::clone(&example.chunk());
+    assert!(Rc::ptr_eq(&chunk._root.get().unwrap().get(), &root));
+    assert!(Rc::ptr_eq(&chunk._parent.get().unwrap().get(), &root));
+
+    // links are weak: with the root gone, instances cannot reach it
+    drop(header);
+    drop(body);
+    drop(example);
+    drop(root);
+    assert_eq!(chunk._parent.get().unwrap_err(), KError::MissingParent);
+}
+
+#[test]
+fn faults() {
+    let data = std::fs::read(FIXTURE).unwrap();
+
+    let mut bad_magic = data.clone();
+    bad_magic[3] = 2;
+    assert!(matches!(
+        parse(bad_magic).unwrap_err(),
+        KError::ValidationFailed(ValidationFailedError {
+            kind: ValidationKind::NotEqual,
+            ..
+        })
+    ));
+
+    let mut unknown_kind = data.clone();
+    unknown_kind[4] = 9;
+    let example = parse(unknown_kind).unwrap();
+    assert_eq!(*example.header().kind(), Example_Kind::Unknown(9));
+    assert!(!*example.chunk().is_audio().unwrap());
+
+    // a body longer than the data
+    let mut long_body = data.clone();
+    long_body[9] = 40;
+    assert!(parse(long_body).unwrap_err().is_eof());
+
+    // values overrunning the body stop at its end, not at the chunk
+    let mut short_body = data.clone();
+    short_body[9] = 8;
+    let example = parse(short_body).unwrap();
+    assert_eq!(*example.body().values(), [0x0102, 0x0304]);
+    assert_eq!(*example.chunk().data(), [0x05, 0x06, 0xaa]);
+
+    // the parameter sizes the chunk
+    let mut long_chunk = data.clone();
+    long_chunk[6] = 8;
+    let example = parse(long_chunk).unwrap();
+    assert_eq!(*example.chunk().data(), [0xaa, 0xbb, 0xcc, 0, 0, 0, 0, 0]);
+
+    let mut far_trailer = data;
+    far_trailer[7] = 31;
+    let example = parse(far_trailer).unwrap();
+    assert!(example.trailer().unwrap_err().is_eof());
+}